@@ -0,0 +1,237 @@
+//! Throughput benchmarks
+//!
+//! the request parser, the router and a whole request, timed and compared
+//! with a JSON baseline so a change that slows the hot path shows up
+//!
+//! The parser and router are crate-private, out of reach of a `benches/`
+//! target, so the suite is an ignored unit test run in an optimized build:
+//!
+//! ```text
+//! cargo test --release --lib bench -- --ignored --nocapture
+//! ```
+//!
+//! Each case's requests per second are compared with `benches/baseline.json`
+//! and the run fails when one fell more than `AEGIS_BENCH_THRESHOLD` percent
+//! (20 by default) below it. `AEGIS_BENCH_SAVE=1` records the numbers as the
+//! new baseline instead.
+
+use std::collections::BTreeMap;
+use std::env;
+use std::fs;
+use std::hint::black_box;
+use std::io;
+use std::mem::MaybeUninit;
+use std::path::PathBuf;
+use std::time::{Duration, Instant};
+
+use bytes::BytesMut;
+
+use crate::http::h2::Detached;
+use crate::request::request::{decode, BodyState, Endpoints, Request, MAX_HEADERS};
+use crate::router::route_matcher::{RouteMatcher, RoutePolicy};
+use crate::server::config::ServerConfig;
+use crate::server::server::Server;
+use crate::test::TestClient;
+use crate::Response;
+
+// how long each case is timed for, after as long again to warm up
+const RUN: Duration = Duration::from_secs(1);
+const THRESHOLD: f64 = 20.0;
+
+const TARGET: &str = "/orgs/acme/users/7?fields=name";
+const REQUEST: &[u8] = b"GET /orgs/acme/users/7?fields=name HTTP/1.1\r\n\
+    Host: api.example.com\r\n\
+    User-Agent: bench/1.0\r\n\
+    Accept: application/json\r\n\
+    Accept-Encoding: gzip, br\r\n\
+    Accept-Language: en\r\n\
+    Connection: keep-alive\r\n\
+    Cookie: session=0123456789abcdef\r\n\r\n";
+
+// a small API's routes, with the one `TARGET` matches last
+fn routes() -> Vec<(&'static str, String)> {
+    let mut routes = Vec::new();
+    for resource in ["users", "orgs", "repos", "issues", "teams"] {
+        routes.push(("GET", format!("/{}", resource)));
+        routes.push(("POST", format!("/{}", resource)));
+        routes.push(("GET", format!("/{}/:id", resource)));
+        routes.push(("PUT", format!("/{}/:id", resource)));
+        routes.push(("DELETE", format!("/{}/:id", resource)));
+    }
+    routes.push(("GET", "/orgs/:org/users/:id".to_owned()));
+    routes
+}
+
+// calls of `f` per second, timed over `RUN`
+fn per_second(mut f: impl FnMut()) -> f64 {
+    let mut rate = 0.0;
+    // the first round only warms up
+    for _ in 0..2 {
+        let (start, mut calls) = (Instant::now(), 0u64);
+        while start.elapsed() < RUN {
+            for _ in 0..64 {
+                f();
+            }
+            calls += 64;
+        }
+        rate = calls as f64 / start.elapsed().as_secs_f64();
+    }
+    rate
+}
+
+fn parser(config: &ServerConfig) -> f64 {
+    let mut req_buf = BytesMut::new();
+    per_second(|| {
+        req_buf.clear();
+        req_buf.extend_from_slice(REQUEST);
+        // the header slots the connection loop keeps on its stack
+        let mut headers = [MaybeUninit::uninit(); MAX_HEADERS];
+        let mut state = BodyState::default();
+        let mut detached = Detached;
+        let req = decode(
+            &mut headers,
+            &mut req_buf,
+            &mut detached,
+            &mut state,
+            config,
+            Endpoints::default(),
+        );
+        assert!(matches!(req, Ok(Some(_))));
+        black_box(req).ok();
+    })
+}
+
+fn router(config: &ServerConfig) -> f64 {
+    let mut matcher = RouteMatcher::new();
+    for (method, path) in routes() {
+        matcher.add_route(method, &path, Box::new(|_, _| Ok(())));
+    }
+    let mut headers = [MaybeUninit::uninit(); MAX_HEADERS];
+    let mut req_buf = BytesMut::from(REQUEST);
+    let mut state = BodyState::default();
+    let mut detached = Detached;
+    let req = decode(
+        &mut headers,
+        &mut req_buf,
+        &mut detached,
+        &mut state,
+        config,
+        Endpoints::default(),
+    );
+    let req = req.ok().flatten().unwrap();
+    per_second(|| {
+        let matched = matcher.match_route(&req, RoutePolicy::default());
+        assert!(black_box(matched).is_some());
+    })
+}
+
+fn ok(_: Request, res: &mut Response) -> io::Result<()> {
+    res.send("ok")
+}
+
+fn end_to_end() -> f64 {
+    let mut server = Server::new();
+    for (method, path) in routes() {
+        match method {
+            "GET" => server.get(&path, ok),
+            "POST" => server.post(&path, ok),
+            "PUT" => server.put(&path, ok),
+            _ => server.delete(&path, ok),
+        };
+    }
+    let client = TestClient::new(&server);
+    per_second(|| {
+        let res = client
+            .get(TARGET)
+            .header("Accept", "application/json")
+            .send();
+        assert_eq!(black_box(res).status(), 200);
+    })
+}
+
+fn baseline_path() -> PathBuf {
+    PathBuf::from(env!("CARGO_MANIFEST_DIR")).join("benches/baseline.json")
+}
+
+// the cases more than `threshold` percent slower than the baseline, with
+// their baseline and current rates; cases new to either side are skipped
+fn regressions(
+    baseline: &BTreeMap<String, f64>,
+    current: &BTreeMap<String, f64>,
+    threshold: f64,
+) -> Vec<(String, f64, f64)> {
+    current
+        .iter()
+        .filter_map(|(name, &now)| {
+            let &then = baseline.get(name)?;
+            (now < then * (1.0 - threshold / 100.0)).then(|| (name.clone(), then, now))
+        })
+        .collect()
+}
+
+#[test]
+fn regressions_are_the_cases_past_the_threshold() {
+    let rates = |pairs: &[(&str, f64)]| {
+        pairs
+            .iter()
+            .map(|&(name, rate)| (name.to_owned(), rate))
+            .collect::<BTreeMap<_, _>>()
+    };
+    let baseline = rates(&[("parser", 1000.0), ("router", 1000.0), ("gone", 5.0)]);
+    let current = rates(&[("parser", 810.0), ("router", 790.0), ("new", 1.0)]);
+    assert_eq!(
+        regressions(&baseline, &current, 20.0),
+        [("router".to_owned(), 1000.0, 790.0)]
+    );
+    assert!(regressions(&baseline, &current, 25.0).is_empty());
+}
+
+// one test, so the cases don't compete with each other for the CPU
+#[test]
+#[ignore = "benchmark; run with --release -- --ignored"]
+fn throughput_against_baseline() {
+    let config = ServerConfig::default();
+    let mut current = BTreeMap::new();
+    current.insert("parser".to_owned(), parser(&config));
+    current.insert("router".to_owned(), router(&config));
+    current.insert("end_to_end".to_owned(), end_to_end());
+    for (name, rate) in &current {
+        println!("{:>10}: {:>12.0} per second", name, rate);
+    }
+    if cfg!(debug_assertions) {
+        println!("unoptimized build, not compared with the baseline");
+        return;
+    }
+
+    let path = baseline_path();
+    if env::var_os("AEGIS_BENCH_SAVE").is_some() {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, serde_json::to_string_pretty(&current).unwrap()).unwrap();
+        println!("saved as the baseline in {}", path.display());
+        return;
+    }
+    let baseline: BTreeMap<String, f64> = match fs::read_to_string(&path) {
+        Ok(json) => serde_json::from_str(&json).unwrap(),
+        Err(_) => {
+            println!(
+                "no baseline in {}; AEGIS_BENCH_SAVE=1 records one",
+                path.display()
+            );
+            return;
+        }
+    };
+    let threshold = env::var("AEGIS_BENCH_THRESHOLD")
+        .ok()
+        .and_then(|t| t.parse().ok())
+        .unwrap_or(THRESHOLD);
+    let slower = regressions(&baseline, &current, threshold);
+    for (name, then, now) in &slower {
+        println!("{} regressed: {:.0} -> {:.0} per second", name, then, now);
+    }
+    assert!(
+        slower.is_empty(),
+        "{} case(s) more than {}% below the baseline",
+        slower.len(),
+        threshold
+    );
+}
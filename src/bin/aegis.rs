@@ -0,0 +1,496 @@
+//! serves a directory, or proxies to another server, from the command line
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::path::{Path, PathBuf};
+use std::process;
+use std::sync::Arc;
+use std::{env, io};
+
+use aegis_server::{
+    date, ClientResponse, HttpClient, ResponseCache, SeekableBody, Server, StatusCode,
+};
+use bytes::Bytes;
+use may::sync::Mutex;
+use serde_json::Value;
+
+const USAGE: &str = "usage: aegis [--config FILE] [--listen ADDR] [--workers N] \
+                     (--dir PATH | --proxy http://HOST[:PORT] [--no-cache])
+
+  --config FILE   JSON object with any of listen, workers, dir, proxy and
+                  cache; flags override it
+  --listen ADDR   address to serve on, 127.0.0.1:8080 by default
+  --workers N     worker threads
+  --dir PATH      serve the files under PATH, index.html for directories
+  --proxy URL     pass every request on to URL, keeping what it sends for
+                  reuse while fresh and revalidation after
+  --no-cache      keep nothing the proxied server sends";
+
+// request headers not passed on to the proxied server
+const HOP_BY_HOP: [&str; 8] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "te",
+    "trailer",
+    "transfer-encoding",
+    "upgrade",
+    "content-length",
+];
+
+// response headers passed back from the proxied server
+const PASSED_BACK: [&str; 10] = [
+    "content-type",
+    "content-encoding",
+    "content-disposition",
+    "cache-control",
+    "etag",
+    "last-modified",
+    "location",
+    "vary",
+    "set-cookie",
+    "expires",
+];
+
+// the client's validators, checked against a kept response instead of
+// passed on when the proxy revalidates its own
+const CONDITIONAL: [&str; 2] = ["if-none-match", "if-modified-since"];
+
+// responses kept for revalidation, and the largest body kept
+const KEPT_MAX: usize = 1024;
+const KEPT_BODY_MAX: usize = 4 * 1024 * 1024;
+
+#[derive(Default)]
+struct Options {
+    listen: Option<String>,
+    workers: Option<usize>,
+    dir: Option<PathBuf>,
+    proxy: Option<String>,
+    no_cache: bool,
+}
+
+// 200s to GETs that carry a validator, by target, so the proxied server
+// is asked whether they changed rather than for them again
+#[derive(Clone, Default)]
+struct Revalidation(Arc<Mutex<HashMap<String, Arc<Kept>>>>);
+
+struct Kept {
+    etag: Option<String>,
+    last_modified: Option<String>,
+    // the ones passed back
+    headers: Vec<(String, String)>,
+    body: Bytes,
+}
+
+fn main() {
+    let options = match parse(env::args().skip(1)) {
+        Ok(options) => options,
+        Err(e) => {
+            eprintln!("aegis: {}\n\n{}", e, USAGE);
+            process::exit(2);
+        }
+    };
+    if let Err(e) = run(options) {
+        eprintln!("aegis: {}", e);
+        process::exit(1);
+    }
+}
+
+fn run(options: Options) -> io::Result<()> {
+    let mut server = Server::new();
+    if let Some(workers) = options.workers {
+        server.workers(workers);
+    }
+    let listen = options
+        .listen
+        .unwrap_or_else(|| "127.0.0.1:8080".to_owned());
+    if options.no_cache && options.proxy.is_none() {
+        return Err(invalid("--no-cache only applies to --proxy"));
+    }
+    match (options.dir, options.proxy) {
+        (Some(dir), None) => {
+            let root = fs::canonicalize(&dir)?;
+            eprintln!("aegis: serving {} on {}", root.display(), listen);
+            serve_dir(&mut server, root);
+        }
+        (None, Some(target)) => {
+            if !target.starts_with("http://") {
+                return Err(invalid("only http:// targets can be proxied to"));
+            }
+            eprintln!("aegis: proxying {} to {}", listen, target);
+            let target = target.trim_end_matches('/').to_owned();
+            proxy(&mut server, target, !options.no_cache);
+        }
+        _ => return Err(invalid("one of --dir and --proxy is required")),
+    }
+    server.listen(&listen)
+}
+
+fn serve_dir(server: &mut Server, root: PathBuf) {
+    for method in ["GET", "HEAD"] {
+        for pattern in ["/", "/*"] {
+            let root = root.clone();
+            server.add_route_handler(method, pattern, move |req, res| {
+                let file = resolve(&root, req.path()).and_then(|path| {
+                    let file = File::open(&path).ok()?;
+                    Some((path, file))
+                });
+                let (path, file) = match file {
+                    Some(found) => found,
+                    None => {
+                        res.not_found();
+                        return Ok(());
+                    }
+                };
+                let mut body = SeekableBody::new(file).content_type(content_type(&path));
+                if let Ok(modified) = fs::metadata(&path).and_then(|meta| meta.modified()) {
+                    body = body.last_modified(modified);
+                }
+                body.respond(&req, res)
+            });
+        }
+    }
+}
+
+// the file under `root` a request path names, `index.html` for a
+// directory; nothing outside `root`, or hidden, is ever named
+fn resolve(root: &Path, target: &str) -> Option<PathBuf> {
+    let path = target.split(['?', '#']).next().unwrap_or("/");
+    let mut resolved = root.to_path_buf();
+    for segment in path.split('/').filter(|s| !s.is_empty()) {
+        let segment = percent_decode(segment)?;
+        if segment.starts_with('.') || segment.contains(['/', '\\', '\0']) {
+            return None;
+        }
+        resolved.push(segment);
+    }
+    if resolved.is_dir() {
+        resolved.push("index.html");
+    }
+    // symlinks may point anywhere
+    let resolved = fs::canonicalize(resolved).ok()?;
+    (resolved.starts_with(root) && resolved.is_file()).then_some(resolved)
+}
+
+fn percent_decode(segment: &str) -> Option<String> {
+    let bytes = segment.as_bytes();
+    let mut decoded = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        if bytes[i] == b'%' {
+            let hex = std::str::from_utf8(bytes.get(i + 1..i + 3)?).ok()?;
+            decoded.push(u8::from_str_radix(hex, 16).ok()?);
+            i += 3;
+        } else {
+            decoded.push(bytes[i]);
+            i += 1;
+        }
+    }
+    String::from_utf8(decoded).ok()
+}
+
+fn content_type(path: &Path) -> &'static str {
+    let extension = path.extension().and_then(|e| e.to_str()).unwrap_or("");
+    match extension.to_ascii_lowercase().as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" => "application/json",
+        "txt" | "md" => "text/plain; charset=utf-8",
+        "xml" => "application/xml",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "ico" => "image/x-icon",
+        "wasm" => "application/wasm",
+        "woff2" => "font/woff2",
+        "pdf" => "application/pdf",
+        _ => "application/octet-stream",
+    }
+}
+
+fn proxy(server: &mut Server, target: String, caching: bool) {
+    let client = HttpClient::new();
+    // fresh responses are answered here; stale ones are revalidated
+    let cache = ResponseCache::new();
+    let revalidation = Revalidation::default();
+    for pattern in ["/", "/*"] {
+        let client = client.clone();
+        let target = target.clone();
+        let revalidation = revalidation.clone();
+        let route = server.add_route_handler("*", pattern, move |mut req, res| {
+            let path = req.path().to_owned();
+            let get = req.method() == "GET";
+            let kept = match get && caching {
+                true => revalidation.get(&path),
+                false => None,
+            };
+            let mut outbound = client.request(req.method(), &format!("{}{}", target, path));
+            for header in req.headers() {
+                let name = header.name.to_ascii_lowercase();
+                if HOP_BY_HOP.contains(&name.as_str()) || name == "host" {
+                    continue;
+                }
+                if kept.is_some() && CONDITIONAL.contains(&name.as_str()) {
+                    continue;
+                }
+                if let Ok(value) = std::str::from_utf8(header.value) {
+                    outbound = outbound.header(header.name, value);
+                }
+            }
+            if let Some(kept) = &kept {
+                if let Some(etag) = &kept.etag {
+                    outbound = outbound.header("If-None-Match", etag);
+                }
+                if let Some(modified) = &kept.last_modified {
+                    outbound = outbound.header("If-Modified-Since", modified);
+                }
+            }
+            if let Some(ip) = req.client_ip() {
+                outbound = outbound.header("X-Forwarded-For", &ip.to_string());
+            }
+            let asked: Vec<Option<String>> = CONDITIONAL
+                .iter()
+                .map(|name| req.header(name).map(str::to_owned))
+                .collect();
+            let credentials =
+                req.header("authorization").is_some() || req.header("cookie").is_some();
+            let body = req.buffer_body()?.to_vec();
+            // interim responses go on to the client as they arrive
+            let answer = outbound.body(body).send_with_interim(|interim| {
+                let status = match StatusCode::from_u16(interim.status()) {
+                    Some(status) => status,
+                    None => return Ok(()),
+                };
+                let headers: Vec<(&str, &str)> = interim
+                    .headers()
+                    .iter()
+                    .filter(|(name, _)| !HOP_BY_HOP.contains(&name.to_ascii_lowercase().as_str()))
+                    .map(|(name, value)| (name.as_str(), value.as_str()))
+                    .collect();
+                req.send_interim(status, &headers)
+            });
+            let answer = match answer {
+                Ok(answer) => answer,
+                Err(e) => {
+                    eprintln!("aegis: no usable answer from {}: {}", target, e);
+                    res.status_code(502, "Bad Gateway");
+                    return Ok(());
+                }
+            };
+            let answer = match (kept, answer.status()) {
+                (Some(kept), 304) => Ok(revalidation.refresh(&path, &kept, &answer)),
+                _ if get && caching => revalidation.update(&path, answer, credentials),
+                _ => Err(answer),
+            };
+            // what's kept goes back, or 304 when the client has it too
+            let answer = match answer {
+                Ok(kept) => {
+                    for (name, value) in &kept.headers {
+                        res.set_header(name, value)?;
+                    }
+                    if kept.unchanged(asked[0].as_deref(), asked[1].as_deref()) {
+                        res.status_code(304, "Not Modified");
+                    } else {
+                        res.status_code(200, "OK");
+                        res.body_segments(vec![kept.body.clone()]);
+                    }
+                    return Ok(());
+                }
+                Err(answer) => answer,
+            };
+            match StatusCode::from_u16(answer.status()) {
+                Some(status) => res.status_code(status.as_u16() as usize, status.reason()),
+                None => res.status_code(502, "Bad Gateway"),
+            };
+            for (name, value) in answer.headers() {
+                if PASSED_BACK.contains(&name.to_ascii_lowercase().as_str()) {
+                    res.set_header(name, value)?;
+                }
+            }
+            res.body_vec(answer.into_body());
+            Ok(())
+        });
+        if caching {
+            route.cache(&cache);
+        }
+    }
+}
+
+impl Revalidation {
+    fn get(&self, target: &str) -> Option<Arc<Kept>> {
+        self.0.lock().unwrap().get(target).cloned()
+    }
+
+    // keep a 200 that can be revalidated, handing back any other answer;
+    // either way what was kept for `target` before is outdated
+    fn update(
+        &self,
+        target: &str,
+        answer: ClientResponse,
+        credentials: bool,
+    ) -> Result<Arc<Kept>, ClientResponse> {
+        let mut kept = self.0.lock().unwrap();
+        if !Kept::allowed(&answer, credentials) {
+            kept.remove(target);
+            return Err(answer);
+        }
+        let passed_back = answer
+            .headers()
+            .iter()
+            .filter(|(name, _)| PASSED_BACK.contains(&name.to_ascii_lowercase().as_str()))
+            .cloned()
+            .collect();
+        let fresh = Arc::new(Kept {
+            etag: answer.header("etag").map(str::to_owned),
+            last_modified: answer.header("last-modified").map(str::to_owned),
+            headers: passed_back,
+            body: Bytes::from(answer.into_body()),
+        });
+        if kept.len() >= KEPT_MAX && !kept.contains_key(target) {
+            // no use is tracked, so any one goes
+            let any = kept.keys().next().cloned();
+            if let Some(any) = any {
+                kept.remove(&any);
+            }
+        }
+        kept.insert(target.to_owned(), Arc::clone(&fresh));
+        Ok(fresh)
+    }
+
+    // a 304 may carry newer headers, which replace the kept ones
+    fn refresh(&self, target: &str, kept: &Kept, answer: &ClientResponse) -> Arc<Kept> {
+        let newer: Vec<(String, String)> = answer
+            .headers()
+            .iter()
+            .filter(|(name, _)| PASSED_BACK.contains(&name.to_ascii_lowercase().as_str()))
+            .cloned()
+            .collect();
+        let mut headers: Vec<(String, String)> = kept
+            .headers
+            .iter()
+            .filter(|(name, _)| !newer.iter().any(|(n, _)| n.eq_ignore_ascii_case(name)))
+            .cloned()
+            .collect();
+        headers.extend(newer);
+        let refreshed = Arc::new(Kept {
+            etag: answer
+                .header("etag")
+                .map(str::to_owned)
+                .or_else(|| kept.etag.clone()),
+            last_modified: answer
+                .header("last-modified")
+                .map(str::to_owned)
+                .or_else(|| kept.last_modified.clone()),
+            headers,
+            body: kept.body.clone(),
+        });
+        let mut all = self.0.lock().unwrap();
+        all.insert(target.to_owned(), Arc::clone(&refreshed));
+        refreshed
+    }
+}
+
+impl Kept {
+    // a 200 with a validator, meant for anyone, and small enough
+    fn allowed(answer: &ClientResponse, credentials: bool) -> bool {
+        let has = |name: &str| {
+            answer
+                .headers()
+                .iter()
+                .any(|(n, _)| n.eq_ignore_ascii_case(name))
+        };
+        let cache_control = answer
+            .headers()
+            .iter()
+            .filter(|(name, _)| name.eq_ignore_ascii_case("cache-control"))
+            .map(|(_, value)| value.to_ascii_lowercase())
+            .collect::<Vec<_>>()
+            .join(",");
+        answer.status() == 200
+            && (has("etag") || has("last-modified"))
+            // the variants of one target can't be told apart
+            && !has("vary")
+            && !has("set-cookie")
+            && !credentials
+            && !cache_control.contains("no-store")
+            && !cache_control.contains("private")
+            && answer.body().len() <= KEPT_BODY_MAX
+    }
+
+    fn unchanged(&self, if_none_match: Option<&str>, if_modified_since: Option<&str>) -> bool {
+        let weak = |tag: &str| tag.trim_start_matches("W/").to_owned();
+        if let Some(tags) = if_none_match {
+            return tags.split(',').map(str::trim).any(|tag| {
+                tag == "*"
+                    || self
+                        .etag
+                        .as_deref()
+                        .map_or(false, |etag| weak(tag) == weak(etag))
+            });
+        }
+        let date = |value: Option<&str>| date::parse(value?);
+        match (date(self.last_modified.as_deref()), date(if_modified_since)) {
+            (Some(modified), Some(since)) => modified <= since,
+            _ => false,
+        }
+    }
+}
+
+fn parse(mut args: impl Iterator<Item = String>) -> io::Result<Options> {
+    let mut options = Options::default();
+    let mut flags = Options::default();
+    while let Some(arg) = args.next() {
+        let mut value = || {
+            args.next()
+                .ok_or_else(|| invalid(&format!("{} needs a value", arg)))
+        };
+        match arg.as_str() {
+            "--config" => options = config_file(Path::new(&value()?))?,
+            "--listen" => flags.listen = Some(value()?),
+            "--workers" => {
+                let workers = value()?;
+                let workers = workers
+                    .parse()
+                    .map_err(|_| invalid(&format!("invalid worker count: {}", workers)))?;
+                flags.workers = Some(workers);
+            }
+            "--dir" => flags.dir = Some(PathBuf::from(value()?)),
+            "--proxy" => flags.proxy = Some(value()?),
+            "--no-cache" => flags.no_cache = true,
+            "-h" | "--help" => {
+                println!("{}", USAGE);
+                process::exit(0);
+            }
+            _ => return Err(invalid(&format!("unknown argument: {}", arg))),
+        }
+    }
+    Ok(Options {
+        listen: flags.listen.or(options.listen),
+        workers: flags.workers.or(options.workers),
+        dir: flags.dir.or(options.dir),
+        proxy: flags.proxy.or(options.proxy),
+        no_cache: flags.no_cache || options.no_cache,
+    })
+}
+
+fn config_file(path: &Path) -> io::Result<Options> {
+    let config: Value = serde_json::from_slice(&fs::read(path)?)?;
+    let string = |key: &str| config.get(key).and_then(Value::as_str).map(str::to_owned);
+    Ok(Options {
+        listen: string("listen"),
+        workers: config
+            .get("workers")
+            .and_then(Value::as_u64)
+            .map(|n| n as usize),
+        dir: string("dir").map(PathBuf::from),
+        proxy: string("proxy"),
+        no_cache: config.get("cache").and_then(Value::as_bool) == Some(false),
+    })
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
@@ -0,0 +1,97 @@
+use std::collections::HashMap;
+use std::io;
+use std::sync::Arc;
+
+use crate::request::headers::preferred_language;
+
+/// Translations of error messages, chosen per request by Accept-Language.
+///
+/// Installed with `Server::error_catalog`, it localizes the problem
+/// documents `HttpError`s are answered with. The title is looked up under
+/// `status.<code>`, e.g. `status.404`, and the detail under itself, so a
+/// handler can return a key such as
+/// `HttpError::NotFound("user.not_found".into())`. A message missing from
+/// the chosen language comes from the default one; one missing from both
+/// is sent as it is.
+#[derive(Clone, Debug)]
+pub struct MessageCatalog {
+    default_language: String,
+    // in the order added, so earlier languages win ties
+    languages: Vec<(String, HashMap<String, String>)>,
+}
+
+impl MessageCatalog {
+    /// An empty catalog answering in `default_language`, e.g. `en`, when
+    /// the client accepts none of the others.
+    pub fn new(default_language: &str) -> Self {
+        MessageCatalog {
+            default_language: default_language.to_owned(),
+            languages: vec![(default_language.to_owned(), HashMap::new())],
+        }
+    }
+
+    pub fn insert(&mut self, language: &str, key: &str, message: &str) -> &mut Self {
+        let index = match self
+            .languages
+            .iter()
+            .position(|(tag, _)| tag.eq_ignore_ascii_case(language))
+        {
+            Some(index) => index,
+            None => {
+                self.languages.push((language.to_owned(), HashMap::new()));
+                self.languages.len() - 1
+            }
+        };
+        self.languages[index]
+            .1
+            .insert(key.to_owned(), message.to_owned());
+        self
+    }
+
+    /// Adds `language`'s messages from a flat JSON object of keys to
+    /// messages, e.g. a file shipped with the application.
+    pub fn insert_json(&mut self, language: &str, json: &str) -> io::Result<&mut Self> {
+        let messages: HashMap<String, String> = serde_json::from_str(json)?;
+        for (key, message) in &messages {
+            self.insert(language, key, message);
+        }
+        Ok(self)
+    }
+
+    /// The message for `key` in `language`, else in the default language.
+    pub fn message(&self, language: &str, key: &str) -> Option<&str> {
+        let lookup = |language: &str| {
+            self.languages
+                .iter()
+                .find(|(tag, _)| tag.eq_ignore_ascii_case(language))
+                .and_then(|(_, messages)| messages.get(key))
+        };
+        lookup(language)
+            .or_else(|| lookup(&self.default_language))
+            .map(String::as_str)
+    }
+
+    // the language to answer in, given the request's Accept-Language
+    fn language(&self, accept_language: Option<&str>) -> &str {
+        let offered: Vec<&str> = self.languages.iter().map(|(tag, _)| tag.as_str()).collect();
+        preferred_language(accept_language, &offered).unwrap_or(&self.default_language)
+    }
+}
+
+// what an error response needs to pick its language, set on the response
+// before the handler runs
+#[derive(Clone)]
+pub(crate) struct Locale {
+    pub(crate) catalog: Arc<MessageCatalog>,
+    pub(crate) accept_language: Option<String>,
+}
+
+impl Locale {
+    pub(crate) fn language(&self) -> &str {
+        self.catalog.language(self.accept_language.as_deref())
+    }
+
+    pub(crate) fn message(&self, language: &str, key: &str) -> Option<&str> {
+        self.catalog.message(language, key)
+    }
+}
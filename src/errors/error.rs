@@ -0,0 +1,175 @@
+//! the crate-wide error, from any of the narrower ones
+
+use std::fmt;
+use std::io;
+use std::num::{ParseFloatError, ParseIntError};
+use std::str::Utf8Error;
+
+use crate::errors::errors::RequestError;
+use crate::errors::http_error::HttpError;
+use crate::errors::validation::ValidationErrors;
+use crate::request::param::ParamError;
+use crate::response::respond::IntoResponse;
+use crate::response::response::Response;
+use crate::response::status::StatusCode;
+
+/// Any way a request can fail, each with the status it's answered with.
+///
+/// Handlers wrapped in `respond` can return it as the `Err` side; plain
+/// handlers return it through `?`, as an `io::Error`, and the server
+/// answers it like an `HttpError`. Failed reads and writes still fail the
+/// request, i.e. a 500 or a dropped connection.
+#[derive(Debug)]
+pub enum Error {
+    Http(HttpError),
+    Request(RequestError),
+    Param(ParamError),
+    Io(io::Error),
+}
+
+/// A `Result` failing with the crate's `Error`.
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+impl Error {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            Error::Http(e) => e.status(),
+            Error::Request(RequestError::Io(_)) | Error::Io(_) => StatusCode::INTERNAL_SERVER_ERROR,
+            Error::Request(
+                RequestError::UnsupportedCharset(_) | RequestError::UnsupportedMediaType(_),
+            ) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            Error::Request(_) | Error::Param(_) => StatusCode::BAD_REQUEST,
+        }
+    }
+
+    // the error as the client is told it, or the I/O error that fails the
+    // request instead
+    pub(crate) fn into_http(self) -> std::result::Result<HttpError, io::Error> {
+        let status = self.status();
+        match self {
+            Error::Http(e) => Ok(e),
+            Error::Request(RequestError::Io(e)) | Error::Io(e) => Err(e),
+            Error::Request(e) if status == StatusCode::UNSUPPORTED_MEDIA_TYPE => {
+                Ok(HttpError::UnsupportedMediaType(e.to_string()))
+            }
+            Error::Request(e) => Ok(HttpError::BadRequest(e.to_string())),
+            Error::Param(e) => Ok(HttpError::from(e)),
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Error::Http(e) => e.fmt(f),
+            Error::Request(e) => e.fmt(f),
+            Error::Param(e) => e.fmt(f),
+            Error::Io(e) => e.fmt(f),
+        }
+    }
+}
+
+impl std::error::Error for Error {
+    fn source(&self) -> Option<&(dyn std::error::Error + 'static)> {
+        match self {
+            Error::Http(e) => Some(e),
+            Error::Request(e) => Some(e),
+            Error::Param(e) => Some(e),
+            Error::Io(e) => Some(e),
+        }
+    }
+}
+
+impl From<HttpError> for Error {
+    fn from(e: HttpError) -> Self {
+        Error::Http(e)
+    }
+}
+
+impl From<RequestError> for Error {
+    fn from(e: RequestError) -> Self {
+        match e {
+            RequestError::Io(e) => Error::from(e),
+            e => Error::Request(e),
+        }
+    }
+}
+
+impl From<ValidationErrors> for Error {
+    fn from(e: ValidationErrors) -> Self {
+        Error::Http(HttpError::Invalid(e))
+    }
+}
+
+impl From<ParamError> for Error {
+    fn from(e: ParamError) -> Self {
+        Error::Param(e)
+    }
+}
+
+impl From<serde_json::Error> for Error {
+    fn from(e: serde_json::Error) -> Self {
+        Error::Request(RequestError::JsonError(e))
+    }
+}
+
+impl From<Utf8Error> for Error {
+    fn from(e: Utf8Error) -> Self {
+        Error::Request(RequestError::Utf8Error(e))
+    }
+}
+
+impl From<ParseIntError> for Error {
+    fn from(e: ParseIntError) -> Self {
+        Error::Http(HttpError::BadRequest(e.to_string()))
+    }
+}
+
+impl From<ParseFloatError> for Error {
+    fn from(e: ParseFloatError) -> Self {
+        Error::Http(HttpError::BadRequest(e.to_string()))
+    }
+}
+
+/// Takes back any of the crate's errors an `io::Error` was made from.
+impl From<io::Error> for Error {
+    fn from(e: io::Error) -> Self {
+        let known = e.get_ref().map_or(false, |inner| {
+            inner.is::<Error>() || inner.is::<HttpError>() || inner.is::<ParamError>()
+        });
+        if !known {
+            return Error::Io(e);
+        }
+        let inner = e.into_inner().unwrap();
+        let inner = match inner.downcast::<Error>() {
+            Ok(e) => return *e,
+            Err(inner) => inner,
+        };
+        match inner.downcast::<HttpError>() {
+            Ok(e) => Error::Http(*e),
+            Err(inner) => Error::Param(*inner.downcast::<ParamError>().unwrap()),
+        }
+    }
+}
+
+impl From<Error> for io::Error {
+    fn from(e: Error) -> Self {
+        match e {
+            Error::Io(e) => e,
+            Error::Param(e) => e.into(),
+            e => io::Error::new(io::ErrorKind::Other, e),
+        }
+    }
+}
+
+impl From<RequestError> for io::Error {
+    fn from(e: RequestError) -> Self {
+        Error::from(e).into()
+    }
+}
+
+impl IntoResponse for Error {
+    fn into_response(self, res: &mut Response) -> io::Result<()> {
+        self.into_http()?.into_response(res)
+    }
+}
@@ -1,5 +1,6 @@
 use serde_json::Error as JsonError;
 use std::fmt;
+use std::io;
 
 use std::str::Utf8Error;
 
@@ -7,6 +8,15 @@ use std::str::Utf8Error;
 pub enum RequestError {
     JsonError(JsonError),
     Utf8Error(Utf8Error),
+    Io(io::Error),
+    UnsupportedCharset(String),
+    UnsupportedMediaType(String),
+    // a JSON body past the server's depth or token limit
+    JsonTooComplex(String),
+    #[cfg(feature = "msgpack")]
+    MsgpackError(rmp_serde::decode::Error),
+    #[cfg(feature = "cbor")]
+    CborError(ciborium::de::Error<io::Error>),
 }
 
 impl fmt::Display for RequestError {
@@ -14,6 +24,18 @@ impl fmt::Display for RequestError {
         match self {
             RequestError::JsonError(e) => write!(f, "JSON Error: {}", e),
             RequestError::Utf8Error(e) => write!(f, "UTF-8 Error: {}", e),
+            RequestError::Io(e) => write!(f, "IO Error: {}", e),
+            RequestError::UnsupportedCharset(charset) => {
+                write!(f, "Unsupported charset: {}", charset)
+            }
+            RequestError::UnsupportedMediaType(media) => {
+                write!(f, "Unsupported media type: {}", media)
+            }
+            RequestError::JsonTooComplex(why) => write!(f, "JSON body too complex: {}", why),
+            #[cfg(feature = "msgpack")]
+            RequestError::MsgpackError(e) => write!(f, "MessagePack Error: {}", e),
+            #[cfg(feature = "cbor")]
+            RequestError::CborError(e) => write!(f, "CBOR Error: {}", e),
         }
     }
 }
@@ -31,3 +53,23 @@ impl From<Utf8Error> for RequestError {
         RequestError::Utf8Error(e)
     }
 }
+
+impl From<io::Error> for RequestError {
+    fn from(e: io::Error) -> Self {
+        RequestError::Io(e)
+    }
+}
+
+#[cfg(feature = "msgpack")]
+impl From<rmp_serde::decode::Error> for RequestError {
+    fn from(e: rmp_serde::decode::Error) -> Self {
+        RequestError::MsgpackError(e)
+    }
+}
+
+#[cfg(feature = "cbor")]
+impl From<ciborium::de::Error<io::Error>> for RequestError {
+    fn from(e: ciborium::de::Error<io::Error>) -> Self {
+        RequestError::CborError(e)
+    }
+}
@@ -0,0 +1,177 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::time::Duration;
+
+use crate::errors::error;
+use crate::errors::validation::ValidationErrors;
+use crate::request::param::ParamError;
+use crate::response::respond::IntoResponse;
+use crate::response::response::Response;
+use crate::response::status::StatusCode;
+
+/// A domain error for the client, answered with its status, any headers
+/// it implies and an RFC 9457 `application/problem+json` body, localized
+/// when the server has a `MessageCatalog`.
+///
+/// Handlers wrapped in `respond` can return it as the `Err` side; plain
+/// handlers return it through `?`, as an `io::Error`, and the server
+/// answers it instead of with a 500.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum HttpError {
+    BadRequest(String),
+    Unauthorized(String),
+    Forbidden(String),
+    NotFound(String),
+    Conflict(String),
+    UnsupportedMediaType(String),
+    Unprocessable(String),
+    /// 422 with each invalid field's messages.
+    Invalid(ValidationErrors),
+    /// 429 with Retry-After.
+    RateLimited {
+        retry_after: Duration,
+    },
+    /// 503, with Retry-After when it's known.
+    Unavailable {
+        retry_after: Option<Duration>,
+    },
+    /// Any other status, with a detail message.
+    Status(StatusCode, String),
+}
+
+impl HttpError {
+    pub fn status(&self) -> StatusCode {
+        match self {
+            HttpError::BadRequest(_) => StatusCode::BAD_REQUEST,
+            HttpError::Unauthorized(_) => StatusCode::UNAUTHORIZED,
+            HttpError::Forbidden(_) => StatusCode::FORBIDDEN,
+            HttpError::NotFound(_) => StatusCode::NOT_FOUND,
+            HttpError::Conflict(_) => StatusCode::CONFLICT,
+            HttpError::UnsupportedMediaType(_) => StatusCode::UNSUPPORTED_MEDIA_TYPE,
+            HttpError::Unprocessable(_) | HttpError::Invalid(_) => StatusCode::UNPROCESSABLE_ENTITY,
+            HttpError::RateLimited { .. } => StatusCode::TOO_MANY_REQUESTS,
+            HttpError::Unavailable { .. } => StatusCode::SERVICE_UNAVAILABLE,
+            HttpError::Status(status, _) => *status,
+        }
+    }
+
+    /// The message for the client, if there's one.
+    pub fn detail(&self) -> Option<&str> {
+        let detail = match self {
+            HttpError::BadRequest(detail)
+            | HttpError::Unauthorized(detail)
+            | HttpError::Forbidden(detail)
+            | HttpError::NotFound(detail)
+            | HttpError::Conflict(detail)
+            | HttpError::UnsupportedMediaType(detail)
+            | HttpError::Unprocessable(detail)
+            | HttpError::Status(_, detail) => detail,
+            HttpError::Invalid(_) => return Some("request has invalid fields"),
+            HttpError::RateLimited { .. } | HttpError::Unavailable { .. } => return None,
+        };
+        (!detail.is_empty()).then_some(detail.as_str())
+    }
+
+    fn retry_after(&self) -> Option<Duration> {
+        match self {
+            HttpError::RateLimited { retry_after } => Some(*retry_after),
+            HttpError::Unavailable { retry_after } => *retry_after,
+            _ => None,
+        }
+    }
+
+    // the status, headers and problem document, on a response nothing
+    // else was written to
+    fn write(&self, res: &mut Response, request_id: Option<&str>) -> io::Result<()> {
+        let status = self.status();
+        res.status_code(status.as_u16() as usize, status.reason());
+        if let Some(wait) = self.retry_after() {
+            // whole seconds, rounded up so the client doesn't come back early
+            let secs = wait.as_secs() + u64::from(wait.subsec_nanos() > 0);
+            res.header_owned(format!("Retry-After: {}", secs));
+        }
+        let mut title = status.reason().to_owned();
+        let mut detail = self.detail().map(str::to_owned);
+        if let Some(locale) = res.locale.clone() {
+            let language = locale.language();
+            let key = format!("status.{}", status.as_u16());
+            if let Some(message) = locale.message(language, &key) {
+                title = message.to_owned();
+            }
+            // a detail without an entry is sent as it is
+            detail = detail.map(|detail| match locale.message(language, &detail) {
+                Some(message) => message.to_owned(),
+                None => detail,
+            });
+            res.header("Vary: Accept-Language");
+            res.header_owned(format!("Content-Language: {}", language));
+        }
+        let mut problem = serde_json::json!({
+            "type": "about:blank",
+            "title": title,
+            "status": status.as_u16(),
+        });
+        if let Some(detail) = detail {
+            problem["detail"] = detail.into();
+        }
+        if let HttpError::Invalid(errors) = self {
+            problem["errors"] = errors.to_map();
+        }
+        if let Some(id) = request_id {
+            problem["request_id"] = id.into();
+        }
+        res.header("Content-Type: application/problem+json");
+        res.body_vec(serde_json::to_vec(&problem)?);
+        Ok(())
+    }
+}
+
+impl fmt::Display for HttpError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self.detail() {
+            Some(detail) => write!(f, "{}: {}", self.status(), detail),
+            None => write!(f, "{}", self.status()),
+        }
+    }
+}
+
+impl Error for HttpError {}
+
+impl From<HttpError> for io::Error {
+    fn from(e: HttpError) -> Self {
+        io::Error::new(io::ErrorKind::Other, e)
+    }
+}
+
+impl From<ParamError> for HttpError {
+    fn from(e: ParamError) -> Self {
+        HttpError::BadRequest(e.to_string())
+    }
+}
+
+impl From<ValidationErrors> for HttpError {
+    fn from(e: ValidationErrors) -> Self {
+        HttpError::Invalid(e)
+    }
+}
+
+impl IntoResponse for HttpError {
+    fn into_response(self, res: &mut Response) -> io::Result<()> {
+        let id = res.header_value("x-request-id").map(str::to_owned);
+        self.write(res, id.as_deref())
+    }
+}
+
+// answers a handler's `Error`, `HttpError` or `ParamError` with its
+// problem document in place of whatever the handler set up; I/O errors are
+// passed on
+pub(crate) fn answer(result: io::Result<()>, res: &mut Response, id: &str) -> io::Result<()> {
+    let error = match result {
+        Err(e) => error::Error::from(e).into_http()?,
+        ok => return ok,
+    };
+    res.clear();
+    res.header_owned(format!("X-Request-Id: {}", id));
+    error.write(res, Some(id))
+}
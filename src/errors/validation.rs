@@ -0,0 +1,135 @@
+//! field-level errors gathered from a request, answered together as 422
+
+use std::fmt;
+use std::io;
+
+use serde_json::{Map, Value};
+
+use crate::errors::http_error::HttpError;
+use crate::request::param::ParamError;
+use crate::response::respond::IntoResponse;
+use crate::response::response::Response;
+use crate::server::schema::SchemaError;
+
+/// What's wrong with each field of a request, collected so the client
+/// hears about all of them at once.
+///
+/// Answered as 422 with a problem document whose `errors` member maps each
+/// field to its messages; body fields are named by their JSON pointer.
+/// Handlers return it as the `Err` side of `respond`, or through `?`.
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct ValidationErrors {
+    // field and message, in the order found
+    errors: Vec<(String, String)>,
+}
+
+impl ValidationErrors {
+    pub fn new() -> Self {
+        ValidationErrors::default()
+    }
+
+    pub fn add(&mut self, field: &str, message: &str) -> &mut Self {
+        self.errors.push((field.to_owned(), message.to_owned()));
+        self
+    }
+
+    /// The parsed parameter, or `None` with its error recorded, e.g.
+    /// `let id = errors.check(req.param::<u64>("id"));`.
+    pub fn check<T>(&mut self, result: Result<T, ParamError>) -> Option<T> {
+        match result {
+            Ok(value) => Some(value),
+            Err(e) => {
+                self.push_param(e);
+                None
+            }
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.errors.is_empty()
+    }
+
+    pub fn len(&self) -> usize {
+        self.errors.len()
+    }
+
+    /// Each field and message, in the order they were found.
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &str)> {
+        self.errors
+            .iter()
+            .map(|(field, message)| (field.as_str(), message.as_str()))
+    }
+
+    /// `Ok` if nothing was recorded, so a handler can `?` once all its
+    /// fields are checked.
+    pub fn into_result(self) -> Result<(), ValidationErrors> {
+        match self.is_empty() {
+            true => Ok(()),
+            false => Err(self),
+        }
+    }
+
+    // each field's messages, for the problem document
+    pub(crate) fn to_map(&self) -> Value {
+        let mut map = Map::new();
+        for (field, message) in &self.errors {
+            match map.get_mut(field) {
+                Some(Value::Array(messages)) => messages.push(message.as_str().into()),
+                _ => {
+                    map.insert(field.clone(), Value::Array(vec![message.as_str().into()]));
+                }
+            }
+        }
+        Value::Object(map)
+    }
+
+    fn push_param(&mut self, e: ParamError) {
+        match e {
+            ParamError::Missing(name) => self.errors.push((name, "is required".to_owned())),
+            ParamError::Invalid { name, expected, .. } => {
+                self.errors.push((name, format!("must be {}", expected)))
+            }
+        }
+    }
+}
+
+impl fmt::Display for ValidationErrors {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "invalid fields")?;
+        for (i, (field, message)) in self.errors.iter().enumerate() {
+            let sep = if i == 0 { ": " } else { "; " };
+            write!(f, "{}{} {}", sep, field, message)?;
+        }
+        Ok(())
+    }
+}
+
+impl std::error::Error for ValidationErrors {}
+
+impl From<ParamError> for ValidationErrors {
+    fn from(e: ParamError) -> Self {
+        let mut errors = ValidationErrors::new();
+        errors.push_param(e);
+        errors
+    }
+}
+
+impl From<Vec<SchemaError>> for ValidationErrors {
+    fn from(errors: Vec<SchemaError>) -> Self {
+        ValidationErrors {
+            errors: errors.into_iter().map(|e| (e.path, e.message)).collect(),
+        }
+    }
+}
+
+impl From<ValidationErrors> for io::Error {
+    fn from(e: ValidationErrors) -> Self {
+        HttpError::from(e).into()
+    }
+}
+
+impl IntoResponse for ValidationErrors {
+    fn into_response(self, res: &mut Response) -> io::Result<()> {
+        HttpError::from(self).into_response(res)
+    }
+}
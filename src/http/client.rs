@@ -0,0 +1,673 @@
+//! outbound HTTP/1.1 client on may's sockets, for calls made from handlers
+
+use std::collections::HashMap;
+use std::io::{self, Write};
+use std::net::ToSocketAddrs;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bytes::{Buf, BytesMut};
+use may::net::TcpStream;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::http::http_server::{read_into, reserve_buf};
+use crate::http::socket;
+
+const DEFAULT_CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(60);
+const DEFAULT_MAX_IDLE_PER_HOST: usize = 8;
+const DEFAULT_MAX_RESPONSE_SIZE: usize = 16 * 1024 * 1024;
+// longest chunk-size line or trailer section we wait for
+const MAX_HEAD: usize = 64 * 1024;
+const DEFAULT_MAX_RESPONSE_HEAD: usize = 64 * 1024;
+const DEFAULT_MAX_RESPONSE_HEADERS: usize = 64;
+const USER_AGENT: &str = concat!("aegis_server/", env!("CARGO_PKG_VERSION"));
+
+#[derive(Clone)]
+struct ClientConfig {
+    connect_timeout: Duration,
+    timeout: Duration,
+    idle_timeout: Duration,
+    max_idle_per_host: usize,
+    max_response_size: usize,
+    max_response_head: usize,
+    max_response_headers: usize,
+}
+
+/// HTTP/1.1 client for calling other services from handlers.
+///
+/// It runs on may's sockets, so waiting on a response parks the calling
+/// coroutine instead of a scheduler thread. Clones share a pool of
+/// keep-alive connections per host. Only `http://` URLs are supported, as
+/// the crate has no TLS. Host names go through the system resolver, which
+/// does block; pooled connections save most lookups.
+#[derive(Clone)]
+pub struct HttpClient {
+    config: Arc<ClientConfig>,
+    pool: Arc<Mutex<HashMap<String, Vec<Pooled>>>>,
+}
+
+impl Default for HttpClient {
+    fn default() -> Self {
+        HttpClient::new()
+    }
+}
+
+impl HttpClient {
+    pub fn new() -> Self {
+        HttpClient {
+            config: Arc::new(ClientConfig {
+                connect_timeout: DEFAULT_CONNECT_TIMEOUT,
+                timeout: DEFAULT_TIMEOUT,
+                idle_timeout: DEFAULT_IDLE_TIMEOUT,
+                max_idle_per_host: DEFAULT_MAX_IDLE_PER_HOST,
+                max_response_size: DEFAULT_MAX_RESPONSE_SIZE,
+                max_response_head: DEFAULT_MAX_RESPONSE_HEAD,
+                max_response_headers: DEFAULT_MAX_RESPONSE_HEADERS,
+            }),
+            pool: Arc::new(Mutex::new(HashMap::new())),
+        }
+    }
+
+    /// How long connecting may take, 10 seconds by default.
+    pub fn connect_timeout(&mut self, timeout: Duration) -> &mut Self {
+        Arc::make_mut(&mut self.config).connect_timeout = timeout;
+        self
+    }
+
+    /// How long a single read or write may block, 30 seconds by default.
+    pub fn timeout(&mut self, timeout: Duration) -> &mut Self {
+        Arc::make_mut(&mut self.config).timeout = timeout;
+        self
+    }
+
+    /// How long an unused connection is kept for reuse, 60 seconds by
+    /// default.
+    pub fn idle_timeout(&mut self, timeout: Duration) -> &mut Self {
+        Arc::make_mut(&mut self.config).idle_timeout = timeout;
+        self
+    }
+
+    /// Most unused connections kept per host, 8 by default; 0 turns
+    /// keep-alive off.
+    pub fn max_idle_per_host(&mut self, n: usize) -> &mut Self {
+        Arc::make_mut(&mut self.config).max_idle_per_host = n;
+        self
+    }
+
+    /// Largest response body accepted, 16 MiB by default.
+    pub fn max_response_size(&mut self, limit: usize) -> &mut Self {
+        Arc::make_mut(&mut self.config).max_response_size = limit;
+        self
+    }
+
+    /// Largest response head accepted, status line and headers, 64 KiB
+    /// by default; a longer one fails the request with InvalidData.
+    pub fn max_response_head(&mut self, bytes: usize) -> &mut Self {
+        Arc::make_mut(&mut self.config).max_response_head = bytes;
+        self
+    }
+
+    /// Most response headers accepted, 64 by default; more fail the
+    /// request with InvalidData.
+    pub fn max_response_headers(&mut self, n: usize) -> &mut Self {
+        Arc::make_mut(&mut self.config).max_response_headers = n;
+        self
+    }
+
+    pub fn request(&self, method: &str, url: &str) -> ClientRequest {
+        ClientRequest {
+            client: self.clone(),
+            method: method.to_owned(),
+            url: url.to_owned(),
+            headers: Vec::new(),
+            body: Vec::new(),
+        }
+    }
+
+    pub fn get(&self, url: &str) -> io::Result<ClientResponse> {
+        self.request("GET", url).send()
+    }
+
+    /// GETs `url` and reads a successful response as JSON.
+    pub fn get_json<T: DeserializeOwned>(&self, url: &str) -> io::Result<T> {
+        self.get(url)?.error_for_status()?.json()
+    }
+
+    pub fn post_json<T: Serialize + ?Sized>(
+        &self,
+        url: &str,
+        body: &T,
+    ) -> io::Result<ClientResponse> {
+        self.request("POST", url).json(body)?.send()
+    }
+
+    fn execute(
+        &self,
+        req: &ClientRequest,
+        on_interim: &mut dyn FnMut(&ClientResponse) -> io::Result<()>,
+    ) -> io::Result<ClientResponse> {
+        let target = Target::parse(&req.url)?;
+        let request = self.encode(req, &target)?;
+        let head_only = req.method == "HEAD";
+        let idempotent = matches!(
+            req.method.as_str(),
+            "GET" | "HEAD" | "PUT" | "DELETE" | "OPTIONS" | "TRACE"
+        );
+        loop {
+            let (mut conn, reused) = match self.checkout(&target.key) {
+                Some(conn) => (conn, true),
+                None => (self.connect(&target)?, false),
+            };
+            match conn.exchange(&request, head_only, &self.config, on_interim) {
+                Ok((response, reusable)) => {
+                    if reusable {
+                        self.checkin(&target.key, conn);
+                    }
+                    return Ok(response);
+                }
+                // the server may have closed a pooled connection just as it
+                // was picked up; nothing was answered, so it's safe to retry
+                Err(e) if reused && idempotent && !conn.received => {
+                    debug!(
+                        "pooled connection to {} failed, retrying: {}",
+                        target.key, e
+                    );
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn encode(&self, req: &ClientRequest, target: &Target) -> io::Result<Vec<u8>> {
+        if req.method.is_empty() || !req.method.bytes().all(is_token) {
+            return Err(invalid_input("invalid method"));
+        }
+        let mut head = format!("{} {} HTTP/1.1\r\n", req.method, target.path);
+        let mut host = false;
+        let mut user_agent = false;
+        for (name, value) in &req.headers {
+            let valid_value = value
+                .bytes()
+                .all(|b| b == b'\t' || (b >= b' ' && b != 0x7f));
+            if name.is_empty() || !name.bytes().all(is_token) || !valid_value {
+                return Err(invalid_input("invalid header"));
+            }
+            match name.to_ascii_lowercase().as_str() {
+                "content-length" | "transfer-encoding" | "connection" => continue,
+                "host" => host = true,
+                "user-agent" => user_agent = true,
+                _ => {}
+            }
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        if !host {
+            head.push_str(&format!("Host: {}\r\n", target.authority));
+        }
+        if !user_agent {
+            head.push_str(&format!("User-Agent: {}\r\n", USER_AGENT));
+        }
+        if self.config.max_idle_per_host == 0 {
+            head.push_str("Connection: close\r\n");
+        }
+        if !req.body.is_empty() || matches!(req.method.as_str(), "POST" | "PUT" | "PATCH") {
+            head.push_str(&format!("Content-Length: {}\r\n", req.body.len()));
+        }
+        head.push_str("\r\n");
+        let mut request = head.into_bytes();
+        request.extend_from_slice(&req.body);
+        Ok(request)
+    }
+
+    // an idle connection to `key` that still looks open
+    fn checkout(&self, key: &str) -> Option<Pooled> {
+        let mut pool = self.pool.lock().unwrap();
+        let idle = pool.get_mut(key)?;
+        while let Some(conn) = idle.pop() {
+            if conn.idle_since.elapsed() < self.config.idle_timeout
+                && !socket::peer_gone(&conn.stream)
+            {
+                return Some(conn);
+            }
+        }
+        None
+    }
+
+    fn checkin(&self, key: &str, mut conn: Pooled) {
+        let mut pool = self.pool.lock().unwrap();
+        let idle = pool.entry(key.to_owned()).or_default();
+        if idle.len() < self.config.max_idle_per_host {
+            conn.idle_since = Instant::now();
+            idle.push(conn);
+        }
+    }
+
+    fn connect(&self, target: &Target) -> io::Result<Pooled> {
+        let mut last_error = None;
+        for addr in (target.host.as_str(), target.port).to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, self.config.connect_timeout) {
+                Ok(stream) => {
+                    stream.set_nodelay(true)?;
+                    stream.set_read_timeout(Some(self.config.timeout))?;
+                    stream.set_write_timeout(Some(self.config.timeout))?;
+                    return Ok(Pooled {
+                        stream,
+                        buf: BytesMut::new(),
+                        idle_since: Instant::now(),
+                        received: false,
+                    });
+                }
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error.unwrap_or_else(|| {
+            let msg = format!("no address found for {}", target.host);
+            io::Error::new(io::ErrorKind::NotFound, msg)
+        }))
+    }
+}
+
+/// An outbound request, built with `HttpClient::request`.
+pub struct ClientRequest {
+    client: HttpClient,
+    method: String,
+    url: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl ClientRequest {
+    /// Adds a header. Content-Length, Transfer-Encoding and Connection are
+    /// managed by the client and ignored here.
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_owned(), value.to_owned()));
+        self
+    }
+
+    pub fn body<B: Into<Vec<u8>>>(mut self, body: B) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    /// Sends `value` as a JSON body.
+    pub fn json<T: Serialize + ?Sized>(self, value: &T) -> io::Result<Self> {
+        let body = serde_json::to_vec(value)?;
+        Ok(self.header("Content-Type", "application/json").body(body))
+    }
+
+    pub fn send(self) -> io::Result<ClientResponse> {
+        self.client.execute(&self, &mut |_| Ok(()))
+    }
+
+    /// Like `send`, handing each interim response, e.g. 103 Early Hints,
+    /// to `on_interim` as it arrives, in order and without a body; an error
+    /// from it ends the exchange. 101 is final here, as for `send`.
+    pub fn send_with_interim<F>(self, mut on_interim: F) -> io::Result<ClientResponse>
+    where
+        F: FnMut(&ClientResponse) -> io::Result<()>,
+    {
+        self.client.execute(&self, &mut on_interim)
+    }
+}
+
+/// A response read in full by `ClientRequest::send`.
+#[derive(Debug)]
+pub struct ClientResponse {
+    status: u16,
+    reason: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+}
+
+impl ClientResponse {
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    pub fn reason(&self) -> &str {
+        &self.reason
+    }
+
+    pub fn is_success(&self) -> bool {
+        (200..300).contains(&self.status)
+    }
+
+    /// The first `name` header, compared case-insensitively.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn headers(&self) -> &[(String, String)] {
+        &self.headers
+    }
+
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+
+    pub fn into_body(self) -> Vec<u8> {
+        self.body
+    }
+
+    pub fn text(&self) -> io::Result<&str> {
+        std::str::from_utf8(&self.body).map_err(|e| invalid_data(&e.to_string()))
+    }
+
+    pub fn json<T: DeserializeOwned>(&self) -> io::Result<T> {
+        Ok(serde_json::from_slice(&self.body)?)
+    }
+
+    /// Turns a non-2xx response into an error naming its status.
+    pub fn error_for_status(self) -> io::Result<Self> {
+        match self.is_success() {
+            true => Ok(self),
+            false => Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("HTTP {} {}", self.status, self.reason),
+            )),
+        }
+    }
+}
+
+// where a URL points: what to connect to and what to send
+struct Target {
+    host: String,
+    port: u16,
+    // for the Host header
+    authority: String,
+    path: String,
+    // the pool key
+    key: String,
+}
+
+impl Target {
+    fn parse(url: &str) -> io::Result<Target> {
+        let rest = match url.split_once("://") {
+            Some((scheme, rest)) if scheme.eq_ignore_ascii_case("http") => rest,
+            Some((scheme, _)) => {
+                return Err(invalid_input(&format!("unsupported scheme {}", scheme)))
+            }
+            None => return Err(invalid_input("URL without a scheme")),
+        };
+        let rest = rest.split('#').next().unwrap_or(rest);
+        let (authority, path) = match rest.find(['/', '?']) {
+            Some(i) => rest.split_at(i),
+            None => (rest, "/"),
+        };
+        if authority.contains('@') {
+            return Err(invalid_input("credentials in URLs aren't supported"));
+        }
+        let (host, port) = match authority.strip_prefix('[') {
+            // IPv6 literal
+            Some(literal) => {
+                let (host, rest) = literal
+                    .split_once(']')
+                    .ok_or_else(|| invalid_input("unclosed IPv6 literal"))?;
+                (host, rest.strip_prefix(':'))
+            }
+            None => match authority.split_once(':') {
+                Some((host, port)) => (host, Some(port)),
+                None => (authority, None),
+            },
+        };
+        let port = match port {
+            Some(port) => port.parse().map_err(|_| invalid_input("invalid port"))?,
+            None => 80,
+        };
+        if host.is_empty() {
+            return Err(invalid_input("URL without a host"));
+        }
+        let path = match path.starts_with('?') {
+            true => format!("/{}", path),
+            false => path.to_owned(),
+        };
+        // anything that could end the request line early
+        if path.bytes().any(|b| b <= b' ' || b == 0x7f) {
+            return Err(invalid_input("invalid character in URL path"));
+        }
+        Ok(Target {
+            key: format!("{}:{}", host.to_ascii_lowercase(), port),
+            host: host.to_owned(),
+            port,
+            authority: authority.to_owned(),
+            path,
+        })
+    }
+}
+
+// a connection to one host, idle in the pool or carrying a request
+struct Pooled {
+    stream: TcpStream,
+    // bytes read past the last response
+    buf: BytesMut,
+    idle_since: Instant,
+    // whether any of the current response has arrived
+    received: bool,
+}
+
+struct Head {
+    version: u8,
+    status: u16,
+    reason: String,
+    headers: Vec<(String, String)>,
+}
+
+impl Pooled {
+    // send `request` and read the response; also returns whether the
+    // connection can carry another request
+    fn exchange(
+        &mut self,
+        request: &[u8],
+        head_only: bool,
+        config: &ClientConfig,
+        on_interim: &mut dyn FnMut(&ClientResponse) -> io::Result<()>,
+    ) -> io::Result<(ClientResponse, bool)> {
+        self.received = false;
+        self.stream.write_all(request)?;
+        let mut head = self.read_head(config)?;
+        // interim responses, e.g. 100 Continue, precede the real one
+        while (100..200).contains(&head.status) && head.status != 101 {
+            on_interim(&ClientResponse {
+                status: head.status,
+                reason: head.reason,
+                headers: head.headers,
+                body: Vec::new(),
+            })?;
+            head = self.read_head(config)?;
+        }
+        let limit = config.max_response_size;
+        let header = |name| values(&head.headers, name);
+        let connection = |token: &str| {
+            header("connection").any(|value| {
+                value
+                    .split(',')
+                    .any(|t| t.trim().eq_ignore_ascii_case(token))
+            })
+        };
+        let keep_alive = match head.version {
+            1 => !connection("close"),
+            _ => connection("keep-alive"),
+        };
+        let chunked = header("transfer-encoding").last().map_or(false, |value| {
+            let coding = value.rsplit(',').next().unwrap_or(value);
+            coding.trim().eq_ignore_ascii_case("chunked")
+        });
+        let length = match header("content-length").next() {
+            Some(value) => Some(
+                value
+                    .trim()
+                    .parse::<usize>()
+                    .map_err(|_| invalid_data("invalid Content-Length"))?,
+            ),
+            None => None,
+        };
+        let bodiless = head_only || matches!(head.status, 101 | 204 | 304);
+        let (body, framed) = if bodiless {
+            (Vec::new(), head.status != 101)
+        } else if chunked {
+            (self.read_chunked(limit)?, true)
+        } else if let Some(length) = length {
+            if length > limit {
+                return Err(too_large());
+            }
+            (self.read_sized(length)?, true)
+        } else {
+            // delimited by the server closing the connection
+            (self.read_to_end(limit)?, false)
+        };
+        let reusable = keep_alive && framed && self.buf.is_empty();
+        let response = ClientResponse {
+            status: head.status,
+            reason: head.reason,
+            headers: head.headers,
+            body,
+        };
+        Ok((response, reusable))
+    }
+
+    fn read_head(&mut self, config: &ClientConfig) -> io::Result<Head> {
+        loop {
+            if !self.buf.is_empty() {
+                let mut headers = vec![httparse::EMPTY_HEADER; config.max_response_headers];
+                let mut res = httparse::Response::new(&mut headers);
+                let status = res.parse(&self.buf).map_err(|e| match e {
+                    httparse::Error::TooManyHeaders => invalid_data("too many response headers"),
+                    e => invalid_data(&format!("invalid response head: {:?}", e)),
+                })?;
+                match status {
+                    httparse::Status::Complete(len) if len > config.max_response_head => {
+                        return Err(invalid_data("response head too large"))
+                    }
+                    httparse::Status::Complete(len) => {
+                        let head = Head {
+                            version: res.version.unwrap_or(1),
+                            status: res.code.unwrap_or(0),
+                            reason: res.reason.unwrap_or("").to_owned(),
+                            headers: res
+                                .headers
+                                .iter()
+                                .map(|header| {
+                                    let value = String::from_utf8_lossy(header.value);
+                                    (header.name.to_owned(), value.into_owned())
+                                })
+                                .collect(),
+                        };
+                        self.buf.advance(len);
+                        return Ok(head);
+                    }
+                    httparse::Status::Partial if self.buf.len() > config.max_response_head => {
+                        return Err(invalid_data("response head too large"))
+                    }
+                    httparse::Status::Partial => {}
+                }
+            }
+            self.fill()?;
+        }
+    }
+
+    fn read_sized(&mut self, length: usize) -> io::Result<Vec<u8>> {
+        self.buf.reserve(length.saturating_sub(self.buf.len()));
+        while self.buf.len() < length {
+            self.fill()?;
+        }
+        Ok(self.buf.split_to(length).to_vec())
+    }
+
+    fn read_chunked(&mut self, limit: usize) -> io::Result<Vec<u8>> {
+        let mut body = Vec::new();
+        loop {
+            let (consumed, size) = loop {
+                match httparse::parse_chunk_size(&self.buf) {
+                    Ok(httparse::Status::Complete(parsed)) => break parsed,
+                    Ok(httparse::Status::Partial) if self.buf.len() <= MAX_HEAD => self.fill()?,
+                    _ => return Err(invalid_data("invalid chunk size")),
+                }
+            };
+            self.buf.advance(consumed);
+            if size == 0 {
+                break;
+            }
+            let size = usize::try_from(size).map_err(|_| too_large())?;
+            if body.len().saturating_add(size) > limit {
+                return Err(too_large());
+            }
+            while self.buf.len() < size + 2 {
+                self.fill()?;
+            }
+            if &self.buf[size..size + 2] != b"\r\n" {
+                return Err(invalid_data("chunk without its line ending"));
+            }
+            body.extend_from_slice(&self.buf[..size]);
+            self.buf.advance(size + 2);
+        }
+        // trailers are skipped up to the empty line that ends them
+        loop {
+            match self.buf.windows(2).position(|w| w == b"\r\n") {
+                Some(0) => {
+                    self.buf.advance(2);
+                    return Ok(body);
+                }
+                Some(end) => self.buf.advance(end + 2),
+                None if self.buf.len() > MAX_HEAD => {
+                    return Err(invalid_data("trailers too large"))
+                }
+                None => self.fill()?,
+            }
+        }
+    }
+
+    fn read_to_end(&mut self, limit: usize) -> io::Result<Vec<u8>> {
+        while self.read_more()? {
+            if self.buf.len() > limit {
+                return Err(too_large());
+            }
+        }
+        Ok(self.buf.split().to_vec())
+    }
+
+    fn fill(&mut self) -> io::Result<()> {
+        match self.read_more()? {
+            true => Ok(()),
+            false => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection closed mid-response",
+            )),
+        }
+    }
+
+    // false once the server has closed the connection
+    fn read_more(&mut self) -> io::Result<bool> {
+        reserve_buf(&mut self.buf);
+        let n = read_into(&mut self.stream, &mut self.buf)?;
+        self.received |= n > 0;
+        Ok(n > 0)
+    }
+}
+
+fn values<'h>(headers: &'h [(String, String)], name: &'h str) -> impl Iterator<Item = &'h str> {
+    headers
+        .iter()
+        .filter(move |(key, _)| key.eq_ignore_ascii_case(name))
+        .map(|(_, value)| value.as_str())
+}
+
+fn is_token(b: u8) -> bool {
+    b"!#$%&'*+-.^_`|~".contains(&b) || b.is_ascii_alphanumeric()
+}
+
+fn invalid_input(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidInput, msg)
+}
+
+fn invalid_data(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn too_large() -> io::Error {
+    invalid_data("response body over the size limit")
+}
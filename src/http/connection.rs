@@ -0,0 +1,22 @@
+use std::io::{self, Read, Write};
+use std::time::Duration;
+
+use may::net::TcpStream;
+
+/// A byte stream requests are decoded from and responses written to.
+///
+/// Implemented for `TcpStream`; TLS, unix socket or in-memory streams only
+/// need to implement it to share the same decode and body path.
+pub trait Connection: Read + Write {
+    /// Bounds the blocking body reads that follow; `None` waits forever.
+    /// Streams that never block can keep the default no-op.
+    fn set_read_timeout(&mut self, _timeout: Option<Duration>) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Connection for TcpStream {
+    fn set_read_timeout(&mut self, timeout: Option<Duration>) -> io::Result<()> {
+        TcpStream::set_read_timeout(self, timeout)
+    }
+}
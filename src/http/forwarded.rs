@@ -0,0 +1,155 @@
+//! client address resolution through trusted reverse proxies
+
+use std::fmt;
+use std::net::{IpAddr, SocketAddr};
+
+// a network, e.g. of proxies trusted to report the client they forward
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct IpNet {
+    net: IpAddr,
+    prefix_len: u8,
+}
+
+impl IpNet {
+    pub(crate) fn new(net: IpAddr, prefix_len: u8) -> Self {
+        match (net, canonical(net)) {
+            (IpAddr::V4(_), net) => IpNet {
+                net,
+                prefix_len: prefix_len.min(32),
+            },
+            // an IPv4-mapped network covers the IPv4 addresses it maps
+            (IpAddr::V6(_), net @ IpAddr::V4(_)) => IpNet {
+                net,
+                prefix_len: prefix_len.saturating_sub(96).min(32),
+            },
+            (IpAddr::V6(_), net) => IpNet {
+                net,
+                prefix_len: prefix_len.min(128),
+            },
+        }
+    }
+
+    // `10.0.0.0/8`, `2001:db8::/32`, or a single address
+    pub(crate) fn parse(s: &str) -> Option<Self> {
+        let (net, prefix_len) = match s.trim().split_once('/') {
+            Some((net, len)) => (net.parse().ok()?, Some(len.parse().ok()?)),
+            None => (s.trim().parse().ok()?, None),
+        };
+        let max = match net {
+            IpAddr::V4(_) => 32,
+            IpAddr::V6(_) => 128,
+        };
+        match prefix_len {
+            Some(len) if len > max => None,
+            len => Some(IpNet::new(net, len.unwrap_or(max))),
+        }
+    }
+
+    pub(crate) fn contains(&self, ip: IpAddr) -> bool {
+        match (self.net, canonical(ip)) {
+            (IpAddr::V4(net), IpAddr::V4(ip)) => {
+                let mask = u32::MAX
+                    .checked_shl(32 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u32::from(net) & mask == u32::from(ip) & mask
+            }
+            (IpAddr::V6(net), IpAddr::V6(ip)) => {
+                let mask = u128::MAX
+                    .checked_shl(128 - self.prefix_len as u32)
+                    .unwrap_or(0);
+                u128::from(net) & mask == u128::from(ip) & mask
+            }
+            _ => false,
+        }
+    }
+}
+
+impl fmt::Display for IpNet {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "{}/{}", self.net, self.prefix_len)
+    }
+}
+
+// the client a request came from: while the hop that delivered it is a
+// trusted proxy, step back through what that proxy reported, right to left
+pub(crate) fn client_ip(
+    peer: Option<SocketAddr>,
+    headers: &[httparse::Header],
+    trusted: &[IpNet],
+) -> Option<IpAddr> {
+    let mut client = canonical(peer?.ip());
+    if trusted.is_empty() || !is_trusted(trusted, client) {
+        return Some(client);
+    }
+    // proxies append, so later header lines hold nearer hops
+    let mut hops = Vec::new();
+    for name in ["forwarded", "x-forwarded-for", "x-real-ip"] {
+        for header in headers.iter().filter(|h| h.name.eq_ignore_ascii_case(name)) {
+            let value = match std::str::from_utf8(header.value) {
+                Ok(value) => value,
+                Err(_) => return Some(client),
+            };
+            for element in value.split(',') {
+                let hop = match name {
+                    "forwarded" => forwarded_for(element),
+                    _ => Some(element),
+                };
+                hops.extend(hop);
+            }
+        }
+        if !hops.is_empty() {
+            break;
+        }
+    }
+    for hop in hops.iter().rev() {
+        match parse_hop(hop) {
+            Some(ip) => client = ip,
+            // "unknown" or obfuscated; nothing past it can be trusted
+            None => break,
+        }
+        if !is_trusted(trusted, client) {
+            break;
+        }
+    }
+    Some(client)
+}
+
+fn is_trusted(trusted: &[IpNet], ip: IpAddr) -> bool {
+    trusted.iter().any(|proxy| proxy.contains(ip))
+}
+
+// IPv4-mapped IPv6 addresses compare as the IPv4 address they carry
+fn canonical(ip: IpAddr) -> IpAddr {
+    match ip {
+        IpAddr::V6(v6) => match v6.to_ipv4_mapped() {
+            Some(v4) => IpAddr::V4(v4),
+            None => ip,
+        },
+        ip => ip,
+    }
+}
+
+// the `for=` parameter of one RFC 7239 element
+fn forwarded_for(element: &str) -> Option<&str> {
+    element.split(';').find_map(|pair| {
+        let (key, value) = pair.split_once('=')?;
+        key.trim()
+            .eq_ignore_ascii_case("for")
+            .then(|| value.trim().trim_matches('"'))
+    })
+}
+
+// "192.0.2.1", "192.0.2.1:80", "2001:db8::1" or "[2001:db8::1]:80"
+fn parse_hop(hop: &str) -> Option<IpAddr> {
+    let hop = hop.trim();
+    if let Ok(ip) = hop.parse::<IpAddr>() {
+        return Some(canonical(ip));
+    }
+    if let Ok(addr) = hop.parse::<SocketAddr>() {
+        return Some(canonical(addr.ip()));
+    }
+    hop.strip_prefix('[')
+        .and_then(|rest| rest.strip_suffix(']'))
+        .and_then(|ip| ip.parse().ok())
+        .map(canonical)
+}
@@ -0,0 +1,1322 @@
+//! HTTP/2 over cleartext (h2c), by prior knowledge or `Upgrade: h2c`
+//!
+//! each stream's request is rebuilt as HTTP/1.1 and run through the usual
+//! decoder and handlers; the connection reads frames in its own coroutine,
+//! handlers run in one coroutine per stream and a writer coroutine owns the
+//! write side and flow control
+
+use std::borrow::Cow;
+use std::collections::{HashMap, HashSet, VecDeque};
+use std::io::{self, Read, Write};
+use std::mem::MaybeUninit;
+use std::net::Shutdown;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use bytes::{Buf, BufMut, Bytes, BytesMut};
+use may::go;
+use may::net::TcpStream;
+use may::sync::mpsc::{self, Receiver, Sender};
+
+use crate::http::connection::Connection;
+use crate::http::hpack::{self, Decoder, HeaderField};
+use crate::http::http_server::{
+    dispatch, is_dropped, is_timeout, read_into, reserve_buf, HttpService,
+};
+use crate::http::shutdown::{Lifecycle, Served};
+use crate::request::request::{self, BodyState, DecodeError, Endpoints, Framing, RawRequest};
+use crate::response::date::append_date;
+use crate::response::response::{self, Response};
+use crate::server::config::ServerConfig;
+use crate::util::base64url;
+
+pub(crate) const PREFACE: &[u8] = b"PRI * HTTP/2.0\r\n\r\nSM\r\n\r\n";
+pub(crate) const SWITCHING: &[u8] =
+    b"HTTP/1.1 101 Switching Protocols\r\nConnection: Upgrade\r\nUpgrade: h2c\r\n\r\n";
+
+// frame types (RFC 7540 6)
+const DATA: u8 = 0x0;
+const HEADERS: u8 = 0x1;
+const PRIORITY: u8 = 0x2;
+const RST_STREAM: u8 = 0x3;
+const SETTINGS: u8 = 0x4;
+const PUSH_PROMISE: u8 = 0x5;
+const PING: u8 = 0x6;
+const GOAWAY: u8 = 0x7;
+const WINDOW_UPDATE: u8 = 0x8;
+const CONTINUATION: u8 = 0x9;
+
+// frame flags
+const END_STREAM: u8 = 0x1;
+const ACK: u8 = 0x1;
+const END_HEADERS: u8 = 0x4;
+const PADDED: u8 = 0x8;
+const PRIORITY_FLAG: u8 = 0x20;
+
+// error codes (RFC 7540 7)
+const NO_ERROR: u32 = 0x0;
+const PROTOCOL_ERROR: u32 = 0x1;
+const FLOW_CONTROL_ERROR: u32 = 0x3;
+const STREAM_CLOSED: u32 = 0x5;
+const FRAME_SIZE_ERROR: u32 = 0x6;
+const REFUSED_STREAM: u32 = 0x7;
+const CANCEL: u32 = 0x8;
+const COMPRESSION_ERROR: u32 = 0x9;
+const ENHANCE_YOUR_CALM: u32 = 0xb;
+const HTTP_1_1_REQUIRED: u32 = 0xd;
+
+// settings (RFC 7540 6.5.2)
+const SETTINGS_ENABLE_PUSH: u16 = 0x2;
+const SETTINGS_MAX_CONCURRENT_STREAMS: u16 = 0x3;
+const SETTINGS_INITIAL_WINDOW_SIZE: u16 = 0x4;
+const SETTINGS_MAX_FRAME_SIZE: u16 = 0x5;
+const SETTINGS_MAX_HEADER_LIST_SIZE: u16 = 0x6;
+
+const MAX_STREAMS: u32 = 100;
+const DEFAULT_WINDOW: i64 = 65_535;
+const MAX_WINDOW: i64 = (1 << 31) - 1;
+// we never raise SETTINGS_MAX_FRAME_SIZE, so the client can't send more
+const MAX_FRAME_SIZE: usize = 16_384;
+// how much body the writer queues up before going to the socket
+const WRITE_BATCH: usize = 64 * 1024;
+// how often the reader looks up from the socket to check its deadlines
+const TICK: Duration = Duration::from_secs(1);
+
+// headers that only mean something to one HTTP/1.1 connection (RFC 7540
+// 8.1.2.2)
+const CONNECTION_SPECIFIC: [&str; 5] = [
+    "connection",
+    "keep-alive",
+    "proxy-connection",
+    "transfer-encoding",
+    "upgrade",
+];
+
+// what an HTTP/1.1 connection hands over when it turns into HTTP/2
+pub(crate) struct Switch {
+    // bytes read past the point of switching
+    pub(crate) buf: BytesMut,
+    pub(crate) endpoints: Endpoints,
+    // the request that asked for the upgrade, answered on stream 1, with
+    // the settings it carried
+    pub(crate) upgraded: Option<(Incoming, Vec<u8>)>,
+}
+
+pub(crate) enum Sniff {
+    Preface,
+    Incomplete,
+    Http1,
+}
+
+// whether a new connection opens with the HTTP/2 preface
+pub(crate) fn sniff(buf: &[u8]) -> Sniff {
+    let n = buf.len().min(PREFACE.len());
+    if buf[..n] != PREFACE[..n] {
+        Sniff::Http1
+    } else if n < PREFACE.len() {
+        Sniff::Incomplete
+    } else {
+        Sniff::Preface
+    }
+}
+
+// the stream 1 request and client settings, if `req` asks to switch to h2c
+// (RFC 7540 3.2); a request with a body stays on HTTP/1.1, as the body
+// would have to be read before switching
+pub(crate) fn upgrade(req: &RawRequest) -> Option<(Incoming, Vec<u8>)> {
+    let wants = req.header("upgrade").map_or(false, |value| {
+        value
+            .split(',')
+            .any(|protocol| protocol.trim().eq_ignore_ascii_case("h2c"))
+    });
+    if !wants || req.version() != 1 || req.framing() != Ok(Framing::Length(0)) {
+        return None;
+    }
+    let settings = base64url(req.header("http2-settings")?)?;
+    let mut head = format!("{} {} HTTP/1.1\r\n", req.method(), req.path()).into_bytes();
+    for header in req.headers() {
+        let name = header.name.to_ascii_lowercase();
+        let hop = CONNECTION_SPECIFIC.contains(&name.as_str())
+            || name == "http2-settings"
+            || name == "content-length";
+        if !hop {
+            field_line(&mut head, name.as_bytes(), header.value);
+        }
+    }
+    let incoming = Incoming {
+        head_only: req.method() == "HEAD",
+        head,
+        body: Vec::new(),
+        length: None,
+    };
+    Some((incoming, settings))
+}
+
+// a request stream being received
+pub(crate) struct Incoming {
+    // request line and header lines in HTTP/1.1 form
+    head: Vec<u8>,
+    body: Vec<u8>,
+    // the content-length the client declared, checked once the body is in
+    length: Option<usize>,
+    head_only: bool,
+}
+
+impl Incoming {
+    fn into_http1(self) -> BytesMut {
+        let mut buf = BytesMut::with_capacity(self.head.len() + self.body.len() + 32);
+        buf.extend_from_slice(&self.head);
+        buf.extend_from_slice(b"content-length: ");
+        buf.extend_from_slice(itoa::Buffer::new().format(self.body.len()).as_bytes());
+        buf.extend_from_slice(b"\r\n\r\n");
+        buf.extend_from_slice(&self.body);
+        buf
+    }
+}
+
+struct Frame {
+    kind: u8,
+    flags: u8,
+    id: u32,
+    payload: Bytes,
+}
+
+// sent to the writer
+enum Outbound {
+    Frame(Bytes),
+    Response {
+        id: u32,
+        block: Bytes,
+        body: Bytes,
+    },
+    // `code` is `None` when the client reset the stream itself
+    Reset {
+        id: u32,
+        code: Option<u32>,
+    },
+    WindowUpdate {
+        id: u32,
+        increment: u32,
+    },
+    Settings {
+        initial_window: Option<u32>,
+        max_frame: Option<u32>,
+    },
+}
+
+// serves the connection until it closes; the HTTP/1.1 loop has already
+// answered any upgrade request with 101
+pub(crate) fn serve<T: HttpService + Clone + Send + 'static>(
+    stream: &mut TcpStream,
+    service: T,
+    config: &Arc<ServerConfig>,
+    lifecycle: &Lifecycle,
+    switch: Switch,
+) -> io::Result<()> {
+    trace!("switching connection to HTTP/2");
+    let (tx, rx) = mpsc::channel();
+    let in_flight = Arc::new(Mutex::new(HashSet::new()));
+    let writer = Writer::new(stream.try_clone()?, in_flight.clone());
+    writer.stream.set_write_timeout(config.write_timeout)?;
+    let writer = go!(move || writer.run(rx));
+    let mut conn = Conn {
+        service,
+        config: config.clone(),
+        endpoints: switch.endpoints,
+        served: lifecycle.served().clone(),
+        tx,
+        decoder: Decoder::new(),
+        incoming: HashMap::new(),
+        in_flight,
+        last_id: 0,
+        continuation: None,
+        accepting: true,
+    };
+    let result = conn.run(stream, lifecycle, switch.buf, switch.upgraded);
+    // handlers still running keep the writer going until they answer
+    drop(conn);
+    let written = writer
+        .join()
+        .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::Other, "writer panicked")));
+    stream.shutdown(Shutdown::Both).ok();
+    result.and(written)
+}
+
+// the reading side of a connection
+struct Conn<T> {
+    service: T,
+    config: Arc<ServerConfig>,
+    endpoints: Endpoints,
+    served: Arc<Served>,
+    tx: Sender<Outbound>,
+    decoder: Decoder,
+    // streams whose request is still arriving
+    incoming: HashMap<u32, Incoming>,
+    // streams handed to a handler and not yet answered in full
+    in_flight: Arc<Mutex<HashSet<u32>>>,
+    // the highest stream the client has opened
+    last_id: u32,
+    // a header block waiting for CONTINUATION frames, and its END_STREAM
+    continuation: Option<(u32, Vec<u8>, bool)>,
+    // cleared once either side has sent GOAWAY
+    accepting: bool,
+}
+
+impl<T: HttpService + Clone + Send + 'static> Conn<T> {
+    fn run(
+        &mut self,
+        stream: &mut TcpStream,
+        lifecycle: &Lifecycle,
+        mut buf: BytesMut,
+        upgraded: Option<(Incoming, Vec<u8>)>,
+    ) -> io::Result<()> {
+        self.send(Outbound::Frame(server_settings(&self.config)));
+        if let Some((request, settings)) = upgraded {
+            match parse_settings(&settings) {
+                Ok(update) => self.send(update),
+                Err(code) => return self.go_away(code),
+            }
+            self.last_id = 1;
+            self.spawn(1, request);
+        }
+        stream.set_read_timeout(Some(TICK))?;
+        let opened = Instant::now();
+        let mut active = Instant::now();
+        let mut preface = false;
+        let mut settled = false;
+        loop {
+            if !preface {
+                match sniff(&buf) {
+                    Sniff::Preface => {
+                        buf.advance(PREFACE.len());
+                        preface = true;
+                    }
+                    Sniff::Incomplete => {}
+                    Sniff::Http1 => return self.go_away(PROTOCOL_ERROR),
+                }
+            }
+            if preface {
+                loop {
+                    let frame = match next_frame(&mut buf) {
+                        Ok(Some(frame)) => frame,
+                        Ok(None) => break,
+                        Err(code) => return self.go_away(code),
+                    };
+                    // the client's SETTINGS come first (RFC 7540 3.5)
+                    if !settled && frame.kind != SETTINGS {
+                        return self.go_away(PROTOCOL_ERROR);
+                    }
+                    settled = true;
+                    active = Instant::now();
+                    if let Err(code) = self.frame(frame) {
+                        return self.go_away(code);
+                    }
+                }
+            }
+            if !self.accepting && self.idle() {
+                return Ok(());
+            }
+            reserve_buf(&mut buf);
+            match read_into(stream, &mut buf) {
+                Ok(0) => return Ok(()),
+                Ok(_) => {}
+                Err(e) if is_timeout(&e) => {
+                    let aged = self
+                        .config
+                        .max_connection_age
+                        .map_or(false, |age| opened.elapsed() >= age);
+                    if self.accepting && (lifecycle.draining() || aged) {
+                        self.go_away(NO_ERROR)?;
+                    }
+                    let stalled = self
+                        .config
+                        .keep_alive_timeout
+                        .map_or(false, |timeout| active.elapsed() >= timeout);
+                    if stalled && self.idle() {
+                        debug!("idle HTTP/2 connection, closing it");
+                        return self.go_away(NO_ERROR);
+                    }
+                }
+                Err(e) => return Err(e),
+            }
+        }
+    }
+
+    fn frame(&mut self, frame: Frame) -> Result<(), u32> {
+        // nothing may come between a header block's frames
+        if let Some((id, ..)) = self.continuation {
+            if frame.kind != CONTINUATION || frame.id != id {
+                return Err(PROTOCOL_ERROR);
+            }
+        }
+        match frame.kind {
+            DATA => self.data(frame),
+            HEADERS => self.headers(frame),
+            CONTINUATION => self.continuation(frame),
+            PRIORITY if frame.id == 0 => Err(PROTOCOL_ERROR),
+            PRIORITY if frame.payload.len() != 5 => Err(FRAME_SIZE_ERROR),
+            PRIORITY => Ok(()),
+            RST_STREAM => self.rst_stream(frame),
+            SETTINGS => self.settings(frame),
+            PUSH_PROMISE => Err(PROTOCOL_ERROR),
+            PING => self.ping(frame),
+            GOAWAY if frame.id != 0 => Err(PROTOCOL_ERROR),
+            GOAWAY => {
+                self.accepting = false;
+                Ok(())
+            }
+            WINDOW_UPDATE => self.window_update(frame),
+            // unknown frame types are ignored (RFC 7540 4.1)
+            _ => Ok(()),
+        }
+    }
+
+    fn data(&mut self, frame: Frame) -> Result<(), u32> {
+        if frame.id == 0 {
+            return Err(PROTOCOL_ERROR);
+        }
+        let data = unpad(&frame)?;
+        // the body is buffered whole, so windows are reopened right away
+        if !frame.payload.is_empty() {
+            self.send(window_update(0, frame.payload.len()));
+        }
+        let received = match self.incoming.get(&frame.id) {
+            Some(incoming) => incoming.body.len() + data.len(),
+            None if frame.id > self.last_id => return Err(PROTOCOL_ERROR),
+            None => {
+                self.reset(frame.id, STREAM_CLOSED);
+                return Ok(());
+            }
+        };
+        let end = frame.flags & END_STREAM != 0;
+        if received > self.config.max_body_size {
+            self.incoming.remove(&frame.id);
+            self.refuse(frame.id, 413, !end);
+            return Ok(());
+        }
+        let incoming = self.incoming.get_mut(&frame.id).unwrap();
+        incoming.body.extend_from_slice(data);
+        if end {
+            let incoming = self.incoming.remove(&frame.id).unwrap();
+            self.complete(frame.id, incoming);
+        } else if !frame.payload.is_empty() {
+            self.send(window_update(frame.id, frame.payload.len()));
+        }
+        Ok(())
+    }
+
+    fn headers(&mut self, frame: Frame) -> Result<(), u32> {
+        if frame.id == 0 || frame.id % 2 == 0 {
+            return Err(PROTOCOL_ERROR);
+        }
+        let mut block = unpad(&frame)?;
+        if frame.flags & PRIORITY_FLAG != 0 {
+            block = block.get(5..).ok_or(PROTOCOL_ERROR)?;
+        }
+        let end_stream = frame.flags & END_STREAM != 0;
+        if frame.flags & END_HEADERS == 0 {
+            self.continuation = Some((frame.id, block.to_vec(), end_stream));
+            return Ok(());
+        }
+        self.header_block(frame.id, block, end_stream)
+    }
+
+    fn continuation(&mut self, frame: Frame) -> Result<(), u32> {
+        let (id, mut block, end_stream) = self.continuation.take().ok_or(PROTOCOL_ERROR)?;
+        block.extend_from_slice(&frame.payload);
+        // this much compressed header is an attack, not a request
+        if block.len() > self.config.max_header_bytes {
+            return Err(ENHANCE_YOUR_CALM);
+        }
+        if frame.flags & END_HEADERS == 0 {
+            self.continuation = Some((id, block, end_stream));
+            return Ok(());
+        }
+        self.header_block(id, &block, end_stream)
+    }
+
+    fn header_block(&mut self, id: u32, block: &[u8], end_stream: bool) -> Result<(), u32> {
+        // decoded even for refused streams, to keep the table in step
+        let fields = self
+            .decoder
+            .decode(block, self.config.max_header_bytes)
+            .map_err(|_| COMPRESSION_ERROR)?;
+        if self.incoming.contains_key(&id) {
+            // trailers close the stream; handlers never see them
+            let incoming = self.incoming.remove(&id).unwrap();
+            match end_stream {
+                true => self.complete(id, incoming),
+                false => self.reset(id, PROTOCOL_ERROR),
+            }
+            return Ok(());
+        }
+        if id <= self.last_id {
+            return Err(STREAM_CLOSED);
+        }
+        self.last_id = id;
+        if !self.accepting {
+            return Ok(());
+        }
+        let open = self.incoming.len() + self.in_flight.lock().unwrap().len();
+        if open >= MAX_STREAMS as usize {
+            self.reset(id, REFUSED_STREAM);
+            return Ok(());
+        }
+        let fields = match fields {
+            Some(fields) => fields,
+            None => {
+                self.refuse(id, 431, !end_stream);
+                return Ok(());
+            }
+        };
+        match incoming(fields) {
+            Some(incoming) if end_stream => self.complete(id, incoming),
+            Some(incoming) => {
+                self.incoming.insert(id, incoming);
+            }
+            None => self.reset(id, PROTOCOL_ERROR),
+        }
+        Ok(())
+    }
+
+    fn rst_stream(&mut self, frame: Frame) -> Result<(), u32> {
+        if frame.id == 0 || frame.id > self.last_id {
+            return Err(PROTOCOL_ERROR);
+        }
+        if frame.payload.len() != 4 {
+            return Err(FRAME_SIZE_ERROR);
+        }
+        self.incoming.remove(&frame.id);
+        self.send(Outbound::Reset {
+            id: frame.id,
+            code: None,
+        });
+        Ok(())
+    }
+
+    fn settings(&mut self, frame: Frame) -> Result<(), u32> {
+        if frame.id != 0 {
+            return Err(PROTOCOL_ERROR);
+        }
+        if frame.flags & ACK != 0 {
+            return match frame.payload.is_empty() {
+                true => Ok(()),
+                false => Err(FRAME_SIZE_ERROR),
+            };
+        }
+        let update = parse_settings(&frame.payload)?;
+        self.send(update);
+        self.send(Outbound::Frame(encode_frame(SETTINGS, ACK, 0, &[])));
+        Ok(())
+    }
+
+    fn ping(&mut self, frame: Frame) -> Result<(), u32> {
+        if frame.id != 0 {
+            return Err(PROTOCOL_ERROR);
+        }
+        if frame.payload.len() != 8 {
+            return Err(FRAME_SIZE_ERROR);
+        }
+        if frame.flags & ACK == 0 {
+            self.send(Outbound::Frame(encode_frame(PING, ACK, 0, &frame.payload)));
+        }
+        Ok(())
+    }
+
+    fn window_update(&mut self, frame: Frame) -> Result<(), u32> {
+        if frame.payload.len() != 4 {
+            return Err(FRAME_SIZE_ERROR);
+        }
+        if frame.id > self.last_id {
+            return Err(PROTOCOL_ERROR);
+        }
+        let increment = read_u32(&frame.payload) & 0x7fff_ffff;
+        match (frame.id, increment) {
+            (0, 0) => return Err(PROTOCOL_ERROR),
+            (id, 0) => self.reset(id, PROTOCOL_ERROR),
+            (id, increment) => self.send(Outbound::WindowUpdate { id, increment }),
+        }
+        Ok(())
+    }
+
+    // the whole request is in
+    fn complete(&mut self, id: u32, incoming: Incoming) {
+        if incoming
+            .length
+            .map_or(false, |length| length != incoming.body.len())
+        {
+            self.reset(id, PROTOCOL_ERROR);
+            return;
+        }
+        self.spawn(id, incoming);
+    }
+
+    fn spawn(&mut self, id: u32, incoming: Incoming) {
+        self.in_flight.lock().unwrap().insert(id);
+        let mut service = self.service.clone();
+        let config = self.config.clone();
+        let endpoints = self.endpoints.clone();
+        let served = self.served.clone();
+        let tx = self.tx.clone();
+        go!(move || {
+            let answer = respond(&mut service, id, incoming, &config, endpoints, &served);
+            tx.send(answer).ok();
+        });
+    }
+
+    // answer with a bare status before the handler gets involved; `open`
+    // when the client may still be sending, which a reset stops
+    fn refuse(&mut self, id: u32, status: usize, open: bool) {
+        debug!("refusing HTTP/2 stream {} with {}", id, status);
+        self.in_flight.lock().unwrap().insert(id);
+        let mut block = BytesMut::new();
+        let fields = [("content-length".to_owned(), "0".to_owned())];
+        hpack::encode(status, &fields, &mut block);
+        self.send(Outbound::Response {
+            id,
+            block: block.freeze(),
+            body: Bytes::new(),
+        });
+        if open {
+            self.reset(id, NO_ERROR);
+        }
+    }
+
+    fn reset(&mut self, id: u32, code: u32) {
+        self.send(Outbound::Reset {
+            id,
+            code: Some(code),
+        });
+    }
+
+    fn go_away(&mut self, code: u32) -> io::Result<()> {
+        if code != NO_ERROR {
+            debug!("HTTP/2 connection error {:#x}", code);
+        }
+        let mut payload = [0; 8];
+        payload[..4].copy_from_slice(&self.last_id.to_be_bytes());
+        payload[4..].copy_from_slice(&code.to_be_bytes());
+        self.send(Outbound::Frame(encode_frame(GOAWAY, 0, 0, &payload)));
+        self.accepting = false;
+        Ok(())
+    }
+
+    fn idle(&self) -> bool {
+        self.incoming.is_empty() && self.in_flight.lock().unwrap().is_empty()
+    }
+
+    // a writer that has gone has already failed the connection
+    fn send(&self, message: Outbound) {
+        self.tx.send(message).ok();
+    }
+}
+
+// run one stream's request through the HTTP/1.1 decoder and the handler,
+// so handlers see exactly what they would over HTTP/1.1
+fn respond<T: HttpService>(
+    service: &mut T,
+    id: u32,
+    incoming: Incoming,
+    config: &ServerConfig,
+    endpoints: Endpoints,
+    served: &Served,
+) -> Outbound {
+    let head_only = incoming.head_only;
+    let mut req_buf = incoming.into_http1();
+    let mut body_buf = BytesMut::new();
+    let mut headers = vec![MaybeUninit::uninit(); config.max_headers];
+    let mut state = BodyState::default();
+    let mut detached = Detached;
+    let mut rsp = Response::new(&mut body_buf);
+    let req = request::decode(
+        &mut headers,
+        &mut req_buf,
+        &mut detached,
+        &mut state,
+        config,
+        endpoints,
+    );
+    let mut result = match req {
+        Ok(Some(req)) => {
+            let result = dispatch(service, req, &mut rsp);
+            served.record(&result, &rsp);
+            result
+        }
+        Err(DecodeError::Reject(code, msg)) => {
+            rsp.status_code(code, msg);
+            Ok(())
+        }
+        // the head was rebuilt from checked fields, so what's left to fail
+        // is the client's
+        _ => {
+            rsp.status_code(400, "Bad Request");
+            Ok(())
+        }
+    };
+    if let Some((code, msg)) = state.error.take() {
+        rsp.clear();
+        rsp.status_code(code, msg);
+        result = Ok(());
+    }
+    // frames are built from memory, so files are read in
+    if result.is_ok() {
+        result = rsp.load_file();
+    }
+    match result {
+        Ok(()) => match response::into_parts(rsp) {
+            Some((status, headers, body)) => reply(id, status, &headers, body, head_only, config),
+            // streamed bodies and tunnels are HTTP/1.1 loop only; the
+            // client may retry there (RFC 7540 8.1.2)
+            None => Outbound::Reset {
+                id,
+                code: Some(HTTP_1_1_REQUIRED),
+            },
+        },
+        // only the stream goes; the connection carries others
+        Err(e) if is_dropped(&e) => Outbound::Reset {
+            id,
+            code: Some(CANCEL),
+        },
+        Err(e) => {
+            error!("error in service: err = {:?}", e);
+            reply(id, 500, &[], Bytes::from(e.to_string()), head_only, config)
+        }
+    }
+}
+
+fn reply(
+    id: u32,
+    status: usize,
+    headers: &[Cow<'static, str>],
+    body: Bytes,
+    head_only: bool,
+    config: &ServerConfig,
+) -> Outbound {
+    let mut date = BytesMut::new();
+    append_date(&mut date);
+    let mut fields = vec![
+        (
+            "date".to_owned(),
+            String::from_utf8_lossy(&date).into_owned(),
+        ),
+        ("content-length".to_owned(), body.len().to_string()),
+    ];
+    let own_server = headers.iter().any(|header| {
+        let name = header.split(':').next().unwrap_or_default();
+        name.trim().eq_ignore_ascii_case("server")
+    });
+    if let (Some(server), false) = (&config.server_header, own_server) {
+        fields.insert(0, ("server".to_owned(), server.to_string()));
+    }
+    for header in headers {
+        let (name, value) = header.split_once(':').unwrap_or((header, ""));
+        let name = name.trim().to_ascii_lowercase();
+        if name == "content-length" || CONNECTION_SPECIFIC.contains(&name.as_str()) {
+            continue;
+        }
+        fields.push((name, value.trim().to_owned()));
+    }
+    let mut block = BytesMut::new();
+    hpack::encode(status, &fields, &mut block);
+    Outbound::Response {
+        id,
+        block: block.freeze(),
+        body: if head_only { Bytes::new() } else { body },
+    }
+}
+
+// the connection a stream's request is decoded against: the body is in the
+// buffer already, and interim responses have nowhere to go
+pub(crate) struct Detached;
+
+impl Read for Detached {
+    fn read(&mut self, _buf: &mut [u8]) -> io::Result<usize> {
+        Ok(0)
+    }
+}
+
+impl Write for Detached {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Connection for Detached {}
+
+// the request a decoded header list stands for, `None` if it's malformed
+// (RFC 7540 8.1.2)
+fn incoming(fields: Vec<HeaderField>) -> Option<Incoming> {
+    let mut method = None;
+    let mut scheme = None;
+    let mut path = None;
+    let mut authority = None;
+    let mut length = None;
+    let mut host = false;
+    let mut regular = false;
+    let mut cookies = Vec::new();
+    let mut lines = Vec::new();
+    for (name, value) in fields {
+        if value.iter().any(|&b| b == b'\r' || b == b'\n' || b == 0) {
+            return None;
+        }
+        if let Some(pseudo) = name.strip_prefix(b":") {
+            let slot = match pseudo {
+                b"method" => &mut method,
+                b"scheme" => &mut scheme,
+                b"path" => &mut path,
+                b"authority" => &mut authority,
+                _ => return None,
+            };
+            // pseudo-headers come first, once each
+            if regular || slot.replace(value).is_some() {
+                return None;
+            }
+            continue;
+        }
+        regular = true;
+        let token = |b: &u8| b"!#$%&'*+-.^_`|~".contains(b) || b.is_ascii_alphanumeric();
+        if name.is_empty() || !name.iter().all(|b| token(b) && !b.is_ascii_uppercase()) {
+            return None;
+        }
+        match &name[..] {
+            b"content-length" => {
+                let declared = std::str::from_utf8(&value).ok()?.trim().parse().ok()?;
+                if length
+                    .replace(declared)
+                    .map_or(false, |seen| seen != declared)
+                {
+                    return None;
+                }
+                continue;
+            }
+            b"te" if value != b"trailers" => return None,
+            // the body is already here by the time the handler runs
+            b"te" | b"expect" => continue,
+            b"cookie" => {
+                cookies.push(value);
+                continue;
+            }
+            b"host" => host = true,
+            name if CONNECTION_SPECIFIC.iter().any(|c| c.as_bytes() == name) => return None,
+            _ => {}
+        }
+        field_line(&mut lines, &name, &value);
+    }
+    // CONNECT has no :path and isn't supported
+    let (method, path, _scheme) = (method?, path?, scheme?);
+    if method.is_empty() || path.is_empty() || path.contains(&b' ') {
+        return None;
+    }
+    let mut head = Vec::with_capacity(method.len() + path.len() + lines.len() + 64);
+    head.extend_from_slice(&method);
+    head.push(b' ');
+    head.extend_from_slice(&path);
+    head.extend_from_slice(b" HTTP/1.1\r\n");
+    if let Some(authority) = authority.filter(|_| !host) {
+        field_line(&mut head, b"host", &authority);
+    }
+    // split cookies are joined back for HTTP/1.1 parsers (RFC 7540 8.1.2.5)
+    if !cookies.is_empty() {
+        field_line(&mut head, b"cookie", &cookies.join(&b"; "[..]));
+    }
+    head.extend_from_slice(&lines);
+    Some(Incoming {
+        head_only: method == b"HEAD",
+        head,
+        body: Vec::new(),
+        length,
+    })
+}
+
+fn field_line(buf: &mut Vec<u8>, name: &[u8], value: &[u8]) {
+    buf.extend_from_slice(name);
+    buf.extend_from_slice(b": ");
+    buf.extend_from_slice(value);
+    buf.extend_from_slice(b"\r\n");
+}
+
+// the next complete frame in `buf`
+fn next_frame(buf: &mut BytesMut) -> Result<Option<Frame>, u32> {
+    if buf.len() < 9 {
+        return Ok(None);
+    }
+    let len = (buf[0] as usize) << 16 | (buf[1] as usize) << 8 | buf[2] as usize;
+    if len > MAX_FRAME_SIZE {
+        return Err(FRAME_SIZE_ERROR);
+    }
+    if buf.len() < 9 + len {
+        return Ok(None);
+    }
+    let head = buf.split_to(9);
+    Ok(Some(Frame {
+        kind: head[3],
+        flags: head[4],
+        id: read_u32(&head[5..]) & 0x7fff_ffff,
+        payload: buf.split_to(len).freeze(),
+    }))
+}
+
+// the payload without its padding
+fn unpad(frame: &Frame) -> Result<&[u8], u32> {
+    if frame.flags & PADDED == 0 {
+        return Ok(&frame.payload);
+    }
+    let (&pad, rest) = frame.payload.split_first().ok_or(PROTOCOL_ERROR)?;
+    let len = rest.len().checked_sub(pad as usize).ok_or(PROTOCOL_ERROR)?;
+    Ok(&rest[..len])
+}
+
+// the client settings the writer cares about
+fn parse_settings(payload: &[u8]) -> Result<Outbound, u32> {
+    if payload.len() % 6 != 0 {
+        return Err(FRAME_SIZE_ERROR);
+    }
+    let mut initial_window = None;
+    let mut max_frame = None;
+    for setting in payload.chunks(6) {
+        let value = read_u32(&setting[2..]);
+        match u16::from_be_bytes([setting[0], setting[1]]) {
+            SETTINGS_ENABLE_PUSH if value > 1 => return Err(PROTOCOL_ERROR),
+            SETTINGS_INITIAL_WINDOW_SIZE if i64::from(value) > MAX_WINDOW => {
+                return Err(FLOW_CONTROL_ERROR)
+            }
+            SETTINGS_INITIAL_WINDOW_SIZE => initial_window = Some(value),
+            SETTINGS_MAX_FRAME_SIZE if !(16_384..=16_777_215).contains(&value) => {
+                return Err(PROTOCOL_ERROR)
+            }
+            SETTINGS_MAX_FRAME_SIZE => max_frame = Some(value),
+            // the encoder never indexes, so the table size doesn't matter
+            _ => {}
+        }
+    }
+    Ok(Outbound::Settings {
+        initial_window,
+        max_frame,
+    })
+}
+
+fn server_settings(config: &ServerConfig) -> Bytes {
+    let settings = [
+        (SETTINGS_MAX_CONCURRENT_STREAMS, MAX_STREAMS),
+        (
+            SETTINGS_MAX_HEADER_LIST_SIZE,
+            config.max_header_bytes.min(u32::MAX as usize) as u32,
+        ),
+    ];
+    let mut payload = Vec::with_capacity(settings.len() * 6);
+    for (id, value) in settings {
+        payload.extend_from_slice(&id.to_be_bytes());
+        payload.extend_from_slice(&value.to_be_bytes());
+    }
+    encode_frame(SETTINGS, 0, 0, &payload)
+}
+
+fn window_update(id: u32, increment: usize) -> Outbound {
+    let increment = (increment as u32).to_be_bytes();
+    Outbound::Frame(encode_frame(WINDOW_UPDATE, 0, id, &increment))
+}
+
+fn encode_frame(kind: u8, flags: u8, id: u32, payload: &[u8]) -> Bytes {
+    let mut buf = BytesMut::with_capacity(9 + payload.len());
+    put_frame(&mut buf, kind, flags, id, payload);
+    buf.freeze()
+}
+
+fn put_frame(buf: &mut BytesMut, kind: u8, flags: u8, id: u32, payload: &[u8]) {
+    buf.extend_from_slice(&(payload.len() as u32).to_be_bytes()[1..]);
+    buf.put_u8(kind);
+    buf.put_u8(flags);
+    buf.extend_from_slice(&id.to_be_bytes());
+    buf.extend_from_slice(payload);
+}
+
+fn read_u32(bytes: &[u8]) -> u32 {
+    u32::from_be_bytes([bytes[0], bytes[1], bytes[2], bytes[3]])
+}
+
+// owns the write side: frames go out in the order they're sent, response
+// bodies as the flow control windows allow
+struct Writer<S = TcpStream> {
+    stream: S,
+    out: BytesMut,
+    in_flight: Arc<Mutex<HashSet<u32>>>,
+    max_frame: usize,
+    initial_window: i64,
+    window: i64,
+    // per-stream windows, once they differ from `initial_window`
+    windows: HashMap<u32, i64>,
+    // bodies waiting for window, served round robin
+    queue: VecDeque<(u32, Bytes)>,
+    // streams reset while their handler was still running
+    cancelled: HashSet<u32>,
+    // the highest stream answered
+    last_id: u32,
+}
+
+impl<S: Write> Writer<S> {
+    fn new(stream: S, in_flight: Arc<Mutex<HashSet<u32>>>) -> Self {
+        Writer {
+            stream,
+            out: BytesMut::with_capacity(WRITE_BATCH),
+            in_flight,
+            max_frame: MAX_FRAME_SIZE,
+            initial_window: DEFAULT_WINDOW,
+            window: DEFAULT_WINDOW,
+            windows: HashMap::new(),
+            queue: VecDeque::new(),
+            cancelled: HashSet::new(),
+            last_id: 0,
+        }
+    }
+
+    fn serve(&mut self, rx: &Receiver<Outbound>) -> io::Result<()> {
+        while let Ok(message) = rx.recv() {
+            let mut handled = self.handle(message);
+            while handled.is_ok() {
+                match rx.try_recv() {
+                    Ok(message) => handled = self.handle(message),
+                    Err(_) => break,
+                }
+            }
+            if let Err(code) = handled {
+                let mut payload = [0; 8];
+                payload[..4].copy_from_slice(&self.last_id.to_be_bytes());
+                payload[4..].copy_from_slice(&code.to_be_bytes());
+                put_frame(&mut self.out, GOAWAY, 0, 0, &payload);
+                self.stream.write_all(&self.out).ok();
+                let msg = "HTTP/2 flow control error";
+                return Err(io::Error::new(io::ErrorKind::InvalidData, msg));
+            }
+            loop {
+                self.schedule();
+                if self.out.is_empty() {
+                    break;
+                }
+                self.stream.write_all(&self.out)?;
+                self.out.clear();
+            }
+        }
+        Ok(())
+    }
+
+    fn handle(&mut self, message: Outbound) -> Result<(), u32> {
+        match message {
+            Outbound::Frame(frame) => self.out.extend_from_slice(&frame),
+            Outbound::Response { id, block, body } => self.respond(id, &block, body),
+            Outbound::Reset { id, code } => {
+                if let Some(code) = code {
+                    put_frame(&mut self.out, RST_STREAM, 0, id, &code.to_be_bytes());
+                }
+                self.cancel(id);
+            }
+            Outbound::WindowUpdate { id: 0, increment } => {
+                self.window += i64::from(increment);
+                if self.window > MAX_WINDOW {
+                    return Err(FLOW_CONTROL_ERROR);
+                }
+            }
+            Outbound::WindowUpdate { id, increment } => {
+                // updates can trail a stream that has finished
+                if !self.in_flight.lock().unwrap().contains(&id) {
+                    return Ok(());
+                }
+                let window = self.windows.entry(id).or_insert(self.initial_window);
+                *window += i64::from(increment);
+                if *window > MAX_WINDOW {
+                    let code = FLOW_CONTROL_ERROR.to_be_bytes();
+                    put_frame(&mut self.out, RST_STREAM, 0, id, &code);
+                    self.cancel(id);
+                }
+            }
+            Outbound::Settings {
+                initial_window,
+                max_frame,
+            } => {
+                if let Some(size) = max_frame {
+                    self.max_frame = size as usize;
+                }
+                if let Some(size) = initial_window {
+                    // applies to the windows of open streams too (RFC 7540 6.9.2)
+                    let delta = i64::from(size) - self.initial_window;
+                    self.initial_window = i64::from(size);
+                    for window in self.windows.values_mut() {
+                        *window += delta;
+                        if *window > MAX_WINDOW {
+                            return Err(FLOW_CONTROL_ERROR);
+                        }
+                    }
+                }
+            }
+        }
+        Ok(())
+    }
+
+    fn respond(&mut self, id: u32, block: &[u8], body: Bytes) {
+        self.last_id = self.last_id.max(id);
+        if self.cancelled.remove(&id) {
+            self.finish(id);
+            return;
+        }
+        let mut fragments = block.chunks(self.max_frame);
+        let first = fragments.next().unwrap_or_default();
+        let mut flags = if body.is_empty() { END_STREAM } else { 0 };
+        if fragments.len() == 0 {
+            flags |= END_HEADERS;
+        }
+        put_frame(&mut self.out, HEADERS, flags, id, first);
+        while let Some(fragment) = fragments.next() {
+            let flags = if fragments.len() == 0 { END_HEADERS } else { 0 };
+            put_frame(&mut self.out, CONTINUATION, flags, id, fragment);
+        }
+        match body.is_empty() {
+            true => self.finish(id),
+            false => self.queue.push_back((id, body)),
+        }
+    }
+
+    // move as much queued body into `out` as the windows allow
+    fn schedule(&mut self) {
+        let mut progress = true;
+        while progress && self.out.len() < WRITE_BATCH {
+            progress = false;
+            for _ in 0..self.queue.len() {
+                let (id, mut body) = match self.queue.pop_front() {
+                    Some(queued) => queued,
+                    None => break,
+                };
+                let window = self
+                    .windows
+                    .get(&id)
+                    .copied()
+                    .unwrap_or(self.initial_window);
+                let n = body
+                    .len()
+                    .min(self.max_frame)
+                    .min(self.window.max(0) as usize)
+                    .min(window.max(0) as usize);
+                if n > 0 {
+                    let chunk = body.split_to(n);
+                    self.window -= n as i64;
+                    self.windows.insert(id, window - n as i64);
+                    progress = true;
+                    let end = body.is_empty();
+                    let flags = if end { END_STREAM } else { 0 };
+                    put_frame(&mut self.out, DATA, flags, id, &chunk);
+                    if end {
+                        self.finish(id);
+                        continue;
+                    }
+                }
+                self.queue.push_back((id, body));
+            }
+        }
+    }
+
+    fn cancel(&mut self, id: u32) {
+        if let Some(i) = self.queue.iter().position(|(queued, _)| *queued == id) {
+            self.queue.remove(i);
+            self.finish(id);
+        } else if self.in_flight.lock().unwrap().contains(&id) {
+            self.cancelled.insert(id);
+        }
+    }
+
+    fn finish(&mut self, id: u32) {
+        self.windows.remove(&id);
+        self.in_flight.lock().unwrap().remove(&id);
+    }
+}
+
+impl Writer {
+    // runs until every sender has hung up
+    fn run(mut self, rx: Receiver<Outbound>) -> io::Result<()> {
+        let result = self.serve(&rx);
+        if result.is_err() {
+            self.stream.shutdown(Shutdown::Both).ok();
+        }
+        result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn writer() -> Writer<Vec<u8>> {
+        Writer::new(Vec::new(), Arc::new(Mutex::new(HashSet::new())))
+    }
+
+    fn frames(buf: &mut BytesMut) -> Vec<Frame> {
+        std::iter::from_fn(|| next_frame(buf).unwrap()).collect()
+    }
+
+    // the upgraded request as HTTP/1.1 and the settings it carried
+    fn upgraded(sent: &str) -> Option<(BytesMut, Vec<u8>)> {
+        let config = ServerConfig::default();
+        let mut headers = [MaybeUninit::uninit(); request::MAX_HEADERS];
+        let mut req_buf = BytesMut::from(sent);
+        let mut detached = Detached;
+        let mut state = BodyState::default();
+        let req = request::decode(
+            &mut headers,
+            &mut req_buf,
+            &mut detached,
+            &mut state,
+            &config,
+            Endpoints::default(),
+        );
+        let req = match req {
+            Ok(Some(req)) => req,
+            _ => panic!("request did not decode"),
+        };
+        let (incoming, settings) = upgrade(&req)?;
+        Some((incoming.into_http1(), settings))
+    }
+
+    #[test]
+    fn preface_settings_and_headers_round_trip() {
+        assert!(matches!(sniff(&PREFACE[..10]), Sniff::Incomplete));
+        assert!(matches!(sniff(b"GET / HTTP/1.1\r\n"), Sniff::Http1));
+        let mut buf = BytesMut::from(PREFACE);
+        assert!(matches!(sniff(&buf), Sniff::Preface));
+        buf.advance(PREFACE.len());
+        put_frame(&mut buf, SETTINGS, 0, 0, &[0, 4, 0, 0, 0xff, 0xff]);
+        // RFC 7541 C.3.1
+        let mut block = vec![0x82, 0x86, 0x84, 0x41, 0x0f];
+        block.extend_from_slice(b"www.example.com");
+        put_frame(&mut buf, HEADERS, END_HEADERS | END_STREAM, 1, &block);
+
+        let frames = frames(&mut buf);
+        assert!(buf.is_empty());
+        assert_eq!((frames[0].kind, frames[0].id), (SETTINGS, 0));
+        let settings = parse_settings(&frames[0].payload);
+        assert!(matches!(
+            settings,
+            Ok(Outbound::Settings {
+                initial_window: Some(65_535),
+                max_frame: None
+            })
+        ));
+        assert_eq!((frames[1].kind, frames[1].id), (HEADERS, 1));
+        let fields = Decoder::new().decode(&frames[1].payload, 8192).unwrap();
+        let request = incoming(fields.unwrap()).unwrap().into_http1();
+        assert_eq!(
+            &request[..],
+            b"GET / HTTP/1.1\r\nhost: www.example.com\r\ncontent-length: 0\r\n\r\n"
+        );
+    }
+
+    #[test]
+    fn upgrade_request_becomes_stream_one() {
+        let sent = "GET /x?y=1 HTTP/1.1\r\nHost: example.com\r\n\
+                    Connection: Upgrade, HTTP2-Settings\r\nUpgrade: h2c\r\n\
+                    HTTP2-Settings: AAMAAABkAAQAAP__\r\nAccept: */*\r\n\r\n";
+        let (request, settings) = upgraded(sent).expect("upgrade refused");
+        assert_eq!(
+            &request[..],
+            b"GET /x?y=1 HTTP/1.1\r\nhost: example.com\r\naccept: */*\r\ncontent-length: 0\r\n\r\n"
+        );
+        assert!(matches!(
+            parse_settings(&settings),
+            Ok(Outbound::Settings {
+                initial_window: Some(65_535),
+                max_frame: None
+            })
+        ));
+
+        // a body would have to be read before switching
+        let sent = "POST / HTTP/1.1\r\nContent-Length: 2\r\nUpgrade: h2c\r\n\
+                    HTTP2-Settings: AAMAAABk\r\n\r\nhi";
+        assert!(upgraded(sent).is_none());
+        let sent = "GET / HTTP/1.0\r\nUpgrade: h2c\r\nHTTP2-Settings: AAMAAABk\r\n\r\n";
+        assert!(upgraded(sent).is_none());
+    }
+
+    #[test]
+    fn window_settings_past_the_maximum_are_refused() {
+        // SETTINGS_INITIAL_WINDOW_SIZE of 2^31
+        let setting = [0, 4, 0x80, 0, 0, 0];
+        assert!(matches!(parse_settings(&setting), Err(FLOW_CONTROL_ERROR)));
+        assert!(matches!(parse_settings(&[0, 4, 0]), Err(FRAME_SIZE_ERROR)));
+    }
+
+    #[test]
+    fn connection_window_past_the_maximum_fails_the_connection() {
+        let mut writer = writer();
+        let update = Outbound::WindowUpdate {
+            id: 0,
+            increment: MAX_WINDOW as u32,
+        };
+        assert_eq!(writer.handle(update), Err(FLOW_CONTROL_ERROR));
+    }
+
+    #[test]
+    fn stream_window_past_the_maximum_resets_the_stream() {
+        let mut writer = writer();
+        writer.in_flight.lock().unwrap().insert(1);
+        let update = Outbound::WindowUpdate {
+            id: 1,
+            increment: MAX_WINDOW as u32,
+        };
+        assert_eq!(writer.handle(update), Ok(()));
+        let frames = frames(&mut writer.out);
+        assert_eq!((frames[0].kind, frames[0].id), (RST_STREAM, 1));
+        assert_eq!(&frames[0].payload[..], FLOW_CONTROL_ERROR.to_be_bytes());
+        assert!(writer.cancelled.contains(&1));
+    }
+
+    #[test]
+    fn initial_window_raised_past_an_open_window_fails_the_connection() {
+        let mut writer = writer();
+        writer.in_flight.lock().unwrap().insert(1);
+        let update = Outbound::WindowUpdate {
+            id: 1,
+            increment: 100,
+        };
+        assert_eq!(writer.handle(update), Ok(()));
+        let settings = Outbound::Settings {
+            initial_window: Some(MAX_WINDOW as u32),
+            max_frame: None,
+        };
+        assert_eq!(writer.handle(settings), Err(FLOW_CONTROL_ERROR));
+    }
+
+    #[test]
+    fn body_waits_for_both_windows() {
+        let mut writer = writer();
+        writer.in_flight.lock().unwrap().insert(1);
+        writer.respond(1, &[0x88], Bytes::from(vec![0; 70_000]));
+        writer.schedule();
+        let sent = frames(&mut writer.out);
+        assert_eq!((sent[0].kind, sent[0].flags), (HEADERS, END_HEADERS));
+        let data: usize = sent[1..].iter().map(|frame| frame.payload.len()).sum();
+        assert_eq!(data, DEFAULT_WINDOW as usize);
+        assert!(sent[1..].iter().all(|frame| frame.flags & END_STREAM == 0));
+        writer.schedule();
+        assert!(writer.out.is_empty());
+
+        // the stream's own window still holds it back
+        let connection = Outbound::WindowUpdate {
+            id: 0,
+            increment: 10_000,
+        };
+        assert_eq!(writer.handle(connection), Ok(()));
+        writer.schedule();
+        assert!(writer.out.is_empty());
+        let stream = Outbound::WindowUpdate {
+            id: 1,
+            increment: 10_000,
+        };
+        assert_eq!(writer.handle(stream), Ok(()));
+        writer.schedule();
+        let sent = frames(&mut writer.out);
+        let data: usize = sent.iter().map(|frame| frame.payload.len()).sum();
+        assert_eq!(data, 70_000 - DEFAULT_WINDOW as usize);
+        assert_eq!(sent.last().unwrap().flags, END_STREAM);
+        assert!(writer.in_flight.lock().unwrap().is_empty());
+    }
+}
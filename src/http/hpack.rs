@@ -0,0 +1,557 @@
+//! HPACK header compression for HTTP/2 (RFC 7541)
+
+use std::collections::VecDeque;
+
+use bytes::{BufMut, BytesMut};
+use once_cell::sync::Lazy;
+
+// RFC 7541 Appendix A
+const STATIC_TABLE: [(&str, &str); 61] = [
+    (":authority", ""),
+    (":method", "GET"),
+    (":method", "POST"),
+    (":path", "/"),
+    (":path", "/index.html"),
+    (":scheme", "http"),
+    (":scheme", "https"),
+    (":status", "200"),
+    (":status", "204"),
+    (":status", "206"),
+    (":status", "304"),
+    (":status", "400"),
+    (":status", "404"),
+    (":status", "500"),
+    ("accept-charset", ""),
+    ("accept-encoding", "gzip, deflate"),
+    ("accept-language", ""),
+    ("accept-ranges", ""),
+    ("accept", ""),
+    ("access-control-allow-origin", ""),
+    ("age", ""),
+    ("allow", ""),
+    ("authorization", ""),
+    ("cache-control", ""),
+    ("content-disposition", ""),
+    ("content-encoding", ""),
+    ("content-language", ""),
+    ("content-length", ""),
+    ("content-location", ""),
+    ("content-range", ""),
+    ("content-type", ""),
+    ("cookie", ""),
+    ("date", ""),
+    ("etag", ""),
+    ("expect", ""),
+    ("expires", ""),
+    ("from", ""),
+    ("host", ""),
+    ("if-match", ""),
+    ("if-modified-since", ""),
+    ("if-none-match", ""),
+    ("if-range", ""),
+    ("if-unmodified-since", ""),
+    ("last-modified", ""),
+    ("link", ""),
+    ("location", ""),
+    ("max-forwards", ""),
+    ("proxy-authenticate", ""),
+    ("proxy-authorization", ""),
+    ("range", ""),
+    ("referer", ""),
+    ("refresh", ""),
+    ("retry-after", ""),
+    ("server", ""),
+    ("set-cookie", ""),
+    ("strict-transport-security", ""),
+    ("transfer-encoding", ""),
+    ("user-agent", ""),
+    ("vary", ""),
+    ("via", ""),
+    ("www-authenticate", ""),
+];
+
+// RFC 7541 Appendix B as code lengths; the code is canonical, so the codes
+// themselves follow from the lengths
+const HUFFMAN_LENGTHS: [u8; 257] = [
+    13, 23, 28, 28, 28, 28, 28, 28, 28, 24, 30, 28, 28, 30, 28, 28, 28, 28, 28, 28, 28, 28, 30, 28,
+    28, 28, 28, 28, 28, 28, 28, 28, 6, 10, 10, 12, 13, 6, 8, 11, 10, 10, 8, 11, 8, 6, 6, 6, 5, 5,
+    5, 6, 6, 6, 6, 6, 6, 6, 7, 8, 15, 6, 12, 10, 13, 6, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7, 7,
+    7, 7, 7, 7, 7, 7, 7, 7, 8, 7, 8, 13, 19, 13, 14, 6, 15, 5, 6, 5, 6, 5, 6, 6, 6, 5, 7, 7, 6, 6,
+    6, 5, 6, 7, 6, 5, 5, 6, 7, 7, 7, 7, 7, 15, 11, 14, 13, 28, 20, 22, 20, 20, 22, 22, 22, 23, 22,
+    23, 23, 23, 23, 23, 24, 23, 24, 24, 22, 23, 24, 23, 23, 23, 23, 21, 22, 23, 22, 23, 23, 24, 22,
+    21, 20, 22, 22, 23, 23, 21, 23, 22, 22, 24, 21, 22, 23, 23, 21, 21, 22, 21, 23, 22, 23, 23, 20,
+    22, 22, 22, 23, 22, 22, 23, 26, 26, 20, 19, 22, 23, 22, 25, 26, 26, 26, 27, 27, 26, 24, 25, 19,
+    21, 26, 27, 27, 26, 27, 24, 21, 21, 26, 26, 28, 27, 27, 27, 20, 24, 20, 21, 22, 21, 21, 23, 22,
+    22, 25, 25, 24, 24, 26, 23, 26, 27, 26, 26, 27, 27, 27, 27, 27, 28, 27, 27, 27, 27, 27, 26, 30,
+];
+const EOS: u16 = 256;
+const MAX_CODE_LEN: usize = 30;
+
+// what every table entry is charged on top of its name and value
+const ENTRY_OVERHEAD: usize = 32;
+
+pub(crate) const DEFAULT_TABLE_SIZE: usize = 4096;
+
+// a header block that can't be decoded; fatal to the connection
+#[derive(Debug)]
+pub(crate) struct HpackError;
+
+pub(crate) type HeaderField = (Vec<u8>, Vec<u8>);
+
+// canonical decoding: the codes of each length are consecutive, starting
+// at `first[len]`, and map to `symbols[offset[len]..]`
+struct Huffman {
+    first: [u32; MAX_CODE_LEN + 1],
+    count: [u32; MAX_CODE_LEN + 1],
+    offset: [usize; MAX_CODE_LEN + 1],
+    symbols: Vec<u16>,
+}
+
+static HUFFMAN: Lazy<Huffman> = Lazy::new(|| {
+    let mut symbols: Vec<u16> = (0..=EOS).collect();
+    symbols.sort_by_key(|&symbol| (HUFFMAN_LENGTHS[symbol as usize], symbol));
+    let mut count = [0; MAX_CODE_LEN + 1];
+    for &len in HUFFMAN_LENGTHS.iter() {
+        count[len as usize] += 1;
+    }
+    let mut first = [0; MAX_CODE_LEN + 1];
+    let mut offset = [0; MAX_CODE_LEN + 1];
+    let mut code = 0;
+    let mut index = 0;
+    for len in 1..=MAX_CODE_LEN {
+        first[len] = code;
+        offset[len] = index;
+        code = (code + count[len]) << 1;
+        index += count[len] as usize;
+    }
+    Huffman {
+        first,
+        count,
+        offset,
+        symbols,
+    }
+});
+
+fn huffman_decode(src: &[u8]) -> Result<Vec<u8>, HpackError> {
+    let huffman = &*HUFFMAN;
+    let mut out = Vec::with_capacity(src.len() * 8 / 5);
+    let mut code = 0u32;
+    let mut len = 0;
+    for byte in src {
+        for bit in (0..8).rev() {
+            code = code << 1 | u32::from(byte >> bit & 1);
+            len += 1;
+            if len > MAX_CODE_LEN {
+                return Err(HpackError);
+            }
+            let index = code.wrapping_sub(huffman.first[len]);
+            if index < huffman.count[len] {
+                let symbol = huffman.symbols[huffman.offset[len] + index as usize];
+                if symbol == EOS {
+                    return Err(HpackError);
+                }
+                out.push(symbol as u8);
+                code = 0;
+                len = 0;
+            }
+        }
+    }
+    // padding is the shortest prefix of EOS, i.e. up to 7 one bits
+    if len > 7 || code != (1 << len) - 1 {
+        return Err(HpackError);
+    }
+    Ok(out)
+}
+
+// the decoding side of one connection's header compression
+pub(crate) struct Decoder {
+    table: VecDeque<HeaderField>,
+    size: usize,
+    max_size: usize,
+    // the most we told the peer it may use
+    limit: usize,
+}
+
+impl Decoder {
+    pub(crate) fn new() -> Self {
+        Decoder {
+            table: VecDeque::new(),
+            size: 0,
+            max_size: DEFAULT_TABLE_SIZE,
+            limit: DEFAULT_TABLE_SIZE,
+        }
+    }
+
+    // decode a complete header block, updating the dynamic table; `None`
+    // if the list is over `max_list` bytes, counted the way
+    // SETTINGS_MAX_HEADER_LIST_SIZE counts them
+    pub(crate) fn decode(
+        &mut self,
+        mut block: &[u8],
+        max_list: usize,
+    ) -> Result<Option<Vec<HeaderField>>, HpackError> {
+        let mut fields = Vec::new();
+        let mut listed = 0;
+        let mut leading = true;
+        while let Some(&first) = block.first() {
+            let field = if first & 0x80 != 0 {
+                let index = integer(&mut block, 7)?;
+                self.get(index)?
+            } else if first & 0x40 != 0 {
+                let field = self.literal(&mut block, 6)?;
+                self.insert(field.clone());
+                field
+            } else if first & 0x20 != 0 {
+                // size updates may only open a block
+                if !leading {
+                    return Err(HpackError);
+                }
+                let size = integer(&mut block, 5)?;
+                if size > self.limit {
+                    return Err(HpackError);
+                }
+                self.max_size = size;
+                self.evict(0);
+                continue;
+            } else {
+                // without indexing, or never indexed; the same to a server
+                self.literal(&mut block, 4)?
+            };
+            leading = false;
+            listed += field.0.len() + field.1.len() + ENTRY_OVERHEAD;
+            // the table has to stay in step, so the rest is still decoded
+            if listed <= max_list {
+                fields.push(field);
+            }
+        }
+        Ok((listed <= max_list).then_some(fields))
+    }
+
+    fn get(&self, index: usize) -> Result<HeaderField, HpackError> {
+        match index {
+            0 => Err(HpackError),
+            1..=61 => {
+                let (name, value) = STATIC_TABLE[index - 1];
+                Ok((name.as_bytes().to_vec(), value.as_bytes().to_vec()))
+            }
+            _ => self.table.get(index - 62).cloned().ok_or(HpackError),
+        }
+    }
+
+    fn literal(&self, block: &mut &[u8], prefix: u8) -> Result<HeaderField, HpackError> {
+        let name = match integer(block, prefix)? {
+            0 => string(block)?,
+            index => self.get(index)?.0,
+        };
+        Ok((name, string(block)?))
+    }
+
+    fn insert(&mut self, field: HeaderField) {
+        let size = field.0.len() + field.1.len() + ENTRY_OVERHEAD;
+        self.evict(size);
+        // an entry bigger than the whole table just empties it
+        if size <= self.max_size {
+            self.size += size;
+            self.table.push_front(field);
+        }
+    }
+
+    // drop the oldest entries until `room` more bytes fit
+    fn evict(&mut self, room: usize) {
+        while self.size + room > self.max_size {
+            match self.table.pop_back() {
+                Some((name, value)) => self.size -= name.len() + value.len() + ENTRY_OVERHEAD,
+                None => break,
+            }
+        }
+    }
+}
+
+// an integer with an N-bit prefix (RFC 7541 5.1)
+fn integer(block: &mut &[u8], prefix: u8) -> Result<usize, HpackError> {
+    let (&first, rest) = block.split_first().ok_or(HpackError)?;
+    *block = rest;
+    let max = (1usize << prefix) - 1;
+    let mut value = first as usize & max;
+    if value < max {
+        return Ok(value);
+    }
+    let mut shift = 0;
+    loop {
+        let (&byte, rest) = block.split_first().ok_or(HpackError)?;
+        *block = rest;
+        // anything this long is an attack, not a header
+        if shift > 28 {
+            return Err(HpackError);
+        }
+        value += (byte as usize & 0x7f) << shift;
+        shift += 7;
+        if byte & 0x80 == 0 {
+            return Ok(value);
+        }
+    }
+}
+
+fn string(block: &mut &[u8]) -> Result<Vec<u8>, HpackError> {
+    let huffman = block.first().ok_or(HpackError)? & 0x80 != 0;
+    let len = integer(block, 7)?;
+    if len > block.len() {
+        return Err(HpackError);
+    }
+    let (raw, rest) = block.split_at(len);
+    *block = rest;
+    match huffman {
+        true => huffman_decode(raw),
+        false => Ok(raw.to_vec()),
+    }
+}
+
+// encode a response head; nothing is added to the peer's dynamic table, so
+// the encoder keeps no state, and names are looked up in the static table
+pub(crate) fn encode(status: usize, fields: &[(String, String)], buf: &mut BytesMut) {
+    let status = status.to_string();
+    match STATIC_TABLE[7..14]
+        .iter()
+        .position(|(_, value)| *value == status)
+    {
+        Some(i) => put_integer(buf, 0x80, 7, i + 8),
+        None => {
+            // literal without indexing, with the name of :status
+            put_integer(buf, 0x00, 4, 8);
+            put_string(buf, &status);
+        }
+    }
+    for (name, value) in fields {
+        match STATIC_TABLE.iter().position(|(known, _)| known == name) {
+            Some(i) => put_integer(buf, 0x00, 4, i + 1),
+            None => {
+                buf.put_u8(0x00);
+                put_string(buf, name);
+            }
+        }
+        put_string(buf, value);
+    }
+}
+
+fn put_integer(buf: &mut BytesMut, flags: u8, prefix: u8, mut value: usize) {
+    let max = (1usize << prefix) - 1;
+    if value < max {
+        buf.put_u8(flags | value as u8);
+        return;
+    }
+    buf.put_u8(flags | max as u8);
+    value -= max;
+    while value >= 0x80 {
+        buf.put_u8(value as u8 & 0x7f | 0x80);
+        value >>= 7;
+    }
+    buf.put_u8(value as u8);
+}
+
+fn put_string(buf: &mut BytesMut, s: &str) {
+    put_integer(buf, 0x00, 7, s.len());
+    buf.extend_from_slice(s.as_bytes());
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn hex(s: &str) -> Vec<u8> {
+        let digits: Vec<u8> = s.bytes().filter(|b| !b.is_ascii_whitespace()).collect();
+        digits
+            .chunks(2)
+            .map(|pair| u8::from_str_radix(std::str::from_utf8(pair).unwrap(), 16).unwrap())
+            .collect()
+    }
+
+    fn decode(decoder: &mut Decoder, block: &str) -> Vec<(String, String)> {
+        strings(decoder.decode(&hex(block), usize::MAX).unwrap().unwrap())
+    }
+
+    fn strings(fields: Vec<HeaderField>) -> Vec<(String, String)> {
+        fields
+            .into_iter()
+            .map(|(name, value)| {
+                let text = |b: Vec<u8>| String::from_utf8(b).unwrap();
+                (text(name), text(value))
+            })
+            .collect()
+    }
+
+    fn fields(pairs: &[(&str, &str)]) -> Vec<(String, String)> {
+        pairs
+            .iter()
+            .map(|&(name, value)| (name.to_owned(), value.to_owned()))
+            .collect()
+    }
+
+    // RFC 7541 C.1
+    #[test]
+    fn integer_examples() {
+        for (value, prefix, wire) in [(10, 5, "0a"), (1337, 5, "1f9a0a"), (42, 8, "2a")] {
+            let mut buf = BytesMut::new();
+            put_integer(&mut buf, 0, prefix, value);
+            assert_eq!(&buf[..], hex(wire));
+            assert_eq!(integer(&mut &hex(wire)[..], prefix).unwrap(), value);
+        }
+    }
+
+    #[test]
+    fn overlong_or_truncated_integers_are_refused() {
+        assert!(integer(&mut &hex("7fffffffffff01")[..], 7).is_err());
+        assert!(integer(&mut &hex("1f9a")[..], 5).is_err());
+        // an index far past both tables
+        let block = hex("ffffffff0f");
+        assert!(Decoder::new().decode(&block, usize::MAX).is_err());
+    }
+
+    // RFC 7541 C.2
+    #[test]
+    fn literal_field_examples() {
+        let mut decoder = Decoder::new();
+        let block = "400a 6375 7374 6f6d 2d6b 6579 0d63 7573 746f 6d2d 6865 6164 6572";
+        let custom = fields(&[("custom-key", "custom-header")]);
+        assert_eq!(decode(&mut decoder, block), custom);
+        assert_eq!(decoder.size, 55);
+
+        let mut decoder = Decoder::new();
+        let block = "040c 2f73 616d 706c 652f 7061 7468";
+        assert_eq!(
+            decode(&mut decoder, block),
+            fields(&[(":path", "/sample/path")])
+        );
+        let block = "1008 7061 7373 776f 7264 0673 6563 7265 74";
+        assert_eq!(
+            decode(&mut decoder, block),
+            fields(&[("password", "secret")])
+        );
+        assert_eq!(decode(&mut decoder, "82"), fields(&[(":method", "GET")]));
+        assert!(decoder.table.is_empty());
+    }
+
+    // RFC 7541 C.3 and C.4: the same requests, without and with Huffman
+    #[test]
+    fn request_examples() {
+        let blocks = [
+            [
+                "8286 8441 0f77 7777 2e65 7861 6d70 6c65 2e63 6f6d",
+                "8286 84be 5808 6e6f 2d63 6163 6865",
+                "8287 85bf 400a 6375 7374 6f6d 2d6b 6579 0c63 7573 746f 6d2d 7661 6c75 65",
+            ],
+            [
+                "8286 8441 8cf1 e3c2 e5f2 3a6b a0ab 90f4 ff",
+                "8286 84be 5886 a8eb 1064 9cbf",
+                "8287 85bf 4088 25a8 49e9 5ba9 7d7f 8925 a849 e95b b8e8 b4bf",
+            ],
+        ];
+        let authority = (":authority", "www.example.com");
+        let expected = [
+            fields(&[
+                (":method", "GET"),
+                (":scheme", "http"),
+                (":path", "/"),
+                authority,
+            ]),
+            fields(&[
+                (":method", "GET"),
+                (":scheme", "http"),
+                (":path", "/"),
+                authority,
+                ("cache-control", "no-cache"),
+            ]),
+            fields(&[
+                (":method", "GET"),
+                (":scheme", "https"),
+                (":path", "/index.html"),
+                authority,
+                ("custom-key", "custom-value"),
+            ]),
+        ];
+        for requests in blocks {
+            let mut decoder = Decoder::new();
+            for ((block, expected), size) in requests.iter().zip(&expected).zip([57, 110, 164]) {
+                assert_eq!(&decode(&mut decoder, block), expected);
+                assert_eq!(decoder.size, size);
+            }
+        }
+    }
+
+    // RFC 7541 C.5: a 256 byte table, so entries are evicted
+    #[test]
+    fn response_examples() {
+        let mut decoder = Decoder::new();
+        decoder.max_size = 256;
+        let block = "4803 3330 3258 0770 7269 7661 7465 611d 4d6f 6e2c 2032 3120 4f63 7420 \
+                     3230 3133 2032 303a 3133 3a32 3120 474d 546e 1768 7474 7073 3a2f 2f77 \
+                     7777 2e65 7861 6d70 6c65 2e63 6f6d";
+        let first = [
+            (":status", "302"),
+            ("cache-control", "private"),
+            ("date", "Mon, 21 Oct 2013 20:13:21 GMT"),
+            ("location", "https://www.example.com"),
+        ];
+        assert_eq!(decode(&mut decoder, block), fields(&first));
+        assert_eq!(decoder.size, 222);
+
+        let mut second = first;
+        second[0] = (":status", "307");
+        assert_eq!(decode(&mut decoder, "4803 3330 37c1 c0bf"), fields(&second));
+        assert_eq!(decoder.size, 222);
+
+        let block = "88c1 611d 4d6f 6e2c 2032 3120 4f63 7420 3230 3133 2032 303a 3133 3a32 \
+                     3220 474d 54c0 5a04 677a 6970 7738 666f 6f3d 4153 444a 4b48 514b 425a \
+                     584f 5157 454f 5049 5541 5851 5745 4f49 553b 206d 6178 2d61 6765 3d33 \
+                     3630 303b 2076 6572 7369 6f6e 3d31";
+        let third = fields(&[
+            (":status", "200"),
+            ("cache-control", "private"),
+            ("date", "Mon, 21 Oct 2013 20:13:22 GMT"),
+            ("location", "https://www.example.com"),
+            ("content-encoding", "gzip"),
+            (
+                "set-cookie",
+                "foo=ASDJKHQKBZXOQWEOPIUAXQWEOIU; max-age=3600; version=1",
+            ),
+        ]);
+        assert_eq!(decode(&mut decoder, block), third);
+        assert_eq!(decoder.size, 215);
+        assert_eq!(decoder.table.len(), 3);
+    }
+
+    #[test]
+    fn table_size_updates() {
+        let mut decoder = Decoder::new();
+        decode(&mut decoder, "400a 6375 7374 6f6d 2d6b 6579 0163");
+        assert_eq!(decoder.table.len(), 1);
+        // down to nothing and back up, both ahead of the first field
+        let mut block = BytesMut::from(&hex("20")[..]);
+        put_integer(&mut block, 0x20, 5, DEFAULT_TABLE_SIZE);
+        block.put_u8(0x82);
+        let decoded = decoder.decode(&block, usize::MAX).unwrap().unwrap();
+        assert_eq!(decoded.len(), 1);
+        assert!(decoder.table.is_empty());
+        assert_eq!((decoder.size, decoder.max_size), (0, DEFAULT_TABLE_SIZE));
+        assert!(decoder.decode(&hex("be"), usize::MAX).is_err());
+
+        // past what we allowed
+        let mut buf = BytesMut::new();
+        put_integer(&mut buf, 0x20, 5, DEFAULT_TABLE_SIZE + 1);
+        assert!(Decoder::new().decode(&buf, usize::MAX).is_err());
+        // after a field
+        assert!(Decoder::new().decode(&hex("8220"), usize::MAX).is_err());
+    }
+
+    #[test]
+    fn encoded_response_decodes() {
+        for status in [200, 201] {
+            let headers = fields(&[("content-type", "text/plain"), ("x-trace", "abc")]);
+            let mut block = BytesMut::new();
+            encode(status, &headers, &mut block);
+            let mut expected = fields(&[(":status", &status.to_string())]);
+            expected.extend(headers);
+            let decoded = Decoder::new().decode(&block, usize::MAX).unwrap().unwrap();
+            assert_eq!(strings(decoded), expected);
+        }
+    }
+}
@@ -1,20 +1,35 @@
 //! http server implementation on top of `MAY`
 
-use std::io::{self, Read, Write};
+use std::io::{self, IoSlice, Read, Write};
 use std::mem::MaybeUninit;
 use std::net::ToSocketAddrs;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::{Arc, RwLock};
+use std::time::Instant;
 
-use bytes::{Buf, BytesMut};
+use bytes::{Buf, Bytes, BytesMut};
 
-#[cfg(unix)]
+#[cfg(not(feature = "safe-io"))]
 use bytes::BufMut;
 #[cfg(unix)]
 use may::io::WaitIo;
 use may::net::{TcpListener, TcpStream};
 use may::{coroutine, go};
 
-use crate::request::request::RawRequest;
-use crate::response::response::Response;
+use crate::http::connection::Connection;
+use crate::http::h2::{self, Sniff, Switch};
+use crate::http::memory::{self, BufferGauge, PooledBuf};
+use crate::http::passthrough;
+use crate::http::platform;
+use crate::http::proxy::{self, Preamble};
+use crate::http::shutdown::{ConnectionTags, Lifecycle, ServerHandle};
+use crate::http::sni::{self, Hello};
+use crate::http::socket;
+use crate::http::tunnel;
+use crate::request::request::{BodyState, DecodeError, Endpoints, RawRequest};
+use crate::response::response::{FileBody, Response};
+use crate::response::writer::{BodyWriter, StreamBody, WriteProgress};
+use crate::server::config::{AtCapacity, ServerConfig};
 
 const BUF_LEN: usize = 4096 * 8;
 
@@ -41,6 +56,8 @@ pub trait HttpServiceFactory: Send + Sized + 'static {
 
     fn start<L: ToSocketAddrs>(self, addr: L) -> io::Result<coroutine::JoinHandle<()>> {
         let listener = TcpListener::bind(addr)?;
+        let config = Arc::new(ServerConfig::default());
+        let lifecycle = Arc::new(Lifecycle::default());
         go!(
             coroutine::Builder::new().name("TcpServerFac".to_owned()),
             move || {
@@ -55,15 +72,26 @@ pub trait HttpServiceFactory: Send + Sized + 'static {
                     #[cfg(windows)]
                     let id = stream.as_raw_socket() as usize;
                     // t_c!(stream.set_nodelay(true));
-                    let service = self.new_service(id);
+                    let mut service = self.new_service(id);
+                    let config = config.clone();
+                    let lifecycle = lifecycle.clone();
                     let builder = may::coroutine::Builder::new().id(id);
-                    go!(
-                        builder,
-                        move || if let Err(e) = each_connection_loop(&mut stream, service) {
+                    t_c!(socket::configure(&stream, &config));
+                    go!(builder, move || {
+                        // the default config never switches to HTTP/2
+                        let tags = ConnectionTags::default();
+                        let result = each_connection_loop(
+                            &mut stream,
+                            &mut service,
+                            &config,
+                            &lifecycle,
+                            &tags,
+                        );
+                        if let Err(e) = result {
                             error!("service err = {:?}", e);
-                            stream.shutdown(std::net::Shutdown::Both).ok();
+                            socket::close_on_error(&stream, &config);
                         }
-                    )
+                    })
                     .unwrap();
                 }
             }
@@ -71,62 +99,703 @@ pub trait HttpServiceFactory: Send + Sized + 'static {
     }
 }
 
+// returns the bytes read and whether the peer has shut down its write side
 #[cfg(unix)]
 #[inline]
-fn nonblock_read(stream: &mut impl Read, req_buf: &mut BytesMut) -> io::Result<usize> {
+fn nonblock_read(stream: &mut impl Read, req_buf: &mut BytesMut) -> io::Result<(usize, bool)> {
     let mut read_cnt = 0;
     loop {
-        let read_buf: &mut [u8] = unsafe { std::mem::transmute(req_buf.chunk_mut()) };
-        match stream.read(read_buf) {
-            Ok(0) => return Err(io::Error::new(io::ErrorKind::BrokenPipe, "closed")),
-            Ok(n) => {
-                read_cnt += n;
-                unsafe { req_buf.advance_mut(n) };
-            }
-            Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok(read_cnt),
+        match read_into(stream, req_buf) {
+            Ok(0) => return Ok((read_cnt, true)),
+            Ok(n) => read_cnt += n,
+            Err(err) if err.kind() == io::ErrorKind::WouldBlock => return Ok((read_cnt, false)),
             Err(err) => return Err(err),
         }
     }
 }
 
+// writes what the socket takes without blocking; `write_buf` keeps exactly
+// the bytes not yet sent, so the next call resumes where this one stopped
 #[cfg(unix)]
 #[inline]
-fn nonblock_write(stream: &mut impl Write, write_buf: &mut BytesMut) -> io::Result<usize> {
-    let len = write_buf.len();
-    if len == 0 {
-        return Ok(0);
-    }
-
+pub(crate) fn nonblock_write(
+    stream: &mut impl Write,
+    write_buf: &mut BytesMut,
+) -> io::Result<usize> {
     let mut written = 0;
-    while written < len {
-        match stream.write(unsafe { write_buf.get_unchecked(written..) }) {
-            Ok(0) => return Err(io::Error::new(io::ErrorKind::BrokenPipe, "closed")),
+    while !write_buf.is_empty() {
+        match write_some(stream, write_buf) {
             Ok(n) => written += n,
             Err(err) if err.kind() == io::ErrorKind::WouldBlock => break,
             Err(err) => return Err(err),
         }
     }
-    write_buf.advance(written);
     Ok(written)
 }
 
+// one write from the front of `buf`, retried when interrupted; what was
+// sent is taken off `buf` at once, so a short write or a later error never
+// leaves bytes to be sent twice
+#[inline]
+pub(crate) fn write_some(
+    stream: &mut (impl Write + ?Sized),
+    buf: &mut BytesMut,
+) -> io::Result<usize> {
+    loop {
+        match stream.write(buf) {
+            Ok(0) => return Err(io::Error::new(io::ErrorKind::WriteZero, "write zero byte")),
+            Ok(n) => {
+                buf.advance(n);
+                return Ok(n);
+            }
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => continue,
+            Err(err) => return Err(err),
+        }
+    }
+}
+
 #[inline]
 pub(crate) fn reserve_buf(buf: &mut BytesMut) {
+    reserve_to(buf, BUF_LEN);
+}
+
+// tops a nearly full buffer up to `size` bytes of room
+pub(crate) fn reserve_to(buf: &mut BytesMut, size: usize) {
     let capacity = buf.capacity();
     if capacity < 1024 {
-        buf.reserve(BUF_LEN - capacity);
+        buf.reserve(size - capacity);
+    }
+}
+
+// read into the spare capacity of `buf`, extending it by the bytes read
+#[cfg(not(feature = "safe-io"))]
+#[inline]
+pub(crate) fn read_into(
+    stream: &mut (impl Read + ?Sized),
+    buf: &mut BytesMut,
+) -> io::Result<usize> {
+    // the spare capacity is uninitialized; `read` only ever writes to it
+    let read_buf: &mut [u8] = unsafe { std::mem::transmute(buf.chunk_mut()) };
+    let n = stream.read(read_buf)?;
+    unsafe { buf.advance_mut(n) };
+    Ok(n)
+}
+
+// zero the spare capacity before handing it out, so no uninitialized bytes
+// are ever seen as `&mut [u8]`; slower, but checkable under Miri
+#[cfg(feature = "safe-io")]
+pub(crate) fn read_into(
+    stream: &mut (impl Read + ?Sized),
+    buf: &mut BytesMut,
+) -> io::Result<usize> {
+    let len = buf.len();
+    if buf.capacity() == len {
+        buf.reserve(64);
     }
+    buf.resize(buf.capacity(), 0);
+    let result = stream.read(&mut buf[len..]);
+    buf.truncate(len + *result.as_ref().unwrap_or(&0));
+    result
 }
 
-pub struct HttpServer<T>(pub T);
+pub struct HttpServer<T>(pub T, pub Arc<ServerConfig>);
+
+pub(crate) fn dispatch<T: HttpService>(
+    service: &mut T,
+    mut req: RawRequest,
+    rsp: &mut Response,
+) -> io::Result<()> {
+    // ambiguous framing leaves no safe place to resume reading
+    match req.framing() {
+        Ok(framing) => req.track_body(framing),
+        Err((code, msg)) => {
+            req.reject_body();
+            rsp.status_code(code, msg);
+            return Ok(());
+        }
+    }
+    match panic::catch_unwind(AssertUnwindSafe(|| service.handler(req, rsp))) {
+        Ok(result) => result,
+        Err(payload) => {
+            // may cancels coroutines by unwinding with its own payload; only
+            // handler panics, which carry a message, are answered here
+            let msg = match payload.downcast_ref::<&str>() {
+                Some(msg) => msg.to_string(),
+                None => match payload.downcast_ref::<String>() {
+                    Some(msg) => msg.clone(),
+                    None => panic::resume_unwind(payload),
+                },
+            };
+            error!("handler panicked: {}", msg);
+            rsp.clear();
+            Err(io::Error::new(io::ErrorKind::Other, "handler panicked"))
+        }
+    }
+}
 
+// answer a request whose head broke a limit; nothing after it can be parsed
+fn reject_head(
+    config: &ServerConfig,
+    code: usize,
+    msg: &'static str,
+    body_buf: &mut BytesMut,
+    res_buf: &mut BytesMut,
+) {
+    debug!("rejecting request head: {} {}", code, msg);
+    let mut rsp = Response::new(body_buf);
+    rsp.server = config.server_header.clone();
+    rsp.status_code(code, msg).header("Connection: close");
+    crate::response::response::encode(rsp, res_buf);
+}
+
+// what a TLS client gets on the plaintext port; it can't be sent anywhere
+// else, as it won't read an HTTP redirect
+fn reject_tls(config: &ServerConfig, body_buf: &mut BytesMut, res_buf: &mut BytesMut) {
+    debug!("TLS handshake on the plaintext port, answering 400");
+    let mut rsp = Response::new(body_buf);
+    rsp.server = config.server_header.clone();
+    rsp.status_code(400, "Bad Request")
+        .header("Connection: close")
+        .header("Content-Type: text/plain; charset=utf-8")
+        .body("This port speaks plain HTTP, not HTTPS; use an http:// URL.\n");
+    crate::response::response::encode(rsp, res_buf);
+}
+
+// tracks how long the connection has been waiting on the client: a started
+// head is bounded by the header timeout, an idle connection by keep-alive
+struct ReadClock {
+    since: Instant,
+    partial: bool,
+}
+
+impl ReadClock {
+    fn new() -> Self {
+        ReadClock {
+            since: Instant::now(),
+            partial: false,
+        }
+    }
+
+    // call after each parse pass; trickling bytes into a head doesn't
+    // restart the clock
+    fn update(&mut self, served: bool, req_buf: &BytesMut) {
+        if served || !self.partial {
+            self.since = Instant::now();
+        }
+        self.partial = !req_buf.is_empty();
+    }
+
+    fn deadline(&self, config: &ServerConfig) -> Option<Instant> {
+        let timeout = if self.partial {
+            config.header_timeout
+        } else {
+            config.keep_alive_timeout
+        };
+        timeout.map(|timeout| self.since + timeout)
+    }
+}
+
+// discard body bytes a handler left unread, so the next pipelined request
+// parses from its own first byte; returns whether any are still to come
+fn skip_unread(req_buf: &mut BytesMut, skip: &mut usize) -> bool {
+    let n = (*skip).min(req_buf.len());
+    req_buf.advance(n);
+    *skip -= n;
+    *skip > 0
+}
+
+// the connection has served its share and should hand the client back to
+// the load balancer
+fn drained(config: &ServerConfig, requests: usize, opened: Instant) -> bool {
+    config.max_requests.map_or(false, |max| requests >= max)
+        || config
+            .max_connection_age
+            .map_or(false, |age| opened.elapsed() >= age)
+}
+
+// the client stalled; one that was halfway through a request is told so
+fn time_out(
+    stream: &mut TcpStream,
+    config: &ServerConfig,
+    req_buf: &BytesMut,
+    body_buf: &mut BytesMut,
+    res_buf: &mut BytesMut,
+) -> io::Result<()> {
+    debug!("client stalled, closing the connection");
+    if !req_buf.is_empty() {
+        reject_head(config, 408, "Request Timeout", body_buf, res_buf);
+    }
+    stream.write_all(res_buf)?;
+    stream.shutdown(std::net::Shutdown::Both).ok();
+    Ok(())
+}
+
+// blocking read bounded by `deadline`; `None` once it has passed
 #[cfg(unix)]
-fn each_connection_loop<T: HttpService>(stream: &mut TcpStream, mut service: T) -> io::Result<()> {
-    use crate::{request, response};
+fn read_until(
+    stream: &mut TcpStream,
+    req_buf: &mut BytesMut,
+    deadline: Instant,
+) -> io::Result<Option<usize>> {
+    let now = Instant::now();
+    if deadline <= now {
+        return Ok(None);
+    }
+    stream.set_read_timeout(Some(deadline - now))?;
+    reserve_buf(req_buf);
+    match read_into(stream, req_buf) {
+        Ok(n) => Ok(Some(n)),
+        Err(e) if is_timeout(&e) => Ok(None),
+        Err(e) => Err(e),
+    }
+}
+
+// marks a handler's request to drop the connection without answering
+#[derive(Debug)]
+struct Dropped;
+
+impl std::fmt::Display for Dropped {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        f.write_str("connection dropped")
+    }
+}
 
-    let mut req_buf = BytesMut::with_capacity(BUF_LEN);
-    let mut res_buf = BytesMut::with_capacity(BUF_LEN);
-    let mut body_buf = BytesMut::with_capacity(BUF_LEN);
+impl std::error::Error for Dropped {}
+
+pub(crate) fn dropped() -> io::Error {
+    io::Error::new(io::ErrorKind::ConnectionAborted, Dropped)
+}
+
+pub(crate) fn is_dropped(e: &io::Error) -> bool {
+    e.get_ref().map_or(false, |inner| inner.is::<Dropped>())
+}
+
+pub(crate) fn is_timeout(e: &io::Error) -> bool {
+    matches!(
+        e.kind(),
+        io::ErrorKind::TimedOut | io::ErrorKind::WouldBlock
+    )
+}
+
+// reconcile the handler's response with what happened to the request body
+// and what the client asked for; returns whether the connection has to
+// close afterwards
+fn settle(
+    state: &mut BodyState,
+    rsp: &mut Response,
+    result: &mut io::Result<()>,
+    keep_alive: bool,
+    version: u8,
+) -> bool {
+    if let Some((code, msg)) = state.error.take() {
+        rsp.clear();
+        rsp.status_code(code, msg);
+        *result = Ok(());
+        state.body_rejected = true;
+    }
+    if rsp.closes() {
+        return true;
+    }
+    // the handler answered without asking for the body, so the client may
+    // still send it; we can't frame what follows. a chunked body left
+    // half-read can't be skipped without decoding it either
+    if !keep_alive || state.expect_continue || state.body_rejected || state.chunked_open {
+        rsp.header("Connection: close");
+        return true;
+    }
+    // nor can an HTTP/1.0 client be sent chunks; the close ends the body
+    if version == 0 && rsp.streams() {
+        rsp.header("Connection: close");
+        return true;
+    }
+    // HTTP/1.0 clients close unless told the connection persists
+    if version == 0 {
+        rsp.header("Connection: keep-alive");
+    }
+    false
+}
+
+// flush any responses queued ahead of the stream, then hand the socket to
+// the producer; errors here leave the connection mid-body so they're fatal
+fn write_stream(
+    stream: &mut TcpStream,
+    res_buf: &mut BytesMut,
+    body: StreamBody,
+    chunked: bool,
+    config: &ServerConfig,
+) -> io::Result<()> {
+    stream.write_all(res_buf)?;
+    res_buf.clear();
+    let mut writer = BodyWriter::new(stream, config);
+    if !chunked {
+        writer.unchunked();
+    }
+    body(&mut writer)?;
+    writer.finish()
+}
+
+// queued responses and the head, then the file from the kernel's side where
+// the platform allows
+fn write_file(
+    stream: &mut TcpStream,
+    res_buf: &mut BytesMut,
+    body: FileBody,
+    config: &ServerConfig,
+) -> io::Result<()> {
+    stream.write_all(res_buf)?;
+    res_buf.clear();
+    platform::send_file(stream, &body.file, body.offset, body.len, config)
+}
+
+// queued responses, the head and every segment go out in as few vectored
+// writes as the socket allows, without copying the segments
+fn write_segments(
+    stream: &mut (impl Write + ?Sized),
+    res_buf: &mut BytesMut,
+    segments: Vec<Bytes>,
+) -> io::Result<()> {
+    let mut segments: Vec<Bytes> = segments.into_iter().filter(|s| !s.is_empty()).collect();
+    let mut head = 0;
+    let mut next = 0;
+    while head < res_buf.len() || next < segments.len() {
+        let mut slices = Vec::with_capacity(segments.len() - next + 1);
+        if head < res_buf.len() {
+            slices.push(IoSlice::new(&res_buf[head..]));
+        }
+        slices.extend(segments[next..].iter().map(|s| IoSlice::new(s)));
+        let mut n = match stream.write_vectored(&slices) {
+            Ok(0) => return Err(io::ErrorKind::WriteZero.into()),
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        let taken = n.min(res_buf.len() - head);
+        head += taken;
+        n -= taken;
+        while n > 0 {
+            let segment = &mut segments[next];
+            if n < segment.len() {
+                segment.advance(n);
+                break;
+            }
+            n -= segment.len();
+            next += 1;
+        }
+    }
+    res_buf.clear();
+    Ok(())
+}
+
+// what a connection carries from one read to the next; the platform loops
+// below do the reading and writing around it
+struct ConnState<'a> {
+    req_buf: PooledBuf<'a>,
+    res_buf: PooledBuf<'a>,
+    body_buf: PooledBuf<'a>,
+    close: bool,
+    header_slots: usize,
+    clock: ReadClock,
+    gauge: BufferGauge,
+    opened: Instant,
+    requests: usize,
+    // body bytes of the last request still to be read past
+    skip: usize,
+    endpoints: Endpoints,
+    // the PROXY header, when expected, precedes the first request
+    proxied: bool,
+}
+
+// where serving what's buffered left the connection
+enum Step {
+    // more bytes are wanted, or the responses have to go out first
+    Read,
+    // a body only the socket can write, after the queued responses;
+    // serving resumes once it's out
+    Write(SocketBody),
+    // nothing more is served on it here
+    Handoff(Handoff),
+}
+
+enum SocketBody {
+    // and whether to chunk it, which HTTP/1.0 clients can't decode
+    Stream(StreamBody, bool),
+    File(FileBody),
+}
+
+enum Handoff {
+    // a TLS client for a passthrough backend, by the server name it asked for
+    Passthrough(Option<String>),
+    // an accepted CONNECT, its head queued in `res_buf`
+    Tunnel(TcpStream),
+    Http2(Switch),
+}
+
+impl<'a> ConnState<'a> {
+    fn new(config: &'a ServerConfig, endpoints: Endpoints) -> Self {
+        ConnState {
+            req_buf: PooledBuf::new(config),
+            res_buf: PooledBuf::new(config),
+            body_buf: PooledBuf::new(config),
+            close: false,
+            header_slots: crate::request::request::MAX_HEADERS,
+            clock: ReadClock::new(),
+            gauge: BufferGauge::default(),
+            opened: Instant::now(),
+            requests: 0,
+            skip: 0,
+            endpoints,
+            proxied: config.proxy_protocol,
+        }
+    }
+
+    // parse and answer the requests read so far, queueing the responses in
+    // `res_buf`; `stream` is only read for bodies and written for upgrades
+    fn serve_buffered<T: HttpService>(
+        &mut self,
+        stream: &mut dyn Connection,
+        service: &mut T,
+        config: &ServerConfig,
+        lifecycle: &Lifecycle,
+    ) -> io::Result<Step> {
+        use crate::{request, response};
+
+        let mut served = false;
+        while !self.close {
+            if self.proxied {
+                match proxy::strip(&mut self.req_buf) {
+                    Preamble::Incomplete => break,
+                    Preamble::Done(source) => {
+                        self.endpoints.remote = source.or(self.endpoints.remote);
+                        self.proxied = false;
+                    }
+                    Preamble::Invalid => {
+                        self.close = true;
+                        break;
+                    }
+                }
+            }
+            if skip_unread(&mut self.req_buf, &mut self.skip) {
+                break;
+            }
+            // a TLS client opens with its ClientHello: one for a passthrough
+            // backend is relayed, any other has the wrong port
+            if self.requests == 0 {
+                match sni::sniff(&self.req_buf) {
+                    Hello::Tls(name) if !config.passthrough.by_name.is_empty() => {
+                        return Ok(Step::Handoff(Handoff::Passthrough(name)));
+                    }
+                    Hello::Tls(_) => {
+                        reject_tls(config, &mut self.body_buf, &mut self.res_buf);
+                        self.close = true;
+                        break;
+                    }
+                    Hello::Incomplete => break,
+                    Hello::Plain => {}
+                }
+            }
+            // a client with prior knowledge opens with the HTTP/2 preface
+            if config.http2 && self.requests == 0 {
+                match h2::sniff(&self.req_buf) {
+                    Sniff::Preface => {
+                        let switch = Switch {
+                            buf: self.req_buf.take(),
+                            endpoints: self.endpoints.clone(),
+                            upgraded: None,
+                        };
+                        return Ok(Step::Handoff(Handoff::Http2(switch)));
+                    }
+                    Sniff::Incomplete => break,
+                    Sniff::Http1 => {}
+                }
+            }
+            let mut stack = [MaybeUninit::uninit(); request::request::MAX_HEADERS];
+            let mut heap;
+            // only requests with unusually many headers pay for an allocation
+            let headers: &mut [_] = if self.header_slots > stack.len() {
+                heap = vec![MaybeUninit::uninit(); self.header_slots];
+                &mut heap
+            } else {
+                &mut stack
+            };
+            let mut state = BodyState::default();
+            let req = request::request::decode(
+                headers,
+                &mut self.req_buf,
+                stream,
+                &mut state,
+                config,
+                self.endpoints.clone(),
+            );
+            let mut req = match req {
+                Ok(Some(req)) => req,
+                Ok(None) => break,
+                Err(DecodeError::Grow) => {
+                    self.header_slots = (self.header_slots * 2).min(config.max_headers);
+                    continue;
+                }
+                Err(DecodeError::Reject(code, msg)) => {
+                    reject_head(config, code, msg, &mut self.body_buf, &mut self.res_buf);
+                    self.close = true;
+                    break;
+                }
+                Err(DecodeError::Io(e)) => return Err(e),
+            };
+            if config.http2 {
+                if let Some(upgraded) = h2::upgrade(&req) {
+                    drop(req);
+                    stream.write_all(&self.res_buf)?;
+                    stream.write_all(h2::SWITCHING)?;
+                    let buf = self.req_buf.take();
+                    let switch = Switch {
+                        buf,
+                        endpoints: self.endpoints.clone(),
+                        upgraded: Some(upgraded),
+                    };
+                    return Ok(Step::Handoff(Handoff::Http2(switch)));
+                }
+            }
+            served = true;
+            if !self.res_buf.is_empty() {
+                req.hold_pending(&mut self.res_buf);
+            }
+            self.requests += 1;
+            let keep_alive = req.keep_alive()
+                && !drained(config, self.requests, self.opened)
+                && !lifecycle.draining();
+            let version = req.version();
+            let mut rsp = Response::new(&mut self.body_buf);
+            rsp.server = config.server_header.clone();
+            let mut result = dispatch(service, req, &mut rsp);
+            // what no interim response wrote out still goes first
+            self.res_buf.unsplit(state.pending.split());
+            if settle(&mut state, &mut rsp, &mut result, keep_alive, version) {
+                self.close = true;
+            }
+            lifecycle.served().record(&result, &rsp);
+            self.skip = state.unread;
+            if let (Ok(()), Some(upstream)) = (&result, rsp.take_tunnel()) {
+                response::response::encode_tunnel_head(rsp, &mut self.res_buf);
+                return Ok(Step::Handoff(Handoff::Tunnel(upstream)));
+            }
+            match result {
+                Ok(()) => match rsp.take_stream() {
+                    Some(body) => {
+                        let chunked = version > 0;
+                        response::response::encode_stream_head(rsp, chunked, &mut self.res_buf);
+                        return Ok(Step::Write(SocketBody::Stream(body, chunked)));
+                    }
+                    None => match rsp.take_segments() {
+                        Some(segments) => {
+                            response::response::encode_segments_head(
+                                rsp,
+                                &segments,
+                                &mut self.res_buf,
+                            );
+                            write_segments(stream, &mut self.res_buf, segments)?;
+                        }
+                        None => match rsp.take_file() {
+                            Some(body) => {
+                                response::response::encode_file_head(
+                                    rsp,
+                                    &body,
+                                    &mut self.res_buf,
+                                );
+                                return Ok(Step::Write(SocketBody::File(body)));
+                            }
+                            None => response::response::encode(rsp, &mut self.res_buf),
+                        },
+                    },
+                },
+                // nothing is answered, and nothing after it read
+                Err(e) if is_dropped(&e) => self.close = true,
+                Err(e) => {
+                    let server = config.server_header.as_deref();
+                    response::response::encode_error(e, server, &mut self.res_buf)
+                }
+            }
+        }
+        self.clock.update(served, &self.req_buf);
+        Ok(Step::Read)
+    }
+
+    // give back what the buffers grew past their usual size
+    fn trim(&mut self) {
+        self.req_buf.trim();
+        self.res_buf.trim();
+        self.body_buf.trim();
+        let held = self.req_buf.capacity() + self.res_buf.capacity() + self.body_buf.capacity();
+        self.gauge.set(held);
+    }
+}
+
+fn endpoints(stream: &TcpStream, tags: &ConnectionTags) -> Endpoints {
+    Endpoints {
+        remote: stream.peer_addr().ok(),
+        local: stream.local_addr().ok(),
+        tags: tags.clone(),
+    }
+}
+
+// serve what's buffered, writing out between requests the bodies only the
+// socket can take; `Some` once the connection is to be handed off
+fn serve_socket<T: HttpService>(
+    conn: &mut ConnState,
+    stream: &mut TcpStream,
+    service: &mut T,
+    config: &ServerConfig,
+    lifecycle: &Lifecycle,
+) -> io::Result<Option<Handoff>> {
+    loop {
+        let body = match conn.serve_buffered(stream, service, config, lifecycle)? {
+            Step::Read => return Ok(None),
+            Step::Handoff(handoff) => return Ok(Some(handoff)),
+            Step::Write(body) => body,
+        };
+        match body {
+            SocketBody::Stream(body, chunked) => {
+                write_stream(stream, &mut conn.res_buf, body, chunked, config)?
+            }
+            SocketBody::File(body) => write_file(stream, &mut conn.res_buf, body, config)?,
+        }
+        // the time the client took to read the body isn't held against it
+        conn.clock.update(true, &conn.req_buf);
+    }
+}
+
+// pass the socket on as serving it decided; `Some` to go on in HTTP/2
+fn hand_off(
+    stream: &mut TcpStream,
+    conn: &mut ConnState,
+    handoff: Handoff,
+    config: &ServerConfig,
+    lifecycle: &Lifecycle,
+) -> io::Result<Option<Switch>> {
+    let (res, req) = (&mut conn.res_buf, &mut conn.req_buf);
+    match handoff {
+        Handoff::Passthrough(name) => {
+            passthrough::pass(stream, res, req, name.as_deref(), config, lifecycle)?
+        }
+        Handoff::Tunnel(upstream) => tunnel::open(stream, res, req, upstream, config)?,
+        Handoff::Http2(switch) => return Ok(Some(switch)),
+    }
+    Ok(None)
+}
+
+#[cfg(unix)]
+fn each_connection_loop<T: HttpService>(
+    stream: &mut TcpStream,
+    service: &mut T,
+    config: &ServerConfig,
+    lifecycle: &Lifecycle,
+    tags: &ConnectionTags,
+) -> io::Result<Option<Switch>> {
+    // bounds the blocking writes; buffered writes are checked below
+    stream.set_write_timeout(config.write_timeout)?;
+
+    let mut conn = ConnState::new(config, endpoints(stream, tags));
+    let mut pending: Option<WriteProgress> = None;
+    // bytes picked up by a bounded wait at the end of the last iteration
+    let mut woken = 0;
 
     loop {
         stream.reset_io();
@@ -134,112 +803,878 @@ fn each_connection_loop<T: HttpService>(stream: &mut TcpStream, mut service: T)
         let inner_stream = stream.inner_mut();
 
         // write out the responses
-        nonblock_write(inner_stream, &mut res_buf)?;
+        let written = nonblock_write(inner_stream, &mut conn.res_buf)?;
+        if conn.res_buf.is_empty() {
+            pending = None;
+        } else {
+            // the client isn't keeping up with what we've queued for it
+            pending
+                .get_or_insert_with(|| WriteProgress::new(config))
+                .record(written)?;
+        }
 
         // read the socket for requests
-        reserve_buf(&mut req_buf);
-        let read_cnt = nonblock_read(inner_stream, &mut req_buf)?;
+        reserve_to(&mut conn.req_buf, config.buffer_size);
+        let (mut read_cnt, eof) = nonblock_read(inner_stream, &mut conn.req_buf)?;
+        read_cnt += std::mem::take(&mut woken);
 
         // prepare the requests
         if read_cnt > 0 {
-            loop {
-                let mut headers = [MaybeUninit::uninit(); request::request::MAX_HEADERS];
-                let req = match request::request::decode(&mut headers, &mut req_buf, stream)? {
-                    Some(req) => req,
-                    None => break,
-                };
-                let mut rsp = Response::new(&mut body_buf);
-                match service.handler(req, &mut rsp) {
-                    Ok(()) => response::response::encode(rsp, &mut res_buf),
-                    Err(e) => {
-                        eprintln!("service err = {:?}", e);
-                        response::response::encode_error(e, &mut res_buf);
-                    }
-                }
+            if let Some(handoff) = serve_socket(&mut conn, stream, service, config, lifecycle)? {
+                return hand_off(stream, &mut conn, handoff, config, lifecycle);
             }
         }
+        conn.trim();
 
-        if res_buf.is_empty() {
-            stream.wait_io();
+        // a half-closed client still gets the responses to what it sent
+        if conn.close || eof {
+            stream.write_all(&conn.res_buf)?;
+            if conn.close {
+                stream.shutdown(std::net::Shutdown::Both).ok();
+            }
+            return Ok(None);
+        }
+
+        if conn.res_buf.is_empty() {
+            // a draining server lets idle connections go right away
+            if lifecycle.draining() && conn.req_buf.is_empty() && conn.skip == 0 {
+                stream.shutdown(std::net::Shutdown::Both).ok();
+                return Ok(None);
+            }
+            match conn.clock.deadline(config) {
+                None => stream.wait_io(),
+                Some(deadline) => match read_until(stream, &mut conn.req_buf, deadline)? {
+                    Some(n) => woken = n,
+                    None => {
+                        let (req_buf, body_buf) = (&conn.req_buf, &mut conn.body_buf);
+                        time_out(stream, config, req_buf, body_buf, &mut conn.res_buf)?;
+                        return Ok(None);
+                    }
+                },
+            }
         }
     }
 }
 
 #[cfg(not(unix))]
-fn each_connection_loop<T: HttpService>(stream: &mut TcpStream, mut service: T) -> io::Result<()> {
-    use crate::{request, response};
+fn each_connection_loop<T: HttpService>(
+    stream: &mut TcpStream,
+    service: &mut T,
+    config: &ServerConfig,
+    lifecycle: &Lifecycle,
+    tags: &ConnectionTags,
+) -> io::Result<Option<Switch>> {
+    // bounds every write to the client
+    stream.set_write_timeout(config.write_timeout)?;
 
-    let mut req_buf = BytesMut::with_capacity(BUF_LEN);
-    let mut res_buf = BytesMut::with_capacity(BUF_LEN);
-    let mut body_buf = BytesMut::with_capacity(BUF_LEN);
+    let mut conn = ConnState::new(config, endpoints(stream, tags));
 
     loop {
         // Ensure there is enough space in the buffer
-        reserve_buf(&mut req_buf);
+        reserve_to(&mut conn.req_buf, config.buffer_size);
+
+        // a draining server lets idle connections go right away
+        if lifecycle.draining() && conn.req_buf.is_empty() && conn.skip == 0 {
+            stream.shutdown(std::net::Shutdown::Both).ok();
+            return Ok(None);
+        }
 
         // Prepare a temporary buffer for reading
-        let mut temp_buf = vec![0u8; BUF_LEN];
-        let read_cnt = stream.read(&mut temp_buf)?;
+        let mut temp_buf = vec![0u8; config.buffer_size];
+        let deadline = conn.clock.deadline(config);
+        let timeout = deadline.map(|deadline| deadline.saturating_duration_since(Instant::now()));
+        if timeout == Some(std::time::Duration::ZERO) {
+            let (req_buf, body_buf) = (&conn.req_buf, &mut conn.body_buf);
+            return time_out(stream, config, req_buf, body_buf, &mut conn.res_buf).map(|()| None);
+        }
+        stream.set_read_timeout(timeout)?;
+        let read_cnt = match stream.read(&mut temp_buf) {
+            Ok(n) => n,
+            Err(e) if deadline.is_some() && is_timeout(&e) => {
+                let (req_buf, body_buf) = (&conn.req_buf, &mut conn.body_buf);
+                time_out(stream, config, req_buf, body_buf, &mut conn.res_buf)?;
+                return Ok(None);
+            }
+            Err(e) => return Err(e),
+        };
         if read_cnt == 0 {
-            // Connection was closed
-            return Err(io::Error::new(io::ErrorKind::BrokenPipe, "closed"));
+            // The client is done sending; everything it sent has been answered
+            return Ok(None);
         }
 
         // Append the data read into the request buffer
-        req_buf.extend_from_slice(&temp_buf[..read_cnt]);
+        conn.req_buf.extend_from_slice(&temp_buf[..read_cnt]);
 
         // Prepare the requests
         if read_cnt > 0 {
-            loop {
-                let mut headers = [MaybeUninit::uninit(); request::request::MAX_HEADERS];
-                let req = match request::request::decode(&mut headers, &mut req_buf, stream)? {
-                    Some(req) => req,
-                    None => break,
-                };
-                let mut rsp = Response::new(&mut body_buf);
-                match service.handler(req, &mut rsp) {
-                    Ok(()) => response::response::encode(rsp, &mut res_buf),
-                    Err(e) => {
-                        eprintln!("service err = {:?}", e);
-                        response::response::encode_error(e, &mut res_buf);
-                    }
-                }
+            if let Some(handoff) = serve_socket(&mut conn, stream, service, config, lifecycle)? {
+                return hand_off(stream, &mut conn, handoff, config, lifecycle);
             }
         }
+        conn.trim();
 
         // Send the result back to client
-        while !res_buf.is_empty() {
-            let written = stream.write(&res_buf)?;
-            if written == 0 {
-                // No bytes were written, the connection might have been closed
-                return Err(io::Error::new(io::ErrorKind::WriteZero, "write zero byte"));
-            }
-            res_buf.advance(written);
+        while !conn.res_buf.is_empty() {
+            write_some(stream, &mut conn.res_buf)?;
         }
 
         // Clear the buffer after ensuring all data is sent
-        res_buf.clear();
+        conn.res_buf.clear();
+
+        if conn.close {
+            stream.shutdown(std::net::Shutdown::Both).ok();
+            return Ok(None);
+        }
     }
 }
 
 impl<T: HttpService + Clone + Send + Sync + 'static> HttpServer<T> {
-    pub fn start<L: ToSocketAddrs>(self, addr: L) -> io::Result<coroutine::JoinHandle<()>> {
-        let listener = TcpListener::bind(addr)?;
-        let service = self.0;
-        go!(
-            coroutine::Builder::new().name("TcpServer".to_owned()),
-            move || {
-                for stream in listener.incoming() {
-                    let mut stream = t_c!(stream);
-                    let service = service.clone();
-                    go!(
-                        move || if let Err(e) = each_connection_loop(&mut stream, service) {
-                            error!("service err = {:?}", e);
-                            stream.shutdown(std::net::Shutdown::Both).ok();
-                        }
-                    );
+    // every new connection takes its service and config from `shared`, so
+    // replacing it reconfigures the running server
+    pub(crate) fn start(shared: Arc<RwLock<Self>>, addrs: &[&str]) -> io::Result<ServerHandle> {
+        let config = shared.read().unwrap().1.clone();
+        // a dual-stack IPv6 socket would claim the port an IPv4 one needs
+        let v6_only = addrs.len() > 1;
+        let mut groups = Vec::with_capacity(addrs.len());
+        for addr in addrs {
+            groups.push(socket::bind(addr, &config, v6_only)?);
+        }
+        for listener in &config.inherited {
+            groups.push(vec![socket::adopt(listener)?]);
+        }
+        let lifecycle = Arc::new(Lifecycle::default());
+        // acceptor `i` of each address is scheduled on worker `i`, so
+        // pinning covers it too
+        let acceptors = groups
+            .into_iter()
+            .flat_map(|listeners| listeners.into_iter().enumerate())
+            .map(|(id, listener)| {
+                let shared = shared.clone();
+                let lifecycle = lifecycle.clone();
+                let builder = coroutine::Builder::new()
+                    .name("TcpServer".to_owned())
+                    .id(id);
+                go!(builder, move || accept_loop(listener, shared, lifecycle))
+            })
+            .collect::<io::Result<Vec<_>>>()?;
+        Ok(ServerHandle::new(
+            acceptors,
+            lifecycle,
+            config.shutdown_timeout,
+        ))
+    }
+}
+
+fn accept_loop<T: HttpService + Clone + Send + 'static>(
+    listener: TcpListener,
+    shared: Arc<RwLock<HttpServer<T>>>,
+    lifecycle: Arc<Lifecycle>,
+) {
+    for stream in listener.incoming() {
+        let mut stream = t_c!(stream);
+        let (mut service, config) = {
+            let server = shared.read().unwrap();
+            (server.0.clone(), server.1.clone())
+        };
+        // what's turned away is told so in HTTP, unless it's proxied at L4
+        let http = !config.passthrough.tcp_proxy;
+        // over budget: turn new clients away before their buffers add to it
+        if config
+            .memory_budget
+            .map_or(false, |budget| memory::buffered() >= budget)
+        {
+            debug!("over the memory budget, turning a connection away");
+            if http {
+                stream
+                    .write_all(b"HTTP/1.1 503 Service Unavailable\r\nConnection: close\r\nContent-Length: 0\r\n\r\n")
+                    .ok();
+            }
+            continue;
+        }
+        if let Some((limit, AtCapacity::Reject { retry_after })) = config.max_connections {
+            if lifecycle.open() >= limit {
+                debug!("at {} connections, turning a connection away", limit);
+                let rsp = format!(
+                    "HTTP/1.1 503 Service Unavailable\r\nRetry-After: {}\r\nConnection: close\r\nContent-Length: 0\r\n\r\n",
+                    retry_after.as_secs()
+                );
+                if http {
+                    stream.write_all(rsp.as_bytes()).ok();
                 }
+                continue;
             }
-        )
+        }
+        let full_at = match config.max_connections {
+            Some((limit, AtCapacity::StopAccepting)) => Some(limit),
+            _ => None,
+        };
+        trace!("accepted connection from {:?}", stream.peer_addr().ok());
+        let admission = lifecycle.admit();
+        {
+            let lifecycle = lifecycle.clone();
+            t_c!(socket::configure(&stream, &config));
+            go!(move || {
+                let _admission = admission;
+                let tags = ConnectionTags::default();
+                let _registration = lifecycle.register(&stream, &tags);
+                let served = if config.passthrough.tcp_proxy {
+                    passthrough::serve(&mut stream, &config, &lifecycle).map(|()| None)
+                } else {
+                    each_connection_loop(&mut stream, &mut service, &config, &lifecycle, &tags)
+                };
+                let result = match served {
+                    Ok(Some(switch)) => {
+                        h2::serve(&mut stream, service, &config, &lifecycle, switch)
+                    }
+                    served => served.map(|_| ()),
+                };
+                match result {
+                    Ok(()) => trace!("connection closed"),
+                    Err(e) => {
+                        error!("service err = {:?}", e);
+                        lifecycle.served().connection_error();
+                        socket::close_on_error(&stream, &config);
+                    }
+                }
+            });
+        }
+        // each acceptor can still take one past the limit before it waits
+        if let Some(limit) = full_at {
+            lifecycle.wait_below(limit);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::response::response::{encode, encode_segments_head};
+    use crate::server::server::Server;
+    use std::collections::VecDeque;
+
+    // a socket that takes writes as scripted: `Take(n)` accepts at most n
+    // bytes, the others fail the call; once the script runs out it takes
+    // everything. Each call's slices are recorded as they were offered.
+    enum Turn {
+        Take(usize),
+        Fail(io::ErrorKind),
+    }
+
+    #[derive(Default)]
+    struct Socket {
+        script: VecDeque<Turn>,
+        written: Vec<u8>,
+        calls: Vec<Vec<usize>>,
+    }
+
+    impl Socket {
+        fn new(script: Vec<Turn>) -> Self {
+            Socket {
+                script: script.into(),
+                ..Default::default()
+            }
+        }
+
+        fn take(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+            self.calls.push(bufs.iter().map(|b| b.len()).collect());
+            let mut limit = match self.script.pop_front() {
+                Some(Turn::Take(n)) => n,
+                Some(Turn::Fail(kind)) => return Err(kind.into()),
+                None => usize::MAX,
+            };
+            let mut n = 0;
+            for buf in bufs {
+                let take = buf.len().min(limit);
+                self.written.extend_from_slice(&buf[..take]);
+                n += take;
+                limit -= take;
+            }
+            Ok(n)
+        }
+    }
+
+    impl Write for Socket {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.take(&[IoSlice::new(buf)])
+        }
+
+        fn write_vectored(&mut self, bufs: &[IoSlice]) -> io::Result<usize> {
+            self.take(bufs)
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    #[test]
+    fn write_some_takes_off_only_what_was_sent() {
+        let mut socket = Socket::new(vec![Turn::Take(5)]);
+        let mut buf = BytesMut::from("HTTP/1.1 200 OK\r\n");
+        assert_eq!(write_some(&mut socket, &mut buf).unwrap(), 5);
+        assert_eq!(&buf[..], b"1.1 200 OK\r\n");
+        assert_eq!(write_some(&mut socket, &mut buf).unwrap(), 12);
+        assert!(buf.is_empty());
+        assert_eq!(socket.written, b"HTTP/1.1 200 OK\r\n");
+    }
+
+    #[test]
+    fn write_some_retries_when_interrupted() {
+        let mut socket = Socket::new(vec![
+            Turn::Fail(io::ErrorKind::Interrupted),
+            Turn::Fail(io::ErrorKind::Interrupted),
+            Turn::Take(3),
+        ]);
+        let mut buf = BytesMut::from("abcdef");
+        assert_eq!(write_some(&mut socket, &mut buf).unwrap(), 3);
+        assert_eq!(&buf[..], b"def");
+        assert_eq!(socket.written, b"abc");
+    }
+
+    #[test]
+    fn write_some_fails_on_a_zero_write_and_keeps_the_buffer() {
+        let mut socket = Socket::new(vec![Turn::Take(0)]);
+        let mut buf = BytesMut::from("abc");
+        let err = write_some(&mut socket, &mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WriteZero);
+        assert_eq!(&buf[..], b"abc");
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn nonblock_write_stops_at_would_block_and_resumes() {
+        let response = b"HTTP/1.1 200 OK\r\nContent-Length: 5\r\n\r\nhello";
+        let mut socket = Socket::new(vec![
+            Turn::Take(7),
+            Turn::Fail(io::ErrorKind::Interrupted),
+            Turn::Take(10),
+            Turn::Fail(io::ErrorKind::WouldBlock),
+            Turn::Take(1),
+            Turn::Fail(io::ErrorKind::WouldBlock),
+        ]);
+        let mut buf = BytesMut::from(&response[..]);
+        assert_eq!(nonblock_write(&mut socket, &mut buf).unwrap(), 17);
+        assert_eq!(&buf[..], &response[17..]);
+        assert_eq!(nonblock_write(&mut socket, &mut buf).unwrap(), 1);
+        assert_eq!(&buf[..], &response[18..]);
+        let rest = response.len() - 18;
+        assert_eq!(nonblock_write(&mut socket, &mut buf).unwrap(), rest);
+        assert!(buf.is_empty());
+        assert_eq!(socket.written, &response[..]);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn nonblock_write_passes_other_errors_on() {
+        let mut socket = Socket::new(vec![Turn::Take(2), Turn::Fail(io::ErrorKind::BrokenPipe)]);
+        let mut buf = BytesMut::from("abcdef");
+        let err = nonblock_write(&mut socket, &mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::BrokenPipe);
+        assert_eq!(&buf[..], b"cdef");
+        assert_eq!(socket.written, b"ab");
+    }
+
+    #[test]
+    fn write_segments_sends_head_and_segments_once_across_short_writes() {
+        let head = b"HTTP/1.1 200 OK\r\nContent-Length: 11\r\n\r\n";
+        let mut res_buf = BytesMut::from(&head[..]);
+        let segments = vec![
+            Bytes::from_static(b"hello"),
+            Bytes::new(),
+            Bytes::from_static(b" "),
+            Bytes::from_static(b"world"),
+        ];
+        // splits inside the head, across head and segment, inside a
+        // segment and on a segment boundary, with failures between
+        let mut socket = Socket::new(vec![
+            Turn::Take(10),
+            Turn::Fail(io::ErrorKind::Interrupted),
+            Turn::Take(head.len() - 10 + 2),
+            Turn::Take(3),
+            Turn::Fail(io::ErrorKind::Interrupted),
+            Turn::Take(1),
+            Turn::Take(2),
+        ]);
+        write_segments(&mut socket, &mut res_buf, segments).unwrap();
+
+        let mut expected = head.to_vec();
+        expected.extend_from_slice(b"hello world");
+        assert_eq!(socket.written, expected);
+        assert!(res_buf.is_empty());
+        let rest = head.len() - 10;
+        assert_eq!(
+            socket.calls,
+            vec![
+                vec![head.len(), 5, 1, 5],
+                vec![rest, 5, 1, 5],
+                vec![rest, 5, 1, 5],
+                vec![3, 1, 5],
+                vec![1, 5],
+                vec![1, 5],
+                vec![5],
+                vec![3],
+            ]
+        );
+    }
+
+    #[test]
+    fn write_segments_fails_on_a_zero_write() {
+        let mut res_buf = BytesMut::from("head");
+        let mut socket = Socket::new(vec![Turn::Take(0)]);
+        let segments = vec![Bytes::from_static(b"body")];
+        let err = write_segments(&mut socket, &mut res_buf, segments).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::WriteZero);
+        assert!(socket.written.is_empty());
+    }
+
+    // the head and a body of `len` bytes, as the connection loop writes them
+    fn write_response(len: usize, socket: &mut Socket) -> (usize, bool) {
+        let body = vec![b'x'; len];
+        let at = body.as_ptr();
+        let mut body_buf = BytesMut::new();
+        let mut res_buf = BytesMut::new();
+        let mut rsp = Response::new(&mut body_buf);
+        rsp.body_vec(body);
+        match rsp.take_segments() {
+            Some(segments) => {
+                let moved = segments.len() == 1 && segments[0].as_ptr() == at;
+                encode_segments_head(rsp, &segments, &mut res_buf);
+                let head = res_buf.len();
+                write_segments(socket, &mut res_buf, segments).unwrap();
+                (head, moved)
+            }
+            None => {
+                encode(rsp, &mut res_buf);
+                let written = res_buf.len();
+                socket.write_all(&res_buf).unwrap();
+                (written, false)
+            }
+        }
+    }
+
+    #[test]
+    fn large_body_is_written_as_its_own_segment() {
+        let len = 64 * 1024;
+        let mut socket = Socket::new(vec![]);
+        let (head, moved) = write_response(len, &mut socket);
+        // the body went out as the Vec it was handed over in, beside the head
+        assert!(moved);
+        assert!(head < 128);
+        assert_eq!(socket.calls, vec![vec![head, len]]);
+        assert_eq!(socket.written.len(), head + len);
+        assert!(socket.written[..head].ends_with(b"\r\n\r\n"));
+        assert!(socket.written[head..].iter().all(|&b| b == b'x'));
+    }
+
+    #[test]
+    fn small_body_is_copied_after_the_head() {
+        let len = 100;
+        let mut socket = Socket::new(vec![]);
+        let (written, moved) = write_response(len, &mut socket);
+        assert!(!moved);
+        assert_eq!(socket.calls, vec![vec![written]]);
+        assert!(socket.written.ends_with(&[b'x'; 100]));
+    }
+
+    // what the vectored path saves on a large body, against copying it in
+    // after the head: bytes allocated and copied, and writes made
+    #[cfg(not(any(feature = "jemalloc", feature = "mimalloc")))]
+    #[test]
+    fn large_body_skips_the_copy_and_its_allocations() {
+        use crate::testing::allocations;
+
+        let len = 1024 * 1024;
+        let send = |vectored: bool| {
+            let mut socket = Socket::new(vec![]);
+            // room for what the socket records, which isn't the server's
+            socket.written.reserve(2 * len);
+            let mut body_buf = BytesMut::new();
+            let mut res_buf = BytesMut::new();
+            let mut rsp = Response::new(&mut body_buf);
+            rsp.body_vec(vec![b'x'; len]);
+            let allocated = allocations(|| match vectored {
+                true => {
+                    let segments = rsp.take_segments().unwrap();
+                    encode_segments_head(rsp, &segments, &mut res_buf);
+                    write_segments(&mut socket, &mut res_buf, segments).unwrap();
+                }
+                false => {
+                    encode(rsp, &mut res_buf);
+                    socket.write_all(&res_buf).unwrap();
+                }
+            });
+            assert!(socket.written.len() > len);
+            (allocated, socket.calls.len())
+        };
+        let (copied, copied_writes) = send(false);
+        let (vectored, vectored_writes) = send(true);
+        eprintln!(
+            "1 MiB body: copied {:?} in {} write(s), vectored {:?} in {} write(s)",
+            copied, copied_writes, vectored, vectored_writes
+        );
+        assert!(copied.bytes >= len);
+        assert!(vectored.bytes < 4096);
+        assert_eq!((copied_writes, vectored_writes), (1, 1));
+    }
+
+    // a reader that fails every call
+    struct Broken;
+
+    impl Read for Broken {
+        fn read(&mut self, _: &mut [u8]) -> io::Result<usize> {
+            Err(io::ErrorKind::ConnectionReset.into())
+        }
+    }
+
+    #[test]
+    fn read_into_appends_to_what_is_buffered() {
+        let mut buf = BytesMut::with_capacity(16);
+        buf.extend_from_slice(b"GET ");
+        let mut stream = io::Cursor::new(b"/a HTTP/1.1\r\n".to_vec());
+        let n = read_into(&mut stream, &mut buf).unwrap();
+        assert_eq!(&buf[..], &b"GET /a HTTP/1.1\r\n"[..4 + n]);
+        while read_into(&mut stream, &mut buf).unwrap() > 0 {}
+        assert_eq!(&buf[..], b"GET /a HTTP/1.1\r\n");
+    }
+
+    #[test]
+    fn read_into_grows_a_full_buffer() {
+        let mut buf = BytesMut::from("abc");
+        buf.resize(buf.capacity(), b'-');
+        let len = buf.len();
+        let mut stream = io::Cursor::new(b"def".to_vec());
+        assert!(read_into(&mut stream, &mut buf).unwrap() > 0);
+        assert_eq!(&buf[len..], &b"def"[..buf.len() - len]);
+    }
+
+    #[test]
+    fn read_into_leaves_the_buffer_on_end_of_stream_or_error() {
+        let mut buf = BytesMut::with_capacity(16);
+        buf.extend_from_slice(b"head");
+        assert_eq!(read_into(&mut io::empty(), &mut buf).unwrap(), 0);
+        assert_eq!(&buf[..], b"head");
+        let err = read_into(&mut Broken, &mut buf).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::ConnectionReset);
+        assert_eq!(&buf[..], b"head");
+    }
+
+    // the client end of an in-memory connection; what it sent past the
+    // bytes already buffered is there for body reads
+    #[derive(Default)]
+    struct Peer {
+        rest: io::Cursor<Vec<u8>>,
+        received: Vec<u8>,
+    }
+
+    impl Read for Peer {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            self.rest.read(buf)
+        }
+    }
+
+    impl Write for Peer {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            self.received.extend_from_slice(buf);
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Connection for Peer {}
+
+    fn routes() -> Server {
+        let mut server = Server::new();
+        server.get("/a", |_, res| res.send("a"));
+        server.get("/b", |_, res| res.send("b"));
+        // answers without reading the body
+        server.post("/ignore", |_, res| res.send("ignored"));
+        server.post("/echo", |req, res| {
+            let body = req.text().map_err(io::Error::from)?;
+            res.send(body)
+        });
+        server.get("/stream", |_, res| res.stream(|w| w.write_all(b"streamed")));
+        server
+    }
+
+    // `bytes` as one more read from the client
+    fn serve(
+        conn: &mut ConnState,
+        server: &mut Server,
+        config: &ServerConfig,
+        bytes: &[u8],
+    ) -> Step {
+        conn.req_buf.extend_from_slice(bytes);
+        let lifecycle = Lifecycle::default();
+        conn.serve_buffered(&mut Peer::default(), server, config, &lifecycle)
+            .unwrap()
+    }
+
+    // status, Connection header and body of each response queued in `wire`
+    fn responses(mut wire: &[u8]) -> Vec<(u16, Option<String>, String)> {
+        let mut all = Vec::new();
+        while !wire.is_empty() {
+            let mut headers = [httparse::EMPTY_HEADER; 16];
+            let mut res = httparse::Response::new(&mut headers);
+            let head = match res.parse(wire).unwrap() {
+                httparse::Status::Complete(head) => head,
+                httparse::Status::Partial => panic!("partial response head"),
+            };
+            let header = |name: &str| {
+                res.headers
+                    .iter()
+                    .find(|h| h.name.eq_ignore_ascii_case(name))
+                    .map(|h| String::from_utf8_lossy(h.value).into_owned())
+            };
+            let len = header("content-length").map_or(0, |len| len.parse().unwrap());
+            let body = String::from_utf8_lossy(&wire[head..head + len]).into_owned();
+            all.push((res.code.unwrap(), header("connection"), body));
+            wire = &wire[head + len..];
+        }
+        all
+    }
+
+    fn bodies(wire: &[u8]) -> Vec<String> {
+        responses(wire)
+            .into_iter()
+            .map(|(_, _, body)| body)
+            .collect()
+    }
+
+    #[test]
+    fn pipelined_requests_are_answered_in_order() {
+        let mut server = routes();
+        let config = server.config().clone();
+        let mut conn = ConnState::new(&config, Endpoints::default());
+        let sent = b"GET /a HTTP/1.1\r\n\r\nGET /b HTTP/1.1\r\n\r\nGET /a HTTP/1.1\r\n\r\n";
+        let step = serve(&mut conn, &mut server, &config, sent);
+        assert!(matches!(step, Step::Read));
+        assert_eq!(bodies(&conn.res_buf), ["a", "b", "a"]);
+        assert_eq!(conn.requests, 3);
+        assert!(conn.req_buf.is_empty());
+        assert!(!conn.close);
+    }
+
+    #[test]
+    fn request_split_across_reads_waits_for_the_rest() {
+        let mut server = routes();
+        let config = server.config().clone();
+        let mut conn = ConnState::new(&config, Endpoints::default());
+        let sent = b"GET /a HTTP/1.1\r\n\r\nGET /b HT";
+        let step = serve(&mut conn, &mut server, &config, sent);
+        assert!(matches!(step, Step::Read));
+        assert_eq!(bodies(&conn.res_buf), ["a"]);
+        assert_eq!(&conn.req_buf[..], b"GET /b HT");
+        conn.res_buf.clear();
+        serve(&mut conn, &mut server, &config, b"TP/1.1\r\n\r\n");
+        assert_eq!(bodies(&conn.res_buf), ["b"]);
+        assert!(conn.req_buf.is_empty());
+    }
+
+    #[test]
+    fn unread_body_arriving_later_is_skipped() {
+        let mut server = routes();
+        let config = server.config().clone();
+        let mut conn = ConnState::new(&config, Endpoints::default());
+        let sent = b"POST /ignore HTTP/1.1\r\nContent-Length: 10\r\n\r\nGET /";
+        serve(&mut conn, &mut server, &config, sent);
+        assert_eq!(bodies(&conn.res_buf), ["ignored"]);
+        assert_eq!(conn.skip, 5);
+        conn.res_buf.clear();
+        // the rest of the body looks like a request, and must not be taken for one
+        let sent = b"b HTTGET /a HTTP/1.1\r\n\r\n";
+        serve(&mut conn, &mut server, &config, sent);
+        assert_eq!(bodies(&conn.res_buf), ["a"]);
+        assert_eq!(conn.skip, 0);
+        assert!(!conn.close);
+    }
+
+    #[test]
+    fn connection_close_stops_serving() {
+        let mut server = routes();
+        let config = server.config().clone();
+        let mut conn = ConnState::new(&config, Endpoints::default());
+        let sent = b"GET /a HTTP/1.1\r\nConnection: close\r\n\r\nGET /b HTTP/1.1\r\n\r\n";
+        serve(&mut conn, &mut server, &config, sent);
+        let answered = responses(&conn.res_buf);
+        assert_eq!(answered.len(), 1);
+        assert_eq!(answered[0].1.as_deref(), Some("close"));
+        assert!(conn.close);
+    }
+
+    #[test]
+    fn http_1_0_keeps_alive_only_when_asked() {
+        let mut server = routes();
+        let config = server.config().clone();
+        let mut conn = ConnState::new(&config, Endpoints::default());
+        let sent = b"GET /a HTTP/1.0\r\nConnection: keep-alive\r\n\r\nGET /b HTTP/1.0\r\n\r\n";
+        serve(&mut conn, &mut server, &config, sent);
+        let answered = responses(&conn.res_buf);
+        assert_eq!(answered[0].1.as_deref(), Some("keep-alive"));
+        assert_eq!(answered[1].1.as_deref(), Some("close"));
+        assert!(conn.close);
+    }
+
+    #[test]
+    fn last_request_allowed_closes_the_connection() {
+        let mut server = routes();
+        server.max_requests_per_connection(2);
+        let config = server.config().clone();
+        let mut conn = ConnState::new(&config, Endpoints::default());
+        let sent = b"GET /a HTTP/1.1\r\n\r\nGET /b HTTP/1.1\r\n\r\nGET /a HTTP/1.1\r\n\r\n";
+        serve(&mut conn, &mut server, &config, sent);
+        let answered = responses(&conn.res_buf);
+        assert_eq!(answered.len(), 2);
+        assert_eq!(answered[0].1, None);
+        assert_eq!(answered[1].1.as_deref(), Some("close"));
+        assert!(conn.close);
+    }
+
+    #[test]
+    fn streamed_body_is_left_to_the_socket_and_serving_resumes() {
+        let mut server = routes();
+        let config = server.config().clone();
+        let mut conn = ConnState::new(&config, Endpoints::default());
+        let sent = b"GET /a HTTP/1.1\r\n\r\nGET /stream HTTP/1.1\r\n\r\nGET /b HTTP/1.1\r\n\r\n";
+        let step = serve(&mut conn, &mut server, &config, sent);
+        assert!(matches!(step, Step::Write(SocketBody::Stream(_, true))));
+        // the responses ahead of it and its head, to go out before the body
+        let head = b"Transfer-Encoding: chunked";
+        assert!(conn.res_buf.windows(head.len()).any(|w| w == head));
+        assert_eq!(&conn.req_buf[..], b"GET /b HTTP/1.1\r\n\r\n");
+        conn.res_buf.clear();
+        let step = serve(&mut conn, &mut server, &config, b"");
+        assert!(matches!(step, Step::Read));
+        assert_eq!(bodies(&conn.res_buf), ["b"]);
+    }
+
+    #[test]
+    fn streamed_body_to_http_1_0_is_unframed_and_closes() {
+        let mut server = routes();
+        let config = server.config().clone();
+        let mut conn = ConnState::new(&config, Endpoints::default());
+        let sent = b"GET /stream HTTP/1.0\r\nConnection: keep-alive\r\n\r\n";
+        let step = serve(&mut conn, &mut server, &config, sent);
+        assert!(matches!(step, Step::Write(SocketBody::Stream(_, false))));
+        let head = String::from_utf8_lossy(&conn.res_buf);
+        assert!(!head.contains("Transfer-Encoding"));
+        assert!(!head.contains("Content-Length"));
+        assert!(head.contains("Connection: close"));
+        assert!(!head.contains("keep-alive"));
+        assert!(conn.close);
+    }
+
+    #[test]
+    fn skip_unread_spans_reads() {
+        let mut skip = 5;
+        let mut buf = BytesMut::from("abc");
+        assert!(skip_unread(&mut buf, &mut skip));
+        assert!(buf.is_empty());
+        assert_eq!(skip, 2);
+        buf.extend_from_slice(b"deGET");
+        assert!(!skip_unread(&mut buf, &mut skip));
+        assert_eq!(&buf[..], b"GET");
+        assert_eq!(skip, 0);
+    }
+
+    #[test]
+    fn skipped_body_leaves_the_next_pipelined_request_in_front() {
+        // the unread body of the first request looks like a request itself
+        let mut buf = BytesMut::from("GET /b HTTP/1.1\r\n\r\nGET /a HTTP/1.1\r\n\r\n");
+        let mut skip = "GET /b HTTP/1.1\r\n\r\n".len();
+        assert!(!skip_unread(&mut buf, &mut skip));
+        assert_eq!(&buf[..], b"GET /a HTTP/1.1\r\n\r\n");
+    }
+
+    #[test]
+    fn unread_sized_body_keeps_the_connection() {
+        let mut body_buf = BytesMut::new();
+        let mut rsp = Response::new(&mut body_buf);
+        let mut state = BodyState {
+            unread: 10,
+            ..BodyState::default()
+        };
+        assert!(!settle(&mut state, &mut rsp, &mut Ok(()), true, 1));
+    }
+
+    #[test]
+    fn open_chunked_body_closes_the_connection() {
+        let mut body_buf = BytesMut::new();
+        let mut rsp = Response::new(&mut body_buf);
+        let mut state = BodyState {
+            chunked_open: true,
+            ..BodyState::default()
+        };
+        assert!(settle(&mut state, &mut rsp, &mut Ok(()), true, 1));
+    }
+
+    #[test]
+    fn drained_by_request_count_or_age() {
+        let mut config = ServerConfig::default();
+        let opened = Instant::now();
+        assert!(!drained(&config, 1000, opened));
+        config.max_requests = Some(3);
+        assert!(!drained(&config, 2, opened));
+        assert!(drained(&config, 3, opened));
+        config.max_requests = None;
+        config.max_connection_age = Some(std::time::Duration::ZERO);
+        assert!(drained(&config, 1, opened));
+    }
+
+    #[test]
+    fn unread_sized_body_is_not_taken_for_a_request() {
+        let mut server = routes();
+        let config = server.config().clone();
+        let mut conn = ConnState::new(&config, Endpoints::default());
+        let body = "GET /b HTTP/1.1\r\n\r\n";
+        let sent = format!(
+            "POST /ignore HTTP/1.1\r\nContent-Length: {}\r\n\r\n{}GET /a HTTP/1.1\r\n\r\n",
+            body.len(),
+            body
+        );
+        serve(&mut conn, &mut server, &config, sent.as_bytes());
+        assert_eq!(bodies(&conn.res_buf), ["ignored", "a"]);
+        assert!(conn.req_buf.is_empty());
+        assert!(!conn.close);
+    }
+
+    #[test]
+    fn read_body_is_followed_by_the_next_request() {
+        let mut server = routes();
+        let config = server.config().clone();
+        let mut conn = ConnState::new(&config, Endpoints::default());
+        let sent = b"POST /echo HTTP/1.1\r\nContent-Length: 5\r\n\r\nhelloGET /a HTTP/1.1\r\n\r\n";
+        serve(&mut conn, &mut server, &config, sent);
+        assert_eq!(bodies(&conn.res_buf), ["hello", "a"]);
+        assert_eq!(conn.skip, 0);
+    }
+
+    #[test]
+    fn chunked_body_left_open_closes_the_connection() {
+        let mut server = routes();
+        let config = server.config().clone();
+        let mut conn = ConnState::new(&config, Endpoints::default());
+        let sent = b"POST /ignore HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+            13\r\nGET /b HTTP/1.1\r\n\r\n\r\n0\r\n\r\nGET /a HTTP/1.1\r\n\r\n";
+        serve(&mut conn, &mut server, &config, sent);
+        let answered = responses(&conn.res_buf);
+        assert_eq!(answered.len(), 1);
+        assert_eq!(answered[0].2, "ignored");
+        assert_eq!(answered[0].1.as_deref(), Some("close"));
+        assert!(conn.close);
+    }
+
+    #[test]
+    fn chunked_body_read_to_the_end_keeps_the_connection() {
+        let mut server = routes();
+        let config = server.config().clone();
+        let mut conn = ConnState::new(&config, Endpoints::default());
+        let sent = b"POST /echo HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n\
+            4\r\nWiki\r\n5\r\npedia\r\n0\r\n\r\nGET /a HTTP/1.1\r\n\r\n";
+        serve(&mut conn, &mut server, &config, sent);
+        assert_eq!(bodies(&conn.res_buf), ["Wikipedia", "a"]);
+        assert!(!conn.close);
     }
 }
@@ -0,0 +1,194 @@
+//! accounting of the bytes held in connection buffers, and their reuse
+
+use std::ops::{Deref, DerefMut};
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Mutex;
+
+use bytes::BytesMut;
+
+use crate::server::config::ServerConfig;
+
+static BUFFERED: AtomicUsize = AtomicUsize::new(0);
+
+// idle buffers of closed connections, for the next ones; shared by every
+// server in the process, each taking only what fits its own sizes
+static POOL: Mutex<Vec<BytesMut>> = Mutex::new(Vec::new());
+
+// bytes currently held by all connection buffers in the process
+pub(crate) fn buffered() -> usize {
+    BUFFERED.load(Ordering::Relaxed)
+}
+
+// one connection's (or body writer's) share of the total, given back on drop
+pub(crate) struct BufferGauge {
+    held: usize,
+    total: &'static AtomicUsize,
+}
+
+impl Default for BufferGauge {
+    fn default() -> Self {
+        BufferGauge {
+            held: 0,
+            total: &BUFFERED,
+        }
+    }
+}
+
+impl BufferGauge {
+    pub(crate) fn set(&mut self, bytes: usize) {
+        match bytes.cmp(&self.held) {
+            std::cmp::Ordering::Greater => {
+                self.total.fetch_add(bytes - self.held, Ordering::Relaxed);
+            }
+            std::cmp::Ordering::Less => {
+                self.total.fetch_sub(self.held - bytes, Ordering::Relaxed);
+            }
+            std::cmp::Ordering::Equal => return,
+        }
+        self.held = bytes;
+    }
+}
+
+impl Drop for BufferGauge {
+    fn drop(&mut self) {
+        self.set(0);
+    }
+}
+
+// a connection's buffer, back to the pool when the connection is done
+pub(crate) struct PooledBuf<'a> {
+    buf: BytesMut,
+    config: &'a ServerConfig,
+    pool: &'static Mutex<Vec<BytesMut>>,
+}
+
+impl<'a> PooledBuf<'a> {
+    pub(crate) fn new(config: &'a ServerConfig) -> Self {
+        PooledBuf::from_pool(config, &POOL)
+    }
+
+    fn from_pool(config: &'a ServerConfig, pool: &'static Mutex<Vec<BytesMut>>) -> Self {
+        let pooled = match config.buffer_pool {
+            0 => None,
+            _ => pool.lock().unwrap().pop(),
+        };
+        let buf = match pooled {
+            Some(buf) if buf.capacity() >= config.buffer_size => buf,
+            _ => BytesMut::with_capacity(config.buffer_size),
+        };
+        PooledBuf { buf, config, pool }
+    }
+
+    // an emptied buffer that grew past the maximum starts over
+    pub(crate) fn trim(&mut self) {
+        if self.buf.is_empty() && self.buf.capacity() > self.config.max_buffer_size {
+            self.buf = BytesMut::with_capacity(self.config.buffer_size);
+        }
+    }
+
+    // the buffer itself, leaving an empty one that isn't pooled
+    pub(crate) fn take(&mut self) -> BytesMut {
+        std::mem::take(&mut self.buf)
+    }
+}
+
+impl<'a> Deref for PooledBuf<'a> {
+    type Target = BytesMut;
+
+    fn deref(&self) -> &BytesMut {
+        &self.buf
+    }
+}
+
+impl<'a> DerefMut for PooledBuf<'a> {
+    fn deref_mut(&mut self) -> &mut BytesMut {
+        &mut self.buf
+    }
+}
+
+impl<'a> Drop for PooledBuf<'a> {
+    fn drop(&mut self) {
+        let capacity = self.buf.capacity();
+        if capacity < self.config.buffer_size || capacity > self.config.max_buffer_size {
+            return;
+        }
+        let mut pool = self.pool.lock().unwrap();
+        if pool.len() < self.config.buffer_pool {
+            self.buf.clear();
+            pool.push(self.take());
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::Barrier;
+
+    use crate::testing::{race, THREADS};
+
+    #[test]
+    fn gauges_add_up_across_threads() {
+        static TOTAL: AtomicUsize = AtomicUsize::new(0);
+        let held = Barrier::new(THREADS);
+        let checked = Barrier::new(THREADS);
+        race(|n| {
+            let mut gauge = BufferGauge {
+                held: 0,
+                total: &TOTAL,
+            };
+            for i in 0..1000 {
+                gauge.set((i * 7 + n) % 4096);
+            }
+            gauge.set(1000 + n);
+            // with every gauge settled, one thread checks what they hold
+            if held.wait().is_leader() {
+                let expected: usize = (0..THREADS).map(|n| 1000 + n).sum();
+                assert_eq!(TOTAL.load(Ordering::Relaxed), expected);
+            }
+            checked.wait();
+            for i in 0..1000 {
+                gauge.set((i * 13 + n) % 4096);
+            }
+        });
+        assert_eq!(TOTAL.load(Ordering::Relaxed), 0);
+    }
+
+    #[test]
+    fn pool_keeps_at_most_its_size() {
+        static SHARED: Mutex<Vec<BytesMut>> = Mutex::new(Vec::new());
+        let config = ServerConfig {
+            buffer_pool: 3,
+            ..ServerConfig::default()
+        };
+        race(|_| {
+            for i in 0..200 {
+                let mut buf = PooledBuf::from_pool(&config, &SHARED);
+                assert!(buf.is_empty());
+                assert!(buf.capacity() >= config.buffer_size);
+                buf.extend_from_slice(&[i as u8; 64]);
+                assert!(SHARED.lock().unwrap().len() <= config.buffer_pool);
+            }
+        });
+        let pool = SHARED.lock().unwrap();
+        assert!(!pool.is_empty() && pool.len() <= config.buffer_pool);
+        assert!(pool.iter().all(|buf| buf.is_empty()));
+    }
+
+    #[test]
+    fn oversized_and_taken_buffers_are_not_pooled() {
+        static SHARED: Mutex<Vec<BytesMut>> = Mutex::new(Vec::new());
+        let config = ServerConfig {
+            buffer_pool: 4,
+            ..ServerConfig::default()
+        };
+        let mut grown = PooledBuf::from_pool(&config, &SHARED);
+        grown.reserve(config.max_buffer_size + 1);
+        drop(grown);
+        let mut taken = PooledBuf::from_pool(&config, &SHARED);
+        drop(taken.take());
+        drop(taken);
+        assert!(SHARED.lock().unwrap().is_empty());
+    }
+}
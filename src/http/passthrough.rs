@@ -0,0 +1,199 @@
+//! connections relayed to a backend as they are, without HTTP: TLS ones by
+//! the server name they ask for and, in the L4 proxy mode, any by client
+
+use std::io;
+use std::net::{IpAddr, Shutdown, ToSocketAddrs};
+use std::time::Duration;
+
+use bytes::BytesMut;
+use may::net::TcpStream;
+
+use crate::http::forwarded::IpNet;
+use crate::http::http_server::{is_timeout, read_into, reserve_buf};
+use crate::http::proxy::{self, Preamble};
+use crate::http::shutdown::Lifecycle;
+use crate::http::sni::{self, Hello};
+use crate::http::tunnel;
+use crate::server::config::ServerConfig;
+
+const CONNECT_TIMEOUT: Duration = Duration::from_secs(10);
+// how long an L4 client gets for its PROXY header and ClientHello without
+// a header timeout
+const HELLO_TIMEOUT: Duration = Duration::from_secs(10);
+
+// where connections go instead of being served
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Passthrough {
+    // relay every connection, parsing no HTTP
+    pub(crate) tcp_proxy: bool,
+    // server name patterns, and the backends their TLS connections go to
+    pub(crate) by_name: Vec<(String, String)>,
+    // client networks, and their backends in the L4 mode
+    pub(crate) by_source: Vec<(IpNet, String)>,
+    // where the L4 mode sends what no rule matches
+    pub(crate) fallback: Option<String>,
+}
+
+impl Passthrough {
+    // an exact name first, then the longest matching `*.` pattern, then `*`
+    fn by_name(&self, name: Option<&str>) -> Option<&str> {
+        let name = name.map(str::to_ascii_lowercase);
+        let mut best: Option<&(String, String)> = None;
+        for route in &self.by_name {
+            let pattern = route.0.as_str();
+            let matches = match (pattern.strip_prefix("*."), name.as_deref()) {
+                _ if pattern == "*" => true,
+                (Some(domain), Some(name)) => name
+                    .strip_suffix(domain)
+                    .map_or(false, |sub| sub.len() > 1 && sub.ends_with('.')),
+                (None, Some(name)) => name == pattern,
+                (_, None) => false,
+            };
+            if !matches {
+                continue;
+            }
+            if !pattern.starts_with('*') {
+                return Some(&route.1);
+            }
+            if best.map_or(true, |b| b.0.len() < pattern.len()) {
+                best = Some(route);
+            }
+        }
+        best.map(|route| route.1.as_str())
+    }
+
+    // the first network holding the client
+    fn by_source(&self, client: Option<IpAddr>) -> Option<&str> {
+        let client = client?;
+        self.by_source
+            .iter()
+            .find(|(net, _)| net.contains(client))
+            .map(|(_, backend)| backend.as_str())
+    }
+}
+
+// relay a TLS connection to the backend for the server name it asks for,
+// or close it when there's none; nothing here speaks TLS, so it can't be
+// answered otherwise
+pub(crate) fn pass(
+    client: &mut TcpStream,
+    res_buf: &mut BytesMut,
+    req_buf: &mut BytesMut,
+    name: Option<&str>,
+    config: &ServerConfig,
+    lifecycle: &Lifecycle,
+) -> io::Result<()> {
+    match config.passthrough.by_name(name) {
+        Some(backend) => relay(client, res_buf, req_buf, backend, config, lifecycle),
+        None => {
+            debug!("no TLS backend for server name {:?}, closing", name);
+            client.shutdown(Shutdown::Both).ok();
+            Ok(())
+        }
+    }
+}
+
+// the L4 mode: read no further than the PROXY header and a ClientHello,
+// then relay the connection to the backend its rules choose, or close it
+pub(crate) fn serve(
+    client: &mut TcpStream,
+    config: &ServerConfig,
+    lifecycle: &Lifecycle,
+) -> io::Result<()> {
+    let rules = &config.passthrough;
+    let mut buf = BytesMut::new();
+    let mut source = client.peer_addr().ok().map(|addr| addr.ip());
+    let mut proxied = config.proxy_protocol;
+    // only a TLS client's name is waited for; one that doesn't speak first
+    // is let be when the wait is up
+    let mut sniffing = !rules.by_name.is_empty();
+    let mut tls = None;
+    client.set_read_timeout(Some(config.header_timeout.unwrap_or(HELLO_TIMEOUT)))?;
+    while proxied || sniffing {
+        if proxied {
+            match proxy::strip(&mut buf) {
+                Preamble::Done(proxy_source) => {
+                    source = proxy_source.map(|addr| addr.ip()).or(source);
+                    proxied = false;
+                    continue;
+                }
+                Preamble::Invalid => {
+                    client.shutdown(Shutdown::Both).ok();
+                    return Ok(());
+                }
+                Preamble::Incomplete => {}
+            }
+        } else {
+            match sni::sniff(&buf) {
+                Hello::Tls(name) => {
+                    tls = Some(name);
+                    sniffing = false;
+                    continue;
+                }
+                Hello::Plain => {
+                    sniffing = false;
+                    continue;
+                }
+                Hello::Incomplete => {}
+            }
+        }
+        reserve_buf(&mut buf);
+        match read_into(client, &mut buf) {
+            Ok(0) => return Ok(()),
+            Ok(_) => {}
+            Err(e) if is_timeout(&e) && !proxied => sniffing = false,
+            Err(e) => return Err(e),
+        }
+    }
+    let by_name = tls.and_then(|name| rules.by_name(name.as_deref()));
+    let backend = by_name
+        .or_else(|| rules.by_source(source))
+        .or(rules.fallback.as_deref());
+    match backend {
+        Some(backend) => relay(
+            client,
+            &mut BytesMut::new(),
+            &mut buf,
+            backend,
+            config,
+            lifecycle,
+        ),
+        None => {
+            debug!("no backend for a connection from {:?}, closing", source);
+            client.shutdown(Shutdown::Both).ok();
+            Ok(())
+        }
+    }
+}
+
+fn relay(
+    client: &mut TcpStream,
+    res_buf: &mut BytesMut,
+    req_buf: &mut BytesMut,
+    backend: &str,
+    config: &ServerConfig,
+    lifecycle: &Lifecycle,
+) -> io::Result<()> {
+    let upstream = connect(backend)?;
+    lifecycle.served().relayed();
+    tunnel::open(client, res_buf, req_buf, upstream, config)
+}
+
+fn connect(backend: &str) -> io::Result<TcpStream> {
+    let mut last_error = None;
+    for addr in backend.to_socket_addrs()? {
+        match TcpStream::connect_timeout(&addr, CONNECT_TIMEOUT) {
+            Ok(stream) => {
+                stream.set_nodelay(true)?;
+                return Ok(stream);
+            }
+            Err(e) => last_error = Some(e),
+        }
+    }
+    Err(last_error.unwrap_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::NotFound,
+            format!("{} resolved to no address", backend),
+        )
+    }))
+}
@@ -0,0 +1,403 @@
+//! the platform specific parts of the socket layer and the system RNG, each
+//! with a portable fallback, so the server runs on linux, the BSDs, macOS
+//! and windows
+
+use std::fs::File;
+use std::io;
+#[cfg(unix)]
+use std::mem::MaybeUninit;
+#[cfg(unix)]
+use std::os::fd::{AsRawFd, BorrowedFd, RawFd};
+
+use may::coroutine::Coroutine;
+#[cfg(unix)]
+use may::io::{WaitIo, WaitIoWaker};
+use may::net::TcpStream;
+use socket2::{SockRef, Socket};
+
+#[cfg(unix)]
+use crate::http::http_server::is_timeout;
+use crate::response::writer::WriteProgress;
+use crate::server::config::ServerConfig;
+
+// what one sendfile call is asked for, and what's copied when the socket is
+// full or there's no sendfile
+#[cfg(target_os = "linux")]
+const SENDFILE_MAX: u64 = 1 << 30;
+const FULL_SOCKET_CHUNK: u64 = 64 * 1024;
+
+// reaches a connection's coroutine from outside it: on unix through its
+// socket and the waker of its `wait_io`, elsewhere only by cancelling it
+pub(crate) struct Interrupt {
+    #[cfg(unix)]
+    waker: WaitIoWaker,
+    // open for as long as the connection is registered
+    #[cfg(unix)]
+    fd: RawFd,
+}
+
+impl Interrupt {
+    pub(crate) fn new(stream: &TcpStream) -> Self {
+        #[cfg(not(unix))]
+        let _ = stream;
+        Interrupt {
+            #[cfg(unix)]
+            waker: stream.waker(),
+            #[cfg(unix)]
+            fd: stream.as_raw_fd(),
+        }
+    }
+
+    // rouse a connection parked waiting for the client, so it notices a
+    // drain; without a waker it notices at its next request
+    pub(crate) fn wake(&self) {
+        #[cfg(unix)]
+        self.waker.wakeup();
+    }
+
+    // shut the socket down, so the coroutine sees the client gone at its
+    // next read or write and winds up; without the socket it's cancelled
+    pub(crate) fn close(&self, coroutine: &Coroutine) {
+        #[cfg(unix)]
+        {
+            let _ = coroutine;
+            // registered, so the stream hasn't been closed yet
+            let fd = unsafe { BorrowedFd::borrow_raw(self.fd) };
+            SockRef::from(&fd).shutdown(std::net::Shutdown::Both).ok();
+            self.waker.wakeup();
+        }
+        #[cfg(not(unix))]
+        unsafe {
+            coroutine.cancel()
+        };
+    }
+}
+
+// whether a read would find the connection closed, without blocking: may's
+// unix sockets are nonblocking, so the peek returns at once. windows ones
+// aren't, and there only a pending error tells
+pub(crate) fn peek_closed(socket: &SockRef) -> bool {
+    #[cfg(unix)]
+    {
+        let mut probe = [MaybeUninit::uninit(); 1];
+        match socket.peek(&mut probe) {
+            Ok(0) => return true,
+            Err(e) if !is_timeout(&e) && e.kind() != io::ErrorKind::Interrupted => return true,
+            _ => {}
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = socket;
+    false
+}
+
+// address reuse as std sets it, and port sharing where there is any
+pub(crate) fn set_reuse(socket: &Socket, reuse_port: bool) -> io::Result<()> {
+    // on windows SO_REUSEADDR would let others take the port over
+    #[cfg(unix)]
+    {
+        socket.set_reuse_address(true)?;
+        if reuse_port {
+            socket.set_reuse_port(true)?;
+        }
+    }
+    #[cfg(not(unix))]
+    if reuse_port {
+        let _ = socket;
+        return Err(io::Error::new(
+            io::ErrorKind::Unsupported,
+            "SO_REUSEPORT is not available on this platform",
+        ));
+    }
+    Ok(())
+}
+
+// pick the acceptor from the client address, so a client keeps landing on
+// the same one; listeners are indexed in the order they joined the group
+#[cfg(target_os = "linux")]
+pub(crate) fn steer_by_client(socket: &Socket, acceptors: usize) -> io::Result<()> {
+    // classic BPF opcodes, see linux/filter.h
+    const LD_W_ABS: u16 = 0x20;
+    const LD_B_ABS: u16 = 0x30;
+    const ALU_RSH_K: u16 = 0x74;
+    const ALU_MOD_K: u16 = 0x94;
+    const JMP_JA: u16 = 0x05;
+    const JMP_JEQ_K: u16 = 0x15;
+    const RET_A: u16 = 0x16;
+    // loads relative to the network header rather than the payload
+    const SKF_NET_OFF: u32 = (-0x100000i32) as u32;
+
+    let op = |code, jt, jf, k| libc::sock_filter { code, jt, jf, k };
+    let mut program = [
+        // IP version
+        op(LD_B_ABS, 0, 0, SKF_NET_OFF),
+        op(ALU_RSH_K, 0, 0, 4),
+        op(JMP_JEQ_K, 2, 0, 6),
+        // IPv4 source address
+        op(LD_W_ABS, 0, 0, SKF_NET_OFF + 12),
+        op(JMP_JA, 0, 0, 1),
+        // low word of the IPv6 source address
+        op(LD_W_ABS, 0, 0, SKF_NET_OFF + 20),
+        op(ALU_MOD_K, 0, 0, acceptors as u32),
+        op(RET_A, 0, 0, 0),
+    ];
+    let prog = libc::sock_fprog {
+        len: program.len() as libc::c_ushort,
+        filter: program.as_mut_ptr(),
+    };
+    let ret = unsafe {
+        libc::setsockopt(
+            socket.as_raw_fd(),
+            libc::SOL_SOCKET,
+            libc::SO_ATTACH_REUSEPORT_CBPF,
+            &prog as *const libc::sock_fprog as *const libc::c_void,
+            std::mem::size_of::<libc::sock_fprog>() as libc::socklen_t,
+        )
+    };
+    if ret != 0 {
+        return Err(io::Error::last_os_error());
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn steer_by_client(_: &Socket, _: usize) -> io::Result<()> {
+    warn!("reuseport steering needs SO_ATTACH_REUSEPORT_CBPF, leaving it to the kernel");
+    Ok(())
+}
+
+// `len` bytes of `file` from `offset`, handed from the page cache to the
+// socket with sendfile(2). may's sockets don't block, so when this one is
+// full a chunk is copied through may's own write instead, which parks the
+// coroutine until the client has read enough
+#[cfg(target_os = "linux")]
+pub(crate) fn send_file(
+    stream: &mut TcpStream,
+    file: &File,
+    offset: u64,
+    len: u64,
+    config: &ServerConfig,
+) -> io::Result<()> {
+    use std::io::Write;
+    use std::os::unix::fs::FileExt;
+
+    let mut progress = WriteProgress::new(config);
+    let mut chunk = Vec::new();
+    let (mut offset, end) = (offset, offset + len);
+    while offset < end {
+        let count = (end - offset).min(SENDFILE_MAX) as usize;
+        let mut at = offset as libc::off_t;
+        let sent = unsafe { libc::sendfile(stream.as_raw_fd(), file.as_raw_fd(), &mut at, count) };
+        if sent > 0 {
+            offset += sent as u64;
+            progress.record(sent as usize)?;
+            continue;
+        }
+        if sent == 0 {
+            return Err(ended_early());
+        }
+        let e = io::Error::last_os_error();
+        match e.kind() {
+            io::ErrorKind::Interrupted => {}
+            io::ErrorKind::WouldBlock => {
+                chunk.resize((end - offset).min(FULL_SOCKET_CHUNK) as usize, 0);
+                file.read_exact_at(&mut chunk, offset)?;
+                stream.write_all(&chunk)?;
+                offset += chunk.len() as u64;
+                progress.record(chunk.len())?;
+            }
+            _ => return Err(e),
+        }
+    }
+    Ok(())
+}
+
+// a plain copy through may's write, which parks the coroutine as needed
+#[cfg(not(target_os = "linux"))]
+pub(crate) fn send_file(
+    stream: &mut TcpStream,
+    mut file: &File,
+    offset: u64,
+    len: u64,
+    config: &ServerConfig,
+) -> io::Result<()> {
+    use std::io::{Read, Seek, SeekFrom, Write};
+
+    file.seek(SeekFrom::Start(offset))?;
+    let mut progress = WriteProgress::new(config);
+    let mut chunk = vec![0; len.min(FULL_SOCKET_CHUNK) as usize];
+    let mut left = len;
+    while left > 0 {
+        let n = file.read(&mut chunk[..left.min(FULL_SOCKET_CHUNK) as usize])?;
+        if n == 0 {
+            return Err(ended_early());
+        }
+        stream.write_all(&chunk[..n])?;
+        progress.record(n)?;
+        left -= n as u64;
+    }
+    Ok(())
+}
+
+// the file shrank after its length was taken; the response can't be
+// finished, so the connection goes
+fn ended_early() -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "file ended before its length")
+}
+
+// the sockets systemd passes from fd 3 on, if they're meant for this
+// process; the variables are cleared so child processes don't take them too
+#[cfg(unix)]
+pub(crate) fn systemd_listeners() -> io::Result<Vec<std::net::TcpListener>> {
+    use std::os::fd::FromRawFd;
+
+    const FIRST_FD: RawFd = 3;
+    let var = |name| std::env::var(name).ok().and_then(|v| v.parse::<u32>().ok());
+    let count = match (var("LISTEN_PID"), var("LISTEN_FDS")) {
+        (Some(pid), Some(count)) if pid == std::process::id() => count as RawFd,
+        _ => {
+            return Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "no sockets passed by systemd",
+            ))
+        }
+    };
+    for name in ["LISTEN_PID", "LISTEN_FDS", "LISTEN_FDNAMES"] {
+        std::env::remove_var(name);
+    }
+    (FIRST_FD..FIRST_FD + count)
+        .map(|fd| {
+            // passed to this process alone, and taken over only here
+            let socket = unsafe { socket2::Socket::from_raw_fd(fd) };
+            socket.set_cloexec(true)?;
+            if socket.r#type()? != socket2::Type::STREAM {
+                return Err(io::Error::new(
+                    io::ErrorKind::InvalidInput,
+                    format!("socket {} from systemd isn't a stream socket", fd),
+                ));
+            }
+            Ok(socket.into())
+        })
+        .collect()
+}
+
+#[cfg(not(unix))]
+pub(crate) fn systemd_listeners() -> io::Result<Vec<std::net::TcpListener>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "socket activation needs unix",
+    ))
+}
+
+// `len` bytes from the system's CSPRNG, e.g. for tokens
+#[cfg(unix)]
+pub(crate) fn random_bytes(len: usize) -> io::Result<Vec<u8>> {
+    use std::io::Read;
+
+    let mut bytes = vec![0; len];
+    File::open("/dev/urandom")?.read_exact(&mut bytes)?;
+    Ok(bytes)
+}
+
+#[cfg(windows)]
+pub(crate) fn random_bytes(len: usize) -> io::Result<Vec<u8>> {
+    use std::ffi::c_void;
+
+    // no algorithm handle, the system's preferred RNG instead
+    const BCRYPT_USE_SYSTEM_PREFERRED_RNG: u32 = 2;
+    #[link(name = "bcrypt")]
+    extern "system" {
+        fn BCryptGenRandom(alg: *mut c_void, buf: *mut u8, len: u32, flags: u32) -> i32;
+    }
+    let mut bytes = vec![0; len];
+    for chunk in bytes.chunks_mut(u32::MAX as usize) {
+        let flags = BCRYPT_USE_SYSTEM_PREFERRED_RNG;
+        // writes exactly `chunk.len()` bytes into the chunk
+        let status = unsafe {
+            BCryptGenRandom(
+                std::ptr::null_mut(),
+                chunk.as_mut_ptr(),
+                chunk.len() as u32,
+                flags,
+            )
+        };
+        if status < 0 {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                format!("BCryptGenRandom failed: {:#x}", status),
+            ));
+        }
+    }
+    Ok(bytes)
+}
+
+#[cfg(not(any(unix, windows)))]
+pub(crate) fn random_bytes(_: usize) -> io::Result<Vec<u8>> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "no system RNG on this platform",
+    ))
+}
+
+#[cfg(feature = "signals")]
+pub(crate) enum Signal {
+    Reload,
+    Stop,
+}
+
+// SIGHUP, SIGINT and SIGTERM on unix; windows has no SIGHUP, and its
+// SIGINT and SIGTERM, i.e. Ctrl-C and Ctrl-Break, can only be flagged
+#[cfg(all(unix, feature = "signals"))]
+pub(crate) struct Signals(signal_hook::iterator::Signals);
+
+#[cfg(all(unix, feature = "signals"))]
+impl Signals {
+    pub(crate) fn new() -> io::Result<Self> {
+        use signal_hook::consts::{SIGHUP, SIGINT, SIGTERM};
+
+        signal_hook::iterator::Signals::new([SIGHUP, SIGINT, SIGTERM]).map(Signals)
+    }
+
+    // blocks until the next signal arrives
+    pub(crate) fn wait(&mut self) -> Signal {
+        match self.0.forever().next() {
+            Some(signal_hook::consts::SIGHUP) => Signal::Reload,
+            _ => Signal::Stop,
+        }
+    }
+}
+
+#[cfg(all(not(unix), feature = "signals"))]
+pub(crate) struct Signals(std::sync::Arc<std::sync::atomic::AtomicBool>);
+
+#[cfg(all(not(unix), feature = "signals"))]
+impl Signals {
+    pub(crate) fn new() -> io::Result<Self> {
+        use signal_hook::consts::{SIGINT, SIGTERM};
+
+        let stop = std::sync::Arc::default();
+        signal_hook::flag::register(SIGINT, std::sync::Arc::clone(&stop))?;
+        signal_hook::flag::register(SIGTERM, std::sync::Arc::clone(&stop))?;
+        Ok(Signals(stop))
+    }
+
+    pub(crate) fn wait(&mut self) -> Signal {
+        while !self.0.load(std::sync::atomic::Ordering::Acquire) {
+            std::thread::sleep(std::time::Duration::from_millis(100));
+        }
+        Signal::Stop
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::random_bytes;
+
+    #[test]
+    fn random_bytes_fills_the_length_asked_for() {
+        let (a, b) = (random_bytes(32).unwrap(), random_bytes(32).unwrap());
+        assert_eq!(a.len(), 32);
+        assert_ne!(a, b);
+        assert!(random_bytes(0).unwrap().is_empty());
+    }
+}
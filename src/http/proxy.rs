@@ -0,0 +1,118 @@
+//! PROXY protocol (v1 and v2) header sent by TCP mode load balancers
+
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr, SocketAddr};
+
+use bytes::{Buf, BytesMut};
+
+// longest v1 line, CRLF included
+const V1_MAX: usize = 107;
+const V2_SIGNATURE: &[u8; 12] = b"\r\n\r\n\0\r\nQUIT\n";
+
+pub(crate) enum Preamble {
+    // more bytes needed before the header can be judged
+    Incomplete,
+    // the client the proxy accepted, if it passed one on
+    Done(Option<SocketAddr>),
+    Invalid,
+}
+
+// strip the PROXY header off the front of `buf`
+pub(crate) fn strip(buf: &mut BytesMut) -> Preamble {
+    let parsed = if buf.starts_with(&V2_SIGNATURE[..buf.len().min(12)]) {
+        parse_v2(buf)
+    } else {
+        parse_v1(buf)
+    };
+    match parsed {
+        Parsed::Header(len, source) => {
+            buf.advance(len);
+            Preamble::Done(source)
+        }
+        Parsed::Invalid => Preamble::Invalid,
+        Parsed::Incomplete => Preamble::Incomplete,
+    }
+}
+
+enum Parsed {
+    Incomplete,
+    // header length and source address
+    Header(usize, Option<SocketAddr>),
+    Invalid,
+}
+
+// "PROXY TCP4 <src> <dst> <sport> <dport>\r\n", or "PROXY UNKNOWN ...\r\n"
+fn parse_v1(buf: &[u8]) -> Parsed {
+    if !b"PROXY ".starts_with(&buf[..buf.len().min(6)]) {
+        return Parsed::Invalid;
+    }
+    let end = match buf.iter().take(V1_MAX).position(|&b| b == b'\n') {
+        Some(end) => end,
+        None if buf.len() < V1_MAX => return Parsed::Incomplete,
+        None => return Parsed::Invalid,
+    };
+    let line = match std::str::from_utf8(&buf[..end]) {
+        Ok(line) => line,
+        Err(_) => return Parsed::Invalid,
+    };
+    let line = match line.strip_suffix('\r') {
+        Some(line) => line,
+        None => return Parsed::Invalid,
+    };
+    let mut fields = line.split(' ').skip(1);
+    let source = match fields.next() {
+        Some("UNKNOWN") => None,
+        Some("TCP4") | Some("TCP6") => {
+            let ip = fields.next().and_then(|ip| ip.parse::<IpAddr>().ok());
+            let _dst = fields.next();
+            let port = fields.next().and_then(|port| port.parse::<u16>().ok());
+            match (ip, port) {
+                (Some(ip), Some(port)) => Some(SocketAddr::new(ip, port)),
+                _ => return Parsed::Invalid,
+            }
+        }
+        _ => return Parsed::Invalid,
+    };
+    Parsed::Header(end + 1, source)
+}
+
+// 12 byte signature, version/command, family/protocol, u16 length, then
+// the addresses and any TLVs, which are skipped
+fn parse_v2(buf: &[u8]) -> Parsed {
+    if buf.len() < 16 {
+        return Parsed::Incomplete;
+    }
+    let version_command = buf[12];
+    let family = buf[13];
+    let len = 16 + u16::from_be_bytes([buf[14], buf[15]]) as usize;
+    if version_command >> 4 != 2 {
+        return Parsed::Invalid;
+    }
+    if buf.len() < len {
+        return Parsed::Incomplete;
+    }
+    let addrs = &buf[16..len];
+    let source = match (version_command & 0x0f, family) {
+        // LOCAL: health checks from the proxy itself
+        (0, _) => None,
+        (1, 0x11) if addrs.len() >= 12 => {
+            let ip = Ipv4Addr::new(addrs[0], addrs[1], addrs[2], addrs[3]);
+            Some(SocketAddr::new(
+                ip.into(),
+                u16::from_be_bytes([addrs[8], addrs[9]]),
+            ))
+        }
+        (1, 0x21) if addrs.len() >= 36 => {
+            let mut octets = [0u8; 16];
+            octets.copy_from_slice(&addrs[..16]);
+            let ip = Ipv6Addr::from(octets);
+            Some(SocketAddr::new(
+                ip.into(),
+                u16::from_be_bytes([addrs[32], addrs[33]]),
+            ))
+        }
+        // unspecified or unix socket families carry no usable address
+        (1, 0x00) | (1, 0x31) | (1, 0x32) => None,
+        _ => return Parsed::Invalid,
+    };
+    Parsed::Header(len, source)
+}
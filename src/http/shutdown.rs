@@ -0,0 +1,423 @@
+//! graceful shutdown: stop accepting, drain connections, cancel stragglers
+
+use std::collections::HashMap;
+use std::io;
+use std::net::SocketAddr;
+use std::sync::atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use may::coroutine::{self, Coroutine, JoinHandle};
+use may::net::TcpStream;
+
+use crate::http::http_server::is_dropped;
+use crate::http::platform::Interrupt;
+use crate::response::response::Response;
+use crate::server::health::Health;
+use crate::server::server::Server;
+
+// builds the next server from the running one and swaps it in
+type Reload = Box<dyn Fn(&mut dyn FnMut(&Server) -> Server) + Send + Sync>;
+
+// how often shutdown checks whether the connections have drained
+const DRAIN_POLL: Duration = Duration::from_millis(10);
+
+#[derive(Default)]
+pub(crate) struct Lifecycle {
+    draining: AtomicBool,
+    next_id: AtomicUsize,
+    connections: Mutex<HashMap<usize, Connection>>,
+    // accepted connections, counted from the accept so limits can't race
+    // the coroutines registering themselves
+    open: AtomicUsize,
+    served: Arc<Served>,
+}
+
+// totals over the server's life, for the shutdown report
+#[derive(Default)]
+pub(crate) struct Served {
+    connections: AtomicU64,
+    requests: AtomicU64,
+    statuses: [AtomicU64; 5],
+    connection_errors: AtomicU64,
+    relayed: AtomicU64,
+}
+
+impl Served {
+    // count a handled request by the status it's answered with; dropped
+    // ones have none
+    pub(crate) fn record(&self, result: &io::Result<()>, rsp: &Response) {
+        let status = match result {
+            Ok(()) => rsp.status(),
+            Err(e) if is_dropped(e) => 0,
+            Err(_) => 500,
+        };
+        self.requests.fetch_add(1, Ordering::Relaxed);
+        if let 100..=599 = status {
+            self.statuses[status / 100 - 1].fetch_add(1, Ordering::Relaxed);
+        }
+    }
+
+    pub(crate) fn connection_error(&self) {
+        self.connection_errors.fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn relayed(&self) {
+        self.relayed.fetch_add(1, Ordering::Relaxed);
+    }
+}
+
+/// What a server served, and how its shutdown went, as
+/// `ServerHandle::shutdown` reports it.
+#[derive(Clone, Debug)]
+pub struct ShutdownReport {
+    /// Connections accepted.
+    pub connections: u64,
+    /// Requests that reached a handler, over HTTP/1.1 or HTTP/2.
+    pub requests: u64,
+    /// Responses by status class, 1xx through 5xx.
+    pub statuses: [u64; 5],
+    /// Connections that ended in an I/O or protocol error.
+    pub connection_errors: u64,
+    /// Connections relayed to a passthrough backend rather than served.
+    pub relayed: u64,
+    /// From no longer accepting until the last connection closed, or
+    /// shutdown gave up on it.
+    pub drain: Duration,
+    /// Connections still open at the shutdown timeout, and cancelled.
+    pub forced: usize,
+    /// Connections still open after that, stuck outside may's io.
+    pub abandoned: usize,
+}
+
+impl ShutdownReport {
+    /// Whether every connection closed on its own.
+    pub fn is_clean(&self) -> bool {
+        self.forced == 0
+    }
+}
+
+struct Connection {
+    coroutine: Coroutine,
+    // interrupts a connection parked waiting for the client
+    interrupt: Interrupt,
+    remote: Option<SocketAddr>,
+    opened: Instant,
+    tags: ConnectionTags,
+}
+
+// labels requests put on the connection they arrived on, shared by every
+// request, and HTTP/2 stream, it carries
+#[derive(Clone, Default)]
+pub(crate) struct ConnectionTags(Arc<Mutex<Vec<(String, String)>>>);
+
+impl ConnectionTags {
+    pub(crate) fn set(&self, key: &str, value: &str) {
+        let mut tags = self.0.lock().unwrap();
+        match tags.iter_mut().find(|(k, _)| k == key) {
+            Some((_, v)) => *v = value.to_owned(),
+            None => tags.push((key.to_owned(), value.to_owned())),
+        }
+    }
+
+    pub(crate) fn get(&self, key: &str) -> Option<String> {
+        let tags = self.0.lock().unwrap();
+        tags.iter().find(|(k, _)| k == key).map(|(_, v)| v.clone())
+    }
+
+    fn matches(&self, key: &str, value: &str) -> bool {
+        self.get(key).map_or(false, |v| v == value)
+    }
+}
+
+/// An open connection `ConnectionAdmin::tagged` found.
+#[derive(Clone, Debug)]
+pub struct ConnectionInfo {
+    /// The peer address, a proxy's if the client came through one.
+    pub remote: Option<SocketAddr>,
+    /// How long the connection has been open.
+    pub age: Duration,
+    /// Every tag requests put on it, in the order they were first set.
+    pub tags: Vec<(String, String)>,
+}
+
+impl Lifecycle {
+    pub(crate) fn draining(&self) -> bool {
+        self.draining.load(Ordering::Acquire)
+    }
+
+    // track the calling connection coroutine until the guard drops
+    pub(crate) fn register(&self, stream: &TcpStream, tags: &ConnectionTags) -> Registration {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let connection = Connection {
+            coroutine: coroutine::current(),
+            interrupt: Interrupt::new(stream),
+            remote: stream.peer_addr().ok(),
+            opened: Instant::now(),
+            tags: tags.clone(),
+        };
+        self.connections.lock().unwrap().insert(id, connection);
+        Registration {
+            lifecycle: self,
+            id,
+        }
+    }
+
+    pub(crate) fn tagged(&self, key: &str, value: &str) -> Vec<ConnectionInfo> {
+        let connections = self.connections.lock().unwrap();
+        connections
+            .values()
+            .filter(|connection| connection.tags.matches(key, value))
+            .map(|connection| ConnectionInfo {
+                remote: connection.remote,
+                age: connection.opened.elapsed(),
+                tags: connection.tags.0.lock().unwrap().clone(),
+            })
+            .collect()
+    }
+
+    // shut the sockets of matching connections down, so their coroutines
+    // see the client gone at their next read or write and wind up
+    pub(crate) fn disconnect(&self, key: &str, value: &str) -> usize {
+        let connections = self.connections.lock().unwrap();
+        let mut closed = 0;
+        for connection in connections.values() {
+            if !connection.tags.matches(key, value) {
+                continue;
+            }
+            connection.interrupt.close(&connection.coroutine);
+            closed += 1;
+        }
+        closed
+    }
+
+    fn active(&self) -> usize {
+        self.connections.lock().unwrap().len()
+    }
+
+    pub(crate) fn served(&self) -> &Arc<Served> {
+        &self.served
+    }
+
+    // count an accepted connection until the guard drops
+    pub(crate) fn admit(self: &Arc<Self>) -> Admission {
+        self.served.connections.fetch_add(1, Ordering::Relaxed);
+        self.open.fetch_add(1, Ordering::Relaxed);
+        Admission {
+            lifecycle: self.clone(),
+        }
+    }
+
+    pub(crate) fn open(&self) -> usize {
+        self.open.load(Ordering::Relaxed)
+    }
+
+    // park the acceptor until fewer than `limit` connections are open
+    pub(crate) fn wait_below(&self, limit: usize) {
+        while self.open() >= limit && !self.draining() {
+            coroutine::sleep(DRAIN_POLL);
+        }
+    }
+
+    // poll until every connection has gone or the deadline passes
+    fn wait_drained(&self, deadline: Instant) -> bool {
+        while self.active() > 0 {
+            if Instant::now() >= deadline {
+                return false;
+            }
+            coroutine::sleep(DRAIN_POLL);
+        }
+        true
+    }
+}
+
+pub(crate) struct Registration<'a> {
+    lifecycle: &'a Lifecycle,
+    id: usize,
+}
+
+impl<'a> Drop for Registration<'a> {
+    fn drop(&mut self) {
+        self.lifecycle.connections.lock().unwrap().remove(&self.id);
+    }
+}
+
+pub(crate) struct Admission {
+    lifecycle: Arc<Lifecycle>,
+}
+
+impl Drop for Admission {
+    fn drop(&mut self) {
+        self.lifecycle.open.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+/// Handle to a server started with `Server::start`.
+pub struct ServerHandle {
+    acceptors: Vec<JoinHandle<()>>,
+    lifecycle: Arc<Lifecycle>,
+    timeout: Duration,
+    reload: Option<Reload>,
+    health: Option<Health>,
+}
+
+impl ServerHandle {
+    pub(crate) fn new(
+        acceptors: Vec<JoinHandle<()>>,
+        lifecycle: Arc<Lifecycle>,
+        timeout: Duration,
+    ) -> Self {
+        ServerHandle {
+            acceptors,
+            lifecycle,
+            timeout,
+            reload: None,
+            health: None,
+        }
+    }
+
+    pub(crate) fn set_reload(&mut self, reload: Reload) {
+        self.reload = Some(reload);
+    }
+
+    pub(crate) fn set_health(&mut self, health: Health) {
+        self.health = Some(health);
+    }
+
+    /// Swaps in `server`'s routes and settings: new connections are served
+    /// with them, and open ones switch at their next request, keeping the
+    /// connection settings, e.g. timeouts, they were accepted with. The
+    /// listeners are left as they are, so address and reuseport settings
+    /// keep their original values.
+    pub fn reload(&self, server: &Server) {
+        self.swap(&mut |_| server.clone());
+    }
+
+    /// Swaps in `routes`' routes and virtual hosts, keeping the running
+    /// server's settings; in flight requests finish on the old ones.
+    pub fn replace_router(&self, routes: &Server) {
+        self.swap(&mut |current| current.with_routes(routes));
+    }
+
+    /// Swaps in the running server as changed by `f`, e.g. with new limits,
+    /// timeouts or virtual hosts, as `reload` does.
+    pub fn update<F: FnOnce(&mut Server)>(&self, f: F) {
+        let mut f = Some(f);
+        self.swap(&mut |current| {
+            let mut server = current.clone();
+            if let Some(f) = f.take() {
+                f(&mut server);
+            }
+            server
+        });
+    }
+
+    fn swap(&self, update: &mut dyn FnMut(&Server) -> Server) {
+        match &self.reload {
+            Some(reload) => reload(update),
+            None => warn!("this server can't be reloaded"),
+        }
+    }
+
+    /// Blocks the calling thread handling signals: SIGHUP reloads with the
+    /// server `rebuild` returns, SIGTERM or SIGINT shuts down and returns.
+    /// Windows has no SIGHUP; Ctrl-C and Ctrl-Break shut down.
+    #[cfg(feature = "signals")]
+    pub fn serve_signals<F: FnMut() -> Server>(self, mut rebuild: F) -> std::io::Result<()> {
+        use crate::http::platform::{Signal, Signals};
+
+        let mut signals = Signals::new()?;
+        while let Signal::Reload = signals.wait() {
+            info!("reloading on SIGHUP");
+            self.reload(&rebuild());
+        }
+        self.shutdown();
+        Ok(())
+    }
+
+    /// Connections currently open.
+    pub fn connections(&self) -> usize {
+        self.lifecycle.open()
+    }
+
+    pub(crate) fn lifecycle(&self) -> &Arc<Lifecycle> {
+        &self.lifecycle
+    }
+
+    /// Blocks until the server stops accepting connections.
+    pub fn wait(&self) {
+        for acceptor in &self.acceptors {
+            acceptor.wait();
+        }
+    }
+
+    /// Stops accepting connections and lets open ones finish the requests
+    /// they are serving, answering each last one with `Connection: close`.
+    /// Connections still open after the shutdown timeout are cancelled.
+    /// With `Server::health`, readiness fails for its drain delay first.
+    /// The report is logged too.
+    pub fn shutdown(self) -> ShutdownReport {
+        let ServerHandle {
+            acceptors,
+            lifecycle,
+            timeout,
+            health,
+            ..
+        } = self;
+        if let Some(health) = health {
+            health.shut_down();
+        }
+        lifecycle.draining.store(true, Ordering::Release);
+        let started = Instant::now();
+
+        for acceptor in &acceptors {
+            unsafe { acceptor.coroutine().cancel() };
+        }
+        for acceptor in acceptors {
+            acceptor.join().ok();
+        }
+
+        // idle connections notice the drain once woken
+        for connection in lifecycle.connections.lock().unwrap().values() {
+            connection.interrupt.wake();
+        }
+        let mut forced = 0;
+        let mut abandoned = 0;
+        if !lifecycle.wait_drained(Instant::now() + timeout) {
+            let connections = lifecycle.connections.lock().unwrap();
+            forced = connections.len();
+            for connection in connections.values() {
+                unsafe { connection.coroutine.cancel() };
+            }
+            drop(connections);
+            // a handler stuck outside may's io never sees the cancel
+            if !lifecycle.wait_drained(Instant::now() + timeout) {
+                abandoned = lifecycle.active();
+                warn!("{} connections still open after shutdown", abandoned);
+            }
+        }
+        let served = &lifecycle.served;
+        let report = ShutdownReport {
+            connections: served.connections.load(Ordering::Relaxed),
+            requests: served.requests.load(Ordering::Relaxed),
+            statuses: [0, 1, 2, 3, 4].map(|i| served.statuses[i].load(Ordering::Relaxed)),
+            connection_errors: served.connection_errors.load(Ordering::Relaxed),
+            relayed: served.relayed.load(Ordering::Relaxed),
+            drain: started.elapsed(),
+            forced,
+            abandoned,
+        };
+        info!(
+            "shut down after {} connections and {} requests ({} 4xx, {} 5xx, {} connection \
+             errors); drained in {:?}, {} connections cancelled",
+            report.connections,
+            report.requests,
+            report.statuses[3],
+            report.statuses[4],
+            report.connection_errors,
+            report.drain,
+            report.forced
+        );
+        report
+    }
+}
@@ -0,0 +1,107 @@
+//! the server name a TLS client asks for, read off its ClientHello
+
+// TLS record and handshake message types, and the SNI extension's
+const HANDSHAKE: u8 = 22;
+const CLIENT_HELLO: u8 = 1;
+const SERVER_NAME: u16 = 0;
+const HOST_NAME: u8 = 0;
+const RECORD_HEADER: usize = 5;
+
+pub(crate) enum Hello {
+    // a ClientHello, with the server name it asks for
+    Tls(Option<String>),
+    Incomplete,
+    Plain,
+}
+
+// whether a new connection opens with a TLS handshake; the name is read
+// from the first record only, which is where clients put the ClientHello
+pub(crate) fn sniff(buf: &[u8]) -> Hello {
+    // no HTTP request starts with the handshake record type
+    match buf.first() {
+        None => return Hello::Incomplete,
+        Some(&HANDSHAKE) => {}
+        Some(_) => return Hello::Plain,
+    }
+    if buf.len() < RECORD_HEADER {
+        return Hello::Incomplete;
+    }
+    if buf[1] != 3 {
+        return Hello::Plain;
+    }
+    let len = u16::from_be_bytes([buf[3], buf[4]]) as usize;
+    match buf.get(RECORD_HEADER..RECORD_HEADER + len) {
+        Some(record) => Hello::Tls(server_name(record)),
+        None => Hello::Incomplete,
+    }
+}
+
+// the host_name entry of the SNI extension, lowercased
+fn server_name(record: &[u8]) -> Option<String> {
+    let mut hello = Reader(record);
+    if hello.u8()? != CLIENT_HELLO {
+        return None;
+    }
+    // a ClientHello split over several records isn't looked into
+    let len = hello.u24()?;
+    let mut hello = hello.take(len)?;
+    // version and random
+    hello.take(2 + 32)?;
+    let session_id = hello.u8()? as usize;
+    hello.take(session_id)?;
+    let cipher_suites = hello.u16()? as usize;
+    hello.take(cipher_suites)?;
+    let compression = hello.u8()? as usize;
+    hello.take(compression)?;
+    let len = hello.u16()? as usize;
+    let mut extensions = hello.take(len)?;
+    while !extensions.0.is_empty() {
+        let kind = extensions.u16()?;
+        let len = extensions.u16()? as usize;
+        let mut data = extensions.take(len)?;
+        if kind != SERVER_NAME {
+            continue;
+        }
+        let len = data.u16()? as usize;
+        let mut names = data.take(len)?;
+        while !names.0.is_empty() {
+            let name_type = names.u8()?;
+            let len = names.u16()? as usize;
+            let name = names.take(len)?;
+            if name_type == HOST_NAME {
+                return std::str::from_utf8(name.0)
+                    .ok()
+                    .map(str::to_ascii_lowercase);
+            }
+        }
+    }
+    None
+}
+
+// big-endian fields off the front of a handshake message
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, n: usize) -> Option<Reader<'a>> {
+        if self.0.len() < n {
+            return None;
+        }
+        let (taken, rest) = self.0.split_at(n);
+        self.0 = rest;
+        Some(Reader(taken))
+    }
+
+    fn u8(&mut self) -> Option<u8> {
+        Some(self.take(1)?.0[0])
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        let b = self.take(2)?.0;
+        Some(u16::from_be_bytes([b[0], b[1]]))
+    }
+
+    fn u24(&mut self) -> Option<usize> {
+        let b = self.take(3)?.0;
+        Some((b[0] as usize) << 16 | (b[1] as usize) << 8 | b[2] as usize)
+    }
+}
@@ -0,0 +1,124 @@
+//! socket level options applied to accepted connections
+
+use std::io;
+use std::net::{SocketAddr, ToSocketAddrs};
+use std::time::Duration;
+
+use may::net::{TcpListener, TcpStream};
+use socket2::SockRef;
+
+use crate::http::platform;
+use crate::server::config::{ServerConfig, DEFAULT_BACKLOG};
+
+pub(crate) fn set_linger(stream: &TcpStream, linger: Option<Duration>) -> io::Result<()> {
+    SockRef::from(stream.inner()).set_linger(linger)
+}
+
+pub(crate) fn configure(stream: &TcpStream, config: &ServerConfig) -> io::Result<()> {
+    if config.linger.is_some() {
+        set_linger(stream, config.linger)?;
+    }
+    if config.nodelay {
+        stream.set_nodelay(true)?;
+    }
+    if let Some(keepalive) = &config.tcp_keepalive {
+        SockRef::from(stream.inner()).set_tcp_keepalive(keepalive)?;
+    }
+    Ok(())
+}
+
+// drop a connection the server gave up on; an abortive close resets it
+// instead of leaving it to wind down through FIN_WAIT/TIME_WAIT
+pub(crate) fn close_on_error(stream: &TcpStream, config: &ServerConfig) {
+    if config.abortive_close {
+        set_linger(stream, Some(Duration::ZERO)).ok();
+    } else {
+        stream.shutdown(std::net::Shutdown::Both).ok();
+    }
+}
+
+// whether the client has closed or reset the connection, without waiting
+// on it. early bytes from the client, e.g. a pipelined request, count as
+// connected
+pub(crate) fn peer_gone(stream: &TcpStream) -> bool {
+    let socket = SockRef::from(stream.inner());
+    !matches!(socket.take_error(), Ok(None)) || platform::peek_closed(&socket)
+}
+
+// bind the listening sockets: one normally, or `reuseport` of them sharing
+// the port so each gets its own acceptor; `v6_only` keeps IPv6 sockets off
+// the IPv4 side of the port so both families can be bound
+pub(crate) fn bind<L: ToSocketAddrs>(
+    addr: L,
+    config: &ServerConfig,
+    v6_only: bool,
+) -> io::Result<Vec<TcpListener>> {
+    if config.acceptors().is_none() && config.backlog.is_none() && !v6_only {
+        return Ok(vec![TcpListener::bind(addr)?]);
+    }
+    let addr = addr
+        .to_socket_addrs()?
+        .next()
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidInput, "no address to listen on"))?;
+    let v6_only = v6_only && addr.is_ipv6();
+    let backlog = config.backlog.unwrap_or(DEFAULT_BACKLOG);
+    match config.acceptors() {
+        Some(acceptors) => bind_reuseport(
+            addr,
+            acceptors.max(1),
+            config.reuseport_steering,
+            v6_only,
+            backlog,
+        ),
+        None => {
+            let socket = listen(addr, v6_only, false, backlog)?;
+            Ok(vec![TcpListener::from_std(socket.into())?])
+        }
+    }
+}
+
+// accept on a listener bound elsewhere; the server keeps the original, so
+// a restart can serve on it again
+pub(crate) fn adopt(listener: &std::net::TcpListener) -> io::Result<TcpListener> {
+    TcpListener::from_std(listener.try_clone()?)
+}
+
+// a bound, listening socket; these options only count before the bind
+fn listen(
+    addr: SocketAddr,
+    v6_only: bool,
+    reuse_port: bool,
+    backlog: i32,
+) -> io::Result<socket2::Socket> {
+    use socket2::{Domain, Socket, Type};
+
+    let socket = Socket::new(Domain::for_address(addr), Type::STREAM, None)?;
+    if v6_only {
+        socket.set_only_v6(true)?;
+    }
+    platform::set_reuse(&socket, reuse_port)?;
+    socket.bind(&addr.into())?;
+    socket.listen(backlog)?;
+    Ok(socket)
+}
+
+fn bind_reuseport(
+    addr: SocketAddr,
+    acceptors: usize,
+    steering: bool,
+    v6_only: bool,
+    backlog: i32,
+) -> io::Result<Vec<TcpListener>> {
+    let mut listeners = Vec::with_capacity(acceptors);
+    for _ in 0..acceptors {
+        listeners.push(listen(addr, v6_only, true, backlog)?);
+    }
+    // the program belongs to the whole group, any member can install it
+    if steering {
+        platform::steer_by_client(&listeners[0], acceptors)?;
+    }
+    listeners
+        .into_iter()
+        .map(|socket| TcpListener::from_std(socket.into()))
+        .collect()
+}
@@ -0,0 +1,88 @@
+//! CONNECT tunnels, relaying raw bytes between a client and its target
+
+use std::io::{self, Read, Write};
+use std::net::Shutdown;
+
+use bytes::BytesMut;
+use may::go;
+use may::net::TcpStream;
+
+use crate::request::context::YieldEvery;
+use crate::server::config::ServerConfig;
+
+// what one read of either side takes in
+const PUMP_CHUNK: usize = 16 * 1024;
+
+// write the queued responses, then relay until both sides are done; the
+// connection carries nothing else afterwards
+pub(crate) fn open(
+    client: &mut TcpStream,
+    res_buf: &mut BytesMut,
+    req_buf: &mut BytesMut,
+    mut upstream: TcpStream,
+    config: &ServerConfig,
+) -> io::Result<()> {
+    client.write_all(res_buf)?;
+    res_buf.clear();
+    // whatever the client sent past the CONNECT head is already the tunnel's
+    upstream.write_all(req_buf)?;
+    req_buf.clear();
+    let every = config.yield_every.unwrap_or(0);
+    let (sent, received) = relay(client, upstream, every)?;
+    debug!(
+        "tunnel closed, {} bytes sent and {} received",
+        sent, received
+    );
+    Ok(())
+}
+
+// pump bytes both ways, one coroutine each, passing on each side's end of
+// stream to the other; an error on either cuts both off
+fn relay(client: &mut TcpStream, upstream: TcpStream, every: usize) -> io::Result<(u64, u64)> {
+    // tunnels may sit idle for as long as their ends like
+    client.set_read_timeout(None)?;
+    upstream.set_read_timeout(None)?;
+    let mut from_client = client.try_clone()?;
+    let mut to_upstream = upstream.try_clone()?;
+    let sending = go!(move || {
+        let sent = pump(&mut from_client, &mut to_upstream, every);
+        close(&to_upstream, &from_client, sent.is_ok());
+        sent
+    });
+    let mut from_upstream = upstream;
+    let received = pump(&mut from_upstream, client, every);
+    close(client, &from_upstream, received.is_ok());
+    let sent = sending
+        .join()
+        .unwrap_or_else(|_| Err(io::Error::new(io::ErrorKind::Other, "relay panicked")));
+    Ok((sent?, received?))
+}
+
+// copy until `from` ends, yielding the worker every `every` bytes
+fn pump(from: &mut TcpStream, to: &mut TcpStream, every: usize) -> io::Result<u64> {
+    let mut fair = YieldEvery::new(every);
+    let mut chunk = vec![0; PUMP_CHUNK];
+    let mut total = 0;
+    loop {
+        let n = match from.read(&mut chunk) {
+            Ok(0) => return Ok(total),
+            Ok(n) => n,
+            Err(e) if e.kind() == io::ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        to.write_all(&chunk[..n])?;
+        total += n as u64;
+        fair.advance(n);
+    }
+}
+
+// half-close `to` once what `from` sent is through, or on an error tear
+// both down so the other direction stops too
+fn close(to: &TcpStream, from: &TcpStream, clean: bool) {
+    if clean {
+        to.shutdown(Shutdown::Write).ok();
+    } else {
+        to.shutdown(Shutdown::Both).ok();
+        from.shutdown(Shutdown::Both).ok();
+    }
+}
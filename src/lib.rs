@@ -1,33 +1,185 @@
+// internal diagnostics go through whichever facade is enabled
+#[cfg(not(feature = "tracing"))]
 #[macro_use]
 extern crate log;
+#[cfg(feature = "tracing")]
+#[macro_use]
+extern crate tracing;
 
 pub mod server {
+    pub mod access_log;
+    mod affinity;
+    pub mod allocator;
+    pub mod api_key;
+    pub mod audit;
+    pub mod auth;
+    pub mod config;
+    pub mod connection_admin;
+    pub mod content_store;
+    pub mod cors;
+    pub mod csrf;
+    pub mod deprecation;
+    #[cfg(feature = "dev")]
+    pub mod dev;
+    pub mod embedded;
+    mod explain;
+    pub mod flags;
+    mod hosts;
+    #[cfg(feature = "graphql")]
+    pub mod graphql;
+    pub mod health;
+    pub mod ip_filter;
+    pub mod jsonrpc;
+    #[cfg(feature = "jwt")]
+    pub mod jwt;
+    pub mod kv;
+    pub mod login_limit;
+    pub mod maintenance;
+    pub mod metrics;
+    pub mod nonce;
+    mod openapi;
+    pub mod pagination;
+    pub mod pool;
+    pub mod rate_limit;
+    #[cfg(feature = "redis")]
+    pub mod redis;
+    pub mod response_cache;
+    pub mod schema;
+    pub mod secrets;
+    pub mod security_headers;
     pub mod server;
+    pub mod signing;
+    pub mod versioned;
+    pub mod websocket;
+    pub mod well_known;
 }
 
 mod http {
+    pub mod client;
+    pub mod connection;
+    pub mod forwarded;
+    pub mod h2;
+    pub mod hpack;
     pub mod http_server;
+    pub mod memory;
+    pub mod passthrough;
+    pub mod platform;
+    pub mod proxy;
+    pub mod shutdown;
+    pub mod sni;
+    pub mod socket;
+    pub mod tunnel;
 }
 
 mod request {
+    pub mod context;
+    pub mod extensions;
+    pub mod headers;
+    pub mod json_limits;
+    pub mod logger;
+    pub mod param;
     pub mod request;
+    pub mod spool;
 }
 
 mod response {
+    pub mod codec;
     pub mod date;
+    pub mod disposition;
+    #[cfg(feature = "templates")]
+    pub mod render;
+    pub mod respond;
     pub mod response;
+    pub mod seekable;
+    pub mod sse;
+    pub mod status;
+    pub mod writer;
+    pub mod zip;
 }
 
 mod router {
+    pub mod guard;
     pub mod route_matcher;
 }
 
 mod errors {
+    pub mod catalog;
+    pub mod error;
     pub mod errors;
+    pub mod http_error;
+    pub mod validation;
 }
 
+#[cfg(test)]
+mod bench;
+pub mod test;
+#[cfg(test)]
+mod testing;
+mod util;
+
 use response::response::Response;
 
+pub use errors::catalog::MessageCatalog;
+pub use errors::error::{Error, Result};
+pub use errors::errors::RequestError;
+pub use errors::http_error::HttpError;
+pub use errors::validation::ValidationErrors;
+pub use http::client::{ClientRequest, ClientResponse, HttpClient};
+pub use http::connection::Connection;
+pub use http::shutdown::{ConnectionInfo, ServerHandle, ShutdownReport};
+pub use request::context::{RequestContext, YieldEvery};
+pub use request::headers::{Authorization, MediaType};
+pub use request::logger::RequestLogger;
+pub use request::param::{FromParam, ParamError};
+pub use request::spool::{Spool, SpooledFile};
+#[cfg(feature = "templates")]
+pub use response::render::Templates;
+pub use response::date;
+pub use response::respond::{respond, IntoResponse, Json};
+pub use response::seekable::SeekableBody;
+pub use response::sse::{SseEvent, SseStream};
+pub use response::status::StatusCode;
+pub use response::zip::{ZipMethod, ZipWriter};
+pub use router::guard::{self, Guard};
+pub use router::route_matcher::{RouteInfo, RouteOptions, TrailingSlash};
+pub use server::access_log::{AccessEntry, AccessLog, LogFormat};
+pub use server::api_key::{ApiKey, ApiKeyStore, ApiKeys, MemoryApiKeys};
+pub use server::audit::AuditLog;
+pub use server::auth::Auth;
+pub use server::connection_admin::ConnectionAdmin;
+pub use server::content_store::{ContentStore, Stored};
+pub use server::cors::Cors;
+pub use server::csrf::{Csrf, CsrfToken};
+pub use server::deprecation::{DeprecatedCall, DeprecationUsage};
+#[cfg(feature = "dev")]
+pub use server::dev::DevMode;
+pub use server::embedded::{AssetManifest, EmbeddedAssets};
+pub use server::flags::{rollout, FeatureFlags, FlagProvider};
+pub use server::health::Health;
+pub use server::ip_filter::IpFilter;
+pub use server::jsonrpc::{JsonRpc, RpcError};
+#[cfg(feature = "jwt")]
+pub use server::jwt::{Claims, Jwt};
+pub use server::login_limit::LoginLimiter;
+pub use server::maintenance::Maintenance;
+pub use server::metrics::{Metrics, MetricsSnapshot, RouteLatency, LATENCY_BUCKETS};
+pub use server::nonce::{NonceRejection, NonceTracker};
+pub use server::pagination::{Page, Pagination};
+pub use server::pool::{Pool, Pooled};
+pub use server::kv::{FileKv, KvStore, MemoryKv};
+pub use server::rate_limit::{
+    KvRateLimitStore, MemoryStore, Quota, RateLimit, RateLimitStore, RateLimiter,
+};
+#[cfg(feature = "redis")]
+pub use server::redis::{Redis, RedisValue};
+pub use server::response_cache::ResponseCache;
+pub use server::schema::{JsonSchema, SchemaError};
+pub use server::secrets::{EnvSecrets, FileSecrets, SecretsProvider};
+pub use server::security_headers::SecurityHeaders;
 pub use server::server::{Middleware, RouteHandler, Server};
+pub use server::signing::{RequestSigning, SignedBy};
+pub use server::versioned::Versioned;
+pub use server::websocket::{Subprotocols, WebSocketOrigins};
+pub use server::well_known::{AcmeChallenges, WellKnown};
 
 pub use serde_json::json;
@@ -0,0 +1,147 @@
+//! what a request hands down to the coroutines its handler spawns
+
+use std::cell::RefCell;
+use std::time::{Duration, Instant};
+
+use may::coroutine::JoinHandle;
+
+use crate::request::request::Request;
+
+may::coroutine_local!(static CURRENT: RefCell<Option<RequestContext>> = RefCell::new(None));
+
+/// A request's id, W3C trace context (`traceparent` / `tracestate`) and
+/// deadline, carried into work spawned on its behalf.
+///
+/// Coroutines started with `spawn` see it as `RequestContext::current()`,
+/// and with the `tracing` feature run inside the request's span, so their
+/// logs and outbound calls can be tied back to the request. The deadline
+/// is the handler's to set with `with_deadline`.
+#[derive(Clone, Debug)]
+pub struct RequestContext {
+    request_id: String,
+    traceparent: Option<String>,
+    tracestate: Option<String>,
+    deadline: Option<Instant>,
+    #[cfg(feature = "tracing")]
+    span: tracing::Span,
+}
+
+impl RequestContext {
+    /// The context of the spawned work running now; `None` outside it.
+    pub fn current() -> Option<RequestContext> {
+        CURRENT.with(|current| current.borrow().clone())
+    }
+
+    pub fn request_id(&self) -> &str {
+        &self.request_id
+    }
+
+    pub fn traceparent(&self) -> Option<&str> {
+        self.traceparent.as_deref()
+    }
+
+    pub fn tracestate(&self) -> Option<&str> {
+        self.tracestate.as_deref()
+    }
+
+    pub fn deadline(&self) -> Option<Instant> {
+        self.deadline
+    }
+
+    /// Time left before the deadline, zero once it has passed.
+    pub fn remaining(&self) -> Option<Duration> {
+        self.deadline
+            .map(|deadline| deadline.saturating_duration_since(Instant::now()))
+    }
+
+    pub fn expired(&self) -> bool {
+        self.remaining() == Some(Duration::ZERO)
+    }
+
+    /// Sets the deadline; an earlier one already set is kept, so spawned
+    /// work can only tighten it.
+    pub fn with_deadline(mut self, deadline: Instant) -> Self {
+        self.deadline = Some(self.deadline.map_or(deadline, |d| d.min(deadline)));
+        self
+    }
+
+    /// A counter yielding the worker to its other coroutines every `n`
+    /// units of work, for handlers with long loops, e.g. over rows or bytes.
+    pub fn yield_every(&self, n: usize) -> YieldEvery {
+        YieldEvery::new(n)
+    }
+
+    /// Runs `f` in a new coroutine carrying this context.
+    pub fn spawn<F, T>(&self, f: F) -> JoinHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        let context = self.clone();
+        may::go!(move || {
+            #[cfg(feature = "tracing")]
+            let f = {
+                let span = context.span.clone();
+                move || span.in_scope(f)
+            };
+            CURRENT.with(|current| *current.borrow_mut() = Some(context));
+            f()
+        })
+    }
+}
+
+/// Yields the worker to its other coroutines every `n` units of work
+/// counted with `tick` or `advance`, so a long-running loop shares the
+/// worker rather than starving what else is scheduled on it. Zero never
+/// yields.
+#[derive(Clone, Debug)]
+pub struct YieldEvery {
+    every: usize,
+    done: usize,
+}
+
+impl YieldEvery {
+    pub fn new(n: usize) -> Self {
+        YieldEvery { every: n, done: 0 }
+    }
+
+    /// Counts one unit of work.
+    pub fn tick(&mut self) {
+        self.advance(1);
+    }
+
+    /// Counts `units` of work, e.g. bytes copied.
+    pub fn advance(&mut self, units: usize) {
+        if self.every == 0 {
+            return;
+        }
+        self.done += units;
+        if self.done >= self.every {
+            self.done %= self.every;
+            may::coroutine::yield_now();
+        }
+    }
+}
+
+impl<'buf, 'header, 'stream> Request<'buf, 'header, 'stream> {
+    /// What work spawned for this request inherits.
+    pub fn context(&self) -> RequestContext {
+        RequestContext {
+            request_id: self.request_id.clone(),
+            traceparent: self.header("traceparent").map(str::to_owned),
+            tracestate: self.header("tracestate").map(str::to_owned),
+            deadline: None,
+            #[cfg(feature = "tracing")]
+            span: tracing::Span::current(),
+        }
+    }
+
+    /// Runs `f` in a new coroutine carrying this request's context.
+    pub fn spawn<F, T>(&self, f: F) -> JoinHandle<T>
+    where
+        F: FnOnce() -> T + Send + 'static,
+        T: Send + 'static,
+    {
+        self.context().spawn(f)
+    }
+}
@@ -0,0 +1,54 @@
+//! typed values attached to a request on its way to the handler
+
+use std::any::{Any, TypeId};
+use std::collections::HashMap;
+
+use crate::request::request::Request;
+
+// one value per type, e.g. the principal `Auth` authenticated
+#[derive(Default)]
+pub(crate) struct Extensions {
+    map: HashMap<TypeId, Box<dyn Any + Send + Sync>>,
+}
+
+impl Extensions {
+    pub(crate) fn insert<T: Send + Sync + 'static>(&mut self, value: T) -> Option<T> {
+        self.map
+            .insert(TypeId::of::<T>(), Box::new(value))
+            .and_then(|old| old.downcast().ok().map(|old| *old))
+    }
+
+    fn get<T: 'static>(&self) -> Option<&T> {
+        self.map.get(&TypeId::of::<T>())?.downcast_ref()
+    }
+
+    fn get_mut<T: 'static>(&mut self) -> Option<&mut T> {
+        self.map.get_mut(&TypeId::of::<T>())?.downcast_mut()
+    }
+
+    fn remove<T: 'static>(&mut self) -> Option<T> {
+        let value = self.map.remove(&TypeId::of::<T>())?;
+        value.downcast().ok().map(|value| *value)
+    }
+}
+
+impl<'buf, 'header, 'stream> Request<'buf, 'header, 'stream> {
+    /// The value of type `T` attached to this request, e.g. the principal
+    /// `Auth` put there.
+    pub fn extension<T: 'static>(&self) -> Option<&T> {
+        self.extensions.get()
+    }
+
+    pub fn extension_mut<T: 'static>(&mut self) -> Option<&mut T> {
+        self.extensions.get_mut()
+    }
+
+    /// Attaches `value`, returning the one of the same type it replaces.
+    pub fn insert_extension<T: Send + Sync + 'static>(&mut self, value: T) -> Option<T> {
+        self.extensions.insert(value)
+    }
+
+    pub fn remove_extension<T: 'static>(&mut self) -> Option<T> {
+        self.extensions.remove()
+    }
+}
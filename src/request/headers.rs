@@ -0,0 +1,360 @@
+//! typed access to common request headers
+
+use std::cmp::Ordering;
+
+use crate::request::request::Request;
+use crate::response::response::Response;
+use crate::server::versioned::matches;
+
+/// A parsed media type or range, e.g. `text/html; charset=utf-8` or
+/// `image/*`. Type, subtype and parameter names are lowercased.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct MediaType {
+    essence: String,
+    slash: usize,
+    params: Vec<(String, String)>,
+}
+
+impl MediaType {
+    pub fn parse(s: &str) -> Option<Self> {
+        let mut parts = s.split(';');
+        let essence = parts.next()?.trim().to_ascii_lowercase();
+        let slash = essence.find('/')?;
+        let valid = |part: &str| !part.is_empty() && part.bytes().all(is_token);
+        if !valid(&essence[..slash]) || !valid(&essence[slash + 1..]) {
+            return None;
+        }
+        let params = parts
+            .filter_map(|param| {
+                let (name, value) = param.split_once('=')?;
+                let value = value.trim();
+                let value = match value.strip_prefix('"') {
+                    Some(quoted) => quoted.strip_suffix('"').unwrap_or(quoted),
+                    None => value,
+                };
+                Some((name.trim().to_ascii_lowercase(), value.to_owned()))
+            })
+            .collect();
+        Some(MediaType {
+            essence,
+            slash,
+            params,
+        })
+    }
+
+    /// The top-level type, e.g. `text`.
+    pub fn type_(&self) -> &str {
+        &self.essence[..self.slash]
+    }
+
+    pub fn subtype(&self) -> &str {
+        &self.essence[self.slash + 1..]
+    }
+
+    /// Type and subtype without parameters, e.g. `text/html`.
+    pub fn essence(&self) -> &str {
+        &self.essence
+    }
+
+    pub fn param(&self, name: &str) -> Option<&str> {
+        self.params
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn charset(&self) -> Option<&str> {
+        self.param("charset")
+    }
+
+    /// Whether this is `essence`, e.g. `application/json`, ignoring case
+    /// and parameters.
+    pub fn is(&self, essence: &str) -> bool {
+        self.essence.eq_ignore_ascii_case(essence)
+    }
+
+    /// Whether this range, wildcards included, covers `essence`.
+    pub fn matches(&self, essence: &str) -> bool {
+        let (type_, subtype) = essence.split_once('/').unwrap_or((essence, ""));
+        match (self.type_(), self.subtype()) {
+            ("*", _) => true,
+            (t, "*") => t.eq_ignore_ascii_case(type_),
+            _ => self.is(essence) && !subtype.is_empty(),
+        }
+    }
+
+    // wildcards rank below anything more specific
+    fn specificity(&self) -> u8 {
+        match (self.type_(), self.subtype()) {
+            ("*", _) => 0,
+            (_, "*") => 1,
+            _ => 2,
+        }
+    }
+}
+
+/// The credentials of an `Authorization` header, e.g. `Bearer <token>`.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Authorization<'a> {
+    scheme: &'a str,
+    credentials: &'a str,
+}
+
+impl<'a> Authorization<'a> {
+    pub fn parse(value: &'a str) -> Option<Self> {
+        let value = value.trim();
+        let (scheme, credentials) = value.split_once(' ').unwrap_or((value, ""));
+        match scheme.is_empty() {
+            true => None,
+            false => Some(Authorization {
+                scheme,
+                credentials: credentials.trim(),
+            }),
+        }
+    }
+
+    pub fn scheme(&self) -> &'a str {
+        self.scheme
+    }
+
+    pub fn credentials(&self) -> &'a str {
+        self.credentials
+    }
+
+    /// The token of a `Bearer` scheme.
+    pub fn bearer(&self) -> Option<&'a str> {
+        let bearer = self.scheme.eq_ignore_ascii_case("bearer") && !self.credentials.is_empty();
+        bearer.then_some(self.credentials)
+    }
+}
+
+impl<'buf, 'header, 'stream> Request<'buf, 'header, 'stream> {
+    /// The Content-Type, if present and well-formed.
+    pub fn content_type(&self) -> Option<MediaType> {
+        self.header("content-type").and_then(MediaType::parse)
+    }
+
+    /// The declared body length; `None` for a chunked body.
+    pub fn content_length(&self) -> Option<usize> {
+        self.req.content_length()
+    }
+
+    /// The media ranges in Accept with their q-values, most preferred
+    /// first; more specific ranges win ties. Empty without an Accept.
+    pub fn accept(&self) -> Vec<(MediaType, f32)> {
+        let mut ranges: Vec<(MediaType, f32)> = self
+            .header("accept")
+            .unwrap_or("")
+            .split(',')
+            .filter_map(|item| {
+                let mut range = MediaType::parse(item)?;
+                let q = range
+                    .param("q")
+                    .map_or(Some(1.0), |q| q.parse::<f32>().ok())
+                    .filter(|q| (0.0..=1.0).contains(q))
+                    .unwrap_or(1.0);
+                range.params.retain(|(name, _)| name != "q");
+                Some((range, q))
+            })
+            .collect();
+        ranges.sort_by(|a, b| {
+            b.1.partial_cmp(&a.1)
+                .unwrap_or(Ordering::Equal)
+                .then(b.0.specificity().cmp(&a.0.specificity()))
+        });
+        ranges
+    }
+
+    /// Which of `offered`, e.g. `["application/json", "text/html"]`, the
+    /// client prefers; the first offered when it sent no Accept, and `None`
+    /// when it accepts none of them.
+    pub fn preferred<'o>(&self, offered: &[&'o str]) -> Option<&'o str> {
+        let ranges = self.accept();
+        if ranges.is_empty() {
+            return offered.first().copied();
+        }
+        best(offered, |essence| {
+            // the most specific matching range decides
+            ranges
+                .iter()
+                .filter(|(range, _)| range.matches(essence))
+                .max_by_key(|(range, _)| range.specificity())
+                .map_or(0.0, |(_, q)| *q)
+        })
+    }
+
+    /// `preferred`, also adding `Vary: Accept` so caches keep the variants
+    /// apart. `None` is usually answered with 406.
+    pub fn negotiate<'o>(&self, res: &mut Response, offered: &[&'o str]) -> Option<&'o str> {
+        res.header("Vary: Accept");
+        self.preferred(offered)
+    }
+
+    /// Which of the `offered` language tags, e.g. `["en", "pt-BR"]`, best
+    /// fits Accept-Language, adding `Vary: Accept-Language`. A range
+    /// matches its subtags too, so `en` covers `en-GB`; the first offered
+    /// wins without the header.
+    pub fn negotiate_language<'o>(
+        &self,
+        res: &mut Response,
+        offered: &[&'o str],
+    ) -> Option<&'o str> {
+        res.header("Vary: Accept-Language");
+        preferred_language(self.header("accept-language"), offered)
+    }
+
+    /// Which of the `offered` content codings, e.g. `["br", "gzip"]`, to
+    /// send, adding `Vary: Accept-Encoding`. `identity` is acceptable
+    /// unless refused, so offer it last to fall back to an unencoded body.
+    pub fn negotiate_encoding<'o>(
+        &self,
+        res: &mut Response,
+        offered: &[&'o str],
+    ) -> Option<&'o str> {
+        res.header("Vary: Accept-Encoding");
+        let codings = match self.header("accept-encoding") {
+            Some(header) => weighted(header),
+            None => return offered.first().copied(),
+        };
+        let listed = |coding: &str| {
+            codings
+                .iter()
+                .find(|(name, _)| name.eq_ignore_ascii_case(coding))
+                .map(|(_, q)| *q)
+        };
+        best(offered, |coding| {
+            listed(coding).or_else(|| listed("*")).unwrap_or_else(|| {
+                match coding.eq_ignore_ascii_case("identity") {
+                    true => 1.0,
+                    false => 0.0,
+                }
+            })
+        })
+    }
+
+    pub fn authorization(&self) -> Option<Authorization> {
+        self.header("authorization").and_then(Authorization::parse)
+    }
+
+    /// The value of cookie `name`, from any of the Cookie headers.
+    pub fn cookie(&self, name: &str) -> Option<&str> {
+        self.headers()
+            .iter()
+            .filter(|header| header.name.eq_ignore_ascii_case("cookie"))
+            .filter_map(|header| std::str::from_utf8(header.value).ok())
+            .flat_map(|value| value.split(';'))
+            .filter_map(|pair| pair.trim().split_once('='))
+            .find(|(key, _)| *key == name)
+            .map(|(_, value)| value.trim_matches('"'))
+    }
+
+    /// Whether the request only wants to create the resource,
+    /// `If-None-Match: *`.
+    pub fn if_none_match_any(&self) -> bool {
+        self.header("if-none-match").map(str::trim) == Some("*")
+    }
+
+    /// Whether the request only wants to change an existing resource,
+    /// `If-Match: *`.
+    pub fn if_match_any(&self) -> bool {
+        self.header("if-match").map(str::trim) == Some("*")
+    }
+
+    /// Evaluates If-Match and If-None-Match against the target resource,
+    /// whether it `exists` and its current `etag`, in RFC 9110's order.
+    /// Returns whether the handler may go on; otherwise the response is
+    /// 412, or 304 for a GET or HEAD whose If-None-Match matched.
+    ///
+    /// With `If-None-Match: *` a PUT only creates, and with `If-Match: *`
+    /// it only replaces, so two clients can't both create the resource.
+    pub fn check_preconditions(
+        &mut self,
+        res: &mut Response,
+        exists: bool,
+        etag: Option<&str>,
+    ) -> bool {
+        // a missing resource has no representation for any tag to match
+        let match_failed = self.header("if-match").map_or(false, |tags| {
+            !exists || (tags.trim() != "*" && !etag.map_or(false, |e| matches(tags, e, true)))
+        });
+        let none_match_failed = self.header("if-none-match").map_or(false, |tags| {
+            exists && (tags.trim() == "*" || etag.map_or(false, |e| matches(tags, e, false)))
+        });
+        if !match_failed && !none_match_failed {
+            return true;
+        }
+        self.req.reject_body();
+        if !match_failed && matches!(self.method(), "GET" | "HEAD") {
+            res.status_code(304, "Not Modified");
+            if let Some(etag) = etag {
+                res.header_owned(format!("ETag: {}", etag));
+            }
+        } else {
+            res.status_code(412, "Precondition Failed");
+        }
+        false
+    }
+}
+
+// which of the `offered` language tags an Accept-Language value prefers,
+// the first offered without one
+pub(crate) fn preferred_language<'o>(header: Option<&str>, offered: &[&'o str]) -> Option<&'o str> {
+    let ranges = match header {
+        Some(header) => weighted(header),
+        None => return offered.first().copied(),
+    };
+    best(offered, |tag| {
+        ranges
+            .iter()
+            .filter(|(range, _)| range == "*" || language_matches(range, tag))
+            .max_by_key(|(range, _)| if range == "*" { 0 } else { range.len() })
+            .map_or(0.0, |(_, q)| *q)
+    })
+}
+
+// the offered value with the highest quality above zero, earlier offers
+// winning ties
+fn best<'o>(offered: &[&'o str], quality: impl Fn(&str) -> f32) -> Option<&'o str> {
+    let mut best = None;
+    for &value in offered {
+        let q = quality(value);
+        if q > 0.0 && best.map_or(true, |(_, best_q)| q > best_q) {
+            best = Some((value, q));
+        }
+    }
+    best.map(|(value, _)| value)
+}
+
+// the items of a `token;q=value` list, as in Accept-Language and
+// Accept-Encoding
+fn weighted(header: &str) -> Vec<(String, f32)> {
+    header
+        .split(',')
+        .filter_map(|item| {
+            let mut parts = item.split(';').map(str::trim);
+            let name = parts.next().filter(|name| !name.is_empty())?;
+            let q = parts
+                .find_map(|param| param.strip_prefix("q="))
+                .map_or(Some(1.0), |q| q.parse::<f32>().ok())
+                .filter(|q| (0.0..=1.0).contains(q))
+                .unwrap_or(1.0);
+            Some((name.to_owned(), q))
+        })
+        .collect()
+}
+
+// RFC 4647 basic filtering: `range` equals `tag` or is a prefix ending at
+// a subtag boundary
+fn language_matches(range: &str, tag: &str) -> bool {
+    match tag.get(..range.len()) {
+        Some(prefix) => {
+            prefix.eq_ignore_ascii_case(range)
+                && matches!(tag.as_bytes().get(range.len()), None | Some(b'-'))
+        }
+        None => false,
+    }
+}
+
+fn is_token(b: u8) -> bool {
+    b"!#$%&'*+-.^_`|~".contains(&b) || b.is_ascii_alphanumeric()
+}
@@ -0,0 +1,82 @@
+//! limits on JSON bodies checked before they are parsed
+
+use crate::errors::errors::RequestError;
+
+// as deep as JSON bodies nest by default, half serde_json's own limit
+pub(crate) const DEFAULT_MAX_DEPTH: usize = 64;
+
+// the nesting and size of JSON bodies `json_body` and `body_as` accept
+#[derive(Clone, Copy, Debug)]
+pub(crate) struct JsonLimits {
+    pub(crate) max_depth: usize,
+    pub(crate) max_tokens: Option<usize>,
+}
+
+impl Default for JsonLimits {
+    fn default() -> Self {
+        JsonLimits {
+            max_depth: DEFAULT_MAX_DEPTH,
+            max_tokens: None,
+        }
+    }
+}
+
+impl JsonLimits {
+    // one linear pass counting nesting and tokens, i.e. keys and values,
+    // so a pathological body is refused before any parser sees it; what's
+    // malformed is left for the parser to report
+    pub(crate) fn check(&self, body: &[u8]) -> Result<(), RequestError> {
+        let mut depth = 0usize;
+        let mut tokens = 0usize;
+        let mut in_string = false;
+        let mut escaped = false;
+        let mut in_scalar = false;
+        for &b in body {
+            if in_string {
+                match b {
+                    _ if escaped => escaped = false,
+                    b'\\' => escaped = true,
+                    b'"' => in_string = false,
+                    _ => {}
+                }
+                continue;
+            }
+            let starts = match b {
+                b'"' => {
+                    in_string = true;
+                    true
+                }
+                b'{' | b'[' => {
+                    depth += 1;
+                    if depth > self.max_depth {
+                        return Err(RequestError::JsonTooComplex(format!(
+                            "nested deeper than {}",
+                            self.max_depth
+                        )));
+                    }
+                    true
+                }
+                b'}' | b']' => {
+                    depth = depth.saturating_sub(1);
+                    false
+                }
+                b if b.is_ascii_alphanumeric() || matches!(b, b'-' | b'+' | b'.') => !in_scalar,
+                _ => false,
+            };
+            in_scalar = b.is_ascii_alphanumeric() || matches!(b, b'-' | b'+' | b'.');
+            if starts {
+                tokens += 1;
+                match self.max_tokens {
+                    Some(max) if tokens > max => {
+                        return Err(RequestError::JsonTooComplex(format!(
+                            "more than {} tokens",
+                            max
+                        )));
+                    }
+                    _ => {}
+                }
+            }
+        }
+        Ok(())
+    }
+}
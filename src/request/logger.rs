@@ -0,0 +1,95 @@
+//! a logger that says which request each line is about
+
+use std::fmt;
+
+use log::Level;
+
+/// Logs on behalf of one request, prefixing every line with its request
+/// id, route and, once middleware has named it, tenant, e.g.
+/// `[request_id=3f2a route="GET /users/:id" tenant=acme] user not found`.
+///
+/// Every routed request carries one, found with
+/// `req.extension::<RequestLogger>()`; middleware that identifies the
+/// tenant sets it through `req.extension_mut::<RequestLogger>()`. Clones
+/// are cheap enough to move into work the handler spawns. Lines go through
+/// `log`, or `tracing` with that feature, under this module's target.
+#[derive(Clone, Debug)]
+pub struct RequestLogger {
+    request_id: String,
+    route: String,
+    tenant: Option<String>,
+}
+
+impl RequestLogger {
+    pub(crate) fn new(request_id: &str, method: &str, route: &str) -> Self {
+        RequestLogger {
+            request_id: request_id.to_owned(),
+            route: format!("{} {}", method, route),
+            tenant: None,
+        }
+    }
+
+    pub fn request_id(&self) -> &str {
+        &self.request_id
+    }
+
+    /// The method and path pattern of the matched route.
+    pub fn route(&self) -> &str {
+        &self.route
+    }
+
+    pub fn tenant(&self) -> Option<&str> {
+        self.tenant.as_deref()
+    }
+
+    pub fn set_tenant(&mut self, tenant: &str) -> &mut Self {
+        self.tenant = Some(tenant.to_owned());
+        self
+    }
+
+    /// Logs `args` at `level`, e.g.
+    /// `logger.log(Level::Info, format_args!("created {}", id))`.
+    pub fn log(&self, level: Level, args: fmt::Arguments) {
+        #[cfg(not(feature = "tracing"))]
+        log!(level, "[{}] {}", self, args);
+        #[cfg(feature = "tracing")]
+        match level {
+            Level::Error => error!("[{}] {}", self, args),
+            Level::Warn => warn!("[{}] {}", self, args),
+            Level::Info => info!("[{}] {}", self, args),
+            Level::Debug => debug!("[{}] {}", self, args),
+            Level::Trace => trace!("[{}] {}", self, args),
+        }
+    }
+
+    pub fn error(&self, args: fmt::Arguments) {
+        self.log(Level::Error, args);
+    }
+
+    pub fn warn(&self, args: fmt::Arguments) {
+        self.log(Level::Warn, args);
+    }
+
+    pub fn info(&self, args: fmt::Arguments) {
+        self.log(Level::Info, args);
+    }
+
+    pub fn debug(&self, args: fmt::Arguments) {
+        self.log(Level::Debug, args);
+    }
+
+    pub fn trace(&self, args: fmt::Arguments) {
+        self.log(Level::Trace, args);
+    }
+}
+
+// the prefix, without its brackets
+impl fmt::Display for RequestLogger {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "request_id={} route={:?}", self.request_id, self.route)?;
+        if let Some(tenant) = &self.tenant {
+            write!(f, " tenant={}", tenant)?;
+        }
+        Ok(())
+    }
+}
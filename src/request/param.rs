@@ -0,0 +1,125 @@
+//! typed path and query parameters
+
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::net::{IpAddr, Ipv4Addr, Ipv6Addr};
+
+use crate::request::request::Request;
+
+/// A type a path or query parameter can be parsed into with
+/// `Request::param` and `Request::url_param`.
+pub trait FromParam: Sized {
+    /// What a valid value looks like, for error messages, e.g. `an
+    /// unsigned integer`.
+    const EXPECTED: &'static str;
+
+    fn from_param(value: &str) -> Option<Self>;
+}
+
+macro_rules! from_str_param {
+    ($expected:literal: $($t:ty),*) => {$(
+        impl FromParam for $t {
+            const EXPECTED: &'static str = $expected;
+
+            fn from_param(value: &str) -> Option<Self> {
+                value.parse().ok()
+            }
+        }
+    )*};
+}
+
+from_str_param!("an unsigned integer": u8, u16, u32, u64, u128, usize);
+from_str_param!("an integer": i8, i16, i32, i64, i128, isize);
+from_str_param!("a number": f32, f64);
+from_str_param!("true or false": bool);
+from_str_param!("a single character": char);
+from_str_param!("an IP address": IpAddr);
+from_str_param!("an IPv4 address": Ipv4Addr);
+from_str_param!("an IPv6 address": Ipv6Addr);
+
+impl FromParam for String {
+    const EXPECTED: &'static str = "text";
+
+    fn from_param(value: &str) -> Option<Self> {
+        Some(value.to_owned())
+    }
+}
+
+#[cfg(feature = "uuid")]
+impl FromParam for uuid::Uuid {
+    const EXPECTED: &'static str = "a UUID";
+
+    fn from_param(value: &str) -> Option<Self> {
+        uuid::Uuid::parse_str(value).ok()
+    }
+}
+
+/// A parameter that's missing or doesn't parse.
+///
+/// Returned from a handler through `?`, it becomes an `io::Error` that the
+/// server answers as `HttpError::BadRequest` rather than with a 500.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ParamError {
+    Missing(String),
+    Invalid {
+        name: String,
+        value: String,
+        expected: &'static str,
+    },
+}
+
+impl fmt::Display for ParamError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            ParamError::Missing(name) => write!(f, "missing parameter {}", name),
+            ParamError::Invalid {
+                name,
+                value,
+                expected,
+            } => write!(
+                f,
+                "invalid parameter {}: {:?} is not {}",
+                name, value, expected
+            ),
+        }
+    }
+}
+
+impl Error for ParamError {}
+
+impl From<ParamError> for io::Error {
+    fn from(e: ParamError) -> Self {
+        io::Error::new(io::ErrorKind::InvalidInput, e)
+    }
+}
+
+impl<'buf, 'header, 'stream> Request<'buf, 'header, 'stream> {
+    /// The path parameter `name` parsed as `T`, e.g.
+    /// `req.param::<u64>("id")?` for `/users/:id`.
+    pub fn param<T: FromParam>(&self, name: &str) -> Result<T, ParamError> {
+        parse(name, self.parameter(name))
+    }
+
+    /// The query parameter `name` parsed as `T`.
+    pub fn url_param<T: FromParam>(&self, name: &str) -> Result<T, ParamError> {
+        parse(name, self.url_parameter(name))
+    }
+
+    /// The query parameter `name` parsed as `T`, `None` when it's absent.
+    pub fn url_param_opt<T: FromParam>(&self, name: &str) -> Result<Option<T>, ParamError> {
+        match self.url_parameter(name) {
+            Some(value) => parse(name, Some(value)).map(Some),
+            None => Ok(None),
+        }
+    }
+}
+
+fn parse<T: FromParam>(name: &str, value: Option<&str>) -> Result<T, ParamError> {
+    let value = value.ok_or_else(|| ParamError::Missing(name.to_owned()))?;
+    T::from_param(value).ok_or_else(|| ParamError::Invalid {
+        name: name.to_owned(),
+        value: value.to_owned(),
+        expected: T::EXPECTED,
+    })
+}
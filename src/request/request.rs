@@ -2,18 +2,41 @@ use std::collections::HashMap;
 use std::fmt;
 use std::io::{self, BufRead, Read};
 use std::mem::MaybeUninit;
+use std::net::{IpAddr, SocketAddr};
+use std::time::Duration;
 
 pub(crate) const MAX_HEADERS: usize = 16;
+// longest chunk-size or trailer line we wait for before giving up
+const MAX_CHUNK_LINE: usize = 4096;
+const BAD_REQUEST: (usize, &str) = (400, "Bad Request");
+// upfront reservation cap and read size of `BodyReader::read_to_end`
+const MAX_BODY_PREALLOC: usize = 16 * 1024 * 1024;
+const BODY_READ_STEP: usize = 64 * 1024;
+// most room a body size hint makes in the connection buffer per read
+const HINTED_FILL_MAX: usize = 1024 * 1024;
 
-use bytes::{Buf, BufMut, BytesMut};
-use may::net::TcpStream;
+use bytes::{Buf, Bytes, BytesMut};
+use sha2::Digest;
 
 use crate::errors::errors::RequestError;
+use crate::http::connection::Connection;
+use crate::http::http_server::is_timeout;
+use crate::http::shutdown::ConnectionTags;
+use crate::request::extensions::Extensions;
+use crate::request::json_limits::JsonLimits;
+use crate::response::response::valid_header;
+use crate::response::status::StatusCode;
+use crate::router::route_matcher::PathParams;
+use crate::server::config::ServerConfig;
+use crate::server::flags::FeatureFlags;
 
 #[derive()]
 pub struct Request<'buf, 'header, 'stream> {
-    pub parameters: HashMap<String, String>,
-    pub url_parameters: HashMap<String, String>,
+    pub(crate) parameters: PathParams,
+    pub(crate) client_ip: Option<IpAddr>,
+    pub(crate) flags: FeatureFlags,
+    pub(crate) request_id: String,
+    pub(crate) extensions: Extensions,
     pub(crate) req: RawRequest<'buf, 'header, 'stream>,
 }
 
@@ -30,35 +53,193 @@ impl<'buf, 'header, 'stream> Request<'buf, 'header, 'stream> {
         self.req.version()
     }
 
+    pub fn remote_addr(&self) -> Option<SocketAddr> {
+        self.req.remote_addr()
+    }
+
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.req.local_addr()
+    }
+
+    /// Tags the connection this request arrived on, e.g. with the tenant
+    /// or user middleware authenticated, so `ConnectionAdmin` can find it
+    /// and close it. Setting a key again replaces its value; tags last as
+    /// long as the connection.
+    pub fn tag_connection(&self, key: &str, value: &str) {
+        self.req.connection_tags().set(key, value);
+    }
+
+    /// The value of a tag this request or an earlier one on the same
+    /// connection set.
+    pub fn connection_tag(&self, key: &str) -> Option<String> {
+        self.req.connection_tags().get(key)
+    }
+
+    /// Sends an interim `1xx` response ahead of the final one, which the
+    /// handler still sets on its `Response`. `101` is refused, as switching
+    /// protocols isn't up to a handler. HTTP/1.0 clients, which don't
+    /// expect interim responses, and HTTP/2 streams are sent nothing.
+    pub fn send_interim(&mut self, status: StatusCode, headers: &[(&str, &str)]) -> io::Result<()> {
+        self.req.send_interim(status, headers)
+    }
+
+    /// Sends `103 Early Hints` with a `Link` header for each of `links`,
+    /// e.g. `</app.css>; rel=preload; as=style`, so browsers start fetching
+    /// them while the handler is still working on the page.
+    pub fn early_hints(&mut self, links: &[&str]) -> io::Result<()> {
+        let headers: Vec<_> = links.iter().map(|link| ("Link", *link)).collect();
+        self.send_interim(StatusCode::EARLY_HINTS, &headers)
+    }
+
+    /// The originating client: the peer address, or with trusted proxies
+    /// configured, the nearest untrusted hop they forwarded for.
+    pub fn client_ip(&self) -> Option<IpAddr> {
+        self.client_ip
+    }
+
+    /// Feature flags decided for this request by the server's provider.
+    pub fn flags(&self) -> &FeatureFlags {
+        &self.flags
+    }
+
+    /// The client's or proxy's `X-Request-Id`, or one generated for this
+    /// request; echoed in the response and written to the access log.
+    pub fn request_id(&self) -> &str {
+        &self.request_id
+    }
+
     pub fn headers(&self) -> &[httparse::Header<'_>] {
         self.req.headers()
     }
 
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.req.header(name)
+    }
+
+    /// Parses the body as JSON, refused unread past the server's
+    /// `json_max_depth` or `json_max_tokens`.
     pub fn json_body(self) -> Result<serde_json::Value, RequestError> {
-        let value: serde_json::Value = serde_json::from_reader(self.body())?;
-        Ok(value).map_err(|e| RequestError::JsonError(e))
+        let limits = self.req.json_limits;
+        let body = self.into_vec()?;
+        limits.check(&body)?;
+        Ok(serde_json::from_slice(&body)?)
     }
 
     pub fn body(self) -> BodyReader<'buf, 'stream> {
         self.req.body()
     }
 
+    /// Says about how big the body will be, e.g. from an upload's own
+    /// metadata, so reading it reserves memory once instead of growing
+    /// buffers as it arrives: up to `bytes` for `bytes` and `into_vec`,
+    /// past the 16 MiB a Content-Length alone gets, and up to 1 MiB per
+    /// read of the connection for `body`, e.g. while spooling to disk.
+    /// Reading more or less than that still works.
+    pub fn expect_body_size(&mut self, bytes: usize) -> &mut Self {
+        self.req.expected_body = Some(bytes);
+        self
+    }
+
+    /// Reads the whole body now and keeps it, so `body`, `bytes` and the
+    /// rest serve it from memory and the request stays usable meanwhile,
+    /// e.g. to send interim responses while the body is passed on.
+    pub fn buffer_body(&mut self) -> io::Result<&[u8]> {
+        self.req.prefetch_body()
+    }
+
+    /// Reads the whole body. Past the server's body limit this fails with
+    /// "Payload Too Large", and the client is answered 413.
+    pub fn bytes(self) -> io::Result<Bytes> {
+        self.into_vec().map(Bytes::from)
+    }
+
+    /// Like `bytes`, as a `Vec`.
+    pub fn into_vec(self) -> io::Result<Vec<u8>> {
+        let mut body = Vec::new();
+        self.body().read_to_end(&mut body)?;
+        Ok(body)
+    }
+
+    /// Reads the whole body as text, decoded by the Content-Type charset:
+    /// UTF-8 when none is given, US-ASCII or ISO-8859-1.
+    pub fn text(self) -> Result<String, RequestError> {
+        let charset = self
+            .content_type()
+            .and_then(|media| media.charset().map(str::to_ascii_lowercase));
+        let body = self.into_vec()?;
+        match charset.as_deref() {
+            None | Some("utf-8" | "utf8" | "us-ascii" | "ascii") => {
+                String::from_utf8(body).map_err(|e| RequestError::from(e.utf8_error()))
+            }
+            Some("iso-8859-1" | "latin1") => Ok(body.into_iter().map(char::from).collect()),
+            Some(other) => Err(RequestError::UnsupportedCharset(other.to_owned())),
+        }
+    }
+
     pub fn parameter(&self, name: &str) -> Option<&str> {
-        self.parameters.get(name).map(|s| s.as_str())
+        find(self.parameters(), name)
+    }
+
+    /// The route's path parameters and their values, in path order.
+    pub fn parameters(&self) -> impl Iterator<Item = (&str, &str)> + '_ {
+        let path = self.req.path();
+        self.parameters
+            .iter()
+            .map(move |(name, value)| (&**name, &path[value.clone()]))
+    }
+
+    /// The path parameters as the map the `parameters` field used to be,
+    /// built on each call.
+    pub fn parameter_map(&self) -> HashMap<String, String> {
+        to_map(self.parameters())
     }
 
     pub fn url_parameter(&self, name: &str) -> Option<&str> {
-        self.url_parameters.get(name).map(|s| s.as_str())
+        find(self.url_parameters(), name)
+    }
+
+    /// The query's parameters as sent, read from the target on each call.
+    pub fn url_parameters(&self) -> impl Iterator<Item = (&str, &str)> + '_ {
+        let path = self.req.path();
+        let query = path.find('?').map_or("", |start| &path[start..]);
+        query
+            .trim_start_matches('?')
+            .split('&')
+            .filter(|s| !s.is_empty())
+            .map(|s| {
+                let mut parts = s.split('=');
+                (parts.next().unwrap(), parts.next().unwrap_or(""))
+            })
+    }
+
+    /// The query parameters as the map the `url_parameters` field used to
+    /// be, built on each call. A repeated key keeps its last value.
+    pub fn url_parameter_map(&self) -> HashMap<String, String> {
+        to_map(self.url_parameters())
     }
 
+    /// Whether the client wants the connection kept open after this
+    /// exchange: HTTP/1.1 unless it sent `Connection: close`, HTTP/1.0
+    /// only with `Connection: keep-alive`.
     pub fn keep_alive(&self) -> bool {
-        return self.headers().iter().any(|header| {
-            header.name.eq_ignore_ascii_case("connection")
-                && std::str::from_utf8(header.value).ok() == Some("keep-alive")
-        });
+        self.req.keep_alive()
     }
 }
 
+// the value of the last `name`, as repeated keys override earlier ones
+fn find<'a>(params: impl Iterator<Item = (&'a str, &'a str)>, name: &str) -> Option<&'a str> {
+    params
+        .filter(|(key, _)| *key == name)
+        .last()
+        .map(|(_, value)| value)
+}
+
+fn to_map<'a>(params: impl Iterator<Item = (&'a str, &'a str)>) -> HashMap<String, String> {
+    params
+        .map(|(key, value)| (key.to_owned(), value.to_owned()))
+        .collect()
+}
+
 pub struct BodyReader<'buf, 'stream> {
     // remaining bytes for body
     req_buf: &'buf mut BytesMut,
@@ -67,47 +248,305 @@ pub struct BodyReader<'buf, 'stream> {
     // total read count
     total_read: usize,
     // used to read extra body bytes
-    stream: &'stream mut TcpStream,
+    stream: &'stream mut dyn Connection,
+    // per-exchange state shared with the connection loop
+    state: &'stream mut BodyState,
+    // decoding position within a chunked body, `None` for a sized one
+    chunk: Option<Chunk>,
+    // bound on each blocking read of the body
+    read_timeout: Option<Duration>,
+    // how big the handler expects the body to be
+    expected: Option<usize>,
+    // the whole body, when it was read before the handler ran
+    prefetched: Option<io::Cursor<Vec<u8>>>,
+}
+
+#[derive(Clone, Copy)]
+enum Chunk {
+    Size,
+    Data(usize),
+    DataEnd,
+    Trailers,
+    Done,
 }
 
 impl<'buf, 'stream> BodyReader<'buf, 'stream> {
     pub fn body_limit(&self) -> usize {
         self.body_limit
     }
+
+    /// Hashes the body with `hasher` as it's read, e.g. `Sha256::new()`
+    /// to store it by content without reading it twice.
+    pub fn digest<D: Digest>(self, hasher: D) -> DigestReader<'buf, 'stream, D> {
+        DigestReader {
+            inner: self,
+            hasher,
+            read: 0,
+        }
+    }
+}
+
+/// A `BodyReader` that keeps a running digest of what it has read.
+pub struct DigestReader<'buf, 'stream, D> {
+    inner: BodyReader<'buf, 'stream>,
+    hasher: D,
+    read: u64,
+}
+
+impl<'buf, 'stream, D: Digest + Clone> DigestReader<'buf, 'stream, D> {
+    /// The digest of the body read so far; the whole body's once reads
+    /// return 0.
+    pub fn digest(&self) -> Vec<u8> {
+        self.hasher.clone().finalize().to_vec()
+    }
+
+    /// How many body bytes went into the digest.
+    pub fn bytes_read(&self) -> u64 {
+        self.read
+    }
+}
+
+impl<'buf, 'stream, D: Digest> Read for DigestReader<'buf, 'stream, D> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let n = self.inner.read(buf)?;
+        self.hasher.update(&buf[..n]);
+        self.read += n as u64;
+        Ok(n)
+    }
+
+    // keep the body reader's direct read, hashing what it appended
+    fn read_to_end(&mut self, buf: &mut Vec<u8>) -> io::Result<usize> {
+        let start = buf.len();
+        let result = self.inner.read_to_end(buf);
+        self.hasher.update(&buf[start..]);
+        self.read += (buf.len() - start) as u64;
+        result
+    }
+}
+
+impl<'buf, 'stream, D> fmt::Debug for DigestReader<'buf, 'stream, D> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<HTTP DigestReader>")
+    }
+}
+
+impl<'buf, 'stream> BodyReader<'buf, 'stream> {
+    // pull more bytes from the stream into req_buf, blocking the coroutine
+    fn fill(&mut self) -> io::Result<()> {
+        match self.expected {
+            Some(expected) => {
+                let left = expected.saturating_sub(self.total_read);
+                let room = left.clamp(BODY_READ_STEP, HINTED_FILL_MAX);
+                crate::http::http_server::reserve_to(self.req_buf, room)
+            }
+            None => crate::http::http_server::reserve_buf(self.req_buf),
+        }
+        self.read_stream(|stream, req_buf| crate::http::http_server::read_into(stream, req_buf))?;
+        Ok(())
+    }
+
+    // blocking read of more body bytes; `read` does the actual read, into
+    // req_buf or straight into the caller's buffer
+    fn read_stream<F>(&mut self, read: F) -> io::Result<usize>
+    where
+        F: FnOnce(&mut dyn Connection, &mut BytesMut) -> io::Result<usize>,
+    {
+        // the client is waiting for permission before sending the body
+        if self.state.expect_continue {
+            write_pending(self.stream, self.state)?;
+            self.stream.write_all(b"HTTP/1.1 100 Continue\r\n\r\n")?;
+            self.state.expect_continue = false;
+        }
+
+        // perform block read from the stream, counted once it's handed out
+        self.stream.set_read_timeout(self.read_timeout)?;
+        match read(self.stream, self.req_buf) {
+            Ok(0) => Err(io::Error::new(
+                io::ErrorKind::UnexpectedEof,
+                "connection closed before end of body",
+            )),
+            Ok(n) => Ok(n),
+            Err(e) if self.read_timeout.is_some() && is_timeout(&e) => {
+                Err(self.fail(408, "Request Timeout"))
+            }
+            Err(e) => Err(e),
+        }
+    }
+
+    fn consumed(&mut self, n: usize) {
+        self.total_read += n;
+        self.state.unread = self.state.unread.saturating_sub(n);
+    }
+
+    // record the status the connection loop answers with instead of the
+    // handler's response
+    pub(crate) fn fail(&mut self, code: usize, msg: &'static str) -> io::Error {
+        self.state.error = Some((code, msg));
+        io::Error::new(io::ErrorKind::InvalidData, msg)
+    }
+
+    // step through the framing to the next body bytes, reading until some
+    // are buffered; how many at the front of req_buf are body, 0 at its end
+    fn buffered_body(&mut self) -> io::Result<usize> {
+        if self.chunk.is_none() {
+            if self.total_read >= self.body_limit {
+                self.state.expect_continue = false;
+                return Ok(0);
+            }
+            while self.req_buf.is_empty() {
+                self.fill()?;
+            }
+            return Ok(self.req_buf.len().min(self.body_limit - self.total_read));
+        }
+        loop {
+            match self.chunk {
+                Some(Chunk::Size) => match httparse::parse_chunk_size(self.req_buf.chunk()) {
+                    Ok(httparse::Status::Complete((used, 0))) => {
+                        self.req_buf.advance(used);
+                        self.chunk = Some(Chunk::Trailers);
+                    }
+                    Ok(httparse::Status::Complete((used, size))) => {
+                        self.req_buf.advance(used);
+                        if size > (self.body_limit - self.total_read) as u64 {
+                            return Err(self.fail(413, "Payload Too Large"));
+                        }
+                        self.chunk = Some(Chunk::Data(size as usize));
+                    }
+                    Ok(httparse::Status::Partial) if self.req_buf.len() < MAX_CHUNK_LINE => {
+                        self.fill()?
+                    }
+                    _ => return Err(self.fail(400, "Bad Request")),
+                },
+                Some(Chunk::Data(remaining)) => {
+                    if self.req_buf.is_empty() {
+                        self.fill()?;
+                        continue;
+                    }
+                    return Ok(remaining.min(self.req_buf.len()));
+                }
+                Some(Chunk::DataEnd) => {
+                    if self.req_buf.len() < 2 {
+                        self.fill()?;
+                        continue;
+                    }
+                    if &self.req_buf[..2] != b"\r\n" {
+                        return Err(self.fail(400, "Bad Request"));
+                    }
+                    self.req_buf.advance(2);
+                    self.chunk = Some(Chunk::Size);
+                }
+                // trailer fields are skipped, but still count against the limit
+                Some(Chunk::Trailers) => match self.req_buf.windows(2).position(|w| w == b"\r\n") {
+                    Some(0) => {
+                        self.req_buf.advance(2);
+                        self.chunk = Some(Chunk::Done);
+                        self.state.expect_continue = false;
+                        self.state.chunked_open = false;
+                    }
+                    Some(line) => {
+                        if line > self.body_limit - self.total_read {
+                            return Err(self.fail(413, "Payload Too Large"));
+                        }
+                        self.total_read += line;
+                        self.req_buf.advance(line + 2);
+                    }
+                    None if self.req_buf.len() < MAX_CHUNK_LINE => self.fill()?,
+                    None => return Err(self.fail(400, "Bad Request")),
+                },
+                Some(Chunk::Done) | None => return Ok(0),
+            }
+        }
+    }
+
+    // hand out the first `n` of the bytes `buffered_body` found
+    fn advance_body(&mut self, n: usize) {
+        self.req_buf.advance(n);
+        self.consumed(n);
+        if let Some(Chunk::Data(remaining)) = self.chunk {
+            self.chunk = Some(match remaining - n {
+                0 => Chunk::DataEnd,
+                left => Chunk::Data(left),
+            });
+        }
+    }
 }
 
 impl<'buf, 'stream> Read for BodyReader<'buf, 'stream> {
     // the user should control the body reading, don't exceeds the body!
     fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
-        if self.total_read >= self.body_limit {
-            return Ok(0);
+        if let Some(body) = &mut self.prefetched {
+            return body.read(buf);
         }
+        let n = self.buffered_body()?.min(buf.len());
+        buf[..n].copy_from_slice(&self.req_buf[..n]);
+        self.advance_body(n);
+        Ok(n)
+    }
 
-        loop {
-            if !self.req_buf.is_empty() {
-                let min_len = buf.len().min(self.body_limit - self.total_read);
-                let n = self.req_buf.reader().read(&mut buf[..min_len])?;
-                self.total_read += n;
-                return Ok(n);
+    // with the length known, reserve once and read the rest of the body
+    // straight from the socket into `buf`, skipping the copy through req_buf
+    fn read_to_end(&mut self, buf: &mut Vec<u8>) -> io::Result<usize> {
+        if let Some(body) = &mut self.prefetched {
+            return body.read_to_end(buf);
+        }
+        let start = buf.len();
+        if self.chunk.is_some() {
+            if let Some(expected) = self.expected {
+                buf.reserve(expected.min(self.body_limit - self.total_read));
             }
+            let mut chunk = [0u8; 8192];
+            loop {
+                match self.read(&mut chunk)? {
+                    0 => return Ok(buf.len() - start),
+                    n => buf.extend_from_slice(&chunk[..n]),
+                }
+            }
+        }
 
-            crate::http::http_server::reserve_buf(self.req_buf);
-            let read_buf: &mut [u8] = unsafe { std::mem::transmute(self.req_buf.chunk_mut()) };
-            // perform block read from the stream
-            let n = self.stream.read(read_buf)?;
-            self.total_read += n;
-            unsafe { self.req_buf.advance_mut(n) };
+        let remaining = self.body_limit - self.total_read;
+        // a huge Content-Length alone shouldn't commit that much memory
+        buf.reserve(remaining.min(self.expected.unwrap_or(MAX_BODY_PREALLOC)));
+        let buffered = remaining.min(self.req_buf.len());
+        buf.extend_from_slice(&self.req_buf[..buffered]);
+        self.req_buf.advance(buffered);
+        self.consumed(buffered);
+
+        let target = start + remaining;
+        let mut filled = buf.len();
+        while filled < target {
+            let end = target.min(filled + BODY_READ_STEP);
+            buf.resize(end, 0);
+            match self.read_stream(|stream, _| stream.read(&mut buf[filled..end])) {
+                Ok(n) => {
+                    filled += n;
+                    self.consumed(n);
+                }
+                Err(e) => {
+                    buf.truncate(filled);
+                    return Err(e);
+                }
+            }
         }
+        buf.truncate(filled);
+        self.state.expect_continue = false;
+        Ok(filled - start)
     }
 }
 
 impl<'buf, 'stream> BufRead for BodyReader<'buf, 'stream> {
     fn fill_buf(&mut self) -> io::Result<&[u8]> {
-        Ok(self.req_buf.chunk())
+        if self.prefetched.is_none() {
+            let n = self.buffered_body()?;
+            return Ok(&self.req_buf[..n]);
+        }
+        self.prefetched.as_mut().unwrap().fill_buf()
     }
 
     fn consume(&mut self, amt: usize) {
-        self.req_buf.advance(amt)
+        match &mut self.prefetched {
+            Some(body) => body.consume(amt),
+            None => self.advance_body(amt),
+        }
     }
 }
 
@@ -116,10 +555,51 @@ impl<'buf, 'stream> fmt::Debug for BodyReader<'buf, 'stream> {
         write!(f, "<HTTP BodyReader>")
     }
 }
+#[derive(Default)]
+pub struct BodyState {
+    // `Expect: 100-continue` was received and no interim response sent yet
+    pub(crate) expect_continue: bool,
+    // the body was refused unread, so the connection can't be reused
+    pub(crate) body_rejected: bool,
+    // the body turned out malformed or oversized while being read
+    pub(crate) error: Option<(usize, &'static str)>,
+    // bytes of a sized body the handler hasn't read
+    pub(crate) unread: usize,
+    // a chunked body hasn't been read to its end
+    pub(crate) chunked_open: bool,
+    // responses to earlier pipelined requests, not yet written
+    pub(crate) pending: BytesMut,
+}
+
+// how the end of the request body is found
+// the addresses of the connection a request arrived on, and its tags
+#[derive(Clone, Default)]
+pub struct Endpoints {
+    pub(crate) remote: Option<SocketAddr>,
+    pub(crate) local: Option<SocketAddr>,
+    pub(crate) tags: ConnectionTags,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) enum Framing {
+    Length(usize),
+    Chunked,
+}
+
 pub struct RawRequest<'buf, 'header, 'stream> {
     req: httparse::Request<'header, 'buf>,
+    // the bytes `req` points into
+    _head: BytesMut,
     req_buf: &'buf mut BytesMut,
-    stream: &'stream mut TcpStream,
+    stream: &'stream mut dyn Connection,
+    state: &'stream mut BodyState,
+    max_body_size: usize,
+    read_timeout: Option<Duration>,
+    pub(crate) json_limits: JsonLimits,
+    // the handler's hint of the body's size
+    expected_body: Option<usize>,
+    endpoints: Endpoints,
+    prefetched: Option<Vec<u8>>,
 }
 
 impl<'buf, 'header, 'stream> RawRequest<'buf, 'header, 'stream> {
@@ -135,8 +615,24 @@ impl<'buf, 'header, 'stream> RawRequest<'buf, 'header, 'stream> {
         self.req.version.unwrap()
     }
 
+    /// The client's address; with the PROXY protocol enabled, the one the
+    /// proxy reported rather than the proxy's own.
+    pub fn remote_addr(&self) -> Option<SocketAddr> {
+        self.endpoints.remote
+    }
+
+    /// The address the connection was accepted on.
+    pub fn local_addr(&self) -> Option<SocketAddr> {
+        self.endpoints.local
+    }
+
+    pub(crate) fn connection_tags(&self) -> &ConnectionTags {
+        &self.endpoints.tags
+    }
+
     pub fn json_body(&self) -> Result<serde_json::Value, RequestError> {
         let body_slice = self.req_buf.as_ref();
+        self.json_limits.check(body_slice)?;
         let reader = std::io::Cursor::new(body_slice);
 
         serde_json::from_reader(reader).map_err(RequestError::from)
@@ -146,25 +642,245 @@ impl<'buf, 'header, 'stream> RawRequest<'buf, 'header, 'stream> {
         self.req.headers
     }
 
+    /// The first `name` header, if present and valid UTF-8.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.req
+            .headers
+            .iter()
+            .find(|h| h.name.eq_ignore_ascii_case(name))
+            .and_then(|h| std::str::from_utf8(h.value).ok())
+    }
+
     pub fn body(self) -> BodyReader<'buf, 'stream> {
+        let (body_limit, chunk) = self.body_framing();
         BodyReader {
-            body_limit: self.content_length(),
+            body_limit,
             total_read: 0,
             stream: self.stream,
             req_buf: self.req_buf,
+            state: self.state,
+            chunk,
+            read_timeout: self.read_timeout,
+            expected: self.expected_body,
+            prefetched: self.prefetched.map(io::Cursor::new),
+        }
+    }
+
+    // read the whole body now, e.g. to validate it; `body` then serves it
+    // from memory
+    pub(crate) fn prefetch_body(&mut self) -> io::Result<&[u8]> {
+        if self.prefetched.is_none() {
+            let mut body = Vec::new();
+            self.prefetch_reader().read_to_end(&mut body)?;
+            self.prefetched = Some(body);
+        }
+        Ok(self.prefetched.as_deref().unwrap_or_default())
+    }
+
+    // like `prefetch_body`, hashing the body with `D` as it comes in
+    pub(crate) fn prefetch_body_digest<D: Digest + Clone>(
+        &mut self,
+    ) -> io::Result<(&[u8], Vec<u8>)> {
+        let digest = match &self.prefetched {
+            Some(body) => D::digest(body).to_vec(),
+            None => {
+                let mut reader = self.prefetch_reader().digest(D::new());
+                let mut body = Vec::new();
+                reader.read_to_end(&mut body)?;
+                let digest = reader.digest();
+                self.prefetched = Some(body);
+                digest
+            }
+        };
+        Ok((self.prefetched.as_deref().unwrap_or_default(), digest))
+    }
+
+    fn prefetch_reader(&mut self) -> BodyReader<'_, '_> {
+        let (body_limit, chunk) = self.body_framing();
+        BodyReader {
+            body_limit,
+            total_read: 0,
+            stream: &mut *self.stream,
+            req_buf: &mut *self.req_buf,
+            state: &mut *self.state,
+            chunk,
+            read_timeout: self.read_timeout,
+            expected: self.expected_body,
+            prefetched: None,
+        }
+    }
+
+    fn body_framing(&self) -> (usize, Option<Chunk>) {
+        // without Content-Length a request has no body (RFC 7230 3.3.3)
+        match self.framing() {
+            Ok(Framing::Chunked) => (self.max_body_size, Some(Chunk::Size)),
+            Ok(Framing::Length(len)) => (len.min(self.max_body_size), None),
+            Err(_) => (0, None),
         }
     }
 
-    fn content_length(&self) -> usize {
-        let mut len = usize::MAX;
+    pub fn expects_continue(&self) -> bool {
+        self.state.expect_continue
+    }
+
+    // hold on to responses queued ahead of this request, written out
+    // before any interim response so it can't overtake them
+    pub(crate) fn hold_pending(&mut self, res_buf: &mut BytesMut) {
+        self.state.pending = res_buf.split();
+    }
+
+    pub fn send_interim(&mut self, status: StatusCode, headers: &[(&str, &str)]) -> io::Result<()> {
+        let code = status.as_u16();
+        if !(100..200).contains(&code) || code == 101 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("{} is not an interim status", status),
+            ));
+        }
+        if let Some((name, _)) = headers
+            .iter()
+            .find(|(name, value)| !valid_header(name, value))
+        {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid header: {}", name),
+            ));
+        }
+        if self.version() == 0 {
+            return Ok(());
+        }
+        let mut head = format!("HTTP/1.1 {} {}\r\n", code, status.reason());
+        for (name, value) in headers {
+            head.push_str(&format!("{}: {}\r\n", name, value.trim()));
+        }
+        head.push_str("\r\n");
+        write_pending(self.stream, self.state)?;
+        self.stream.write_all(head.as_bytes())?;
+        self.stream.flush()
+    }
+
+    pub(crate) fn keep_alive(&self) -> bool {
+        if self.connection_has("close") {
+            return false;
+        }
+        self.version() == 1 || self.connection_has("keep-alive")
+    }
+
+    // whether any Connection header lists `token`
+    fn connection_has(&self, token: &str) -> bool {
+        self.req
+            .headers
+            .iter()
+            .filter(|header| header.name.eq_ignore_ascii_case("connection"))
+            .flat_map(|header| header.value.split(|&b| b == b','))
+            .any(|value| trim(value).eq_ignore_ascii_case(token.as_bytes()))
+    }
+
+    pub(crate) fn content_length(&self) -> Option<usize> {
+        match self.framing() {
+            Ok(Framing::Length(len)) => Some(len),
+            _ => None,
+        }
+    }
+
+    // work out the body framing, refusing anything two parsers could read
+    // differently (RFC 7230 3.3.3)
+    pub(crate) fn framing(&self) -> Result<Framing, (usize, &'static str)> {
+        let mut length = None;
+        let mut encoding = None;
         for header in self.req.headers.iter() {
             if header.name.eq_ignore_ascii_case("content-length") {
-                len = std::str::from_utf8(header.value).unwrap().parse().unwrap();
-                break;
+                // even a repeated identical length is rejected
+                if length.is_some() {
+                    return Err(BAD_REQUEST);
+                }
+                length = Some(parse_content_length(header.value).ok_or(BAD_REQUEST)?);
+            } else if header.name.eq_ignore_ascii_case("transfer-encoding") {
+                if encoding.is_some() {
+                    return Err(BAD_REQUEST);
+                }
+                encoding = Some(header.value);
+            }
+        }
+        match (length, encoding) {
+            (None, None) => Ok(Framing::Length(0)),
+            (Some(len), None) => Ok(Framing::Length(len)),
+            (Some(_), Some(_)) => Err(BAD_REQUEST),
+            // HTTP/1.0 has no chunked encoding
+            (None, Some(_)) if self.version() == 0 => Err(BAD_REQUEST),
+            (None, Some(value)) if trim(value).eq_ignore_ascii_case(b"chunked") => {
+                Ok(Framing::Chunked)
+            }
+            (None, Some(_)) => Err((501, "Not Implemented")),
+        }
+    }
+
+    // remember how much body follows the head, so whatever the handler
+    // leaves unread can be skipped before the next request
+    pub(crate) fn track_body(&mut self, framing: Framing) {
+        match framing {
+            Framing::Length(len) => self.state.unread = len,
+            Framing::Chunked => self.state.chunked_open = true,
+        }
+    }
+
+    pub(crate) fn set_max_body_size(&mut self, limit: usize) {
+        self.max_body_size = limit;
+    }
+
+    // answer without reading the body; the connection closes afterwards
+    pub(crate) fn reject_body(&mut self) {
+        self.state.body_rejected = true;
+    }
+}
+
+fn parse_content_length(value: &[u8]) -> Option<usize> {
+    let value = trim(value);
+    if value.is_empty() || !value.iter().all(u8::is_ascii_digit) {
+        return None;
+    }
+    std::str::from_utf8(value).ok()?.parse().ok()
+}
+
+fn trim(value: &[u8]) -> &[u8] {
+    let start = value
+        .iter()
+        .position(|b| !b.is_ascii_whitespace())
+        .unwrap_or(value.len());
+    let end = value
+        .iter()
+        .rposition(|b| !b.is_ascii_whitespace())
+        .map_or(start, |i| i + 1);
+    &value[start..end]
+}
+
+// a query string component, with `+` as space
+pub(crate) fn percent_decode(s: &str) -> String {
+    let bytes = s.as_bytes();
+    let mut out = Vec::with_capacity(bytes.len());
+    let mut i = 0;
+    while i < bytes.len() {
+        let hex = bytes
+            .get(i + 1..i + 3)
+            .filter(|hex| hex.iter().all(u8::is_ascii_hexdigit))
+            .and_then(|hex| std::str::from_utf8(hex).ok())
+            .and_then(|hex| u8::from_str_radix(hex, 16).ok());
+        match (bytes[i], hex) {
+            (b'%', Some(byte)) => {
+                out.push(byte);
+                i += 3;
+            }
+            (b'+', _) => {
+                out.push(b' ');
+                i += 1;
+            }
+            (byte, _) => {
+                out.push(byte);
+                i += 1;
             }
         }
-        len
     }
+    String::from_utf8_lossy(&out).into_owned()
 }
 
 impl<'buf, 'header, 'stream> fmt::Debug for RawRequest<'buf, 'header, 'stream> {
@@ -173,33 +889,471 @@ impl<'buf, 'header, 'stream> fmt::Debug for RawRequest<'buf, 'header, 'stream> {
     }
 }
 
+pub enum DecodeError {
+    // the head has more headers than the slots handed in; retry with more
+    Grow,
+    // a head limit was exceeded, answer with this status and close
+    Reject(usize, &'static str),
+    Io(io::Error),
+}
+
+impl From<io::Error> for DecodeError {
+    fn from(e: io::Error) -> Self {
+        DecodeError::Io(e)
+    }
+}
+
+const HEADERS_TOO_LARGE: DecodeError = DecodeError::Reject(431, "Request Header Fields Too Large");
+const URI_TOO_LONG: DecodeError = DecodeError::Reject(414, "URI Too Long");
+const MALFORMED: DecodeError = DecodeError::Reject(BAD_REQUEST.0, BAD_REQUEST.1);
+
+fn write_pending(stream: &mut dyn Connection, state: &mut BodyState) -> io::Result<()> {
+    if !state.pending.is_empty() {
+        stream.write_all(&state.pending)?;
+        state.pending.clear();
+    }
+    Ok(())
+}
+
 pub fn decode<'header, 'buf, 'stream>(
-    headers: &'header mut [MaybeUninit<httparse::Header<'buf>>; MAX_HEADERS],
+    headers: &'header mut [MaybeUninit<httparse::Header<'buf>>],
     req_buf: &'buf mut BytesMut,
-    stream: &'stream mut TcpStream,
-) -> io::Result<Option<RawRequest<'buf, 'header, 'stream>>> {
+    stream: &'stream mut dyn Connection,
+    state: &'stream mut BodyState,
+    config: &ServerConfig,
+    endpoints: Endpoints,
+) -> Result<Option<RawRequest<'buf, 'header, 'stream>>, DecodeError> {
     let mut req = httparse::Request::new(&mut []);
-    // safety: don't hold the reference of req_buf
-    // so we can transfer the mutable reference to Request
+    // safety: don't hold the reference of req_buf so we can transfer the
+    // mutable reference to Request. once parsed, the head is split off into
+    // `RawRequest::head`, which keeps those bytes alive and out of reach of
+    // `req_buf`, whose reserve could otherwise reuse them while body reads
+    // grow it
     let buf: &[u8] = unsafe { std::mem::transmute(req_buf.chunk()) };
+    let line_len = buf.iter().position(|&b| b == b'\n');
+    if line_len.unwrap_or(buf.len()) > config.max_request_line {
+        return Err(URI_TOO_LONG);
+    }
+    let slots = headers.len();
     let status = match req.parse_with_uninit_headers(buf, headers) {
         Ok(s) => s,
+        Err(httparse::Error::TooManyHeaders) if slots < config.max_headers => {
+            return Err(DecodeError::Grow)
+        }
+        Err(httparse::Error::TooManyHeaders) => return Err(HEADERS_TOO_LARGE),
+        Err(e) if config.strict_requests => {
+            debug!("rejecting malformed request: {e:?}");
+            return Err(MALFORMED);
+        }
         Err(e) => {
-            eprintln!("failed to parse http request: {e:?}");
+            debug!("failed to parse http request: {e:?}");
             let msg = format!("failed to parse http request: {e:?}");
-            return Err(io::Error::new(io::ErrorKind::Other, msg));
+            return Err(io::Error::new(io::ErrorKind::Other, msg).into());
         }
     };
 
     let len = match status {
         httparse::Status::Complete(amt) => amt,
+        httparse::Status::Partial if buf.len() > config.max_header_bytes => {
+            return Err(HEADERS_TOO_LARGE)
+        }
         httparse::Status::Partial => return Ok(None),
     };
-    req_buf.advance(len);
+    if len > config.max_header_bytes || req.headers.len() > config.max_headers {
+        return Err(HEADERS_TOO_LARGE);
+    }
+    if config.strict_requests {
+        if let Some(reason) = strict_violation(&req, &buf[..len]) {
+            debug!("rejecting request: {}", reason);
+            return Err(MALFORMED);
+        }
+    }
+    let head = req_buf.split_to(len);
+
+    state.expect_continue = req.version == Some(1)
+        && req.headers.iter().any(|header| {
+            header.name.eq_ignore_ascii_case("expect")
+                && header.value.eq_ignore_ascii_case(b"100-continue")
+        });
 
     Ok(Some(RawRequest {
         req,
+        _head: head,
         req_buf,
         stream,
+        state,
+        max_body_size: usize::MAX,
+        read_timeout: config.body_read_timeout,
+        json_limits: config.json_limits,
+        expected_body: None,
+        endpoints,
+        prefetched: None,
     }))
 }
+
+// what strict mode refuses that httparse may let through, depending on its
+// version: the parsed fields are checked, and the raw head for what
+// parsing hides
+fn strict_violation(req: &httparse::Request, head: &[u8]) -> Option<&'static str> {
+    let method = req.method.unwrap_or_default();
+    if method.is_empty() || !method.bytes().all(is_tchar) {
+        return Some("method is not a token");
+    }
+    let target = req.path.unwrap_or_default();
+    let asterisk = target == "*" && method == "OPTIONS";
+    if !target.starts_with('/') && !asterisk {
+        return Some("request target is not in origin form");
+    }
+    let controls = |b: &u8| (*b < 0x20 && *b != b'\t') || *b == 0x7f;
+    if req.headers.iter().any(|h| h.value.iter().any(controls)) {
+        return Some("control character in a header value");
+    }
+    // the request line first, then a header per line up to the blank one
+    for line in head.split(|&b| b == b'\n').skip(1) {
+        let line = line.strip_suffix(b"\r").unwrap_or(line);
+        match line.first() {
+            None => break,
+            Some(b' ' | b'\t') => return Some("obsolete line folding"),
+            Some(_) => {}
+        }
+        let name = line.split(|&b| b == b':').next().unwrap_or(line);
+        if name.iter().any(|b| b.is_ascii_whitespace()) {
+            return Some("whitespace before a header's colon");
+        }
+    }
+    None
+}
+
+// RFC 9110 5.6.2
+fn is_tchar(b: u8) -> bool {
+    b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // a client that sends `data` at most `step` bytes per read; nothing
+    // here touches a socket, so these run under Miri
+    struct Trickle {
+        data: Vec<u8>,
+        at: usize,
+        step: usize,
+    }
+
+    impl Trickle {
+        fn new(data: &[u8], step: usize) -> Self {
+            Trickle {
+                data: data.to_vec(),
+                at: 0,
+                step,
+            }
+        }
+
+        fn unsent(&self) -> &[u8] {
+            &self.data[self.at..]
+        }
+    }
+
+    impl Read for Trickle {
+        fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+            let n = self.step.min(buf.len()).min(self.data.len() - self.at);
+            buf[..n].copy_from_slice(&self.data[self.at..self.at + n]);
+            self.at += n;
+            Ok(n)
+        }
+    }
+
+    impl io::Write for Trickle {
+        fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+            Ok(buf.len())
+        }
+
+        fn flush(&mut self) -> io::Result<()> {
+            Ok(())
+        }
+    }
+
+    impl Connection for Trickle {}
+
+    // the path, header count and what's left buffered after decoding `sent`
+    // with `slots` header slots
+    fn outcome(
+        sent: &str,
+        slots: usize,
+        config: &ServerConfig,
+    ) -> Result<Option<(String, usize, Vec<u8>)>, DecodeError> {
+        let mut headers = vec![MaybeUninit::uninit(); slots];
+        let mut req_buf = BytesMut::from(sent);
+        let mut stream = Trickle::new(b"", 1);
+        let mut state = BodyState::default();
+        let endpoints = Endpoints::default();
+        let req = decode(
+            &mut headers,
+            &mut req_buf,
+            &mut stream,
+            &mut state,
+            config,
+            endpoints,
+        )?;
+        let parsed = req.map(|req| (req.path().to_owned(), req.headers().len()));
+        Ok(parsed.map(|(path, count)| (path, count, req_buf.to_vec())))
+    }
+
+    fn rejected_with(outcome: Result<Option<(String, usize, Vec<u8>)>, DecodeError>) -> usize {
+        match outcome {
+            Err(DecodeError::Reject(code, _)) => code,
+            _ => panic!("request was not rejected"),
+        }
+    }
+
+    #[test]
+    fn partial_head_waits_for_more() {
+        let config = ServerConfig::default();
+        let sent = "GET /a HTTP/1.1\r\nHost: x\r\n";
+        assert!(matches!(outcome(sent, MAX_HEADERS, &config), Ok(None)));
+        assert!(matches!(
+            outcome("GET /a HT", MAX_HEADERS, &config),
+            Ok(None)
+        ));
+    }
+
+    #[test]
+    fn head_is_split_off_the_buffer() {
+        let config = ServerConfig::default();
+        let sent = "GET /a HTTP/1.1\r\nHost: x\r\n\r\nGET /b";
+        let (path, headers, rest) = match outcome(sent, MAX_HEADERS, &config) {
+            Ok(Some(parsed)) => parsed,
+            _ => panic!("request did not decode"),
+        };
+        assert_eq!(path, "/a");
+        assert_eq!(headers, 1);
+        assert_eq!(rest, b"GET /b");
+    }
+
+    #[test]
+    fn long_request_line_is_414() {
+        let mut config = ServerConfig::default();
+        config.max_request_line = 16;
+        let sent = "GET /abcdefghijklmnop HTTP/1.1\r\n\r\n";
+        assert_eq!(rejected_with(outcome(sent, MAX_HEADERS, &config)), 414);
+        // refused before the line is even complete
+        let sent = "GET /abcdefghijklmnop";
+        assert_eq!(rejected_with(outcome(sent, MAX_HEADERS, &config)), 414);
+    }
+
+    #[test]
+    fn large_head_is_431() {
+        let mut config = ServerConfig::default();
+        config.max_header_bytes = 48;
+        let sent = "GET / HTTP/1.1\r\nX-Long: 0123456789abcdef0123456789\r\n\r\n";
+        assert_eq!(rejected_with(outcome(sent, MAX_HEADERS, &config)), 431);
+        let sent = "GET / HTTP/1.1\r\nX-Long: 0123456789abcdef0123456789abcdef";
+        assert_eq!(rejected_with(outcome(sent, MAX_HEADERS, &config)), 431);
+    }
+
+    #[test]
+    fn more_headers_than_slots_asks_to_grow_up_to_the_limit() {
+        let mut config = ServerConfig::default();
+        let sent = "GET / HTTP/1.1\r\nA: 1\r\nB: 2\r\nC: 3\r\n\r\n";
+        assert!(matches!(outcome(sent, 2, &config), Err(DecodeError::Grow)));
+        assert!(matches!(outcome(sent, 3, &config), Ok(Some((_, 3, _)))));
+        config.max_headers = 2;
+        assert_eq!(rejected_with(outcome(sent, 2, &config)), 431);
+    }
+
+    #[test]
+    fn sized_body_is_read_past_the_buffer() {
+        let config = ServerConfig::default();
+        let mut headers = [MaybeUninit::uninit(); MAX_HEADERS];
+        let mut req_buf = BytesMut::from("POST / HTTP/1.1\r\nContent-Length: 11\r\n\r\nhel");
+        let mut stream = Trickle::new(b"lo worldGET /b", 3);
+        let mut state = BodyState::default();
+        let endpoints = Endpoints::default();
+        let req = decode(
+            &mut headers,
+            &mut req_buf,
+            &mut stream,
+            &mut state,
+            &config,
+            endpoints,
+        );
+        let mut req = match req {
+            Ok(Some(req)) => req,
+            _ => panic!("request did not decode"),
+        };
+        req.track_body(req.framing().unwrap());
+        let mut body = String::new();
+        req.body().read_to_string(&mut body).unwrap();
+        assert_eq!(body, "hello world");
+        assert_eq!(state.unread, 0);
+        // what came in after the body is left for the next request
+        let mut next = req_buf.to_vec();
+        next.extend_from_slice(stream.unsent());
+        assert_eq!(next, b"GET /b");
+    }
+
+    #[test]
+    fn sized_body_cut_short_is_an_error() {
+        let config = ServerConfig::default();
+        let mut headers = [MaybeUninit::uninit(); MAX_HEADERS];
+        let mut req_buf = BytesMut::from("POST / HTTP/1.1\r\nContent-Length: 10\r\n\r\nabc");
+        let mut stream = Trickle::new(b"de", 1);
+        let mut state = BodyState::default();
+        let endpoints = Endpoints::default();
+        let req = decode(
+            &mut headers,
+            &mut req_buf,
+            &mut stream,
+            &mut state,
+            &config,
+            endpoints,
+        );
+        let req = match req {
+            Ok(Some(req)) => req,
+            _ => panic!("request did not decode"),
+        };
+        let err = req.body().read_to_end(&mut Vec::new()).unwrap_err();
+        assert_eq!(err.kind(), io::ErrorKind::UnexpectedEof);
+    }
+
+    #[test]
+    fn chunked_body_is_decoded_across_reads() {
+        let config = ServerConfig::default();
+        let mut headers = [MaybeUninit::uninit(); MAX_HEADERS];
+        let sent = "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n4\r\nWi";
+        let mut req_buf = BytesMut::from(sent);
+        // byte by byte, so every chunk line, CRLF and trailer is split
+        let rest = b"ki\r\n5\r\npedia\r\n0\r\nX-Trailer: 1\r\n\r\nGET /b";
+        let mut stream = Trickle::new(rest, 1);
+        let mut state = BodyState::default();
+        let endpoints = Endpoints::default();
+        let req = decode(
+            &mut headers,
+            &mut req_buf,
+            &mut stream,
+            &mut state,
+            &config,
+            endpoints,
+        );
+        let mut req = match req {
+            Ok(Some(req)) => req,
+            _ => panic!("request did not decode"),
+        };
+        req.track_body(req.framing().unwrap());
+        let mut body = String::new();
+        req.body().read_to_string(&mut body).unwrap();
+        assert_eq!(body, "Wikipedia");
+        assert!(!state.chunked_open);
+        let mut next = req_buf.to_vec();
+        next.extend_from_slice(stream.unsent());
+        assert_eq!(next, b"GET /b");
+    }
+
+    #[test]
+    fn read_line_stops_at_the_end_of_a_pipelined_body() {
+        let config = ServerConfig::default();
+        let mut headers = [MaybeUninit::uninit(); MAX_HEADERS];
+        let sent = "POST / HTTP/1.1\r\nContent-Length: 13\r\n\r\nfirst\nsec";
+        let mut req_buf = BytesMut::from(sent);
+        // the next request arrives right behind the body
+        let mut stream = Trickle::new(b"ond\nGET /b HTTP/1.1\r\n\r\n", 4);
+        let mut state = BodyState::default();
+        let endpoints = Endpoints::default();
+        let req = decode(
+            &mut headers,
+            &mut req_buf,
+            &mut stream,
+            &mut state,
+            &config,
+            endpoints,
+        );
+        let mut req = match req {
+            Ok(Some(req)) => req,
+            _ => panic!("request did not decode"),
+        };
+        req.track_body(req.framing().unwrap());
+        let mut body = req.body();
+        let mut lines = Vec::new();
+        let mut line = String::new();
+        while body.read_line(&mut line).unwrap() > 0 {
+            lines.push(std::mem::take(&mut line));
+        }
+        assert_eq!(lines, ["first\n", "second\n"]);
+        drop(body);
+        assert_eq!(state.unread, 0);
+        let mut next = req_buf.to_vec();
+        next.extend_from_slice(stream.unsent());
+        assert_eq!(next, b"GET /b HTTP/1.1\r\n\r\n");
+    }
+
+    #[test]
+    fn read_line_follows_chunk_boundaries() {
+        let config = ServerConfig::default();
+        let mut headers = [MaybeUninit::uninit(); MAX_HEADERS];
+        let sent = "POST / HTTP/1.1\r\nTransfer-Encoding: chunked\r\n\r\n";
+        let mut req_buf = BytesMut::from(sent);
+        let rest = b"4\r\nfirs\r\n8\r\nt\nsecond\r\n0\r\n\r\nGET /b HTTP/1.1\r\n\r\n";
+        let mut stream = Trickle::new(rest, 5);
+        let mut state = BodyState::default();
+        let endpoints = Endpoints::default();
+        let req = decode(
+            &mut headers,
+            &mut req_buf,
+            &mut stream,
+            &mut state,
+            &config,
+            endpoints,
+        );
+        let mut req = match req {
+            Ok(Some(req)) => req,
+            _ => panic!("request did not decode"),
+        };
+        req.track_body(req.framing().unwrap());
+        let mut body = req.body();
+        let mut lines = Vec::new();
+        let mut line = String::new();
+        while body.read_line(&mut line).unwrap() > 0 {
+            lines.push(std::mem::take(&mut line));
+        }
+        assert_eq!(lines, ["first\n", "second"]);
+        drop(body);
+        assert!(!state.chunked_open);
+        let mut next = req_buf.to_vec();
+        next.extend_from_slice(stream.unsent());
+        assert_eq!(next, b"GET /b HTTP/1.1\r\n\r\n");
+    }
+
+    #[test]
+    fn headers_stay_valid_while_body_reads_grow_the_buffer() {
+        let config = ServerConfig::default();
+        let mut headers = [MaybeUninit::uninit(); MAX_HEADERS];
+        let long = "x".repeat(200);
+        let len = 256 * 1024;
+        let head = format!(
+            "POST / HTTP/1.1\r\nX-Long: {}\r\nContent-Length: {}\r\n\r\n",
+            long, len
+        );
+        // no room to spare, so reading the body has to reallocate
+        let mut req_buf = BytesMut::with_capacity(head.len());
+        req_buf.extend_from_slice(head.as_bytes());
+        let mut stream = Trickle::new(&vec![b'b'; len], 4096);
+        let mut state = BodyState::default();
+        let endpoints = Endpoints::default();
+        let req = decode(
+            &mut headers,
+            &mut req_buf,
+            &mut stream,
+            &mut state,
+            &config,
+            endpoints,
+        );
+        let mut req = match req {
+            Ok(Some(req)) => req,
+            _ => panic!("request did not decode"),
+        };
+        assert_eq!(req.prefetch_body().unwrap().len(), len);
+        assert_eq!(req.header("x-long"), Some(long.as_str()));
+        assert_eq!(req.path(), "/");
+    }
+}
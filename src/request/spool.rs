@@ -0,0 +1,191 @@
+//! request bodies streamed to disk
+
+use std::env;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Read, Seek, SeekFrom, Write};
+use std::path::{Path, PathBuf};
+
+use crate::http::platform::random_bytes;
+use crate::request::request::BodyReader;
+use crate::server::secrets::hex;
+
+/// How a request body is written to disk by `BodyReader::spool` and
+/// `save_to`, for uploads too big to hold in memory.
+///
+/// The body goes to a new file in `dir`, the system's temporary directory
+/// by default, which is removed if anything fails, e.g. the client going
+/// away or sending more than `max_size`, which is answered with 413.
+#[derive(Clone, Debug, Default)]
+pub struct Spool {
+    dir: Option<PathBuf>,
+    max_size: Option<u64>,
+    sync: bool,
+}
+
+/// A request body on disk. Spooled to a temporary file, it is removed
+/// when dropped unless `persist` moves it somewhere first.
+#[derive(Debug)]
+pub struct SpooledFile {
+    file: File,
+    path: PathBuf,
+    len: u64,
+    temporary: bool,
+}
+
+// a file being written, removed unless it's kept
+struct Partial {
+    path: PathBuf,
+    kept: bool,
+}
+
+impl Drop for Partial {
+    fn drop(&mut self) {
+        if !self.kept {
+            fs::remove_file(&self.path).ok();
+        }
+    }
+}
+
+impl Spool {
+    pub fn new() -> Self {
+        Spool::default()
+    }
+
+    /// Where temporary files go; the files `save_to` writes are created
+    /// next to their destination instead.
+    pub fn dir<P: Into<PathBuf>>(&mut self, dir: P) -> &mut Self {
+        self.dir = Some(dir.into());
+        self
+    }
+
+    /// Refuses bodies over `bytes` with 413, below the route's
+    /// `max_body_size`.
+    pub fn max_size(&mut self, bytes: u64) -> &mut Self {
+        self.max_size = Some(bytes);
+        self
+    }
+
+    /// Flushes the file to the disk before returning it, so it survives
+    /// a crash once the handler has it.
+    pub fn sync(&mut self, sync: bool) -> &mut Self {
+        self.sync = sync;
+        self
+    }
+
+    /// Streams `body` into a temporary file.
+    pub fn spool(&self, body: BodyReader) -> io::Result<SpooledFile> {
+        let dir = self.dir.clone().unwrap_or_else(env::temp_dir);
+        let name = format!("aegis-upload-{}", hex(&random_bytes(8)?));
+        let (file, partial, len) = self.write(body, dir.join(name))?;
+        Ok(SpooledFile {
+            file,
+            path: partial.take(),
+            len,
+            temporary: true,
+        })
+    }
+
+    /// Streams `body` to `path`, replacing the file there only once the
+    /// whole body has arrived.
+    pub fn save_to<P: AsRef<Path>>(&self, body: BodyReader, path: P) -> io::Result<SpooledFile> {
+        let path = path.as_ref();
+        let name = path.file_name().ok_or_else(|| {
+            io::Error::new(io::ErrorKind::InvalidInput, "no file name to save to")
+        })?;
+        let partial_name = format!(
+            ".{}.partial-{}",
+            name.to_string_lossy(),
+            hex(&random_bytes(8)?)
+        );
+        let (file, partial, len) = self.write(body, path.with_file_name(partial_name))?;
+        fs::rename(&partial.path, path)?;
+        partial.take();
+        Ok(SpooledFile {
+            file,
+            path: path.to_path_buf(),
+            len,
+            temporary: false,
+        })
+    }
+
+    // the body written to a new file at `path`, rewound to its start
+    fn write(&self, mut body: BodyReader, path: PathBuf) -> io::Result<(File, Partial, u64)> {
+        let mut file = OpenOptions::new()
+            .read(true)
+            .write(true)
+            .create_new(true)
+            .open(&path)?;
+        let partial = Partial { path, kept: false };
+        let len = match self.max_size {
+            Some(max) => {
+                let len = io::copy(&mut (&mut body).take(max + 1), &mut file)?;
+                if len > max {
+                    return Err(body.fail(413, "Payload Too Large"));
+                }
+                len
+            }
+            None => io::copy(&mut body, &mut file)?,
+        };
+        file.flush()?;
+        if self.sync {
+            file.sync_all()?;
+        }
+        file.seek(SeekFrom::Start(0))?;
+        Ok((file, partial, len))
+    }
+}
+
+impl Partial {
+    fn take(mut self) -> PathBuf {
+        self.kept = true;
+        std::mem::take(&mut self.path)
+    }
+}
+
+impl SpooledFile {
+    /// The file, open for reading from the start of the body.
+    pub fn file(&mut self) -> &mut File {
+        &mut self.file
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Bytes of body written.
+    pub fn len(&self) -> u64 {
+        self.len
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len == 0
+    }
+
+    /// Moves the file to `path` and keeps it there.
+    pub fn persist<P: AsRef<Path>>(mut self, path: P) -> io::Result<File> {
+        fs::rename(&self.path, path.as_ref())?;
+        self.temporary = false;
+        let file = self.file.try_clone()?;
+        Ok(file)
+    }
+}
+
+impl Drop for SpooledFile {
+    fn drop(&mut self) {
+        if self.temporary {
+            fs::remove_file(&self.path).ok();
+        }
+    }
+}
+
+impl<'buf, 'stream> BodyReader<'buf, 'stream> {
+    /// Streams the body into a temporary file with the default `Spool`.
+    pub fn spool(self) -> io::Result<SpooledFile> {
+        Spool::new().spool(self)
+    }
+
+    /// Streams the body to `path` with the default `Spool`.
+    pub fn save_to<P: AsRef<Path>>(self, path: P) -> io::Result<SpooledFile> {
+        Spool::new().save_to(self, path)
+    }
+}
@@ -0,0 +1,95 @@
+//! bodies in JSON, MessagePack or CBOR, picked by Content-Type and Accept
+
+use std::io;
+
+#[cfg(any(feature = "msgpack", feature = "cbor"))]
+use bytes::BufMut;
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::errors::errors::RequestError;
+use crate::request::request::Request;
+use crate::response::response::Response;
+
+#[cfg(feature = "msgpack")]
+const MSGPACK: &str = "application/msgpack";
+#[cfg(feature = "cbor")]
+const CBOR: &str = "application/cbor";
+
+impl<'buf, 'header, 'stream> Request<'buf, 'header, 'stream> {
+    /// Deserializes a MessagePack body.
+    #[cfg(feature = "msgpack")]
+    pub fn msgpack<T: DeserializeOwned>(self) -> Result<T, RequestError> {
+        Ok(rmp_serde::from_slice(&self.into_vec()?)?)
+    }
+
+    /// Deserializes a CBOR body.
+    #[cfg(feature = "cbor")]
+    pub fn cbor<T: DeserializeOwned>(self) -> Result<T, RequestError> {
+        Ok(ciborium::de::from_reader(&self.into_vec()?[..])?)
+    }
+
+    /// Deserializes a JSON body, refused unread past the server's
+    /// `json_max_depth` or `json_max_tokens`.
+    pub fn json<T: DeserializeOwned>(self) -> Result<T, RequestError> {
+        let limits = self.req.json_limits;
+        let body = self.into_vec()?;
+        limits.check(&body)?;
+        Ok(serde_json::from_slice(&body)?)
+    }
+
+    /// Deserializes the body by its Content-Type: JSON, also assumed when
+    /// there's none, or MessagePack and CBOR with their features. Other
+    /// types are an `UnsupportedMediaType` error, usually answered with 415.
+    pub fn body_as<T: DeserializeOwned>(self) -> Result<T, RequestError> {
+        let media = self.content_type();
+        match media.as_ref().map(|media| media.essence()) {
+            None | Some("application/json") => self.json(),
+            Some(essence) if essence.ends_with("+json") => self.json(),
+            #[cfg(feature = "msgpack")]
+            Some("application/msgpack" | "application/x-msgpack" | "application/vnd.msgpack") => {
+                self.msgpack()
+            }
+            #[cfg(feature = "cbor")]
+            Some(CBOR) => self.cbor(),
+            Some(essence) => Err(RequestError::UnsupportedMediaType(essence.to_owned())),
+        }
+    }
+}
+
+impl<'a> Response<'a> {
+    /// Writes `v` as MessagePack, with map keys named like JSON's.
+    #[cfg(feature = "msgpack")]
+    pub fn msgpack<T: Serialize + ?Sized>(&mut self, v: &T) -> io::Result<()> {
+        self.header("Content-Type: application/msgpack");
+        let mut w = self.body_mut().writer();
+        rmp_serde::encode::write_named(&mut w, v)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    #[cfg(feature = "cbor")]
+    pub fn cbor<T: Serialize + ?Sized>(&mut self, v: &T) -> io::Result<()> {
+        self.header("Content-Type: application/cbor");
+        let w = self.body_mut().writer();
+        ciborium::ser::into_writer(v, w).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))
+    }
+
+    /// Writes `v` in whichever of JSON and the enabled binary formats the
+    /// client's Accept prefers, JSON when it prefers none, adding
+    /// `Vary: Accept`.
+    pub fn serialize<T: Serialize>(&mut self, req: &Request, v: &T) -> io::Result<()> {
+        #[allow(unused_mut)]
+        let mut offered = vec!["application/json"];
+        #[cfg(feature = "msgpack")]
+        offered.push(MSGPACK);
+        #[cfg(feature = "cbor")]
+        offered.push(CBOR);
+        match req.negotiate(self, &offered) {
+            #[cfg(feature = "msgpack")]
+            Some(MSGPACK) => self.msgpack(v),
+            #[cfg(feature = "cbor")]
+            Some(CBOR) => self.cbor(v),
+            _ => self.json(v),
+        }
+    }
+}
@@ -1,43 +1,62 @@
-use std::cell::UnsafeCell;
+//! HTTP dates, as in Date, Last-Modified, Expires and the conditional
+//! request headers
+
+use std::cell::RefCell;
 use std::fmt::{self, Write};
-use std::sync::Arc;
+use std::time::{SystemTime, UNIX_EPOCH};
 
 use bytes::BytesMut;
-use once_cell::sync::Lazy;
 
 const DATE_VALUE_LENGTH: usize = 29;
 
-static CURRENT_DATE: Lazy<Arc<DataWrap>> = Lazy::new(|| {
-    let date = Arc::new(DataWrap(UnsafeCell::new(Date::new())));
-    let date_clone = date.clone();
-    may::go!(move || loop {
-        may::coroutine::sleep(std::time::Duration::from_millis(500));
-        unsafe { &mut *(date_clone.0).get() }.update();
-    });
-    date
-});
+/// Parses an HTTP date: an IMF-fixdate, e.g. `Sun, 06 Nov 1994 08:49:37
+/// GMT`, or the obsolete RFC 850 and asctime forms recipients still have
+/// to accept. Surrounding whitespace is ignored.
+pub fn parse(value: &str) -> Option<SystemTime> {
+    httpdate::parse_http_date(value.trim()).ok()
+}
 
-struct DataWrap(UnsafeCell<Date>);
-unsafe impl Sync for DataWrap {}
+/// Formats `time` as an IMF-fixdate, the form to send; fractions of a
+/// second are dropped.
+pub fn format(time: SystemTime) -> String {
+    httpdate::fmt_http_date(time)
+}
+
+/// Whole seconds since the Unix epoch, the precision HTTP dates have, so
+/// times compare as their dates do; 0 for times before the epoch.
+pub fn unix_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs())
+}
+
+// formatted at most once a second per worker thread, for all the
+// coroutines it runs; nothing is shared, so nothing can be read torn
+thread_local! {
+    static CURRENT_DATE: RefCell<Date> = RefCell::new(Date::new());
+}
 
 #[doc(hidden)]
 #[inline]
 pub fn append_date(dst: &mut BytesMut) {
-    let date = unsafe { &*CURRENT_DATE.0.get() };
-    dst.extend_from_slice(date.as_bytes());
+    let now = SystemTime::now();
+    CURRENT_DATE.with(|date| {
+        let mut date = date.borrow_mut();
+        date.refresh(now);
+        dst.extend_from_slice(date.as_bytes());
+    });
 }
 
 struct Date {
     bytes: [u8; DATE_VALUE_LENGTH],
+    // the second `bytes` shows
+    secs: u64,
 }
 
 impl Date {
     fn new() -> Date {
-        let mut date = Date {
+        Date {
             bytes: [0; DATE_VALUE_LENGTH],
-        };
-        date.update();
-        date
+            secs: u64::MAX,
+        }
     }
 
     #[inline]
@@ -45,9 +64,13 @@ impl Date {
         &self.bytes
     }
 
-    fn update(&mut self) {
-        let t = std::time::SystemTime::now();
-        let date = httpdate::HttpDate::from(t);
+    fn refresh(&mut self, now: SystemTime) {
+        let secs = unix_secs(now);
+        if secs == self.secs {
+            return;
+        }
+        self.secs = secs;
+        let date = httpdate::HttpDate::from(now);
         write!(self, "{date}").unwrap();
     }
 }
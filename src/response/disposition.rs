@@ -0,0 +1,53 @@
+//! Content-Disposition for downloads, RFC 6266 with RFC 5987 filenames
+
+use std::fmt::Write;
+
+use crate::response::response::Response;
+
+impl<'a> Response<'a> {
+    /// Has browsers download the body as `filename` rather than show it.
+    /// Names outside plain ASCII are sent UTF-8 encoded in `filename*`, with
+    /// an ASCII fallback for older clients; any directory part is dropped.
+    pub fn attachment(&mut self, filename: &str) -> &mut Self {
+        self.header_owned(disposition("attachment", filename))
+    }
+
+    /// Has browsers show the body in place, the default for most types.
+    pub fn inline(&mut self) -> &mut Self {
+        self.header("Content-Disposition: inline")
+    }
+
+    /// Shows the body in place, suggesting `filename` if the user saves it.
+    pub fn inline_named(&mut self, filename: &str) -> &mut Self {
+        self.header_owned(disposition("inline", filename))
+    }
+}
+
+fn disposition(kind: &str, filename: &str) -> String {
+    let mut header = format!("Content-Disposition: {}", kind);
+    // a client shouldn't be steered into another directory
+    let name = filename.rsplit(['/', '\\']).next().unwrap_or(filename);
+    let fallback: String = name
+        .chars()
+        .filter(|c| !c.is_control())
+        .map(|c| match c {
+            ' '..='~' if c != '"' && c != '\\' => c,
+            _ => '_',
+        })
+        .collect();
+    write!(header, "; filename=\"{}\"", fallback).ok();
+    if fallback != name {
+        header.push_str("; filename*=UTF-8''");
+        for byte in name.bytes().filter(|b| !b.is_ascii_control()) {
+            match byte {
+                b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' => header.push(byte as char),
+                b'!' | b'#' | b'$' | b'&' | b'+' | b'-' | b'.' | b'^' | b'_' | b'`' | b'|'
+                | b'~' => header.push(byte as char),
+                _ => {
+                    write!(header, "%{:02X}", byte).ok();
+                }
+            }
+        }
+    }
+    header
+}
@@ -0,0 +1,97 @@
+//! server-side templates, rendered with tera
+
+use std::collections::HashMap;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+use serde::Serialize;
+
+use crate::errors::http_error::HttpError;
+use crate::response::response::Response;
+use crate::response::status::StatusCode;
+use crate::server::embedded::{content_type, AssetManifest};
+use crate::server::server::Server;
+
+/// The templates under a directory, registered with `Server::templates`
+/// for handlers to answer with `Response::render`.
+///
+/// They use tera's syntax and extend or include one another by their path
+/// under the directory, e.g. `layouts/base.html`. Clones share them.
+#[derive(Clone)]
+pub struct Templates {
+    tera: Arc<tera::Tera>,
+}
+
+impl Templates {
+    /// Loads every template under `dir`, failing on any that doesn't
+    /// parse.
+    pub fn from_dir<P: AsRef<Path>>(dir: P) -> io::Result<Self> {
+        let glob = format!("{}/**/*", dir.as_ref().display());
+        let tera =
+            tera::Tera::new(&glob).map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?;
+        Ok(Templates {
+            tera: Arc::new(tera),
+        })
+    }
+
+    /// Renders template `name` with `context` on its own, e.g. for an email.
+    pub fn render<T: Serialize>(&self, name: &str, context: &T) -> io::Result<String> {
+        tera::Context::from_serialize(context)
+            .and_then(|context| self.tera.render(name, &context))
+            .map_err(|e| io::Error::new(io::ErrorKind::Other, e))
+    }
+
+    /// Gives templates `asset_url(path="app.js")`, the URL `manifest` has
+    /// for a file, so pages link to its fingerprinted name.
+    pub fn asset_urls(&mut self, manifest: &AssetManifest) -> &mut Self {
+        let manifest = manifest.clone();
+        let asset_url = move |args: &HashMap<String, tera::Value>| match args
+            .get("path")
+            .and_then(tera::Value::as_str)
+        {
+            Some(path) => Ok(manifest.url(path).into()),
+            None => Err(tera::Error::msg("asset_url needs a path")),
+        };
+        Arc::make_mut(&mut self.tera).register_function("asset_url", asset_url);
+        self
+    }
+}
+
+impl Server {
+    /// Makes `templates` available to `Response::render` in handlers.
+    pub fn templates(&mut self, templates: &Templates) -> &mut Self {
+        self.config_mut().templates = Some(templates.clone());
+        self
+    }
+}
+
+impl<'a> Response<'a> {
+    /// Answers with template `name` from the server's `Templates`
+    /// rendered with `context`, typed by the template's extension
+    /// (`page.html` and `page.html.tera` are HTML). When it fails to
+    /// render the client gets a 500 and the cause is logged.
+    pub fn render<T: Serialize>(&mut self, name: &str, context: &T) -> io::Result<()> {
+        let rendered = match &self.templates {
+            Some(templates) => templates.render(name, context),
+            None => Err(io::Error::new(
+                io::ErrorKind::NotFound,
+                "no templates registered with the server",
+            )),
+        };
+        let body = match rendered {
+            Ok(body) => body,
+            Err(e) => {
+                error!("rendering template {}: {:?}", name, e);
+                let error = HttpError::Status(
+                    StatusCode::INTERNAL_SERVER_ERROR,
+                    "the page could not be rendered".to_owned(),
+                );
+                return Err(error.into());
+            }
+        };
+        let kind = content_type(name.strip_suffix(".tera").unwrap_or(name));
+        self.header_owned(format!("Content-Type: {}", kind));
+        self.str(body)
+    }
+}
@@ -0,0 +1,146 @@
+//! handlers that return their response instead of writing it
+
+use std::io;
+
+use bytes::Bytes;
+use serde::Serialize;
+
+use crate::errors::error::Error;
+use crate::errors::errors::RequestError;
+use crate::errors::http_error::HttpError;
+use crate::request::param::ParamError;
+use crate::request::request::Request;
+use crate::response::response::Response;
+use crate::response::status::StatusCode;
+
+/// A value a handler wrapped in `respond` can return as its response.
+///
+/// An `Err` from `into_response` fails the request like an `Err` from a
+/// plain handler, i.e. a 500.
+pub trait IntoResponse {
+    fn into_response(self, res: &mut Response) -> io::Result<()>;
+}
+
+/// A body serialized as JSON.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct Json<T>(pub T);
+
+/// Turns a handler that returns its response, e.g.
+/// `|req| Ok::<_, ParamError>(Json(find(req.param::<u64>("id")?)))`,
+/// into one routes take.
+pub fn respond<F, R>(
+    f: F,
+) -> impl Fn(Request, &mut Response) -> io::Result<()> + Send + Sync + 'static
+where
+    F: Fn(Request) -> R + Send + Sync + 'static,
+    R: IntoResponse + 'static,
+{
+    move |req, res| f(req).into_response(res)
+}
+
+impl IntoResponse for () {
+    fn into_response(self, _res: &mut Response) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl IntoResponse for &'static str {
+    fn into_response(self, res: &mut Response) -> io::Result<()> {
+        res.header("Content-Type: text/plain; charset=utf-8");
+        res.body(self);
+        Ok(())
+    }
+}
+
+impl IntoResponse for String {
+    fn into_response(self, res: &mut Response) -> io::Result<()> {
+        res.header("Content-Type: text/plain; charset=utf-8");
+        res.str(self)
+    }
+}
+
+impl IntoResponse for Vec<u8> {
+    fn into_response(self, res: &mut Response) -> io::Result<()> {
+        res.header("Content-Type: application/octet-stream");
+        res.body_vec(self);
+        Ok(())
+    }
+}
+
+impl IntoResponse for Bytes {
+    fn into_response(self, res: &mut Response) -> io::Result<()> {
+        res.header("Content-Type: application/octet-stream");
+        res.body_segments(vec![self]);
+        Ok(())
+    }
+}
+
+impl IntoResponse for serde_json::Value {
+    fn into_response(self, res: &mut Response) -> io::Result<()> {
+        res.json(&self)
+    }
+}
+
+impl<T: Serialize> IntoResponse for Json<T> {
+    fn into_response(self, res: &mut Response) -> io::Result<()> {
+        res.json(&self.0)
+    }
+}
+
+impl IntoResponse for StatusCode {
+    fn into_response(self, res: &mut Response) -> io::Result<()> {
+        res.status_code(self.as_u16() as usize, self.reason());
+        Ok(())
+    }
+}
+
+impl<T: IntoResponse> IntoResponse for (StatusCode, T) {
+    fn into_response(self, res: &mut Response) -> io::Result<()> {
+        self.0.into_response(res)?;
+        self.1.into_response(res)
+    }
+}
+
+/// `None` is a 404.
+impl<T: IntoResponse> IntoResponse for Option<T> {
+    fn into_response(self, res: &mut Response) -> io::Result<()> {
+        match self {
+            Some(value) => value.into_response(res),
+            None => StatusCode::NOT_FOUND.into_response(res),
+        }
+    }
+}
+
+/// Either side answers; make the error type one that maps to a status,
+/// such as `Error`, `HttpError`, `ParamError` or `RequestError`.
+impl<T: IntoResponse, E: IntoResponse> IntoResponse for Result<T, E> {
+    fn into_response(self, res: &mut Response) -> io::Result<()> {
+        match self {
+            Ok(value) => value.into_response(res),
+            Err(e) => e.into_response(res),
+        }
+    }
+}
+
+/// Fails the request, as from a plain handler.
+impl IntoResponse for io::Error {
+    fn into_response(self, _res: &mut Response) -> io::Result<()> {
+        Err(self)
+    }
+}
+
+/// An `HttpError::BadRequest`.
+impl IntoResponse for ParamError {
+    fn into_response(self, res: &mut Response) -> io::Result<()> {
+        HttpError::from(self).into_response(res)
+    }
+}
+
+/// 415 for an unsupported media type or charset, 400 for a body that
+/// doesn't decode, as `HttpError`s; failing to read the body fails the
+/// request.
+impl IntoResponse for RequestError {
+    fn into_response(self, res: &mut Response) -> io::Result<()> {
+        Error::from(self).into_response(res)
+    }
+}
@@ -1,25 +1,64 @@
-use std::io;
+use std::borrow::Cow;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
+use crate::errors::catalog::Locale;
 use crate::request::request::MAX_HEADERS;
+#[cfg(feature = "templates")]
+use crate::response::render::Templates;
+use crate::response::status::StatusCode;
+use crate::response::writer::{BodyWriter, StreamBody};
+use crate::server::security_headers::SecurityHeaders;
 
-use bytes::{BufMut, BytesMut};
+use bytes::{BufMut, Bytes, BytesMut};
+use may::net::TcpStream;
 use serde;
+use smallvec::SmallVec;
+
+// bodies from this size on are written alongside the head with writev
+// rather than copied in after it
+const VECTORED_BODY: usize = 16 * 1024;
 
 pub struct Response<'a> {
-    headers: [&'static str; MAX_HEADERS],
-    headers_len: usize,
+    // inline up to as many as a request may carry, on the heap past that
+    headers: SmallVec<[Cow<'static, str>; MAX_HEADERS]>,
     status_message: StatusMessage,
     body: Body,
     res_buf: &'a mut BytesMut,
+    // time spent serializing bodies, once someone asks for it
+    serialization: Option<Duration>,
+    // for error responses, when the server has a message catalog
+    pub(crate) locale: Option<Locale>,
+    // the matched route's own security headers, in place of the server's
+    pub(crate) security_headers: Option<SecurityHeaders>,
+    // the Server header the connection's server sends, unless set here
+    pub(crate) server: Option<Arc<str>>,
+    // for `render`, when the server has templates
+    #[cfg(feature = "templates")]
+    pub(crate) templates: Option<Templates>,
+    // the target a CONNECT request is relayed to
+    tunnel: Option<TcpStream>,
 }
 
 enum Body {
     StaticStr(&'static str),
     Str(String),
     Vec(Vec<u8>),
+    Segments(Vec<Bytes>),
+    Stream(StreamBody),
+    File(FileBody),
     Dummy,
 }
 
+// a byte range of a file, written by the connection straight from it
+pub(crate) struct FileBody {
+    pub(crate) file: File,
+    pub(crate) offset: u64,
+    pub(crate) len: u64,
+}
+
 struct StatusMessage {
     code: usize,
     msg: &'static str,
@@ -27,17 +66,21 @@ struct StatusMessage {
 
 impl<'a> Response<'a> {
     pub(crate) fn new(res_buf: &'a mut BytesMut) -> Response {
-        let headers: [&'static str; 16] = [""; 16];
-
         Response {
-            headers,
-            headers_len: 0,
+            headers: SmallVec::new(),
             body: Body::Dummy,
             status_message: StatusMessage {
                 code: 200,
                 msg: "Ok",
             },
             res_buf,
+            serialization: None,
+            locale: None,
+            security_headers: None,
+            server: None,
+            #[cfg(feature = "templates")]
+            templates: None,
+            tunnel: None,
         }
     }
 
@@ -47,10 +90,61 @@ impl<'a> Response<'a> {
         self
     }
 
+    /// Answers `status`, e.g. `StatusCode::FOUND` or `SEE_OTHER`, sending
+    /// the client to `location`. A location with a line break is refused
+    /// rather than let it add headers.
+    pub fn redirect(&mut self, location: &str, status: StatusCode) -> io::Result<()> {
+        if location.contains(['\r', '\n']) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "line break in redirect location",
+            ));
+        }
+        self.status_code(status.as_u16() as usize, status.reason());
+        self.header_owned(format!("Location: {}", location));
+        self.body = Body::Dummy;
+        Ok(())
+    }
+
+    /// Redirects with 308, which keeps the method and body and lets
+    /// clients remember the new location.
+    pub fn permanent_redirect(&mut self, location: &str) -> io::Result<()> {
+        self.redirect(location, StatusCode::PERMANENT_REDIRECT)
+    }
+
+    /// Answers 404 with no body, as unrouted paths are.
+    pub fn not_found(&mut self) -> &mut Self {
+        self.body = Body::Dummy;
+        self.status_code(404, "Not Found")
+    }
+
+    pub fn no_content(&mut self) -> &mut Self {
+        self.body = Body::Dummy;
+        self.status_code(204, "No Content")
+    }
+
     #[inline]
     pub fn header(&mut self, header: &'static str) -> &mut Self {
-        self.headers[self.headers_len] = header;
-        self.headers_len += 1;
+        self.headers.push(Cow::Borrowed(header));
+        self
+    }
+
+    /// Adds a header built at runtime, e.g. one passed on from another
+    /// service. A name that isn't a token, or a value with a line break,
+    /// is refused rather than let it add headers.
+    pub fn set_header(&mut self, name: &str, value: &str) -> io::Result<&mut Self> {
+        if !valid_header(name, value) {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid header: {}", name),
+            ));
+        }
+        Ok(self.header_owned(format!("{}: {}", name, value.trim())))
+    }
+
+    // a header built at runtime, e.g. one echoing part of the request
+    pub(crate) fn header_owned(&mut self, header: String) -> &mut Self {
+        self.headers.push(Cow::Owned(header));
         self
     }
 
@@ -69,11 +163,22 @@ impl<'a> Response<'a> {
         self.body = Body::Vec(v.to_vec());
     }
 
+    /// Sends precomputed fragments back to back without concatenating them;
+    /// they go out with vectored writes.
+    #[inline]
+    pub fn body_segments(&mut self, segments: Vec<Bytes>) {
+        self.body = Body::Segments(segments);
+    }
+
     #[inline]
     pub fn json<T: serde::Serialize>(&mut self, v: &T) -> io::Result<()> {
         self.header("Content-Type: application/json");
+        let started = self.serialization.map(|_| Instant::now());
         let w = self.body_mut().writer();
         serde_json::to_writer(w, v)?;
+        if let (Some(total), Some(started)) = (&mut self.serialization, started) {
+            *total += started.elapsed();
+        }
         Ok(())
     }
 
@@ -91,11 +196,207 @@ impl<'a> Response<'a> {
         Ok(())
     }
 
+    /// Sends `len` bytes of `file` from `offset` with a Content-Length. On
+    /// Linux the HTTP/1.1 connection hands them to the socket with sendfile,
+    /// so they never pass through userspace; elsewhere, and over HTTP/2,
+    /// the file is read and copied.
+    pub fn send_file(&mut self, file: File, offset: u64, len: u64) {
+        self.body = Body::File(FileBody { file, offset, len });
+    }
+
+    /// Streams the body with chunked encoding: `f` runs after the head is
+    /// sent and writes through a backpressure-aware `BodyWriter`. An
+    /// HTTP/1.0 client, which can't decode chunks, gets the bytes as
+    /// written, ended by closing the connection.
+    pub fn stream<F>(&mut self, f: F) -> io::Result<()>
+    where
+        F: FnOnce(&mut BodyWriter) -> io::Result<()> + 'static,
+    {
+        self.body = Body::Stream(Box::new(f));
+        Ok(())
+    }
+
+    /// Answers a CONNECT request, once the handler has checked its target
+    /// may be reached, by relaying bytes between the client and `upstream`
+    /// until either closes. The connection carries nothing else afterwards.
+    /// CONNECT targets are `host:port`, so route them with e.g.
+    /// `server.connect("/:authority", ...)`. HTTP/1.1 only; HTTP/2 streams
+    /// are reset.
+    pub fn tunnel(&mut self, upstream: TcpStream) -> &mut Self {
+        self.tunnel = Some(upstream);
+        self.status_code(200, "Connection Established")
+    }
+
+    pub(crate) fn status(&self) -> usize {
+        self.status_message.code
+    }
+
+    pub(crate) fn track_serialization(&mut self) {
+        self.serialization.get_or_insert(Duration::ZERO);
+    }
+
+    pub(crate) fn serialization(&self) -> Option<Duration> {
+        self.serialization
+    }
+
+    // body length, unknown until a streamed body has been written
+    pub(crate) fn body_size(&self) -> Option<usize> {
+        match self.body {
+            Body::Stream(_) => None,
+            _ => Some(self.body_len()),
+        }
+    }
+
+    // whether the handler already asked for the connection to close
+    pub(crate) fn closes(&self) -> bool {
+        self.headers.iter().any(|header| {
+            let (name, value) = header.split_once(':').unwrap_or((header, ""));
+            name.trim().eq_ignore_ascii_case("connection")
+                && value
+                    .split(',')
+                    .any(|v| v.trim().eq_ignore_ascii_case("close"))
+        })
+    }
+
+    // the value of the first `name` header set so far
+    pub(crate) fn header_value(&self, name: &str) -> Option<&str> {
+        self.headers.iter().find_map(|header| {
+            let (key, value) = header.split_once(':')?;
+            key.trim().eq_ignore_ascii_case(name).then(|| value.trim())
+        })
+    }
+
+    // the values of every `name` header set so far
+    pub(crate) fn header_values<'s>(&'s self, name: &'s str) -> impl Iterator<Item = &'s str> {
+        self.headers.iter().filter_map(move |header| {
+            let (key, value) = header.split_once(':')?;
+            key.trim().eq_ignore_ascii_case(name).then(|| value.trim())
+        })
+    }
+
+    pub(crate) fn headers(&self) -> &[Cow<'static, str>] {
+        &self.headers
+    }
+
+    pub(crate) fn status_message(&self) -> (usize, &'static str) {
+        (self.status_message.code, self.status_message.msg)
+    }
+
+    // the body as shared bytes, left in place to be sent as well; `None`
+    // for a streamed or file body, which isn't in memory, or one longer
+    // than `max`
+    pub(crate) fn shared_body(&mut self, max: usize) -> Option<Bytes> {
+        if matches!(self.body, Body::Stream(_) | Body::File(_)) || self.body_len() > max {
+            return None;
+        }
+        let body = match std::mem::replace(&mut self.body, Body::Dummy) {
+            Body::StaticStr(s) => Bytes::from_static(s.as_bytes()),
+            Body::Str(s) => Bytes::from(s),
+            Body::Vec(v) => Bytes::from(v),
+            Body::Segments(mut segments) if segments.len() == 1 => segments.remove(0),
+            Body::Segments(segments) => {
+                let mut joined = BytesMut::new();
+                segments.iter().for_each(|s| joined.extend_from_slice(s));
+                joined.freeze()
+            }
+            Body::Dummy => self.res_buf.split().freeze(),
+            Body::Stream(_) | Body::File(_) => unreachable!(),
+        };
+        self.body = Body::Segments(vec![body.clone()]);
+        Some(body)
+    }
+
+    // drop every `name` header set so far
+    #[cfg(feature = "dev")]
+    pub(crate) fn remove_header(&mut self, name: &str) {
+        self.headers.retain(|header| {
+            let key = header.split_once(':').map_or(&**header, |(key, _)| key);
+            !key.trim().eq_ignore_ascii_case(name)
+        });
+    }
+
+    // drop whatever the handler set up so the response can be rebuilt
+    pub(crate) fn clear(&mut self) {
+        self.headers.clear();
+        self.body = Body::Dummy;
+        self.tunnel = None;
+        self.res_buf.clear();
+        self.status_code(200, "Ok");
+    }
+
+    pub(crate) fn take_tunnel(&mut self) -> Option<TcpStream> {
+        self.tunnel.take()
+    }
+
+    pub(crate) fn streams(&self) -> bool {
+        matches!(self.body, Body::Stream(_))
+    }
+
+    #[inline]
+    pub(crate) fn take_stream(&mut self) -> Option<StreamBody> {
+        match std::mem::replace(&mut self.body, Body::Dummy) {
+            Body::Stream(f) => Some(f),
+            body => {
+                self.body = body;
+                None
+            }
+        }
+    }
+
+    #[inline]
+    pub(crate) fn take_file(&mut self) -> Option<FileBody> {
+        match std::mem::replace(&mut self.body, Body::Dummy) {
+            Body::File(file) => Some(file),
+            body => {
+                self.body = body;
+                None
+            }
+        }
+    }
+
+    // reads a file body into the buffer, for writers that can't send it
+    // from the file
+    pub(crate) fn load_file(&mut self) -> io::Result<()> {
+        if let Some(mut body) = self.take_file() {
+            body.file.seek(SeekFrom::Start(body.offset))?;
+            let mut file = body.file.take(body.len);
+            let copied = io::copy(&mut file, &mut self.body_mut().writer())?;
+            if copied < body.len {
+                let msg = "file ended before its length";
+                return Err(io::Error::new(io::ErrorKind::UnexpectedEof, msg));
+            }
+        }
+        Ok(())
+    }
+
+    // the body as slices to write without copying them into the head's
+    // buffer: segments, or any body large enough for the copy to matter
+    #[inline]
+    pub(crate) fn take_segments(&mut self) -> Option<Vec<Bytes>> {
+        let vectored = match self.body {
+            Body::Segments(_) => true,
+            Body::Stream(_) | Body::File(_) => false,
+            _ => self.body_len() >= VECTORED_BODY,
+        };
+        if !vectored {
+            return None;
+        }
+        match std::mem::replace(&mut self.body, Body::Dummy) {
+            Body::Segments(segments) => Some(segments),
+            Body::StaticStr(s) => Some(vec![Bytes::from_static(s.as_bytes())]),
+            Body::Str(s) => Some(vec![Bytes::from(s)]),
+            Body::Vec(v) => Some(vec![Bytes::from(v)]),
+            // gives up the buffer's allocation, cheaper than copying this much
+            Body::Dummy => Some(vec![self.res_buf.split().freeze()]),
+            Body::Stream(_) | Body::File(_) => unreachable!(),
+        }
+    }
 
     #[inline]
     pub fn body_mut(&mut self) -> &mut BytesMut {
         match self.body {
             Body::Dummy => {}
+            Body::Stream(_) | Body::File(_) => self.body = Body::Dummy,
             Body::StaticStr(s) => {
                 self.res_buf.extend_from_slice(s.as_bytes());
                 self.body = Body::Dummy;
@@ -108,26 +409,39 @@ impl<'a> Response<'a> {
                 self.res_buf.extend_from_slice(v);
                 self.body = Body::Dummy;
             }
+            Body::Segments(ref segments) => {
+                for segment in segments {
+                    self.res_buf.extend_from_slice(segment);
+                }
+                self.body = Body::Dummy;
+            }
         }
         self.res_buf
     }
     #[inline]
     fn body_len(&self) -> usize {
         match self.body {
-            Body::Dummy => self.res_buf.len(),
+            Body::Dummy | Body::Stream(_) => self.res_buf.len(),
             Body::StaticStr(s) => s.len(),
             Body::Str(ref s) => s.len(),
             Body::Vec(ref v) => v.len(),
+            Body::Segments(ref segments) => segments.iter().map(Bytes::len).sum(),
+            Body::File(ref file) => file.len as usize,
         }
     }
 
     #[inline]
     fn get_body(&mut self) -> &[u8] {
+        if let Body::Segments(_) = self.body {
+            return self.body_mut();
+        }
         match self.body {
-            Body::Dummy => self.res_buf.as_ref(),
+            Body::Dummy | Body::Stream(_) => self.res_buf.as_ref(),
             Body::StaticStr(s) => s.as_bytes(),
             Body::Str(ref s) => s.as_bytes(),
             Body::Vec(ref v) => v,
+            // taken by the connection, or loaded, before encoding
+            Body::Segments(_) | Body::File(_) => unreachable!(),
         }
     }
 }
@@ -138,40 +452,107 @@ impl<'a> Drop for Response<'a> {
     }
 }
 
-pub(crate) fn encode(mut rsp: Response, buf: &mut BytesMut) {
+fn encode_status(rsp: &Response, buf: &mut BytesMut) {
     if rsp.status_message.code == 200 {
-        buf.extend_from_slice(b"HTTP/1.1 200 Ok\r\nServer: M\r\nDate: ");
+        buf.extend_from_slice(b"HTTP/1.1 200 Ok");
     } else {
         buf.extend_from_slice(b"HTTP/1.1 ");
         let mut code = itoa::Buffer::new();
         buf.extend_from_slice(code.format(rsp.status_message.code).as_bytes());
         buf.extend_from_slice(b" ");
         buf.extend_from_slice(rsp.status_message.msg.as_bytes());
-        buf.extend_from_slice(b"\r\nServer: M\r\nDate: ");
     }
+    let server = match rsp.header_value("server") {
+        Some(_) => None,
+        None => rsp.server.as_deref(),
+    };
+    encode_server_date(server, buf);
+}
+
+fn encode_server_date(server: Option<&str>, buf: &mut BytesMut) {
+    if let Some(server) = server {
+        buf.extend_from_slice(b"\r\nServer: ");
+        buf.extend_from_slice(server.as_bytes());
+    }
+    buf.extend_from_slice(b"\r\nDate: ");
     crate::response::date::append_date(buf);
-    buf.extend_from_slice(b"\r\nContent-Length: ");
-    let mut length = itoa::Buffer::new();
-    buf.extend_from_slice(length.format(rsp.body_len()).as_bytes());
+}
 
-    // SAFETY: we already have bound check when insert headers
-    let headers = unsafe { rsp.headers.get_unchecked(..rsp.headers_len) };
-    for h in headers {
+fn encode_headers(rsp: &Response, buf: &mut BytesMut) {
+    for h in &rsp.headers {
         buf.extend_from_slice(b"\r\n");
         buf.extend_from_slice(h.as_bytes());
     }
 
     buf.extend_from_slice(b"\r\n\r\n");
+}
+
+fn encode_sized_head(rsp: &Response, len: usize, buf: &mut BytesMut) {
+    encode_status(rsp, buf);
+    buf.extend_from_slice(b"\r\nContent-Length: ");
+    let mut length = itoa::Buffer::new();
+    buf.extend_from_slice(length.format(len).as_bytes());
+    encode_headers(rsp, buf);
+}
+
+// a name that's a token and a value that can't break out of its line
+pub(crate) fn valid_header(name: &str, value: &str) -> bool {
+    let valid_name = !name.is_empty()
+        && name
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"!#$%&'*+-.^_`|~".contains(&b));
+    valid_name && !value.contains(['\r', '\n', '\0'])
+}
+
+pub(crate) fn encode(mut rsp: Response, buf: &mut BytesMut) {
+    encode_sized_head(&rsp, rsp.body_len(), buf);
     buf.extend_from_slice(rsp.get_body());
 }
 
-pub(crate) fn encode_error(e: io::Error, buf: &mut BytesMut) {
+// only the head; the segments themselves are written straight from `Bytes`
+pub(crate) fn encode_segments_head(rsp: Response, segments: &[Bytes], buf: &mut BytesMut) {
+    let len = segments.iter().map(Bytes::len).sum();
+    encode_sized_head(&rsp, len, buf);
+}
+
+// only the head; the file is written by the connection
+pub(crate) fn encode_file_head(rsp: Response, file: &FileBody, buf: &mut BytesMut) {
+    encode_sized_head(&rsp, file.len as usize, buf);
+}
+
+// a 2xx answer to CONNECT has no body, nor any framing for one
+pub(crate) fn encode_tunnel_head(rsp: Response, buf: &mut BytesMut) {
+    encode_status(&rsp, buf);
+    encode_headers(&rsp, buf);
+}
+
+// unchunked for an HTTP/1.0 client, which reads the body to the close
+pub(crate) fn encode_stream_head(rsp: Response, chunked: bool, buf: &mut BytesMut) {
+    encode_status(&rsp, buf);
+    if chunked {
+        buf.extend_from_slice(b"\r\nTransfer-Encoding: chunked");
+    }
+    encode_headers(&rsp, buf);
+}
+
+// status, headers and body for an encoder other than HTTP/1.1's; `None`
+// for a streamed body or a tunnel, which only the HTTP/1.1 loop can write
+pub(crate) fn into_parts(mut rsp: Response) -> Option<(usize, Vec<Cow<'static, str>>, Bytes)> {
+    if matches!(rsp.body, Body::Stream(_)) || rsp.tunnel.is_some() {
+        return None;
+    }
+    let headers = rsp.headers.to_vec();
+    let body = Bytes::copy_from_slice(rsp.get_body());
+    Some((rsp.status_message.code, headers, body))
+}
+
+pub(crate) fn encode_error(e: io::Error, server: Option<&str>, buf: &mut BytesMut) {
     error!("error in service: err = {:?}", e);
     let msg_string = e.to_string();
     let msg = msg_string.as_bytes();
 
-    buf.extend_from_slice(b"HTTP/1.1 500 Internal Server Error\r\nServer: M\r\nDate: ");
-    crate::response::date::append_date(buf);
+    buf.extend_from_slice(b"HTTP/1.1 500 Internal Server Error");
+    encode_server_date(server, buf);
     buf.extend_from_slice(b"\r\nContent-Length: ");
     let mut length = itoa::Buffer::new();
     buf.extend_from_slice(length.format(msg.len()).as_bytes());
@@ -0,0 +1,208 @@
+//! range and conditional requests answered from any `Read + Seek` source
+
+use std::any::Any;
+use std::fs::File;
+use std::io::{self, Read, Seek, SeekFrom};
+use std::time::SystemTime;
+
+use crate::request::request::Request;
+use crate::response::date::{self, unix_secs};
+use crate::response::response::Response;
+use crate::server::versioned::matches;
+
+// bodies up to this size are read up front and sent with a Content-Length
+const BUFFERED_MAX: u64 = 1024 * 1024;
+
+/// A body read from any `Read + Seek` source, e.g. an object-store wrapper
+/// or an archive entry; its length is found by seeking to the end.
+///
+/// `respond` answers a single-range `Range` request with 206, or 416 when
+/// it's out of bounds, and drops the range when If-Range is stale. Given an
+/// ETag or modification time, If-None-Match and If-Modified-Since are
+/// answered with 304, If-Match and If-Unmodified-Since with 412. Up to
+/// 1 MiB is sent in one piece; anything larger is streamed, or for a `File`
+/// sent with `Response::send_file`.
+pub struct SeekableBody<R> {
+    reader: R,
+    content_type: Option<String>,
+    etag: Option<String>,
+    last_modified: Option<SystemTime>,
+}
+
+enum Span {
+    Full,
+    // first and last byte, inclusive
+    Part(u64, u64),
+    Unsatisfiable,
+}
+
+impl<R: Read + Seek + 'static> SeekableBody<R> {
+    pub fn new(reader: R) -> Self {
+        SeekableBody {
+            reader,
+            content_type: None,
+            etag: None,
+            last_modified: None,
+        }
+    }
+
+    pub fn content_type(mut self, content_type: &str) -> Self {
+        self.content_type = Some(content_type.to_owned());
+        self
+    }
+
+    /// The quoted ETag identifying this content, e.g. `"v42"`.
+    pub fn etag(mut self, etag: &str) -> Self {
+        self.etag = Some(etag.to_owned());
+        self
+    }
+
+    pub fn last_modified(mut self, modified: SystemTime) -> Self {
+        self.last_modified = Some(modified);
+        self
+    }
+
+    pub fn respond(mut self, req: &Request, res: &mut Response) -> io::Result<()> {
+        let len = self.reader.seek(SeekFrom::End(0))?;
+        res.header("Accept-Ranges: bytes");
+        if let Some(etag) = &self.etag {
+            res.header_owned(format!("ETag: {}", etag));
+        }
+        if let Some(modified) = self.last_modified {
+            let modified = date::format(modified);
+            res.header_owned(format!("Last-Modified: {}", modified));
+        }
+        if let Some((code, msg)) = self.precondition(req) {
+            res.status_code(code, msg);
+            return Ok(());
+        }
+        let (start, end) = match self.span(req, len) {
+            Span::Full => (0, len),
+            Span::Part(first, last) => {
+                res.status_code(206, "Partial Content");
+                res.header_owned(format!("Content-Range: bytes {}-{}/{}", first, last, len));
+                (first, last + 1)
+            }
+            Span::Unsatisfiable => {
+                res.status_code(416, "Range Not Satisfiable");
+                res.header_owned(format!("Content-Range: bytes */{}", len));
+                return Ok(());
+            }
+        };
+        if let Some(content_type) = &self.content_type {
+            res.header_owned(format!("Content-Type: {}", content_type));
+        }
+        if req.method() == "HEAD" {
+            return Ok(());
+        }
+
+        self.reader.seek(SeekFrom::Start(start))?;
+        let size = end - start;
+        if size <= BUFFERED_MAX {
+            let mut body = Vec::with_capacity(size as usize);
+            self.reader.take(size).read_to_end(&mut body)?;
+            if (body.len() as u64) < size {
+                return Err(ended_early());
+            }
+            res.body_vec(body);
+            return Ok(());
+        }
+        // a file goes from the kernel's side, without passing through here
+        let reader: Box<dyn Any> = Box::new(self.reader);
+        let reader = match reader.downcast::<File>() {
+            Ok(file) => {
+                res.send_file(*file, start, size);
+                return Ok(());
+            }
+            Err(reader) => *reader.downcast::<R>().unwrap(),
+        };
+        let mut reader = reader.take(size);
+        res.stream(move |writer| {
+            let copied = io::copy(&mut reader, writer)?;
+            match copied < size {
+                true => Err(ended_early()),
+                false => Ok(()),
+            }
+        })
+    }
+
+    // the status answering a failed or already satisfied condition
+    fn precondition(&self, req: &Request) -> Option<(usize, &'static str)> {
+        let etag = self.etag.as_deref();
+        let since = |name| {
+            let date = date::parse(req.header(name)?)?;
+            Some((unix_secs(self.last_modified?), unix_secs(date)))
+        };
+        let failed = match req.header("if-match") {
+            Some(tags) => !(tags.trim() == "*" || etag.map_or(false, |e| matches(tags, e, true))),
+            None => since("if-unmodified-since").map_or(false, |(modified, at)| modified > at),
+        };
+        if failed {
+            return Some((412, "Precondition Failed"));
+        }
+        let read = matches!(req.method(), "GET" | "HEAD");
+        let unchanged = match req.header("if-none-match") {
+            Some(tags) => tags.trim() == "*" || etag.map_or(false, |e| matches(tags, e, false)),
+            None if read => {
+                since("if-modified-since").map_or(false, |(modified, at)| modified <= at)
+            }
+            None => false,
+        };
+        match (unchanged, read) {
+            (true, true) => Some((304, "Not Modified")),
+            (true, false) => Some((412, "Precondition Failed")),
+            (false, _) => None,
+        }
+    }
+
+    fn span(&self, req: &Request, len: u64) -> Span {
+        let ranges = match req.header("range") {
+            Some(ranges) if req.method() == "GET" => ranges,
+            _ => return Span::Full,
+        };
+        // a stale If-Range asks for the whole, current content
+        if let Some(validator) = req.header("if-range").map(str::trim) {
+            let current = match date::parse(validator) {
+                Some(date) => self
+                    .last_modified
+                    .map_or(false, |m| unix_secs(m) == unix_secs(date)),
+                None => self.etag.as_deref() == Some(validator) && !validator.starts_with("W/"),
+            };
+            if !current {
+                return Span::Full;
+            }
+        }
+        parse_range(ranges, len)
+    }
+}
+
+// a single `bytes=` range; anything else is ignored, as RFC 7233 allows,
+// and several ranges would need a multipart body, so the whole is sent
+fn parse_range(ranges: &str, len: u64) -> Span {
+    let range = match ranges.trim().strip_prefix("bytes=") {
+        Some(range) if !range.contains(',') => range,
+        _ => return Span::Full,
+    };
+    let (first, last) = match range.split_once('-') {
+        Some((first, last)) => (first.trim(), last.trim()),
+        None => return Span::Full,
+    };
+    let (first, last) = match (first.parse::<u64>(), last.parse::<u64>()) {
+        // the final `n` bytes
+        (Err(_), Ok(n)) if first.is_empty() => match n {
+            0 => return Span::Unsatisfiable,
+            n => (len.saturating_sub(n), len.saturating_sub(1)),
+        },
+        (Ok(first), Err(_)) if last.is_empty() => (first, len.saturating_sub(1)),
+        (Ok(first), Ok(last)) if first <= last => (first, last.min(len.saturating_sub(1))),
+        _ => return Span::Full,
+    };
+    match first < len {
+        true => Span::Part(first, last),
+        false => Span::Unsatisfiable,
+    }
+}
+
+fn ended_early() -> io::Error {
+    io::Error::new(io::ErrorKind::UnexpectedEof, "body source ended early")
+}
@@ -0,0 +1,228 @@
+use std::fmt;
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+use bytes::{BufMut, BytesMut};
+use may::coroutine;
+use may::sync::mpsc::{Receiver, RecvTimeoutError};
+
+use crate::response::response::Response;
+use crate::response::writer::BodyWriter;
+
+const DEFAULT_KEEP_ALIVE: Duration = Duration::from_secs(15);
+// how often a waiting producer checks that the client is still there
+const DISCONNECT_POLL: Duration = Duration::from_secs(1);
+
+/// One Server-Sent Event.
+#[derive(Clone, Debug, Default)]
+pub struct SseEvent {
+    id: Option<String>,
+    event: Option<String>,
+    data: String,
+    retry: Option<Duration>,
+}
+
+impl SseEvent {
+    /// An unnamed event carrying `data`; multi-line data is split across
+    /// `data:` fields.
+    pub fn new(data: &str) -> Self {
+        SseEvent {
+            data: data.to_owned(),
+            ..SseEvent::default()
+        }
+    }
+
+    /// The id the client sends back in Last-Event-ID when it reconnects.
+    pub fn id(mut self, id: &str) -> Self {
+        self.id = Some(single_line(id));
+        self
+    }
+
+    /// The event type, dispatched to `addEventListener(name)` listeners.
+    pub fn event(mut self, name: &str) -> Self {
+        self.event = Some(single_line(name));
+        self
+    }
+
+    /// How long the client waits before reconnecting.
+    pub fn retry(mut self, delay: Duration) -> Self {
+        self.retry = Some(delay);
+        self
+    }
+
+    fn encode(&self, buf: &mut BytesMut) {
+        if let Some(id) = &self.id {
+            field(buf, "id", id);
+        }
+        if let Some(event) = &self.event {
+            field(buf, "event", event);
+        }
+        if let Some(retry) = self.retry {
+            field(buf, "retry", itoa::Buffer::new().format(retry.as_millis()));
+        }
+        for line in lines(&self.data) {
+            field(buf, "data", line);
+        }
+        buf.put_u8(b'\n');
+    }
+}
+
+/// An open `text/event-stream` response, handed to `Response::sse`
+/// producers.
+///
+/// Every event is flushed to the socket as it is sent. While the producer
+/// waits in `sleep` or `recv`, a comment goes out each keep-alive interval
+/// so proxies don't time the connection out. Once the client has gone,
+/// the next write fails, and `sleep` and `recv` fail within a second; the
+/// producer should return the error.
+pub struct SseStream<'w, 'a> {
+    writer: &'w mut BodyWriter<'a>,
+    keep_alive: Duration,
+    last_write: Instant,
+}
+
+impl<'w, 'a> SseStream<'w, 'a> {
+    /// Sets the keep-alive interval, 15 seconds by default.
+    pub fn set_keep_alive(&mut self, interval: Duration) -> &mut Self {
+        self.keep_alive = interval.max(Duration::from_millis(1));
+        self
+    }
+
+    pub fn send(&mut self, event: &SseEvent) -> io::Result<()> {
+        let mut buf = BytesMut::new();
+        event.encode(&mut buf);
+        self.write(&buf)
+    }
+
+    /// Whether the client is still connected, see
+    /// `BodyWriter::is_connected`.
+    pub fn is_connected(&mut self) -> bool {
+        self.writer.is_connected()
+    }
+
+    /// Sends an unnamed event carrying `data`.
+    pub fn data(&mut self, data: &str) -> io::Result<()> {
+        self.send(&SseEvent::new(data))
+    }
+
+    /// Sends a comment, which clients ignore.
+    pub fn comment(&mut self, text: &str) -> io::Result<()> {
+        let mut buf = BytesMut::new();
+        for line in lines(text) {
+            field(&mut buf, "", line);
+        }
+        buf.put_u8(b'\n');
+        self.write(&buf)
+    }
+
+    /// Waits for `duration`, keeping the connection alive meanwhile.
+    pub fn sleep(&mut self, duration: Duration) -> io::Result<()> {
+        let deadline = Instant::now() + duration;
+        loop {
+            let now = Instant::now();
+            if now >= deadline {
+                return Ok(());
+            }
+            self.keep_alive_if_due()?;
+            self.check_connected()?;
+            coroutine::sleep((deadline - now).min(self.until_wake()));
+        }
+    }
+
+    /// Waits for the next message on `rx`, keeping the connection alive
+    /// meanwhile; `None` once every sender has hung up.
+    pub fn recv<T>(&mut self, rx: &Receiver<T>) -> io::Result<Option<T>> {
+        loop {
+            self.keep_alive_if_due()?;
+            self.check_connected()?;
+            match rx.recv_timeout(self.until_wake()) {
+                Ok(message) => return Ok(Some(message)),
+                Err(RecvTimeoutError::Timeout) => {}
+                Err(RecvTimeoutError::Disconnected) => return Ok(None),
+            }
+        }
+    }
+
+    fn until_keep_alive(&self) -> Duration {
+        self.keep_alive.saturating_sub(self.last_write.elapsed())
+    }
+
+    // when a waiting producer next has to look up
+    fn until_wake(&self) -> Duration {
+        self.until_keep_alive().min(DISCONNECT_POLL)
+    }
+
+    fn check_connected(&mut self) -> io::Result<()> {
+        match self.writer.is_connected() {
+            true => Ok(()),
+            false => Err(io::Error::new(
+                io::ErrorKind::ConnectionAborted,
+                "client disconnected",
+            )),
+        }
+    }
+
+    fn keep_alive_if_due(&mut self) -> io::Result<()> {
+        match self.last_write.elapsed() >= self.keep_alive {
+            true => self.write(b":\n\n"),
+            false => Ok(()),
+        }
+    }
+
+    fn write(&mut self, buf: &[u8]) -> io::Result<()> {
+        self.writer.write_all(buf)?;
+        self.writer.flush()?;
+        self.last_write = Instant::now();
+        Ok(())
+    }
+}
+
+impl<'w, 'a> fmt::Debug for SseStream<'w, 'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<SSE Stream keep_alive={:?}>", self.keep_alive)
+    }
+}
+
+impl<'a> Response<'a> {
+    /// Answers with a Server-Sent Events stream: `f` runs once the head is
+    /// sent and pushes events until it returns or the client disconnects.
+    /// Intermediaries are asked not to buffer or cache the stream, and the
+    /// server's minimum write rate doesn't apply to it.
+    pub fn sse<F>(&mut self, f: F) -> io::Result<()>
+    where
+        F: FnOnce(&mut SseStream) -> io::Result<()> + 'static,
+    {
+        self.header("Content-Type: text/event-stream");
+        self.header("Cache-Control: no-cache");
+        // nginx buffers proxied responses unless told otherwise
+        self.header("X-Accel-Buffering: no");
+        self.stream(move |writer| {
+            writer.unmetered();
+            let mut events = SseStream {
+                writer,
+                keep_alive: DEFAULT_KEEP_ALIVE,
+                last_write: Instant::now(),
+            };
+            f(&mut events)
+        })
+    }
+}
+
+fn field(buf: &mut BytesMut, name: &str, value: &str) {
+    buf.extend_from_slice(name.as_bytes());
+    buf.put_u8(b':');
+    if !value.is_empty() {
+        buf.put_u8(b' ');
+        buf.extend_from_slice(value.as_bytes());
+    }
+    buf.put_u8(b'\n');
+}
+
+fn lines(s: &str) -> impl Iterator<Item = &str> {
+    s.split("\r\n").flat_map(|s| s.split(['\r', '\n']))
+}
+
+// a line break would end the field early and start another
+fn single_line(s: &str) -> String {
+    s.replace(['\r', '\n'], " ")
+}
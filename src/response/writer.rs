@@ -0,0 +1,198 @@
+use std::fmt::{self, Write as _};
+use std::io::{self, Write};
+use std::time::{Duration, Instant};
+
+use bytes::BytesMut;
+use may::net::TcpStream;
+
+use crate::http::http_server::write_some;
+use crate::http::memory::BufferGauge;
+use crate::http::socket;
+use crate::request::context::YieldEvery;
+use crate::server::config::{MinWriteRate, ServerConfig};
+
+const DEFAULT_HIGH_WATER_MARK: usize = 4096 * 8;
+
+pub(crate) type StreamBody = Box<dyn FnOnce(&mut BodyWriter) -> io::Result<()> + 'static>;
+
+/// Chunked body writer handed to `Response::stream` producers; to an
+/// HTTP/1.0 client it writes the bytes unframed instead.
+///
+/// Framed chunks are buffered up to the high water mark; past that, `write`
+/// blocks the coroutine until the client drains the socket, so a producer
+/// can never outrun a slow reader by more than one buffer.
+pub struct BodyWriter<'a> {
+    stream: &'a mut TcpStream,
+    // framed chunks not yet accepted by the socket
+    out: BytesMut,
+    high_water_mark: usize,
+    progress: WriteProgress,
+    gauge: BufferGauge,
+    chunked: bool,
+}
+
+impl<'a> BodyWriter<'a> {
+    pub(crate) fn new(stream: &'a mut TcpStream, config: &ServerConfig) -> Self {
+        BodyWriter {
+            stream,
+            out: BytesMut::with_capacity(DEFAULT_HIGH_WATER_MARK),
+            high_water_mark: DEFAULT_HIGH_WATER_MARK,
+            progress: WriteProgress::new(config),
+            gauge: BufferGauge::default(),
+            chunked: true,
+        }
+    }
+
+    /// Bytes buffered but not yet written to the socket.
+    #[inline]
+    pub fn buffered(&self) -> usize {
+        self.out.len()
+    }
+
+    #[inline]
+    pub fn high_water_mark(&self) -> usize {
+        self.high_water_mark
+    }
+
+    #[inline]
+    pub fn set_high_water_mark(&mut self, n: usize) -> &mut Self {
+        self.high_water_mark = n.max(1);
+        self
+    }
+
+    /// Pushes buffered bytes to the socket without blocking and reports
+    /// whether the writer is below its high water mark, i.e. whether the
+    /// producer may keep going without waiting on the client.
+    pub fn poll_write_ready(&mut self) -> io::Result<bool> {
+        #[cfg(unix)]
+        {
+            let n =
+                crate::http::http_server::nonblock_write(self.stream.inner_mut(), &mut self.out)?;
+            self.progress.record(n)?;
+        }
+        Ok(self.out.len() < self.high_water_mark)
+    }
+
+    /// Whether the client is still there: buffered bytes are pushed out
+    /// without blocking and the socket is checked for a close or reset. A
+    /// client that shut down its sending side counts as gone. Cheap enough
+    /// to call between chunks, so a producer of costly data can stop once
+    /// nobody is reading it.
+    pub fn is_connected(&mut self) -> bool {
+        self.poll_write_ready().is_ok() && !socket::peer_gone(self.stream)
+    }
+
+    // long-lived, mostly idle streams would always fall below the minimum
+    // write rate
+    pub(crate) fn unmetered(&mut self) {
+        self.progress.min_rate = None;
+    }
+
+    // an HTTP/1.0 client reads the body as is, up to the connection's close
+    pub(crate) fn unchunked(&mut self) {
+        self.chunked = false;
+    }
+
+    // a failed drain leaves `out` holding just the unsent bytes
+    fn drain(&mut self) -> io::Result<()> {
+        while !self.out.is_empty() {
+            let n = write_some(self.stream, &mut self.out)?;
+            self.progress.record(n)?;
+        }
+        Ok(())
+    }
+
+    pub(crate) fn finish(mut self) -> io::Result<()> {
+        if self.chunked {
+            self.out.extend_from_slice(b"0\r\n\r\n");
+        }
+        self.drain()?;
+        self.stream.flush()
+    }
+}
+
+impl<'a> Write for BodyWriter<'a> {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        // an empty chunk would terminate the body
+        if buf.is_empty() {
+            return Ok(0);
+        }
+        if self.out.len() >= self.high_water_mark {
+            self.drain()?;
+        }
+        if self.chunked {
+            write!(self.out, "{:X}\r\n", buf.len()).map_err(|_| io::ErrorKind::Other)?;
+            self.out.extend_from_slice(buf);
+            self.out.extend_from_slice(b"\r\n");
+        } else {
+            self.out.extend_from_slice(buf);
+        }
+        self.gauge.set(self.out.capacity());
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.drain()?;
+        self.stream.flush()
+    }
+}
+
+impl<'a> fmt::Debug for BodyWriter<'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<HTTP BodyWriter buffered={}>", self.out.len())
+    }
+}
+
+// enforces the write deadline and minimum throughput on a response that is
+// waiting for the client to read it
+pub(crate) struct WriteProgress {
+    fair: YieldEvery,
+    started: Instant,
+    last_progress: Instant,
+    written: usize,
+    timeout: Option<Duration>,
+    min_rate: Option<MinWriteRate>,
+}
+
+impl WriteProgress {
+    pub(crate) fn new(config: &ServerConfig) -> Self {
+        let now = Instant::now();
+        WriteProgress {
+            fair: YieldEvery::new(config.yield_every.unwrap_or(0)),
+            started: now,
+            last_progress: now,
+            written: 0,
+            timeout: config.write_timeout,
+            min_rate: config.min_write_rate,
+        }
+    }
+
+    pub(crate) fn record(&mut self, n: usize) -> io::Result<()> {
+        self.fair.advance(n);
+        if self.timeout.is_none() && self.min_rate.is_none() {
+            return Ok(());
+        }
+        let now = Instant::now();
+        if n > 0 {
+            self.written += n;
+            self.last_progress = now;
+        }
+        if let Some(timeout) = self.timeout {
+            if now - self.last_progress > timeout {
+                return Err(io::Error::new(io::ErrorKind::TimedOut, "write timed out"));
+            }
+        }
+        if let Some(rate) = self.min_rate {
+            let elapsed = now - self.started;
+            if elapsed > rate.grace
+                && (self.written as f64) < rate.bytes_per_sec as f64 * elapsed.as_secs_f64()
+            {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "client below minimum write rate",
+                ));
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,326 @@
+//! ZIP archives streamed to the client as their entries are produced
+
+use std::fmt;
+use std::io::{self, Write};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+#[cfg(feature = "deflate")]
+use flate2::write::DeflateEncoder;
+
+use crate::response::response::Response;
+use crate::response::writer::BodyWriter;
+
+const LOCAL_HEADER: u32 = 0x0403_4b50;
+const DATA_DESCRIPTOR: u32 = 0x0807_4b50;
+const CENTRAL_HEADER: u32 = 0x0201_4b50;
+const END_OF_CENTRAL_DIRECTORY: u32 = 0x0605_4b50;
+const VERSION: u16 = 20;
+// sizes and CRC follow the data (bit 3), names are UTF-8 (bit 11)
+const FLAGS: u16 = 0x0808;
+
+/// How an entry's bytes are kept in the archive.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ZipMethod {
+    /// As they are; best for data that's already compressed.
+    Stored,
+    /// Deflate-compressed, with the `deflate` feature.
+    #[cfg(feature = "deflate")]
+    Deflated,
+}
+
+impl ZipMethod {
+    fn code(self) -> u16 {
+        match self {
+            ZipMethod::Stored => 0,
+            #[cfg(feature = "deflate")]
+            ZipMethod::Deflated => 8,
+        }
+    }
+}
+
+/// A ZIP archive being written to a `Response::zip` body.
+///
+/// `entry` starts a file and writes go into it until the next `entry`;
+/// nothing is buffered beyond what compression needs. Without ZIP64,
+/// archives are limited to 65535 entries and 4 GiB; going past that fails
+/// the stream.
+pub struct ZipWriter<'w, 'a> {
+    writer: &'w mut BodyWriter<'a>,
+    // bytes written so far, where the next local header starts
+    offset: u64,
+    open: Option<Entry>,
+    // central directory records of the finished entries
+    directory: Vec<u8>,
+    entries: u64,
+    // DOS time and date every entry is stamped with
+    modified: (u16, u16),
+}
+
+struct Entry {
+    name: Vec<u8>,
+    method: ZipMethod,
+    offset: u64,
+    crc: u32,
+    size: u64,
+    compressed: u64,
+    #[cfg(feature = "deflate")]
+    encoder: Option<DeflateEncoder<Vec<u8>>>,
+}
+
+impl<'w, 'a> ZipWriter<'w, 'a> {
+    fn new(writer: &'w mut BodyWriter<'a>) -> Self {
+        ZipWriter {
+            writer,
+            offset: 0,
+            open: None,
+            directory: Vec::new(),
+            entries: 0,
+            modified: dos_time(SystemTime::now()),
+        }
+    }
+
+    /// Starts the file `name`, a `/`-separated path within the archive,
+    /// ending the previous one.
+    pub fn entry(&mut self, name: &str, method: ZipMethod) -> io::Result<()> {
+        self.close_entry()?;
+        let name = name.trim_start_matches('/').replace('\\', "/");
+        if name.is_empty() || name.split('/').any(|part| part == "..") {
+            let msg = format!("invalid ZIP entry name {:?}", name);
+            return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+        }
+        if name.len() > u16::MAX as usize {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "ZIP entry name too long",
+            ));
+        }
+        let mut header = Vec::with_capacity(30 + name.len());
+        put_u32(&mut header, LOCAL_HEADER);
+        put_u16(&mut header, VERSION);
+        put_u16(&mut header, FLAGS);
+        put_u16(&mut header, method.code());
+        put_u16(&mut header, self.modified.0);
+        put_u16(&mut header, self.modified.1);
+        // CRC and sizes, in the data descriptor instead
+        header.extend_from_slice(&[0; 12]);
+        put_u16(&mut header, name.len() as u16);
+        put_u16(&mut header, 0);
+        header.extend_from_slice(name.as_bytes());
+        let offset = self.offset;
+        self.emit(&header)?;
+        self.open = Some(Entry {
+            name: name.into_bytes(),
+            method,
+            offset,
+            crc: !0,
+            size: 0,
+            compressed: 0,
+            #[cfg(feature = "deflate")]
+            encoder: match method {
+                ZipMethod::Deflated => Some(DeflateEncoder::new(
+                    Vec::new(),
+                    flate2::Compression::default(),
+                )),
+                ZipMethod::Stored => None,
+            },
+        });
+        Ok(())
+    }
+
+    /// Adds a whole file at once.
+    pub fn file(&mut self, name: &str, method: ZipMethod, contents: &[u8]) -> io::Result<()> {
+        self.entry(name, method)?;
+        self.write_all(contents)
+    }
+
+    // bytes of the archive itself, counted for the offsets
+    fn emit(&mut self, bytes: &[u8]) -> io::Result<()> {
+        self.writer.write_all(bytes)?;
+        self.offset += bytes.len() as u64;
+        Ok(())
+    }
+
+    fn close_entry(&mut self) -> io::Result<()> {
+        let entry = match self.open.take() {
+            Some(entry) => entry,
+            None => return Ok(()),
+        };
+        #[cfg(feature = "deflate")]
+        let mut entry = entry;
+        #[cfg(feature = "deflate")]
+        if let Some(encoder) = entry.encoder.take() {
+            let tail = encoder.finish()?;
+            self.emit(&tail)?;
+            entry.compressed += tail.len() as u64;
+        }
+        let crc = !entry.crc;
+        let too_large = [entry.size, entry.compressed, entry.offset, self.offset]
+            .iter()
+            .any(|&n| n > u32::MAX as u64);
+        if too_large || self.entries == u16::MAX as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "ZIP archive too large without ZIP64",
+            ));
+        }
+        let mut descriptor = Vec::with_capacity(16);
+        put_u32(&mut descriptor, DATA_DESCRIPTOR);
+        put_u32(&mut descriptor, crc);
+        put_u32(&mut descriptor, entry.compressed as u32);
+        put_u32(&mut descriptor, entry.size as u32);
+        self.emit(&descriptor)?;
+
+        let record = &mut self.directory;
+        put_u32(record, CENTRAL_HEADER);
+        put_u16(record, VERSION);
+        put_u16(record, VERSION);
+        put_u16(record, FLAGS);
+        put_u16(record, entry.method.code());
+        put_u16(record, self.modified.0);
+        put_u16(record, self.modified.1);
+        put_u32(record, crc);
+        put_u32(record, entry.compressed as u32);
+        put_u32(record, entry.size as u32);
+        put_u16(record, entry.name.len() as u16);
+        // extra field, comment, disk number, internal and external attributes
+        record.extend_from_slice(&[0; 12]);
+        put_u32(record, entry.offset as u32);
+        record.extend_from_slice(&entry.name);
+        self.entries += 1;
+        Ok(())
+    }
+
+    fn finish(mut self) -> io::Result<()> {
+        self.close_entry()?;
+        let directory = std::mem::take(&mut self.directory);
+        let start = self.offset;
+        self.emit(&directory)?;
+        if self.offset > u32::MAX as u64 {
+            return Err(io::Error::new(
+                io::ErrorKind::Other,
+                "ZIP archive too large without ZIP64",
+            ));
+        }
+        let mut end = Vec::with_capacity(22);
+        put_u32(&mut end, END_OF_CENTRAL_DIRECTORY);
+        // this disk, and the one the directory starts on
+        put_u32(&mut end, 0);
+        put_u16(&mut end, self.entries as u16);
+        put_u16(&mut end, self.entries as u16);
+        put_u32(&mut end, directory.len() as u32);
+        put_u32(&mut end, start as u32);
+        put_u16(&mut end, 0);
+        self.emit(&end)
+    }
+}
+
+impl<'w, 'a> Write for ZipWriter<'w, 'a> {
+    // into the current entry; fails if none was started
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let entry = match &mut self.open {
+            Some(entry) => entry,
+            None => {
+                let msg = "ZIP write outside an entry";
+                return Err(io::Error::new(io::ErrorKind::InvalidInput, msg));
+            }
+        };
+        entry.crc = crc32(entry.crc, buf);
+        entry.size += buf.len() as u64;
+        #[cfg(feature = "deflate")]
+        if let Some(encoder) = &mut entry.encoder {
+            encoder.write_all(buf)?;
+            let compressed = std::mem::take(encoder.get_mut());
+            entry.compressed += compressed.len() as u64;
+            self.emit(&compressed)?;
+            return Ok(buf.len());
+        }
+        entry.compressed += buf.len() as u64;
+        self.emit(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.writer.flush()
+    }
+}
+
+impl<'w, 'a> fmt::Debug for ZipWriter<'w, 'a> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        write!(f, "<ZIP Writer entries={}>", self.entries)
+    }
+}
+
+impl<'a> Response<'a> {
+    /// Answers with a ZIP archive downloaded as `filename`: `f` runs once
+    /// the head is sent and adds entries as it produces them.
+    pub fn zip<F>(&mut self, filename: &str, f: F) -> io::Result<()>
+    where
+        F: FnOnce(&mut ZipWriter) -> io::Result<()> + 'static,
+    {
+        self.header("Content-Type: application/zip");
+        self.attachment(filename);
+        self.stream(move |writer| {
+            let mut zip = ZipWriter::new(writer);
+            f(&mut zip)?;
+            zip.finish()
+        })
+    }
+}
+
+fn put_u16(buf: &mut Vec<u8>, n: u16) {
+    buf.extend_from_slice(&n.to_le_bytes());
+}
+
+fn put_u32(buf: &mut Vec<u8>, n: u32) {
+    buf.extend_from_slice(&n.to_le_bytes());
+}
+
+// CRC-32 (IEEE), a nibble at a time; `crc` starts at !0 and is inverted
+// once done
+fn crc32(mut crc: u32, bytes: &[u8]) -> u32 {
+    const TABLE: [u32; 16] = [
+        0x0000_0000,
+        0x1db7_1064,
+        0x3b6e_20c8,
+        0x26d9_30ac,
+        0x76dc_4190,
+        0x6b6b_51f4,
+        0x4db2_6158,
+        0x5005_713c,
+        0xedb8_8320,
+        0xf00f_9344,
+        0xd6d6_a3e8,
+        0xcb61_b38c,
+        0x9b64_c2b0,
+        0x86d3_d2d4,
+        0xa00a_e278,
+        0xbdbd_f21c,
+    ];
+    for &byte in bytes {
+        crc = TABLE[((crc ^ byte as u32) & 0xf) as usize] ^ (crc >> 4);
+        crc = TABLE[((crc ^ (byte as u32 >> 4)) & 0xf) as usize] ^ (crc >> 4);
+    }
+    crc
+}
+
+// MS-DOS (time, date) in UTC; earlier than 1980 can't be represented
+fn dos_time(now: SystemTime) -> (u16, u16) {
+    let secs = now.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let (days, rem) = (secs / 86400, secs % 86400);
+    let time = (((rem / 3600) << 11) | ((rem % 3600 / 60) << 5) | (rem % 60 / 2)) as u16;
+    // civil date from days since 1970-01-01 (Howard Hinnant's algorithm)
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    if year < 1980 {
+        return (0, 0x21);
+    }
+    let date = (((year - 1980).min(127) << 9) | (month << 5) | day) as u16;
+    (time, date)
+}
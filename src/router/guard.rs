@@ -0,0 +1,95 @@
+//! predicates a route can require of a request, so one method and path can
+//! lead to different handlers
+
+use crate::request::headers::MediaType;
+use crate::request::request::RawRequest;
+
+/// A predicate over the request, attached with `RouteOptions::guard`; a
+/// route whose guards don't all pass is skipped for the next that matches.
+///
+/// Closures taking `&RawRequest` are guards; `from_fn` saves spelling out
+/// the argument type.
+pub trait Guard: Send + Sync {
+    fn check(&self, req: &RawRequest) -> bool;
+}
+
+impl<F> Guard for F
+where
+    F: Fn(&RawRequest) -> bool + Send + Sync,
+{
+    fn check(&self, req: &RawRequest) -> bool {
+        self(req)
+    }
+}
+
+/// A guard from a closure.
+pub fn from_fn<F>(f: F) -> impl Guard
+where
+    F: Fn(&RawRequest) -> bool + Send + Sync,
+{
+    f
+}
+
+/// Passes requests with a `name` header, whatever its value.
+pub fn header(name: &str) -> impl Guard {
+    let name = name.to_owned();
+    move |req: &RawRequest| {
+        req.headers()
+            .iter()
+            .any(|h| h.name.eq_ignore_ascii_case(&name))
+    }
+}
+
+/// Passes requests with a `name` header of `value`, ignoring case and
+/// surrounding whitespace.
+pub fn header_value(name: &str, value: &str) -> impl Guard {
+    let (name, value) = (name.to_owned(), value.trim().to_owned());
+    move |req: &RawRequest| {
+        req.headers().iter().any(|h| {
+            h.name.eq_ignore_ascii_case(&name)
+                && std::str::from_utf8(h.value)
+                    .map_or(false, |v| v.trim().eq_ignore_ascii_case(&value))
+        })
+    }
+}
+
+/// Passes requests whose query has `name`, with or without a value, e.g.
+/// `?preview` or `?preview=1`.
+pub fn query(name: &str) -> impl Guard {
+    let name = name.to_owned();
+    move |req: &RawRequest| {
+        let path = req.path();
+        let query = path.find('?').map_or("", |start| &path[start + 1..]);
+        query
+            .split('&')
+            .any(|pair| pair.split('=').next() == Some(name.as_str()))
+    }
+}
+
+/// Passes requests whose Content-Type is `range`, e.g. `application/json`
+/// or `image/*`, ignoring parameters.
+pub fn content_type(range: &str) -> impl Guard {
+    let range = MediaType::parse(range);
+    move |req: &RawRequest| {
+        let sent = req.header("content-type").and_then(MediaType::parse);
+        match (&range, sent) {
+            (Some(range), Some(sent)) => range.matches(sent.essence()),
+            _ => false,
+        }
+    }
+}
+
+/// Passes requests `guard` doesn't.
+pub fn not<G: Guard>(guard: G) -> impl Guard {
+    move |req: &RawRequest| !guard.check(req)
+}
+
+/// Passes requests both guards pass.
+pub fn all<A: Guard, B: Guard>(a: A, b: B) -> impl Guard {
+    move |req: &RawRequest| a.check(req) && b.check(req)
+}
+
+/// Passes requests either guard passes.
+pub fn any<A: Guard, B: Guard>(a: A, b: B) -> impl Guard {
+    move |req: &RawRequest| a.check(req) || b.check(req)
+}
@@ -1,17 +1,250 @@
-use std::collections::{HashMap, HashSet};
 use std::hash::{Hash, Hasher};
 use std::io;
+use std::ops::Range;
 use std::sync::Arc;
+use std::time::SystemTime;
 
-use crate::request::request::Request;
+use smallvec::SmallVec;
+
+use crate::request::request::{RawRequest, Request};
+use crate::router::guard::Guard;
+use crate::server::api_key::ApiKeys;
+use crate::server::auth::Auth;
+use crate::server::ip_filter::IpFilter;
+#[cfg(feature = "jwt")]
+use crate::server::jwt::Jwt;
+use crate::server::login_limit::LoginLimiter;
+use crate::server::rate_limit::RateLimiter;
+use crate::server::response_cache::ResponseCache;
+use crate::server::schema::JsonSchema;
+use crate::server::security_headers::SecurityHeaders;
+use crate::server::signing::RequestSigning;
 use crate::Response;
 
+// path parameters by name, with where their values lie in the request
+// target; kept inline for the usual handful
+pub(crate) type PathParams = SmallVec<[(Arc<str>, Range<usize>); 4]>;
+
 pub type RouteHandler =
     Box<dyn Fn(Request, &mut Response) -> io::Result<()> + Send + Sync + 'static>;
 
 #[derive(Clone)]
 pub struct RouteMatcher {
-    routes: HashSet<RouteNode>,
+    routes: Vec<RouteNode>,
+}
+
+/// How `Server::trailing_slash` treats `/foo/` against a route registered
+/// as `/foo`, and the other way round.
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub enum TrailingSlash {
+    /// Both match, and are served as requested.
+    #[default]
+    Ignore,
+    /// Both match, and the form the route wasn't registered with is
+    /// redirected to the other with 308.
+    Redirect,
+    /// Only the registered form matches; the other gets 404.
+    Strict,
+}
+
+// how request paths are compared with route patterns
+#[derive(Clone, Copy, Debug, Default)]
+pub(crate) struct RoutePolicy {
+    pub(crate) trailing_slash: TrailingSlash,
+    pub(crate) ignore_case: bool,
+}
+
+/// Per-route settings, returned when a route is registered.
+#[derive(Clone, Default)]
+pub struct RouteOptions {
+    pub(crate) guards: Vec<Arc<dyn Guard>>,
+    pub(crate) max_body_size: Option<usize>,
+    pub(crate) audited: bool,
+    pub(crate) login_limiter: Option<LoginLimiter>,
+    pub(crate) ip_filter: Option<IpFilter>,
+    pub(crate) rate_limit: Option<RateLimiter>,
+    pub(crate) maintenance_exempt: bool,
+    pub(crate) json_schema: Option<JsonSchema>,
+    // documented responses, by status
+    pub(crate) responses: Vec<(u16, JsonSchema)>,
+    pub(crate) security_headers: Option<SecurityHeaders>,
+    pub(crate) cache: Option<ResponseCache>,
+    pub(crate) auth: Option<Auth>,
+    pub(crate) api_key: Option<ApiKeys>,
+    pub(crate) signing: Option<RequestSigning>,
+    #[cfg(feature = "jwt")]
+    pub(crate) jwt: Option<Jwt>,
+    pub(crate) summary: Option<String>,
+    pub(crate) description: Option<String>,
+    pub(crate) tags: Vec<String>,
+    pub(crate) deprecated: Option<SystemTime>,
+    pub(crate) sunset: Option<SystemTime>,
+    pub(crate) deprecation_link: Option<String>,
+}
+
+impl RouteOptions {
+    /// Serves only requests `guard` passes; others fall through to the
+    /// next route that matches, e.g. one for the same method and path
+    /// registered after this one. Registering a method and path again
+    /// replaces an unguarded route, so its guarded variants come first.
+    pub fn guard<G: Guard + 'static>(&mut self, guard: G) -> &mut Self {
+        self.guards.push(Arc::new(guard));
+        self
+    }
+
+    /// Overrides the server-wide request body limit for this route.
+    pub fn max_body_size(&mut self, limit: usize) -> &mut Self {
+        self.max_body_size = Some(limit);
+        self
+    }
+
+    /// Records every call to this route in the server's audit log, e.g.
+    /// for admin endpoints.
+    pub fn audited(&mut self) -> &mut Self {
+        self.audited = true;
+        self
+    }
+
+    /// Backs off clients whose requests here keep failing with 401.
+    pub fn login_limiter(&mut self, limiter: &LoginLimiter) -> &mut Self {
+        self.login_limiter = Some(limiter.clone());
+        self
+    }
+
+    /// Refuses clients `filter` doesn't permit here, e.g. on admin
+    /// endpoints, on top of the server-wide filter.
+    pub fn ip_filter(&mut self, filter: &IpFilter) -> &mut Self {
+        self.ip_filter = Some(filter.clone());
+        self
+    }
+
+    /// Keeps serving this route in maintenance mode, e.g. health checks or
+    /// the admin route that toggles it.
+    pub fn maintenance_exempt(&mut self) -> &mut Self {
+        self.maintenance_exempt = true;
+        self
+    }
+
+    /// Limits this route with `limiter` instead of the server-wide one.
+    pub fn rate_limit(&mut self, limiter: &RateLimiter) -> &mut Self {
+        self.rate_limit = Some(limiter.clone());
+        self
+    }
+
+    /// Requires Basic or Bearer credentials `auth` accepts, answering 401
+    /// before the handler runs otherwise.
+    pub fn auth(&mut self, auth: &Auth) -> &mut Self {
+        self.auth = Some(auth.clone());
+        self
+    }
+
+    /// Requires an API key `keys` accepts, answering 401, 403 or 429
+    /// before the handler runs otherwise.
+    pub fn api_key(&mut self, keys: &ApiKeys) -> &mut Self {
+        self.api_key = Some(keys.clone());
+        self
+    }
+
+    /// Requires requests signed with a key `signing` knows, answering 401
+    /// before the handler runs otherwise.
+    pub fn signed(&mut self, signing: &RequestSigning) -> &mut Self {
+        self.signing = Some(signing.clone());
+        self
+    }
+
+    /// Requires a bearer JWT `jwt` verifies, answering 401, or 403 for a
+    /// missing scope, before the handler runs otherwise.
+    #[cfg(feature = "jwt")]
+    pub fn jwt(&mut self, jwt: &Jwt) -> &mut Self {
+        self.jwt = Some(jwt.clone());
+        self
+    }
+
+    /// Answers 422 to request bodies that aren't JSON matching `schema`,
+    /// before the handler runs.
+    pub fn json_schema(&mut self, schema: &JsonSchema) -> &mut Self {
+        self.json_schema = Some(schema.clone());
+        self
+    }
+
+    /// Documents JSON matching `schema` as the body of `status` responses
+    /// in the OpenAPI document; it isn't checked. Calling it again for the
+    /// same status replaces the schema.
+    pub fn response_schema(&mut self, status: u16, schema: &JsonSchema) -> &mut Self {
+        self.responses.retain(|(s, _)| *s != status);
+        self.responses.push((status, schema.clone()));
+        self
+    }
+
+    /// Sends `headers` on this route's responses instead of the server's
+    /// `Server::security_headers`.
+    pub fn security_headers(&mut self, headers: &SecurityHeaders) -> &mut Self {
+        self.security_headers = Some(headers.clone());
+        self
+    }
+
+    /// Serves this route's GET responses from `cache` while they're fresh;
+    /// see `ResponseCache` for what is stored.
+    pub fn cache(&mut self, cache: &ResponseCache) -> &mut Self {
+        self.cache = Some(cache.clone());
+        self
+    }
+
+    /// A one-line description, for `Server::routes` and `Server::openapi`.
+    pub fn summary(&mut self, summary: &str) -> &mut Self {
+        self.summary = Some(summary.to_owned());
+        self
+    }
+
+    pub fn description(&mut self, description: &str) -> &mut Self {
+        self.description = Some(description.to_owned());
+        self
+    }
+
+    /// Groups the route under `tag` in the OpenAPI document; may be called
+    /// more than once.
+    pub fn tag(&mut self, tag: &str) -> &mut Self {
+        if !self.tags.iter().any(|t| t == tag) {
+            self.tags.push(tag.to_owned());
+        }
+        self
+    }
+
+    /// Marks the route deprecated as of `since`, announced to clients with
+    /// a `Deprecation` header (RFC 9745).
+    pub fn deprecated(&mut self, since: SystemTime) -> &mut Self {
+        self.deprecated = Some(since);
+        self
+    }
+
+    /// Announces with a `Sunset` header (RFC 8594) that the route goes away
+    /// at `at`.
+    pub fn sunset(&mut self, at: SystemTime) -> &mut Self {
+        self.sunset = Some(at);
+        self
+    }
+
+    /// Points deprecation notices at `url`, e.g. a migration guide, with a
+    /// `Link` header of relation `deprecation`.
+    pub fn deprecation_link(&mut self, url: &str) -> &mut Self {
+        self.deprecation_link = Some(url.to_owned());
+        self
+    }
+}
+
+/// A registered route and its documentation, as `Server::routes` lists
+/// them.
+#[derive(Clone, Debug)]
+pub struct RouteInfo {
+    pub method: String,
+    /// The pattern it was registered with, e.g. `/users/:id`.
+    pub path: String,
+    pub summary: Option<String>,
+    pub description: Option<String>,
+    pub tags: Vec<String>,
+    pub deprecated: Option<SystemTime>,
+    pub sunset: Option<SystemTime>,
+    pub deprecation_link: Option<String>,
 }
 
 struct RouteNode {
@@ -19,6 +252,7 @@ struct RouteNode {
     handler: Arc<RouteHandler>,
     path: String,
     segments: Vec<Segment>,
+    options: Arc<RouteOptions>,
 }
 
 impl Clone for RouteNode {
@@ -28,6 +262,7 @@ impl Clone for RouteNode {
             path: self.path.clone(),
             segments: self.segments.clone(),
             handler: Arc::clone(&self.handler),
+            options: Arc::clone(&self.options),
         }
     }
 }
@@ -35,7 +270,7 @@ impl Clone for RouteNode {
 #[derive(PartialEq, Eq, Hash, Clone)]
 enum Segment {
     Static(String),
-    Parameter(String),
+    Parameter(Arc<str>),
     Wildcard,
 }
 
@@ -57,25 +292,28 @@ impl Eq for RouteNode {}
 pub struct MatchedRoute {
     pub method: String,
     pub path: String,
-    pub parameters: HashMap<String, String>,
-    pub url_parameters: HashMap<String, String>,
+    pub(crate) parameters: PathParams,
     pub handler: Arc<RouteHandler>,
+    pub options: Arc<RouteOptions>,
 }
 
 impl RouteMatcher {
     pub fn new() -> RouteMatcher {
-        RouteMatcher {
-            routes: HashSet::new(),
-        }
+        RouteMatcher { routes: Vec::new() }
     }
 
-    pub fn add_route(&mut self, method: &str, path: &str, handler: RouteHandler) {
+    pub fn add_route(
+        &mut self,
+        method: &str,
+        path: &str,
+        handler: RouteHandler,
+    ) -> &mut RouteOptions {
         let segments = path
             .split('/')
             .filter(|s| !s.is_empty())
             .map(|s| {
                 if s.starts_with(':') {
-                    Segment::Parameter(s[1..].to_string())
+                    Segment::Parameter(Arc::from(&s[1..]))
                 } else if s == "*" {
                     Segment::Wildcard
                 } else {
@@ -83,45 +321,81 @@ impl RouteMatcher {
                 }
             })
             .collect::<Vec<_>>();
-        self.routes.insert(RouteNode {
+        let route = RouteNode {
             method: method.to_string(),
             path: path.to_string(),
             segments,
             handler: Arc::new(handler),
-        });
+            options: Arc::new(RouteOptions::default()),
+        };
+        // registering the same method and path again replaces the route,
+        // unless it's guarded and so one of several
+        let replaced = self
+            .routes
+            .iter()
+            .position(|r| *r == route && r.options.guards.is_empty());
+        let index = match replaced {
+            Some(index) => {
+                self.routes[index] = route;
+                index
+            }
+            None => {
+                self.routes.push(route);
+                self.routes.len() - 1
+            }
+        };
+        Arc::make_mut(&mut self.routes[index].options)
     }
 
-    pub fn match_route(&self, method: &str, url: &str) -> Option<MatchedRoute> {
-        let (path, query_string) = url.split_at(url.find('?').unwrap_or_else(|| url.len()));
-        let segments = path
-            .split('/')
-            .filter(|s| !s.is_empty())
-            .collect::<Vec<_>>();
+    // method, pattern and options of each route, in registration order
+    pub(crate) fn iter(&self) -> impl Iterator<Item = (&str, &str, &RouteOptions)> {
+        self.routes
+            .iter()
+            .map(|route| (route.method.as_str(), route.path.as_str(), &*route.options))
+    }
 
-        for route in &self.routes {
-            if let Some(parameters) = route.match_segments(&segments) {
-                let url_parameters = query_string
-                    .trim_start_matches('?')
-                    .split('&')
-                    .filter(|s| !s.is_empty())
-                    .map(|s| {
-                        let mut parts = s.split('=');
-                        (
-                            parts.next().unwrap().to_string(),
-                            parts.next().unwrap_or("").to_string(),
-                        )
-                    })
-                    .collect::<HashMap<_, _>>();
+    pub(crate) fn routes(&self) -> Vec<RouteInfo> {
+        self.iter()
+            .map(|(method, path, options)| RouteInfo {
+                method: method.to_owned(),
+                path: path.to_owned(),
+                summary: options.summary.clone(),
+                description: options.description.clone(),
+                tags: options.tags.clone(),
+                deprecated: options.deprecated,
+                sunset: options.sunset,
+                deprecation_link: options.deprecation_link.clone(),
+            })
+            .collect()
+    }
 
+    pub(crate) fn match_route(
+        &self,
+        req: &RawRequest,
+        policy: RoutePolicy,
+    ) -> Option<MatchedRoute> {
+        let (method, url) = (req.method(), req.path());
+        let (path, _) = url.split_at(url.find('?').unwrap_or_else(|| url.len()));
+        let segments = split_segments(path);
+        let slash = path.len() > 1 && path.ends_with('/');
+
+        for route in &self.routes {
+            if policy.trailing_slash == TrailingSlash::Strict && route.slash() != Some(slash) {
+                continue;
+            }
+            if let Some(parameters) = route.match_segments(&segments, policy.ignore_case) {
                 if &route.method != method && route.method != "*" {
                     continue;
                 }
+                if !route.options.guards.iter().all(|guard| guard.check(req)) {
+                    continue;
+                }
                 return Some(MatchedRoute {
                     method: route.method.clone(),
                     path: route.path.clone(),
                     parameters,
-                    url_parameters,
                     handler: Arc::clone(&route.handler),
+                    options: Arc::clone(&route.options),
                 });
             }
         }
@@ -131,23 +405,38 @@ impl RouteMatcher {
 }
 
 impl RouteNode {
-    fn match_segments(&self, segments: &[&str]) -> Option<HashMap<String, String>> {
+    // whether the pattern ends in a slash; wildcard patterns take either
+    fn slash(&self) -> Option<bool> {
+        if self.segments.contains(&Segment::Wildcard) {
+            return None;
+        }
+        Some(self.path.len() > 1 && self.path.ends_with('/'))
+    }
+
+    // `segments` come with where each starts in the request target, which
+    // parameter values are then found by
+    fn match_segments(&self, segments: &[(usize, &str)], ignore_case: bool) -> Option<PathParams> {
         if self.segments.len() != segments.len() && !self.segments.contains(&Segment::Wildcard) {
             return None;
         }
 
-        let mut parameters = HashMap::new();
+        let mut parameters = PathParams::new();
         let mut wildcard = false;
 
-        for (route_segment, segment) in self.segments.iter().zip(segments.iter()) {
+        for (route_segment, &(start, segment)) in self.segments.iter().zip(segments.iter()) {
             match route_segment {
                 Segment::Static(s) => {
-                    if s != segment {
+                    let matches = if ignore_case {
+                        s.eq_ignore_ascii_case(segment)
+                    } else {
+                        s == segment
+                    };
+                    if !matches {
                         return None;
                     }
                 }
                 Segment::Parameter(param) => {
-                    parameters.insert(param.clone(), (*segment).to_string());
+                    parameters.push((param.clone(), start..start + segment.len()));
                 }
                 Segment::Wildcard => {
                     wildcard = true;
@@ -165,3 +454,112 @@ impl RouteNode {
         }
     }
 }
+
+// the non-empty segments of `path`, each with the offset it starts at
+fn split_segments(path: &str) -> SmallVec<[(usize, &str); 16]> {
+    let mut start = 0;
+    path.split('/')
+        .filter_map(|segment| {
+            let at = start;
+            start += segment.len() + 1;
+            (!segment.is_empty()).then_some((at, segment))
+        })
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use super::*;
+    use crate::server::server::Server;
+    use crate::test::TestClient;
+
+    fn parameters(server: &Server, path: &str) -> String {
+        TestClient::new(server).get(path).send().text().to_owned()
+    }
+
+    fn server() -> Server {
+        let mut server = Server::new();
+        let list = |req: Request, res: &mut Response| {
+            let found = req
+                .parameters()
+                .map(|(name, value)| format!("{}={}", name, value))
+                .collect::<Vec<_>>();
+            res.send(found.join(" "))
+        };
+        server.get("/orgs/:org/users/:id", list);
+        server.get("/static/:bucket/*", list);
+        server
+    }
+
+    #[test]
+    fn segments_keep_their_offsets() {
+        assert_eq!(&split_segments("/a//bc/")[..], [(1, "a"), (4, "bc")]);
+        assert!(split_segments("/").is_empty());
+    }
+
+    #[test]
+    fn parameters_of_a_multi_parameter_route() {
+        let server = server();
+        assert_eq!(parameters(&server, "/orgs/acme/users/7"), "org=acme id=7");
+        assert_eq!(
+            parameters(&server, "/orgs/acme/users/7?org=other&id=8"),
+            "org=acme id=7"
+        );
+    }
+
+    #[test]
+    fn parameters_before_a_wildcard() {
+        let server = server();
+        assert_eq!(parameters(&server, "/static/img/a/b.png"), "bucket=img");
+        assert_eq!(
+            parameters(&server, "/static/css/site.css?v=2"),
+            "bucket=css"
+        );
+    }
+
+    #[test]
+    fn parameter_maps_hold_what_the_fields_did() {
+        let mut server = Server::new();
+        server.get("/orgs/:org/users/:id", |req, res| {
+            let pairs = |pairs: &[(&str, &str)]| {
+                pairs
+                    .iter()
+                    .map(|&(key, value)| (key.to_owned(), value.to_owned()))
+                    .collect::<HashMap<_, _>>()
+            };
+            assert_eq!(req.parameter_map(), pairs(&[("org", "acme"), ("id", "7")]));
+            assert_eq!(req.url_parameter_map(), pairs(&[("v", "2"), ("q", "")]));
+            res.send("ok")
+        });
+        let res = TestClient::new(&server)
+            .get("/orgs/acme/users/7?v=1&q&v=2")
+            .send();
+        assert_eq!(res.status(), 200);
+    }
+
+    // another global allocator leaves nothing to count with
+    #[cfg(not(any(feature = "jemalloc", feature = "mimalloc")))]
+    #[test]
+    fn matching_a_few_parameters_allocates_nothing() {
+        use crate::testing::allocations;
+
+        let route = |path: &str| {
+            let mut matcher = RouteMatcher::new();
+            matcher.add_route("GET", path, Box::new(|_, _| Ok(())));
+            matcher.routes.remove(0)
+        };
+        let few = route("/orgs/:org/users/:id/posts/:post");
+        let segments = split_segments("/orgs/acme/users/7/posts/9");
+        assert_eq!(
+            allocations(|| few.match_segments(&segments, false)).count,
+            0
+        );
+        assert_eq!(allocations(|| split_segments("/a/b/c/d/e/f/g/h")).count, 0);
+        // past the inline four they spill, which the count has to see
+        let many = route("/:a/:b/:c/:d/:e");
+        let segments = split_segments("/1/2/3/4/5");
+        assert!(allocations(|| many.match_segments(&segments, false)).count > 0);
+    }
+}
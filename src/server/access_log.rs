@@ -0,0 +1,208 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, Write};
+use std::net::IpAddr;
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+use crate::request::request::RawRequest;
+use crate::server::flags::FeatureFlags;
+
+const MONTHS: [&str; 12] = [
+    "Jan", "Feb", "Mar", "Apr", "May", "Jun", "Jul", "Aug", "Sep", "Oct", "Nov", "Dec",
+];
+
+/// Line format for the stderr and file sinks.
+#[derive(Clone, Copy, Debug)]
+pub enum LogFormat {
+    /// Common Log Format.
+    Common,
+    /// Common Log Format plus referer and user agent.
+    Combined,
+    /// One JSON object per line, with latency and feature flags.
+    Json,
+}
+
+/// Per-request access log, installed with `Server::access_log`.
+#[derive(Clone)]
+pub struct AccessLog {
+    sink: Arc<Sink>,
+}
+
+enum Sink {
+    Stderr(LogFormat),
+    File(LogFormat, Mutex<File>),
+    Callback(Box<dyn Fn(&AccessEntry) + Send + Sync>),
+}
+
+impl AccessLog {
+    pub fn stderr(format: LogFormat) -> Self {
+        AccessLog {
+            sink: Arc::new(Sink::Stderr(format)),
+        }
+    }
+
+    /// Appends to `path`, creating it if needed.
+    pub fn file<P: AsRef<Path>>(path: P, format: LogFormat) -> io::Result<Self> {
+        let file = OpenOptions::new().append(true).create(true).open(path)?;
+        Ok(AccessLog {
+            sink: Arc::new(Sink::File(format, Mutex::new(file))),
+        })
+    }
+
+    /// Hands every entry to `f`, e.g. to ship it elsewhere.
+    pub fn callback<F>(f: F) -> Self
+    where
+        F: Fn(&AccessEntry) + Send + Sync + 'static,
+    {
+        AccessLog {
+            sink: Arc::new(Sink::Callback(Box::new(f))),
+        }
+    }
+
+    pub(crate) fn write(&self, entry: &AccessEntry) {
+        match &*self.sink {
+            Sink::Stderr(format) => eprintln!("{}", entry.format(*format)),
+            Sink::File(format, file) => {
+                let mut line = entry.format(*format);
+                line.push('\n');
+                if let Err(e) = file.lock().unwrap().write_all(line.as_bytes()) {
+                    error!("access log write failed: {}", e);
+                }
+            }
+            Sink::Callback(f) => f(entry),
+        }
+    }
+}
+
+/// One served request.
+#[derive(Clone, Debug)]
+pub struct AccessEntry {
+    pub time: SystemTime,
+    pub request_id: String,
+    pub client_ip: Option<IpAddr>,
+    pub method: String,
+    pub path: String,
+    /// Pattern of the route that served it, e.g. `/users/:id`.
+    pub route: Option<String>,
+    pub version: u8,
+    pub status: usize,
+    /// Body bytes, unknown for streamed bodies.
+    pub bytes: Option<usize>,
+    pub latency: Duration,
+    pub referer: Option<String>,
+    pub user_agent: Option<String>,
+    /// Prefix of the API key the request authenticated with.
+    pub api_key: Option<String>,
+    pub flags: FeatureFlags,
+}
+
+impl AccessEntry {
+    pub(crate) fn new(req: &RawRequest, request_id: &str) -> Self {
+        AccessEntry {
+            time: SystemTime::now(),
+            request_id: request_id.to_owned(),
+            client_ip: req.remote_addr().map(|addr| addr.ip()),
+            method: req.method().to_owned(),
+            path: req.path().to_owned(),
+            route: None,
+            version: req.version(),
+            status: 0,
+            bytes: None,
+            latency: Duration::ZERO,
+            referer: req.header("referer").map(str::to_owned),
+            user_agent: req.header("user-agent").map(str::to_owned),
+            api_key: None,
+            flags: FeatureFlags::default(),
+        }
+    }
+
+    pub fn format(&self, format: LogFormat) -> String {
+        match format {
+            LogFormat::Common => self.common(),
+            LogFormat::Combined => format!(
+                "{} \"{}\" \"{}\"",
+                self.common(),
+                escape(self.referer.as_deref().unwrap_or("-")),
+                escape(self.user_agent.as_deref().unwrap_or("-")),
+            ),
+            LogFormat::Json => self.json(),
+        }
+    }
+
+    fn common(&self) -> String {
+        let client = self.client_ip.map_or("-".to_owned(), |ip| ip.to_string());
+        let bytes = self.bytes.map_or("-".to_owned(), |n| n.to_string());
+        // the API key prefix stands in for the authenticated user
+        format!(
+            "{} - {} [{}] \"{} {} HTTP/1.{}\" {} {}",
+            client,
+            self.api_key.as_deref().unwrap_or("-"),
+            clf_time(self.time),
+            self.method,
+            escape(&self.path),
+            self.version,
+            self.status,
+            bytes
+        )
+    }
+
+    fn json(&self) -> String {
+        let flags: serde_json::Map<String, serde_json::Value> = self
+            .flags
+            .iter()
+            .map(|(name, on)| (name.to_owned(), on.into()))
+            .collect();
+        let time = self
+            .time
+            .duration_since(UNIX_EPOCH)
+            .map_or(0.0, |since| since.as_secs_f64());
+        serde_json::json!({
+            "time": time,
+            "request_id": self.request_id,
+            "client_ip": self.client_ip.map(|ip| ip.to_string()),
+            "method": self.method,
+            "path": self.path,
+            "route": self.route,
+            "version": format!("HTTP/1.{}", self.version),
+            "status": self.status,
+            "bytes": self.bytes,
+            "latency_ms": self.latency.as_secs_f64() * 1000.0,
+            "referer": self.referer,
+            "user_agent": self.user_agent,
+            "api_key": self.api_key,
+            "flags": flags,
+        })
+        .to_string()
+    }
+}
+
+// keep quoted fields on one line and unambiguous
+fn escape(s: &str) -> String {
+    s.replace('\\', "\\\\").replace('"', "\\\"")
+}
+
+// "10/Oct/2000:13:55:36 +0000"
+fn clf_time(time: SystemTime) -> String {
+    let secs = time.duration_since(UNIX_EPOCH).map_or(0, |d| d.as_secs());
+    let (days, rem) = (secs / 86_400, secs % 86_400);
+    // days since the epoch to a civil date, after Howard Hinnant
+    let z = days as i64 + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z - era * 146_097;
+    let yoe = (doe - doe / 1_460 + doe / 36_524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + i64::from(month <= 2);
+    format!(
+        "{:02}/{}/{}:{:02}:{:02}:{:02} +0000",
+        day,
+        MONTHS[month as usize - 1],
+        year,
+        rem / 3_600,
+        rem % 3_600 / 60,
+        rem % 60
+    )
+}
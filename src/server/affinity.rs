@@ -0,0 +1,89 @@
+//! pinning of may worker threads to CPUs
+
+use std::io;
+
+use may::{coroutine, go};
+
+#[derive(Clone, Debug)]
+pub(crate) enum WorkerPinning {
+    // worker `i` runs on `cpus[i % cpus.len()]`
+    Cpus(Vec<usize>),
+    // every worker may run on any CPU of the node
+    NumaNode(usize),
+}
+
+// pin each of the `workers` may threads; runs a coroutine bound to every
+// worker which sets the affinity of the thread it lands on
+pub(crate) fn pin_workers(workers: usize, pinning: &WorkerPinning) -> io::Result<()> {
+    let node_cpus = match pinning {
+        WorkerPinning::Cpus(cpus) if cpus.is_empty() => {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no cpus to pin to",
+            ))
+        }
+        WorkerPinning::Cpus(_) => Vec::new(),
+        WorkerPinning::NumaNode(node) => node_cpus(*node)?,
+    };
+    let handles = (0..workers)
+        .map(|id| {
+            let cpus = match pinning {
+                WorkerPinning::Cpus(cpus) => vec![cpus[id % cpus.len()]],
+                WorkerPinning::NumaNode(_) => node_cpus.clone(),
+            };
+            go!(
+                coroutine::Builder::new().id(id),
+                move || pin_current_thread(&cpus)
+            )
+        })
+        .collect::<io::Result<Vec<_>>>()?;
+    for handle in handles {
+        handle
+            .join()
+            .map_err(|_| io::Error::new(io::ErrorKind::Other, "worker pinning panicked"))??;
+    }
+    Ok(())
+}
+
+fn node_cpus(node: usize) -> io::Result<Vec<usize>> {
+    let path = format!("/sys/devices/system/node/node{node}/cpulist");
+    parse_cpu_list(std::fs::read_to_string(path)?.trim())
+}
+
+// kernel cpu list format, e.g. "0-3,8-11"
+fn parse_cpu_list(list: &str) -> io::Result<Vec<usize>> {
+    let invalid = || io::Error::new(io::ErrorKind::InvalidData, format!("bad cpu list: {list}"));
+    let mut cpus = Vec::new();
+    for range in list.split(',').filter(|range| !range.is_empty()) {
+        let (first, last) = range.split_once('-').unwrap_or((range, range));
+        let first: usize = first.parse().map_err(|_| invalid())?;
+        let last: usize = last.parse().map_err(|_| invalid())?;
+        cpus.extend(first..=last);
+    }
+    if cpus.is_empty() {
+        return Err(invalid());
+    }
+    Ok(cpus)
+}
+
+#[cfg(target_os = "linux")]
+fn pin_current_thread(cpus: &[usize]) -> io::Result<()> {
+    unsafe {
+        let mut set: libc::cpu_set_t = std::mem::zeroed();
+        for &cpu in cpus {
+            libc::CPU_SET(cpu, &mut set);
+        }
+        if libc::sched_setaffinity(0, std::mem::size_of::<libc::cpu_set_t>(), &set) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+#[cfg(not(target_os = "linux"))]
+fn pin_current_thread(_: &[usize]) -> io::Result<()> {
+    Err(io::Error::new(
+        io::ErrorKind::Unsupported,
+        "worker pinning is only supported on Linux",
+    ))
+}
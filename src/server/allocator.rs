@@ -0,0 +1,39 @@
+//! optional global allocator, picked with the `jemalloc` or `mimalloc` feature
+
+#[cfg(all(feature = "jemalloc", feature = "mimalloc"))]
+compile_error!("features `jemalloc` and `mimalloc` are mutually exclusive");
+
+#[cfg(feature = "jemalloc")]
+#[global_allocator]
+static GLOBAL: tikv_jemallocator::Jemalloc = tikv_jemallocator::Jemalloc;
+
+#[cfg(all(feature = "mimalloc", not(feature = "jemalloc")))]
+#[global_allocator]
+static GLOBAL: mimalloc::MiMalloc = mimalloc::MiMalloc;
+
+/// Heap usage as reported by the allocator.
+#[derive(Clone, Copy, Debug)]
+pub struct AllocatorStats {
+    /// Bytes currently allocated by the application.
+    pub allocated: usize,
+    /// Bytes in physically resident pages mapped by the allocator.
+    pub resident: usize,
+}
+
+#[cfg(feature = "jemalloc")]
+pub(crate) fn stats() -> Option<AllocatorStats> {
+    use tikv_jemalloc_ctl::{epoch, stats};
+
+    // jemalloc caches its statistics until the epoch advances
+    epoch::advance().ok()?;
+    Some(AllocatorStats {
+        allocated: stats::allocated::read().ok()?,
+        resident: stats::resident::read().ok()?,
+    })
+}
+
+// the system allocator and mimalloc don't report statistics
+#[cfg(not(feature = "jemalloc"))]
+pub(crate) fn stats() -> Option<AllocatorStats> {
+    None
+}
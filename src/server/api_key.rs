@@ -0,0 +1,193 @@
+//! API keys for machine-to-machine clients
+
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, RwLock};
+
+use sha2::{Digest, Sha256};
+
+use crate::errors::http_error::HttpError;
+use crate::http::platform::random_bytes;
+use crate::request::request::Request;
+use crate::response::respond::IntoResponse;
+use crate::response::response::Response;
+use crate::server::rate_limit::{MemoryStore, RateLimit, RateLimitStore};
+use crate::server::secrets::{constant_time_eq, hex};
+
+/// A client's API key as stored: the public prefix that identifies it and
+/// a hash of the whole key, never the key itself.
+///
+/// Keys are `<prefix>.<secret>`; the prefix is safe to log and look up,
+/// the secret only ever checked against the hash.
+#[derive(Clone, Debug)]
+pub struct ApiKey {
+    pub prefix: String,
+    /// SHA-256 of the whole key.
+    pub hash: Vec<u8>,
+    /// Who the key was issued to.
+    pub client: String,
+    pub scopes: Vec<String>,
+    /// This key's own limit, if any.
+    pub rate_limit: Option<RateLimit>,
+}
+
+impl ApiKey {
+    /// Issues a new key for `client`, returning the record to store and
+    /// the key to hand over, which can't be recovered later.
+    pub fn generate(client: &str) -> io::Result<(ApiKey, String)> {
+        let prefix = format!("ak_{}", hex(&random_bytes(6)?));
+        let key = format!("{}.{}", prefix, hex(&random_bytes(32)?));
+        let record = ApiKey {
+            prefix,
+            hash: Sha256::digest(&key).to_vec(),
+            client: client.to_owned(),
+            scopes: Vec::new(),
+            rate_limit: None,
+        };
+        Ok((record, key))
+    }
+
+    /// The prefix of `key`, e.g. to log which key a request used before
+    /// it's checked.
+    pub fn prefix_of(key: &str) -> Option<&str> {
+        let (prefix, secret) = key.split_once('.')?;
+        (!prefix.is_empty() && !secret.is_empty()).then_some(prefix)
+    }
+
+    pub fn has_scope(&self, scope: &str) -> bool {
+        self.scopes.iter().any(|s| s == scope)
+    }
+
+    fn matches(&self, key: &str) -> bool {
+        constant_time_eq(&Sha256::digest(key), &self.hash)
+    }
+}
+
+/// Where API keys are kept, looked up by prefix.
+pub trait ApiKeyStore: Send + Sync {
+    fn find(&self, prefix: &str) -> io::Result<Option<ApiKey>>;
+}
+
+/// Any `Fn(&str) -> io::Result<Option<ApiKey>>`, e.g. a database query.
+impl<F> ApiKeyStore for F
+where
+    F: Fn(&str) -> io::Result<Option<ApiKey>> + Send + Sync,
+{
+    fn find(&self, prefix: &str) -> io::Result<Option<ApiKey>> {
+        self(prefix)
+    }
+}
+
+/// Keys in process memory. Clones share their keys, so they can be added
+/// and revoked while the server runs.
+#[derive(Clone, Default)]
+pub struct MemoryApiKeys {
+    keys: Arc<RwLock<HashMap<String, ApiKey>>>,
+}
+
+impl MemoryApiKeys {
+    pub fn new() -> Self {
+        MemoryApiKeys::default()
+    }
+
+    /// Adds `key`, replacing one with the same prefix.
+    pub fn insert(&self, key: ApiKey) {
+        self.keys.write().unwrap().insert(key.prefix.clone(), key);
+    }
+
+    pub fn revoke(&self, prefix: &str) -> Option<ApiKey> {
+        self.keys.write().unwrap().remove(prefix)
+    }
+}
+
+impl ApiKeyStore for MemoryApiKeys {
+    fn find(&self, prefix: &str) -> io::Result<Option<ApiKey>> {
+        Ok(self.keys.read().unwrap().get(prefix).cloned())
+    }
+}
+
+/// API key authentication, installed with `RouteOptions::api_key`.
+///
+/// Requests send their key in the `X-API-Key` header. Those without a
+/// valid one get 401, those whose key lacks a required scope 403, and
+/// those over their key's rate limit 429. Otherwise the key's `ApiKey` is
+/// attached to the request, and its prefix logged as the access log's
+/// user. Clones share their rate limit buckets.
+#[derive(Clone)]
+pub struct ApiKeys {
+    store: Arc<dyn ApiKeyStore>,
+    header: String,
+    scopes: Vec<String>,
+    limits: Arc<dyn RateLimitStore>,
+}
+
+impl ApiKeys {
+    pub fn new<S: ApiKeyStore + 'static>(store: S) -> Self {
+        ApiKeys {
+            store: Arc::new(store),
+            header: "x-api-key".to_owned(),
+            scopes: Vec::new(),
+            limits: Arc::new(MemoryStore::default()),
+        }
+    }
+
+    /// Reads keys from header `name` instead of `X-API-Key`.
+    pub fn header(&mut self, name: &str) -> &mut Self {
+        self.header = name.to_ascii_lowercase();
+        self
+    }
+
+    /// Requires keys to carry `scope`; may be called more than once.
+    pub fn scope(&mut self, scope: &str) -> &mut Self {
+        self.scopes.push(scope.to_owned());
+        self
+    }
+
+    /// Keeps the per-key rate limit buckets in `store`, e.g. one shared by
+    /// several instances.
+    pub fn rate_limit_store<S: RateLimitStore + 'static>(&mut self, store: S) -> &mut Self {
+        self.limits = Arc::new(store);
+        self
+    }
+
+    // attaches the key, or answers 401, 403 or 429 and says so; failing
+    // to look the key up fails the request
+    pub(crate) fn check(&self, req: &mut Request, res: &mut Response) -> io::Result<bool> {
+        let presented = req.header(&self.header).map(|key| key.trim().to_owned());
+        let error = match self.verify(presented.as_deref())? {
+            Ok(key) => {
+                req.insert_extension(key);
+                return Ok(true);
+            }
+            Err(error) => error,
+        };
+        req.req.reject_body();
+        error.into_response(res)?;
+        Ok(false)
+    }
+
+    fn verify(&self, presented: Option<&str>) -> io::Result<Result<ApiKey, HttpError>> {
+        let presented = match presented {
+            Some(presented) => presented,
+            None => return Ok(Err(HttpError::Unauthorized("API key required".to_owned()))),
+        };
+        let key = match ApiKey::prefix_of(presented) {
+            Some(prefix) => self.store.find(prefix)?,
+            None => None,
+        };
+        let key = match key {
+            Some(key) if key.matches(presented) => key,
+            _ => return Ok(Err(HttpError::Unauthorized("invalid API key".to_owned()))),
+        };
+        if !self.scopes.iter().all(|scope| key.has_scope(scope)) {
+            return Ok(Err(HttpError::Forbidden("insufficient scope".to_owned())));
+        }
+        if let Some(limit) = key.rate_limit {
+            let bucket = format!("api_key:{}", key.prefix);
+            if let Err(wait) = self.limits.acquire(&bucket, limit) {
+                return Ok(Err(HttpError::RateLimited { retry_after: wait }));
+            }
+        }
+        Ok(Ok(key))
+    }
+}
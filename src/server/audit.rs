@@ -0,0 +1,125 @@
+use std::fs::{File, OpenOptions};
+use std::io::{self, BufRead, BufReader, Write};
+use std::path::Path;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use serde_json::{Map, Value};
+use sha2::{Digest, Sha256};
+
+// `prev` of the first entry in a log
+const GENESIS: &str = "0000000000000000000000000000000000000000000000000000000000000000";
+
+/// Append-only trail of security-relevant events, kept apart from access
+/// logs.
+///
+/// Every line is a JSON object holding the SHA-256 of the line before it,
+/// so editing, reordering or dropping an entry breaks the chain from that
+/// point on; `AuditLog::verify` finds where.
+#[derive(Clone)]
+pub struct AuditLog {
+    chain: Arc<Mutex<Chain>>,
+}
+
+struct Chain {
+    file: File,
+    seq: u64,
+    prev: String,
+}
+
+impl AuditLog {
+    /// Opens `path` for appending, continuing the chain already in it.
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let file = OpenOptions::new()
+            .read(true)
+            .append(true)
+            .create(true)
+            .open(path)?;
+        let (seq, prev) = match last_entry(&file)? {
+            Some(entry) => {
+                let seq = entry.get("seq").and_then(Value::as_u64);
+                let hash = entry.get("hash").and_then(Value::as_str);
+                match (seq, hash) {
+                    (Some(seq), Some(hash)) => (seq + 1, hash.to_owned()),
+                    _ => return Err(corrupt("last entry has no seq or hash")),
+                }
+            }
+            None => (0, GENESIS.to_owned()),
+        };
+        Ok(AuditLog {
+            chain: Arc::new(Mutex::new(Chain { file, seq, prev })),
+        })
+    }
+
+    /// Appends `event` with free-form `detail`, e.g. an auth failure
+    /// reported by the application.
+    pub fn record(&self, event: &str, detail: Value) -> io::Result<()> {
+        let time = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .map_or(0, |since| since.as_secs());
+        let mut chain = self.chain.lock().unwrap();
+        let mut entry = Map::new();
+        entry.insert("seq".to_owned(), chain.seq.into());
+        entry.insert("time".to_owned(), time.into());
+        entry.insert("event".to_owned(), event.into());
+        entry.insert("detail".to_owned(), detail);
+        entry.insert("prev".to_owned(), chain.prev.clone().into());
+        let hash = digest(&entry)?;
+        entry.insert("hash".to_owned(), hash.clone().into());
+
+        let mut line = serde_json::to_vec(&entry)?;
+        line.push(b'\n');
+        // one write per entry, so a crash can't interleave half lines
+        chain.file.write_all(&line)?;
+        chain.seq += 1;
+        chain.prev = hash;
+        Ok(())
+    }
+
+    /// Checks the chain in `path`; returns the `seq` of the first entry
+    /// that doesn't follow from the one before it, if any.
+    pub fn verify<P: AsRef<Path>>(path: P) -> io::Result<Option<u64>> {
+        let mut prev = GENESIS.to_owned();
+        for (seq, line) in BufReader::new(File::open(path)?).lines().enumerate() {
+            let seq = seq as u64;
+            let mut entry: Map<String, Value> = match serde_json::from_str(&line?) {
+                Ok(entry) => entry,
+                Err(_) => return Ok(Some(seq)),
+            };
+            let hash = match entry.remove("hash") {
+                Some(Value::String(hash)) => hash,
+                _ => return Ok(Some(seq)),
+            };
+            let linked = entry.get("seq").and_then(Value::as_u64) == Some(seq)
+                && entry.get("prev").and_then(Value::as_str) == Some(prev.as_str());
+            if !linked || digest(&entry)? != hash {
+                return Ok(Some(seq));
+            }
+            prev = hash;
+        }
+        Ok(None)
+    }
+}
+
+// hex SHA-256 of the entry without its `hash`; map keys serialize sorted
+fn digest(entry: &Map<String, Value>) -> io::Result<String> {
+    let hash = Sha256::digest(serde_json::to_vec(entry)?);
+    Ok(hash.iter().map(|b| format!("{:02x}", b)).collect())
+}
+
+fn last_entry(file: &File) -> io::Result<Option<Map<String, Value>>> {
+    let mut last = None;
+    for line in BufReader::new(file).lines() {
+        last = Some(line?);
+    }
+    match last {
+        Some(line) => serde_json::from_str(&line)
+            .map(Some)
+            .map_err(|_| corrupt("last entry is not valid JSON")),
+        None => Ok(None),
+    }
+}
+
+fn corrupt(msg: &'static str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, format!("audit log: {}", msg))
+}
@@ -0,0 +1,178 @@
+use std::sync::Arc;
+
+use crate::errors::http_error::HttpError;
+use crate::request::extensions::Extensions;
+use crate::request::request::Request;
+use crate::response::respond::IntoResponse;
+use crate::response::response::Response;
+use crate::util::base64;
+
+// checks credentials and, if they're good, attaches the principal
+type BasicFn = Arc<dyn Fn(&str, &str, &mut Extensions) -> bool + Send + Sync>;
+type BearerFn = Arc<dyn Fn(&str, &mut Extensions) -> bool + Send + Sync>;
+
+/// Basic and Bearer authentication, installed with `RouteOptions::auth`.
+///
+/// Requests without valid credentials for one of the configured schemes
+/// get 401 with a `WWW-Authenticate` challenge per scheme, the Bearer one
+/// carrying an RFC 6750 error code once a token was sent; a malformed
+/// Bearer token gets 400 with `invalid_request`. Otherwise the
+/// principal the validator returned is attached to the request, where the
+/// handler finds it with `req.extension::<P>()`. Validators should compare
+/// secrets in constant time. Basic sends the password with every request,
+/// so only use it behind TLS.
+#[derive(Clone)]
+pub struct Auth {
+    realm: String,
+    basic: Option<BasicFn>,
+    bearer: Option<BearerFn>,
+}
+
+impl Auth {
+    /// No schemes yet; `realm` names the protected area in challenges.
+    pub fn new(realm: &str) -> Self {
+        Auth {
+            realm: realm.replace(['"', '\\'], ""),
+            basic: None,
+            bearer: None,
+        }
+    }
+
+    /// Accepts Basic credentials `f` returns a principal for, given the
+    /// user name and password.
+    pub fn basic<P, F>(&mut self, f: F) -> &mut Self
+    where
+        P: Send + Sync + 'static,
+        F: Fn(&str, &str) -> Option<P> + Send + Sync + 'static,
+    {
+        self.basic = Some(Arc::new(move |user, password, extensions| {
+            f(user, password).map_or(false, |principal| {
+                extensions.insert(principal);
+                true
+            })
+        }));
+        self
+    }
+
+    /// Accepts Bearer tokens `f` returns a principal for.
+    pub fn bearer<P, F>(&mut self, f: F) -> &mut Self
+    where
+        P: Send + Sync + 'static,
+        F: Fn(&str) -> Option<P> + Send + Sync + 'static,
+    {
+        self.bearer = Some(Arc::new(move |token, extensions| {
+            f(token).map_or(false, |principal| {
+                extensions.insert(principal);
+                true
+            })
+        }));
+        self
+    }
+
+    // attaches the principal, or answers 401 and says so
+    pub(crate) fn check(&self, req: &mut Request, res: &mut Response) -> bool {
+        let credentials = req.authorization().map(|auth| {
+            let scheme = auth.scheme().to_ascii_lowercase();
+            (scheme, auth.credentials().to_owned())
+        });
+        let mut refused = None;
+        if let Some((scheme, credentials)) = &credentials {
+            match (scheme.as_str(), &self.basic, &self.bearer) {
+                ("basic", Some(basic), _) => {
+                    let decoded = base64(credentials).and_then(|b| String::from_utf8(b).ok());
+                    if let Some((user, password)) =
+                        decoded.as_deref().and_then(|d| d.split_once(':'))
+                    {
+                        if basic(user, password, &mut req.extensions) {
+                            return true;
+                        }
+                    }
+                }
+                ("bearer", _, Some(bearer)) => {
+                    if !is_b64token(credentials) {
+                        refused = Some(BearerError::InvalidRequest("malformed token"));
+                    } else if bearer(credentials, &mut req.extensions) {
+                        return true;
+                    } else {
+                        refused = Some(BearerError::InvalidToken("invalid token"));
+                    }
+                }
+                _ => {}
+            }
+        }
+        req.req.reject_body();
+        if self.basic.is_some() {
+            res.header_owned(format!(
+                "WWW-Authenticate: Basic realm=\"{}\", charset=\"UTF-8\"",
+                self.realm
+            ));
+        }
+        if self.bearer.is_some() {
+            res.header_owned(bearer_challenge(Some(&self.realm), refused.as_ref(), None));
+        }
+        let error = match (refused, credentials) {
+            (Some(BearerError::InvalidRequest(detail)), _) => {
+                HttpError::BadRequest(detail.to_owned())
+            }
+            (_, Some(_)) => HttpError::Unauthorized("invalid credentials".to_owned()),
+            (_, None) => HttpError::Unauthorized("authentication required".to_owned()),
+        };
+        if let Err(e) = error.into_response(res) {
+            warn!("failed to answer unauthenticated request: {}", e);
+        }
+        false
+    }
+}
+
+// why a bearer token was refused, as RFC 6750 codes it, with a description
+pub(crate) enum BearerError {
+    // the token isn't even well formed; answered 400
+    InvalidRequest(&'static str),
+    InvalidToken(&'static str),
+    #[cfg(feature = "jwt")]
+    InsufficientScope(&'static str),
+}
+
+impl BearerError {
+    fn code(&self) -> (&'static str, &'static str) {
+        match self {
+            BearerError::InvalidRequest(description) => ("invalid_request", description),
+            BearerError::InvalidToken(description) => ("invalid_token", description),
+            #[cfg(feature = "jwt")]
+            BearerError::InsufficientScope(description) => ("insufficient_scope", description),
+        }
+    }
+}
+
+// the `WWW-Authenticate: Bearer` header; a request that sent no token gets
+// no error code
+pub(crate) fn bearer_challenge(
+    realm: Option<&str>,
+    error: Option<&BearerError>,
+    scope: Option<&str>,
+) -> String {
+    let mut params = Vec::new();
+    if let Some(realm) = realm {
+        params.push(format!("realm=\"{}\"", realm));
+    }
+    if let Some((code, description)) = error.map(BearerError::code) {
+        params.push(format!("error=\"{}\"", code));
+        params.push(format!("error_description=\"{}\"", description));
+    }
+    if let Some(scope) = scope {
+        params.push(format!("scope=\"{}\"", scope.replace(['"', '\\'], "")));
+    }
+    match params.is_empty() {
+        true => "WWW-Authenticate: Bearer".to_owned(),
+        false => format!("WWW-Authenticate: Bearer {}", params.join(", ")),
+    }
+}
+
+// the token68 syntax RFC 6750 gives bearer tokens
+pub(crate) fn is_b64token(token: &str) -> bool {
+    let value = token.trim_end_matches('=');
+    !value.is_empty()
+        && value
+            .bytes()
+            .all(|b| b.is_ascii_alphanumeric() || b"-._~+/".contains(&b))
+}
@@ -0,0 +1,328 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde_json::{json, Value};
+use socket2::TcpKeepalive;
+
+use crate::errors::catalog::MessageCatalog;
+use crate::http::forwarded::IpNet;
+use crate::http::passthrough::Passthrough;
+use crate::request::json_limits::JsonLimits;
+#[cfg(feature = "templates")]
+use crate::response::render::Templates;
+use crate::router::route_matcher::RoutePolicy;
+use crate::server::access_log::AccessLog;
+use crate::server::affinity::WorkerPinning;
+use crate::server::audit::AuditLog;
+use crate::server::connection_admin::ConnectionAdmin;
+use crate::server::cors::Cors;
+use crate::server::csrf::Csrf;
+use crate::server::deprecation::DeprecationUsage;
+#[cfg(feature = "dev")]
+use crate::server::dev::DevMode;
+use crate::server::flags::FlagProvider;
+use crate::server::health::Health;
+use crate::server::hosts::AllowedHosts;
+use crate::server::ip_filter::IpFilter;
+use crate::server::maintenance::Maintenance;
+use crate::server::metrics::Metrics;
+use crate::server::rate_limit::RateLimiter;
+use crate::server::security_headers::SecurityHeaders;
+use crate::server::websocket::WebSocketOrigins;
+
+pub(crate) const DEFAULT_MAX_BODY_SIZE: usize = 2 * 1024 * 1024;
+pub(crate) const DEFAULT_MAX_HEADERS: usize = 64;
+pub(crate) const DEFAULT_MAX_HEADER_BYTES: usize = 16 * 1024;
+pub(crate) const DEFAULT_MAX_REQUEST_LINE: usize = 8 * 1024;
+pub(crate) const DEFAULT_SERVER_HEADER: &str = "M";
+pub(crate) const DEFAULT_SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(30);
+pub(crate) const DEFAULT_BACKLOG: i32 = 1024;
+pub(crate) const DEFAULT_WORKERS: usize = 8;
+pub(crate) const DEFAULT_BUFFER_SIZE: usize = 32 * 1024;
+pub(crate) const DEFAULT_MAX_BUFFER_SIZE: usize = 1024 * 1024;
+pub(crate) const MIN_BUFFER_SIZE: usize = 4 * 1024;
+
+#[derive(Clone)]
+pub struct ServerConfig {
+    pub(crate) max_body_size: usize,
+    pub(crate) write_timeout: Option<Duration>,
+    pub(crate) min_write_rate: Option<MinWriteRate>,
+    // bytes of a bulk transfer between yields to the worker's others
+    pub(crate) yield_every: Option<usize>,
+    pub(crate) linger: Option<Duration>,
+    pub(crate) nodelay: bool,
+    pub(crate) tcp_keepalive: Option<TcpKeepalive>,
+    pub(crate) abortive_close: bool,
+    pub(crate) max_headers: usize,
+    pub(crate) max_header_bytes: usize,
+    pub(crate) max_request_line: usize,
+    pub(crate) strict_requests: bool,
+    pub(crate) json_limits: JsonLimits,
+    pub(crate) server_header: Option<Arc<str>>,
+    pub(crate) backlog: Option<i32>,
+    pub(crate) reuseport: Option<usize>,
+    // a reuseport listener for each worker, unless `reuseport` says how many
+    pub(crate) accept_per_worker: bool,
+    pub(crate) reuseport_steering: bool,
+    pub(crate) pinning: Option<WorkerPinning>,
+    pub(crate) workers: usize,
+    // in bytes
+    pub(crate) stack_size: Option<usize>,
+    pub(crate) coroutine_pool: Option<usize>,
+    pub(crate) header_timeout: Option<Duration>,
+    pub(crate) body_read_timeout: Option<Duration>,
+    pub(crate) keep_alive_timeout: Option<Duration>,
+    pub(crate) memory_budget: Option<usize>,
+    pub(crate) buffer_size: usize,
+    pub(crate) max_buffer_size: usize,
+    // idle buffers kept for new connections
+    pub(crate) buffer_pool: usize,
+    pub(crate) max_requests: Option<usize>,
+    pub(crate) max_connection_age: Option<Duration>,
+    pub(crate) shutdown_timeout: Duration,
+    // accepted on next to the address passed to `start`
+    pub(crate) extra_addrs: Vec<String>,
+    // bound by someone else, e.g. systemd, and accepted on as they are
+    pub(crate) inherited: Vec<Arc<std::net::TcpListener>>,
+    pub(crate) audit: Option<AuditLog>,
+    pub(crate) error_catalog: Option<Arc<MessageCatalog>>,
+    #[cfg(feature = "templates")]
+    pub(crate) templates: Option<Templates>,
+    pub(crate) proxy_protocol: bool,
+    pub(crate) http2: bool,
+    // the HTTPS port every request is redirected to, serving nothing itself
+    pub(crate) https_redirect: Option<u16>,
+    pub(crate) passthrough: Passthrough,
+    pub(crate) explain_routes: bool,
+    pub(crate) routing: RoutePolicy,
+    pub(crate) response_timing: bool,
+    pub(crate) log_config: bool,
+    // the path the OpenAPI document is served at, with its title and version
+    pub(crate) openapi: Option<Arc<(String, String, String)>>,
+    pub(crate) trusted_proxies: Vec<IpNet>,
+    pub(crate) allowed_hosts: Option<AllowedHosts>,
+    pub(crate) ip_filter: Option<IpFilter>,
+    pub(crate) websocket_origins: Option<WebSocketOrigins>,
+    pub(crate) cors: Option<Cors>,
+    pub(crate) csrf: Option<Csrf>,
+    pub(crate) security_headers: Option<SecurityHeaders>,
+    pub(crate) rate_limit: Option<RateLimiter>,
+    pub(crate) maintenance: Option<Maintenance>,
+    pub(crate) max_connections: Option<(usize, AtCapacity)>,
+    pub(crate) flags: Option<Arc<dyn FlagProvider>>,
+    pub(crate) access_log: Option<AccessLog>,
+    pub(crate) metrics: Option<Metrics>,
+    pub(crate) connection_admin: Option<ConnectionAdmin>,
+    pub(crate) health: Option<Health>,
+    pub(crate) deprecation_usage: Option<DeprecationUsage>,
+    #[cfg(feature = "dev")]
+    pub(crate) dev: Option<DevMode>,
+}
+
+/// Minimum bytes per second a client must accept once `grace` has passed
+/// since a response started waiting on it.
+#[derive(Clone, Copy, Debug)]
+pub struct MinWriteRate {
+    pub bytes_per_sec: usize,
+    pub grace: Duration,
+}
+
+impl Default for ServerConfig {
+    fn default() -> Self {
+        ServerConfig {
+            max_body_size: DEFAULT_MAX_BODY_SIZE,
+            write_timeout: None,
+            min_write_rate: None,
+            yield_every: None,
+            linger: None,
+            nodelay: false,
+            tcp_keepalive: None,
+            abortive_close: false,
+            max_headers: DEFAULT_MAX_HEADERS,
+            max_header_bytes: DEFAULT_MAX_HEADER_BYTES,
+            max_request_line: DEFAULT_MAX_REQUEST_LINE,
+            strict_requests: false,
+            json_limits: JsonLimits::default(),
+            server_header: Some(Arc::from(DEFAULT_SERVER_HEADER)),
+            backlog: None,
+            reuseport: None,
+            accept_per_worker: false,
+            reuseport_steering: false,
+            pinning: None,
+            workers: DEFAULT_WORKERS,
+            stack_size: None,
+            coroutine_pool: None,
+            header_timeout: None,
+            body_read_timeout: None,
+            keep_alive_timeout: None,
+            memory_budget: None,
+            buffer_size: DEFAULT_BUFFER_SIZE,
+            max_buffer_size: DEFAULT_MAX_BUFFER_SIZE,
+            buffer_pool: 0,
+            max_requests: None,
+            max_connection_age: None,
+            shutdown_timeout: DEFAULT_SHUTDOWN_TIMEOUT,
+            extra_addrs: Vec::new(),
+            inherited: Vec::new(),
+            audit: None,
+            error_catalog: None,
+            #[cfg(feature = "templates")]
+            templates: None,
+            proxy_protocol: false,
+            http2: false,
+            https_redirect: None,
+            passthrough: Passthrough::default(),
+            explain_routes: false,
+            routing: RoutePolicy::default(),
+            response_timing: false,
+            log_config: false,
+            openapi: None,
+            trusted_proxies: Vec::new(),
+            allowed_hosts: None,
+            ip_filter: None,
+            websocket_origins: None,
+            cors: None,
+            csrf: None,
+            security_headers: None,
+            rate_limit: None,
+            maintenance: None,
+            max_connections: None,
+            flags: None,
+            access_log: None,
+            metrics: None,
+            connection_admin: None,
+            health: None,
+            deprecation_usage: None,
+            #[cfg(feature = "dev")]
+            dev: None,
+        }
+    }
+}
+
+impl ServerConfig {
+    // listeners per address sharing the port, when there's more than one
+    pub(crate) fn acceptors(&self) -> Option<usize> {
+        self.reuseport
+            .or_else(|| self.accept_per_worker.then_some(self.workers))
+    }
+
+    // the settings in effect, defaults filled in; components are only said
+    // to be there, so nothing they hold, e.g. keys, ends up in logs
+    pub(crate) fn describe(&self) -> Value {
+        let secs = |d: Option<Duration>| d.map(|d| d.as_secs_f64());
+        let (max_connections, at_capacity) = match self.max_connections {
+            Some((limit, AtCapacity::StopAccepting)) => (Some(limit), json!("stop_accepting")),
+            Some((limit, AtCapacity::Reject { retry_after })) => (
+                Some(limit),
+                json!({ "reject": { "retry_after_secs": retry_after.as_secs() } }),
+            ),
+            None => (None, Value::Null),
+        };
+        let passthrough = &self.passthrough;
+        let tls_passthrough: Vec<&str> = passthrough
+            .by_name
+            .iter()
+            .map(|(name, _)| name.as_str())
+            .collect();
+        let tcp_passthrough: Vec<String> = passthrough
+            .by_source
+            .iter()
+            .map(|(net, _)| net.to_string())
+            .collect();
+        #[cfg(feature = "templates")]
+        let templates = self.templates.is_some();
+        #[cfg(not(feature = "templates"))]
+        let templates = false;
+        #[cfg(feature = "dev")]
+        let dev = self.dev.is_some();
+        #[cfg(not(feature = "dev"))]
+        let dev = false;
+        json!({
+            "workers": self.workers,
+            "acceptors": self.acceptors().unwrap_or(1),
+            "reuseport_steering": self.reuseport_steering,
+            "worker_pinning": self.pinning.is_some(),
+            "stack_size": self.stack_size,
+            "coroutine_pool": self.coroutine_pool,
+            "extra_addrs": self.extra_addrs,
+            "inherited_listeners": self.inherited.len(),
+            "backlog": self.backlog.unwrap_or(DEFAULT_BACKLOG),
+            "nodelay": self.nodelay,
+            "tcp_keepalive": self.tcp_keepalive.is_some(),
+            "linger_secs": secs(self.linger),
+            "abortive_close": self.abortive_close,
+            "proxy_protocol": self.proxy_protocol,
+            "http2": self.http2,
+            "https_redirect_port": self.https_redirect,
+            "tls_passthrough": tls_passthrough,
+            "tcp_proxy": passthrough.tcp_proxy,
+            "tcp_passthrough": tcp_passthrough,
+            "tcp_fallback": passthrough.fallback,
+            "max_connections": max_connections,
+            "at_capacity": at_capacity,
+            "max_body_size": self.max_body_size,
+            "max_headers": self.max_headers,
+            "max_header_bytes": self.max_header_bytes,
+            "max_request_line": self.max_request_line,
+            "strict_requests": self.strict_requests,
+            "json_max_depth": self.json_limits.max_depth,
+            "json_max_tokens": self.json_limits.max_tokens,
+            "header_timeout_secs": secs(self.header_timeout),
+            "body_read_timeout_secs": secs(self.body_read_timeout),
+            "keep_alive_timeout_secs": secs(self.keep_alive_timeout),
+            "write_timeout_secs": secs(self.write_timeout),
+            "min_write_rate": self.min_write_rate.map(|rate| json!({
+                "bytes_per_sec": rate.bytes_per_sec,
+                "grace_secs": rate.grace.as_secs_f64(),
+            })),
+            "yield_every": self.yield_every,
+            "max_requests_per_connection": self.max_requests,
+            "max_connection_age_secs": secs(self.max_connection_age),
+            "shutdown_timeout_secs": self.shutdown_timeout.as_secs_f64(),
+            "memory_budget": self.memory_budget,
+            "buffer_size": self.buffer_size,
+            "max_buffer_size": self.max_buffer_size,
+            "buffer_pool": self.buffer_pool,
+            "server_header": self.server_header.as_deref(),
+            "trailing_slash": format!("{:?}", self.routing.trailing_slash),
+            "case_insensitive_routes": self.routing.ignore_case,
+            "explain_routes": self.explain_routes,
+            "response_timing": self.response_timing,
+            "trusted_proxies": self
+                .trusted_proxies
+                .iter()
+                .map(ToString::to_string)
+                .collect::<Vec<_>>(),
+            "components": {
+                "allowed_hosts": self.allowed_hosts.is_some(),
+                "ip_filter": self.ip_filter.is_some(),
+                "websocket_origins": self.websocket_origins.is_some(),
+                "cors": self.cors.is_some(),
+                "csrf": self.csrf.is_some(),
+                "security_headers": self.security_headers.is_some(),
+                "rate_limit": self.rate_limit.is_some(),
+                "maintenance": self.maintenance.is_some(),
+                "feature_flags": self.flags.is_some(),
+                "access_log": self.access_log.is_some(),
+                "audit_log": self.audit.is_some(),
+                "metrics": self.metrics.is_some(),
+                "connection_admin": self.connection_admin.is_some(),
+                "health": self.health.is_some(),
+                "deprecation_usage": self.deprecation_usage.is_some(),
+                "error_catalog": self.error_catalog.is_some(),
+                "templates": templates,
+                "dev": dev,
+            },
+        })
+    }
+}
+
+/// What the server does with new connections once `max_connections` are
+/// open.
+#[derive(Clone, Copy, Debug)]
+pub enum AtCapacity {
+    /// Leaves them in the listen backlog until a connection closes.
+    StopAccepting,
+    /// Answers 503 with this Retry-After and closes them.
+    Reject { retry_after: Duration },
+}
@@ -0,0 +1,97 @@
+//! finding and closing connections by the tags requests put on them
+
+use std::io;
+use std::sync::{Arc, Mutex, Weak};
+
+use crate::errors::http_error::HttpError;
+use crate::http::shutdown::{ConnectionInfo, Lifecycle};
+use crate::request::request::Request;
+use crate::response::respond::IntoResponse;
+use crate::response::response::Response;
+
+/// Lists and closes open connections by tag, once installed with
+/// `Server::connection_admin`, e.g. to cut off an abusive client or tenant
+/// while the server runs.
+///
+/// Handlers and middleware tag connections with `Request::tag_connection`.
+/// Closing shuts the socket down, so a request in progress fails at its
+/// next read or write. Clones share the servers they manage.
+#[derive(Clone, Default)]
+pub struct ConnectionAdmin {
+    servers: Arc<Mutex<Vec<Weak<Lifecycle>>>>,
+}
+
+impl ConnectionAdmin {
+    pub fn new() -> Self {
+        ConnectionAdmin::default()
+    }
+
+    /// The open connections tagged `key` = `value`.
+    pub fn tagged(&self, key: &str, value: &str) -> Vec<ConnectionInfo> {
+        self.lifecycles()
+            .iter()
+            .flat_map(|lifecycle| lifecycle.tagged(key, value))
+            .collect()
+    }
+
+    /// Closes the open connections tagged `key` = `value` and returns how
+    /// many there were.
+    pub fn disconnect(&self, key: &str, value: &str) -> usize {
+        self.lifecycles()
+            .iter()
+            .map(|lifecycle| lifecycle.disconnect(key, value))
+            .sum()
+    }
+
+    /// A handler for a route with `:key` and `:value` parameters, e.g.
+    /// `/admin/connections/:key/:value`, registered for GET to list the
+    /// matching connections and for DELETE to close them. Keep it behind
+    /// authentication.
+    pub fn endpoint(
+        &self,
+    ) -> impl Fn(Request, &mut Response) -> io::Result<()> + Send + Sync + 'static {
+        let admin = self.clone();
+        move |req, res| {
+            let (key, value) = match (req.url_parameter("key"), req.url_parameter("value")) {
+                (Some(key), Some(value)) => (key, value),
+                _ => {
+                    return HttpError::BadRequest("a tag key and value are required".to_owned())
+                        .into_response(res)
+                }
+            };
+            if req.method() == "DELETE" {
+                let closed = admin.disconnect(key, value);
+                info!("closed {} connections tagged {}={}", closed, key, value);
+                return res.json(&serde_json::json!({ "closed": closed }));
+            }
+            let connections: Vec<_> = admin
+                .tagged(key, value)
+                .into_iter()
+                .map(|connection| {
+                    let tags: serde_json::Map<_, _> = connection
+                        .tags
+                        .into_iter()
+                        .map(|(k, v)| (k, serde_json::Value::String(v)))
+                        .collect();
+                    serde_json::json!({
+                        "remote": connection.remote.map(|addr| addr.to_string()),
+                        "age_ms": connection.age.as_millis() as u64,
+                        "tags": tags,
+                    })
+                })
+                .collect();
+            res.json(&connections)
+        }
+    }
+
+    pub(crate) fn track(&self, lifecycle: &Arc<Lifecycle>) {
+        let mut servers = self.servers.lock().unwrap();
+        servers.retain(|server| server.strong_count() > 0);
+        servers.push(Arc::downgrade(lifecycle));
+    }
+
+    fn lifecycles(&self) -> Vec<Arc<Lifecycle>> {
+        let servers = self.servers.lock().unwrap();
+        servers.iter().filter_map(Weak::upgrade).collect()
+    }
+}
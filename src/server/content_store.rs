@@ -0,0 +1,114 @@
+//! uploads stored under the hash of their contents
+
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+
+use sha2::{Digest, Sha256};
+
+use crate::http::platform::random_bytes;
+use crate::request::request::Request;
+use crate::response::response::Response;
+use crate::server::secrets::hex;
+
+/// A directory of blobs, each named by the hex SHA-256 of its contents,
+/// as a building block for artifact stores.
+///
+/// Route a path to `upload` to accept blobs: the body is hashed while it
+/// streams to a temporary file, which then takes its hash as name. The
+/// same contents uploaded twice are stored once. Bound uploads with
+/// `RouteOptions::max_body_size`.
+#[derive(Clone, Debug)]
+pub struct ContentStore {
+    dir: PathBuf,
+}
+
+// a partial upload, removed unless it's kept
+struct Partial {
+    path: PathBuf,
+    kept: bool,
+}
+
+impl Drop for Partial {
+    fn drop(&mut self) {
+        if !self.kept {
+            fs::remove_file(&self.path).ok();
+        }
+    }
+}
+
+impl ContentStore {
+    /// Stores blobs in `dir`, creating it if needed.
+    pub fn new<P: AsRef<Path>>(dir: P) -> io::Result<Self> {
+        let dir = dir.as_ref().to_path_buf();
+        fs::create_dir_all(&dir)?;
+        Ok(ContentStore { dir })
+    }
+
+    /// Where the blob with hex SHA-256 `address` is stored, if it is;
+    /// anything but a well-formed address is `None`.
+    pub fn path(&self, address: &str) -> Option<PathBuf> {
+        let address = address.strip_prefix("sha256:").unwrap_or(address);
+        let well_formed = address.len() == 64
+            && address
+                .bytes()
+                .all(|b| b.is_ascii_digit() || (b'a'..=b'f').contains(&b));
+        let path = self.dir.join(address);
+        (well_formed && path.is_file()).then_some(path)
+    }
+
+    /// Streams the body into the store and returns its address.
+    pub fn store(&self, req: Request) -> io::Result<Stored> {
+        let mut partial = Partial {
+            path: self.dir.join(format!(".upload-{}", hex(&random_bytes(8)?))),
+            kept: false,
+        };
+        let mut file = File::create(&partial.path)?;
+        let mut body = req.body().digest(Sha256::new());
+        let size = io::copy(&mut body, &mut file)?;
+        file.flush()?;
+        file.sync_all()?;
+        let address = hex(&body.digest());
+        let path = self.dir.join(&address);
+        // identical contents are already there under the same name
+        let created = !path.is_file();
+        if created {
+            fs::rename(&partial.path, &path)?;
+            partial.kept = true;
+        }
+        Ok(Stored {
+            address: format!("sha256:{}", address),
+            size,
+            created,
+        })
+    }
+
+    /// A handler storing uploads, e.g. `server.put("/blobs",
+    /// store.upload())`. It answers 201 for new contents and 200 for ones
+    /// already stored, with `{"address": "sha256:<hex>", "size": <bytes>}`.
+    pub fn upload(
+        &self,
+    ) -> impl Fn(Request, &mut Response) -> io::Result<()> + Send + Sync + 'static {
+        let store = self.clone();
+        move |req, res| {
+            let stored = store.store(req)?;
+            if stored.created {
+                res.status_code(201, "Created");
+            }
+            res.json(&serde_json::json!({
+                "address": stored.address,
+                "size": stored.size,
+            }))
+        }
+    }
+}
+
+/// A blob `ContentStore::store` took in.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Stored {
+    /// `sha256:<hex>`.
+    pub address: String,
+    pub size: u64,
+    /// Whether the contents weren't stored before.
+    pub created: bool,
+}
@@ -0,0 +1,194 @@
+use std::borrow::Cow;
+use std::sync::Arc;
+use std::time::Duration;
+
+use crate::response::response::Response;
+
+type OriginFn = Arc<dyn Fn(&str) -> bool + Send + Sync>;
+type PolicyFn = Arc<dyn Fn(&str) -> Option<Cors> + Send + Sync>;
+
+/// Cross-origin resource sharing policy, installed with `Server::cors`.
+///
+/// Preflights from allowed origins are answered with 204 before routing;
+/// other responses to them get the allow headers and `Vary: Origin`.
+#[derive(Clone, Default)]
+pub struct Cors {
+    origins: Vec<String>,
+    origin_fn: Option<OriginFn>,
+    origin_policy: Option<PolicyFn>,
+    methods: Vec<String>,
+    headers: Vec<String>,
+    credentials: bool,
+    max_age: Option<Duration>,
+    private_network: bool,
+}
+
+impl Cors {
+    pub fn new() -> Self {
+        Cors::default()
+    }
+
+    /// Allows an origin: exact (`https://app.example.com`), with one `*`
+    /// wildcard (`https://*.example.com`), or `*` for any.
+    pub fn allow_origin(&mut self, origin: &str) -> &mut Self {
+        self.origins.push(origin.to_owned());
+        self
+    }
+
+    /// Allows origins `f` accepts, for rules patterns can't express.
+    pub fn allow_origin_fn<F>(&mut self, f: F) -> &mut Self
+    where
+        F: Fn(&str) -> bool + Send + Sync + 'static,
+    {
+        self.origin_fn = Some(Arc::new(f));
+        self
+    }
+
+    /// Decides origins the ones above don't allow at runtime, e.g. from a
+    /// tenant database: `f` gets the Origin and returns the policy for it,
+    /// or `None` to refuse it. That policy's methods, headers,
+    /// credentials, max age and private network access apply to the
+    /// request; its origins are ignored. `f` runs once per cross-origin
+    /// request, so slow lookups should be cached.
+    pub fn origin_policy<F>(&mut self, f: F) -> &mut Self
+    where
+        F: Fn(&str) -> Option<Cors> + Send + Sync + 'static,
+    {
+        self.origin_policy = Some(Arc::new(f));
+        self
+    }
+
+    /// Methods preflights may ask for; by default the one requested.
+    pub fn allow_methods(&mut self, methods: &[&str]) -> &mut Self {
+        self.methods = methods.iter().map(|m| m.to_string()).collect();
+        self
+    }
+
+    /// Request headers preflights may ask for; by default those requested.
+    pub fn allow_headers(&mut self, headers: &[&str]) -> &mut Self {
+        self.headers = headers.iter().map(|h| h.to_string()).collect();
+        self
+    }
+
+    /// Lets browsers send cookies and credentials cross-origin.
+    pub fn allow_credentials(&mut self, enabled: bool) -> &mut Self {
+        self.credentials = enabled;
+        self
+    }
+
+    /// How long browsers may cache a preflight answer; zero turns their
+    /// caching off. Browsers cap it, Chromium at two hours and Firefox at
+    /// a day, and without it cache for five seconds.
+    pub fn max_age(&mut self, max_age: Duration) -> &mut Self {
+        self.max_age = Some(max_age);
+        self
+    }
+
+    /// Lets allowed public origins reach this server on a private network
+    /// or loopback, answering Private Network Access preflights (those
+    /// with `Access-Control-Request-Private-Network: true`) with
+    /// `Access-Control-Allow-Private-Network: true`.
+    pub fn allow_private_network(&mut self, enabled: bool) -> &mut Self {
+        self.private_network = enabled;
+        self
+    }
+
+    // answer a preflight; disallowed origins get a bare 204 the browser
+    // will refuse
+    pub(crate) fn preflight(
+        &self,
+        origin: &str,
+        method: &str,
+        headers: Option<&str>,
+        private_network: bool,
+        res: &mut Response,
+    ) {
+        res.status_code(204, "No Content");
+        res.header("Vary: Origin, Access-Control-Request-Method, Access-Control-Request-Headers");
+        if self.private_network || self.origin_policy.is_some() {
+            res.header("Vary: Access-Control-Request-Private-Network");
+        }
+        let policy = match self.policy_for(origin) {
+            Some(policy) => policy,
+            None => return,
+        };
+        policy.allow_origin_headers(origin, res);
+        if private_network && policy.private_network {
+            res.header("Access-Control-Allow-Private-Network: true");
+        }
+        let methods = if policy.methods.is_empty() {
+            method.to_owned()
+        } else {
+            policy.methods.join(", ")
+        };
+        res.header_owned(format!("Access-Control-Allow-Methods: {}", methods));
+        let headers = if policy.headers.is_empty() {
+            headers.map(str::to_owned)
+        } else {
+            Some(policy.headers.join(", "))
+        };
+        if let Some(headers) = headers.filter(|h| !h.is_empty()) {
+            res.header_owned(format!("Access-Control-Allow-Headers: {}", headers));
+        }
+        if let Some(max_age) = policy.max_age {
+            res.header_owned(format!("Access-Control-Max-Age: {}", max_age.as_secs()));
+        }
+    }
+
+    // headers for a response to an actual cross-origin request
+    pub(crate) fn decorate(&self, origin: Option<&str>, res: &mut Response) {
+        // the answer depends on Origin unless every origin gets a bare `*`
+        if !self.any_origin() || self.credentials {
+            res.header("Vary: Origin");
+        }
+        if let Some(origin) = origin {
+            if let Some(policy) = self.policy_for(origin) {
+                policy.allow_origin_headers(origin, res);
+            }
+        }
+    }
+
+    // this policy when its own origins take `origin`, otherwise the one
+    // the callback gives, if any
+    fn policy_for(&self, origin: &str) -> Option<Cow<'_, Cors>> {
+        if self.allows(origin) {
+            return Some(Cow::Borrowed(self));
+        }
+        let policy = self.origin_policy.as_ref()?;
+        policy(origin).map(Cow::Owned)
+    }
+
+    fn allow_origin_headers(&self, origin: &str, res: &mut Response) {
+        // `*` can't be combined with credentials, so echo the origin then
+        if self.any_origin() && !self.credentials {
+            res.header("Access-Control-Allow-Origin: *");
+        } else {
+            res.header_owned(format!("Access-Control-Allow-Origin: {}", origin));
+        }
+        if self.credentials {
+            res.header("Access-Control-Allow-Credentials: true");
+        }
+    }
+
+    fn any_origin(&self) -> bool {
+        self.origins.iter().any(|o| o == "*")
+    }
+
+    fn allows(&self, origin: &str) -> bool {
+        self.origins
+            .iter()
+            .any(|pattern| origin_matches(pattern, origin))
+            || self.origin_fn.as_ref().map_or(false, |f| f(origin))
+    }
+}
+
+pub(crate) fn origin_matches(pattern: &str, origin: &str) -> bool {
+    match pattern.split_once('*') {
+        Some((prefix, suffix)) => {
+            origin.len() > prefix.len() + suffix.len()
+                && origin.starts_with(prefix)
+                && origin.ends_with(suffix)
+        }
+        None => pattern.eq_ignore_ascii_case(origin),
+    }
+}
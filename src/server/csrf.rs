@@ -0,0 +1,174 @@
+//! cross-site request forgery protection for cookie-authenticated apps
+
+use std::io;
+use std::sync::Arc;
+
+use crate::errors::http_error::HttpError;
+use crate::http::platform::random_bytes;
+use crate::request::request::{percent_decode, Request};
+use crate::response::respond::IntoResponse;
+use crate::response::response::Response;
+use crate::server::secrets::{constant_time_eq, hex, hmac_sha256, SecretsProvider};
+
+const NONCE_LEN: usize = 16;
+
+/// Signed double-submit CSRF protection, installed with `Server::csrf`.
+///
+/// Safe requests get a token cookie if they don't carry a valid one, and
+/// every request that passes has the token attached as a `CsrfToken` for
+/// the handler to put in its forms. Unsafe requests, e.g. POST, must send
+/// the cookie's token back in the `X-CSRF-Token` header or a `csrf_token`
+/// form field, or they get 403. Tokens are signed with the key the
+/// `SecretsProvider` holds, so a cookie planted from a sibling subdomain
+/// doesn't pass; rotating the key invalidates the outstanding ones.
+#[derive(Clone)]
+pub struct Csrf {
+    secrets: Arc<dyn SecretsProvider>,
+    key: String,
+    cookie: String,
+    header: String,
+    field: String,
+    secure: bool,
+    exempt: Vec<String>,
+}
+
+/// The request's CSRF token, to embed in forms or hand to scripts.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct CsrfToken(String);
+
+impl CsrfToken {
+    pub fn as_str(&self) -> &str {
+        &self.0
+    }
+}
+
+impl Csrf {
+    /// Signs tokens with the secret `key` names.
+    pub fn new<S: SecretsProvider + 'static>(secrets: S, key: &str) -> Self {
+        Csrf {
+            secrets: Arc::new(secrets),
+            key: key.to_owned(),
+            cookie: "csrf_token".to_owned(),
+            header: "x-csrf-token".to_owned(),
+            field: "csrf_token".to_owned(),
+            secure: true,
+            exempt: Vec::new(),
+        }
+    }
+
+    /// The token cookie's name, `csrf_token` by default.
+    pub fn cookie_name(&mut self, name: &str) -> &mut Self {
+        self.cookie = name.to_owned();
+        self
+    }
+
+    /// The header unsafe requests send the token in, `X-CSRF-Token` by
+    /// default.
+    pub fn header_name(&mut self, name: &str) -> &mut Self {
+        self.header = name.to_ascii_lowercase();
+        self
+    }
+
+    /// The form field unsafe requests send the token in, `csrf_token` by
+    /// default.
+    pub fn field_name(&mut self, name: &str) -> &mut Self {
+        self.field = name.to_owned();
+        self
+    }
+
+    /// Whether the cookie is marked Secure, on by default; turn it off
+    /// only to develop over plain HTTP.
+    pub fn secure(&mut self, secure: bool) -> &mut Self {
+        self.secure = secure;
+        self
+    }
+
+    /// Skips verification for paths starting with `prefix`, e.g. webhooks
+    /// that authenticate by signature instead of cookies.
+    pub fn exempt(&mut self, prefix: &str) -> &mut Self {
+        self.exempt.push(prefix.to_owned());
+        self
+    }
+
+    // attaches the token, issuing one if needed, or answers 403 and says
+    // so; failing to get the key fails the request
+    pub(crate) fn check(&self, req: &mut Request, res: &mut Response) -> io::Result<bool> {
+        let secret = self.secrets.secret(&self.key)?;
+        let cookie = req
+            .cookie(&self.cookie)
+            .filter(|token| signed(&secret, token))
+            .map(str::to_owned);
+        let safe = matches!(req.method(), "GET" | "HEAD" | "OPTIONS" | "TRACE");
+        let exempt = self
+            .exempt
+            .iter()
+            .any(|prefix| req.path().starts_with(prefix));
+        if !safe && !exempt {
+            let submitted = self.submitted(req)?;
+            let matches = match (&cookie, &submitted) {
+                (Some(cookie), Some(submitted)) => {
+                    constant_time_eq(cookie.as_bytes(), submitted.as_bytes())
+                }
+                _ => false,
+            };
+            if !matches {
+                let detail = match cookie {
+                    Some(_) => "CSRF token mismatch",
+                    None => "CSRF cookie missing or invalid",
+                };
+                req.req.reject_body();
+                HttpError::Forbidden(detail.to_owned()).into_response(res)?;
+                return Ok(false);
+            }
+        }
+        let token = match cookie {
+            Some(token) => token,
+            None => {
+                let token = issue(&secret)?;
+                let secure = if self.secure { "; Secure" } else { "" };
+                res.header_owned(format!(
+                    "Set-Cookie: {}={}; Path=/; SameSite=Lax{}",
+                    self.cookie, token, secure
+                ));
+                token
+            }
+        };
+        req.insert_extension(CsrfToken(token));
+        Ok(true)
+    }
+
+    // the token from the header, else from a urlencoded form body
+    fn submitted(&self, req: &mut Request) -> io::Result<Option<String>> {
+        if let Some(token) = req.header(&self.header) {
+            return Ok(Some(token.trim().to_owned()));
+        }
+        let form = req.content_type();
+        if !form.map_or(false, |form| form.is("application/x-www-form-urlencoded")) {
+            return Ok(None);
+        }
+        let body = req.req.prefetch_body()?;
+        let body = String::from_utf8_lossy(body);
+        Ok(body
+            .split('&')
+            .filter_map(|pair| pair.split_once('='))
+            .find(|(name, _)| percent_decode(name) == self.field)
+            .map(|(_, value)| percent_decode(value)))
+    }
+}
+
+// a random nonce and its signature, in hex: `<nonce>.<mac>`
+fn issue(secret: &[u8]) -> io::Result<String> {
+    let nonce = hex(&random_bytes(NONCE_LEN)?);
+    let mac = hex(&hmac_sha256(secret, nonce.as_bytes()));
+    Ok(format!("{}.{}", nonce, mac))
+}
+
+fn signed(secret: &[u8], token: &str) -> bool {
+    match token.split_once('.') {
+        Some((nonce, mac)) => {
+            let expected = hex(&hmac_sha256(secret, nonce.as_bytes()));
+            constant_time_eq(expected.as_bytes(), mac.as_bytes())
+        }
+        None => false,
+    }
+}
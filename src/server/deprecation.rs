@@ -0,0 +1,112 @@
+//! notices on deprecated routes, and who still calls them
+
+use std::collections::BTreeMap;
+use std::sync::{Arc, Mutex};
+use std::time::{SystemTime, UNIX_EPOCH};
+
+use crate::request::request::Request;
+use crate::response::date;
+use crate::response::response::Response;
+use crate::router::route_matcher::RouteOptions;
+
+// past this many route and caller pairs, new callers are counted together
+const MAX_CALLERS: usize = 10_000;
+const OTHER_CALLERS: &str = "other";
+
+type CallerFn = Arc<dyn Fn(&Request) -> Option<String> + Send + Sync>;
+// keyed by method, route pattern and caller
+type Calls = BTreeMap<(String, String, String), Usage>;
+
+/// Counts calls to deprecated routes per caller, once installed with
+/// `Server::deprecation_usage`, to find who still has to migrate. Clones
+/// share their counts.
+#[derive(Clone)]
+pub struct DeprecationUsage {
+    caller: CallerFn,
+    calls: Arc<Mutex<Calls>>,
+}
+
+struct Usage {
+    count: u64,
+    last_seen: SystemTime,
+}
+
+/// The calls one caller made to one deprecated route.
+#[derive(Clone, Debug)]
+pub struct DeprecatedCall {
+    pub method: String,
+    /// The pattern the route was registered with, e.g. `/v1/users/:id`.
+    pub route: String,
+    pub caller: String,
+    pub count: u64,
+    pub last_seen: SystemTime,
+}
+
+impl Default for DeprecationUsage {
+    fn default() -> Self {
+        DeprecationUsage::new()
+    }
+}
+
+impl DeprecationUsage {
+    /// Tells callers apart by client IP.
+    pub fn new() -> Self {
+        DeprecationUsage {
+            caller: Arc::new(|req| req.client_ip().map(|ip| ip.to_string())),
+            calls: Arc::default(),
+        }
+    }
+
+    /// Tells callers apart by what `f` returns, e.g. an API key's name or
+    /// the principal `Auth` attached; `None` counts as `unknown`.
+    pub fn caller<F>(&mut self, f: F) -> &mut Self
+    where
+        F: Fn(&Request) -> Option<String> + Send + Sync + 'static,
+    {
+        self.caller = Arc::new(f);
+        self
+    }
+
+    /// The counts so far, by route and then caller.
+    pub fn snapshot(&self) -> Vec<DeprecatedCall> {
+        let calls = self.calls.lock().unwrap();
+        calls
+            .iter()
+            .map(|((method, route, caller), usage)| DeprecatedCall {
+                method: method.clone(),
+                route: route.clone(),
+                caller: caller.clone(),
+                count: usage.count,
+                last_seen: usage.last_seen,
+            })
+            .collect()
+    }
+
+    pub(crate) fn record(&self, method: &str, route: &str, req: &Request) {
+        let caller = (self.caller)(req).unwrap_or_else(|| "unknown".to_owned());
+        let mut calls = self.calls.lock().unwrap();
+        let mut key = (method.to_owned(), route.to_owned(), caller);
+        if calls.len() >= MAX_CALLERS && !calls.contains_key(&key) {
+            key.2 = OTHER_CALLERS.to_owned();
+        }
+        let usage = calls.entry(key).or_insert(Usage {
+            count: 0,
+            last_seen: UNIX_EPOCH,
+        });
+        usage.count += 1;
+        usage.last_seen = SystemTime::now();
+    }
+}
+
+// the RFC 9745 and RFC 8594 notices for a route on its way out
+pub(crate) fn notices(options: &RouteOptions, res: &mut Response) {
+    if let Some(since) = options.deprecated {
+        res.header_owned(format!("Deprecation: @{}", date::unix_secs(since)));
+    }
+    if let Some(sunset) = options.sunset {
+        res.header_owned(format!("Sunset: {}", date::format(sunset)));
+    }
+    if let Some(link) = &options.deprecation_link {
+        res.header_owned(format!("Link: <{}>; rel=\"deprecation\"", link));
+    }
+}
@@ -0,0 +1,178 @@
+use std::collections::hash_map::DefaultHasher;
+use std::fs;
+use std::hash::{Hash, Hasher};
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Once};
+use std::thread;
+use std::time::Duration;
+
+use crate::response::response::Response;
+use crate::response::sse::SseEvent;
+use crate::server::server::Server;
+
+const RELOAD_PATH: &str = "/__livereload";
+const DEFAULT_POLL: Duration = Duration::from_millis(500);
+
+// reloads on a change event, and after the server comes back from a restart
+const RELOAD_SCRIPT: &[u8] = b"<script>(function(){\
+var s=new EventSource(\"/__livereload\"),down=false;\
+s.addEventListener(\"reload\",function(){location.reload()});\
+s.onerror=function(){down=true};\
+s.onopen=function(){if(down)location.reload()};\
+})();</script>";
+
+// validators and lifetimes a browser would cache by
+const CACHING_HEADERS: [&str; 4] = ["cache-control", "etag", "last-modified", "expires"];
+
+/// Local development conveniences, switched on with `Server::dev_mode`.
+///
+/// The watched directories, e.g. templates and static files, are polled
+/// for changes, and browsers showing a page from the server reload when
+/// one happens: HTML responses get a script that listens on
+/// `/__livereload`. Responses also lose their caching headers so nothing
+/// stale is kept around. Handler code itself can't be swapped in a running
+/// binary; the script also reloads once a restarted server is back, so
+/// pair it with something like `cargo watch -x run`.
+#[derive(Clone)]
+pub struct DevMode {
+    dirs: Arc<Vec<PathBuf>>,
+    poll: Duration,
+    generation: Arc<AtomicU64>,
+    watching: Arc<Once>,
+}
+
+impl Default for DevMode {
+    fn default() -> Self {
+        DevMode::new()
+    }
+}
+
+impl DevMode {
+    pub fn new() -> Self {
+        DevMode {
+            dirs: Arc::new(Vec::new()),
+            poll: DEFAULT_POLL,
+            generation: Arc::new(AtomicU64::new(0)),
+            watching: Arc::new(Once::new()),
+        }
+    }
+
+    /// Watches everything under `dir`.
+    pub fn watch<P: AsRef<Path>>(&mut self, dir: P) -> &mut Self {
+        Arc::make_mut(&mut self.dirs).push(dir.as_ref().to_owned());
+        self
+    }
+
+    /// How often the directories are scanned, every 500ms by default.
+    pub fn poll_interval(&mut self, interval: Duration) -> &mut Self {
+        self.poll = interval.max(Duration::from_millis(10));
+        self
+    }
+
+    /// Bumped on every change, so handlers caching what they read from the
+    /// watched directories can tell when to read it again.
+    pub fn generation(&self) -> u64 {
+        self.generation.load(Ordering::Acquire)
+    }
+
+    fn start_watching(&self) {
+        self.watching.call_once(|| {
+            let dirs = self.dirs.clone();
+            let poll = self.poll;
+            let generation = self.generation.clone();
+            thread::spawn(move || {
+                let mut last = fingerprint(&dirs);
+                loop {
+                    thread::sleep(poll);
+                    let current = fingerprint(&dirs);
+                    if current != last {
+                        last = current;
+                        generation.fetch_add(1, Ordering::AcqRel);
+                        info!("dev mode: watched files changed, reloading browsers");
+                    }
+                }
+            });
+        });
+    }
+
+    // strip caching headers and inject the reload script into HTML
+    pub(crate) fn decorate(&self, res: &mut Response) {
+        for name in CACHING_HEADERS {
+            res.remove_header(name);
+        }
+        res.header("Cache-Control: no-store");
+
+        let html = res.header_value("content-type").map_or(false, |value| {
+            value.to_ascii_lowercase().starts_with("text/html")
+        });
+        // streamed and encoded bodies can't be edited
+        let encoded = res.header_value("content-encoding").is_some();
+        if !html || encoded || res.body_size().is_none() {
+            return;
+        }
+        let body = res.body_mut();
+        let at = rfind_ignore_case(body, b"</body>").unwrap_or(body.len());
+        let tail = body.split_off(at);
+        body.extend_from_slice(RELOAD_SCRIPT);
+        body.unsplit(tail);
+    }
+}
+
+impl Server {
+    /// Turns on `dev`; meant for local runs only.
+    pub fn dev_mode(&mut self, dev: &DevMode) -> &mut Self {
+        warn!("dev mode is on; don't run this build in production");
+        dev.start_watching();
+        let watched = dev.clone();
+        self.get(RELOAD_PATH, move |_req, res| {
+            let dev = watched.clone();
+            res.sse(move |events| {
+                let seen = dev.generation();
+                while dev.generation() == seen {
+                    events.sleep(dev.poll)?;
+                }
+                events.send(&SseEvent::new("").event("reload"))
+            })
+        })
+        .maintenance_exempt();
+        self.config_mut().dev = Some(dev.clone());
+        self
+    }
+}
+
+// paths, sizes and modification times of every file under `dirs`
+fn fingerprint(dirs: &[PathBuf]) -> u64 {
+    fn walk(dir: &Path, hasher: &mut DefaultHasher) {
+        let mut entries: Vec<_> = match fs::read_dir(dir) {
+            Ok(entries) => entries.filter_map(Result::ok).collect(),
+            Err(_) => return,
+        };
+        entries.sort_by_key(|entry| entry.path());
+        for entry in entries {
+            let path = entry.path();
+            let metadata = match entry.metadata() {
+                Ok(metadata) => metadata,
+                Err(_) => continue,
+            };
+            if metadata.is_dir() {
+                walk(&path, hasher);
+                continue;
+            }
+            path.hash(hasher);
+            metadata.len().hash(hasher);
+            metadata.modified().ok().hash(hasher);
+        }
+    }
+    let mut hasher = DefaultHasher::new();
+    for dir in dirs {
+        walk(dir, &mut hasher);
+    }
+    hasher.finish()
+}
+
+fn rfind_ignore_case(haystack: &[u8], needle: &[u8]) -> Option<usize> {
+    (0..=haystack.len().checked_sub(needle.len())?)
+        .rev()
+        .find(|&i| haystack[i..i + needle.len()].eq_ignore_ascii_case(needle))
+}
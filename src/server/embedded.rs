@@ -0,0 +1,295 @@
+use std::collections::{BTreeMap, HashMap};
+use std::fmt::Write;
+use std::io;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use sha2::{Digest, Sha256};
+
+use crate::request::request::{percent_decode, Request};
+use crate::response::response::Response;
+use crate::server::server::Server;
+use crate::server::versioned::{matches, strong_etag};
+
+// precompressed siblings, tried in order of preference
+const ENCODINGS: [(&str, &str); 2] = [("br", ".br"), ("gzip", ".gz")];
+// fingerprinted names never change content, so they're cached for a year
+const IMMUTABLE: &str = "Cache-Control: public, max-age=31536000, immutable";
+
+/// Files compiled into the binary, for single-binary deployments; served
+/// with `Server::embedded`.
+///
+/// Add them with `file` and `include_bytes!`, or, with the `embed`
+/// feature, a whole `include_dir!` tree with `from_dir`. A `name.br` or
+/// `name.gz` next to `name` is sent instead to clients accepting that
+/// encoding. Responses carry a strong ETag and answer a matching
+/// If-None-Match with 304.
+#[derive(Clone)]
+pub struct EmbeddedAssets {
+    files: BTreeMap<String, &'static [u8]>,
+    cache_control: String,
+    fingerprinted: bool,
+}
+
+impl Default for EmbeddedAssets {
+    fn default() -> Self {
+        EmbeddedAssets::new()
+    }
+}
+
+impl EmbeddedAssets {
+    pub fn new() -> Self {
+        EmbeddedAssets {
+            files: BTreeMap::new(),
+            cache_control: "no-cache".to_owned(),
+            fingerprinted: false,
+        }
+    }
+
+    /// Adds a file at `path`, relative to the mount point.
+    pub fn file(&mut self, path: &str, contents: &'static [u8]) -> &mut Self {
+        self.files
+            .insert(path.trim_start_matches('/').to_owned(), contents);
+        self
+    }
+
+    /// Adds every file under `dir`.
+    #[cfg(feature = "embed")]
+    pub fn from_dir(dir: &'static include_dir::Dir<'static>) -> Self {
+        fn walk(assets: &mut EmbeddedAssets, dir: &'static include_dir::Dir<'static>) {
+            for file in dir.files() {
+                let path = file.path().to_string_lossy().replace('\\', "/");
+                assets.file(&path, file.contents());
+            }
+            for dir in dir.dirs() {
+                walk(assets, dir);
+            }
+        }
+        let mut assets = EmbeddedAssets::new();
+        walk(&mut assets, dir);
+        assets
+    }
+
+    /// Cache-Control sent with every file; `no-cache` (revalidate by
+    /// ETag) unless set.
+    pub fn cache_control(&mut self, value: &str) -> &mut Self {
+        self.cache_control = value.to_owned();
+        self
+    }
+
+    /// Also serves each file under a name with its content hash, e.g.
+    /// `app.3f2a9c1b.js` for `app.js`, cached for a year as immutable;
+    /// `manifest` gives those names. The plain names are still served, with
+    /// the usual Cache-Control.
+    pub fn fingerprint(&mut self) -> &mut Self {
+        self.fingerprinted = true;
+        self
+    }
+
+    /// The fingerprinted URL of each file as mounted under `prefix`, for
+    /// pages to link to; see `fingerprint`.
+    pub fn manifest(&self, prefix: &str) -> AssetManifest {
+        let names = match self.fingerprinted {
+            true => self.fingerprints(),
+            false => BTreeMap::new(),
+        };
+        AssetManifest {
+            prefix: prefix.trim_end_matches('/').to_owned(),
+            names: Arc::new(names),
+        }
+    }
+
+    // each file's fingerprinted name; precompressed siblings are sent in
+    // place of their file, so they get none of their own
+    fn fingerprints(&self) -> BTreeMap<String, String> {
+        let sibling = |path: &str| {
+            ENCODINGS.iter().any(|(_, suffix)| {
+                path.strip_suffix(suffix)
+                    .map_or(false, |file| self.files.contains_key(file))
+            })
+        };
+        self.files
+            .iter()
+            .filter(|(path, _)| !sibling(path))
+            .map(|(path, contents)| (path.clone(), fingerprinted(path, contents)))
+            .collect()
+    }
+}
+
+/// Where `EmbeddedAssets` serves each file under its fingerprinted name.
+/// Clones share the names.
+#[derive(Clone, Debug)]
+pub struct AssetManifest {
+    prefix: String,
+    // file path to fingerprinted path, both under the prefix
+    names: Arc<BTreeMap<String, String>>,
+}
+
+impl AssetManifest {
+    /// The URL to link `path`, e.g. `app.js`, by: its fingerprinted name
+    /// when there's one, else the plain path.
+    pub fn url(&self, path: &str) -> String {
+        let path = path.trim_start_matches('/');
+        let name = self.names.get(path).map_or(path, String::as_str);
+        format!("{}/{}", self.prefix, name)
+    }
+}
+
+// one file with the representations it can be sent as
+struct Asset {
+    content_type: &'static str,
+    // (content coding, bytes, etag), identity last
+    variants: Vec<(Option<&'static str>, &'static [u8], String)>,
+}
+
+impl Server {
+    /// Serves `assets` under `prefix`, e.g. `/static`; a directory path
+    /// gets its `index.html`.
+    pub fn embedded(&mut self, prefix: &str, assets: &EmbeddedAssets) -> &mut Self {
+        let prefix = prefix.trim_end_matches('/').to_owned();
+        let mut table = HashMap::new();
+        let mut hashed = HashMap::new();
+        if assets.fingerprinted {
+            for (path, name) in assets.fingerprints() {
+                hashed.insert(name, path);
+            }
+        }
+        for (path, contents) in &assets.files {
+            let mut variants = Vec::new();
+            for (coding, suffix) in ENCODINGS {
+                if let Some(encoded) = assets.files.get(&format!("{}{}", path, suffix)) {
+                    variants.push((Some(coding), *encoded, strong_etag(encoded)));
+                }
+            }
+            variants.push((None, *contents, strong_etag(contents)));
+            let asset = Asset {
+                content_type: content_type(path),
+                variants,
+            };
+            table.insert(path.clone(), asset);
+        }
+        let mounted = Arc::new(Mounted {
+            cache_control: format!("Cache-Control: {}", assets.cache_control),
+            prefix,
+            table,
+            hashed,
+        });
+        let root = mounted.clone();
+        self.get(&format!("{}/", root.prefix), move |req, res| {
+            root.serve(req, res)
+        });
+        let path = format!("{}/*", mounted.prefix);
+        self.get(&path, move |req, res| mounted.serve(req, res));
+        self
+    }
+}
+
+struct Mounted {
+    prefix: String,
+    table: HashMap<String, Asset>,
+    // fingerprinted path to the file's own
+    hashed: HashMap<String, String>,
+    cache_control: String,
+}
+
+impl Mounted {
+    fn serve(&self, req: Request, res: &mut Response) -> io::Result<()> {
+        let path = req.path().split('?').next().unwrap_or("");
+        let path = percent_decode(path.strip_prefix(self.prefix.as_str()).unwrap_or(path));
+        let path = path.trim_start_matches('/');
+        let hashed = self.hashed.get(path);
+        let asset = match path.is_empty() || path.ends_with('/') {
+            true => self.table.get(&format!("{}index.html", path)),
+            false => self.table.get(hashed.map_or(path, String::as_str)),
+        };
+        let asset = match asset {
+            Some(asset) => asset,
+            None => {
+                res.status_code(404, "Not Found");
+                return Ok(());
+            }
+        };
+        let accepted = req.header("accept-encoding").unwrap_or("");
+        // identity is last and always acceptable
+        let (coding, contents, etag) = asset
+            .variants
+            .iter()
+            .find(|(coding, ..)| coding.map_or(true, |c| accepts(accepted, c)))
+            .unwrap();
+        res.header_owned(format!("ETag: {}", etag));
+        match hashed {
+            Some(_) => res.header(IMMUTABLE),
+            None => res.header_owned(self.cache_control.clone()),
+        };
+        if asset.variants.len() > 1 {
+            res.header("Vary: Accept-Encoding");
+        }
+        let fresh = req
+            .header("if-none-match")
+            .map_or(false, |tags| matches(tags, etag, false));
+        if fresh {
+            res.status_code(304, "Not Modified");
+            return Ok(());
+        }
+        res.header_owned(format!("Content-Type: {}", asset.content_type));
+        if let Some(coding) = coding {
+            res.header_owned(format!("Content-Encoding: {}", coding));
+        }
+        res.body_segments(vec![Bytes::from_static(contents)]);
+        Ok(())
+    }
+}
+
+// `path` with the start of its content's SHA-256 before the extension
+fn fingerprinted(path: &str, contents: &[u8]) -> String {
+    let hash = Sha256::digest(contents);
+    let mut tag = String::with_capacity(8);
+    for byte in &hash[..4] {
+        let _ = write!(tag, "{:02x}", byte);
+    }
+    let (dir, file) = path.split_at(path.rfind('/').map_or(0, |slash| slash + 1));
+    match file.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() => {
+            format!("{}{}.{}.{}", dir, stem, tag, extension)
+        }
+        _ => format!("{}{}.{}", dir, file, tag),
+    }
+}
+
+// whether an Accept-Encoding value allows `coding`
+fn accepts(header: &str, coding: &str) -> bool {
+    header.split(',').any(|item| {
+        let mut parts = item.split(';').map(str::trim);
+        let name = parts.next().unwrap_or("");
+        let refused = parts.any(|param| {
+            param
+                .strip_prefix("q=")
+                .map_or(false, |q| q.parse::<f32>().map_or(false, |q| q <= 0.0))
+        });
+        (name.eq_ignore_ascii_case(coding) || name == "*") && !refused
+    })
+}
+
+pub(crate) fn content_type(path: &str) -> &'static str {
+    let extension = path.rsplit('.').next().unwrap_or("");
+    match extension.to_ascii_lowercase().as_str() {
+        "html" | "htm" => "text/html; charset=utf-8",
+        "css" => "text/css; charset=utf-8",
+        "js" | "mjs" => "text/javascript; charset=utf-8",
+        "json" | "map" => "application/json",
+        "txt" => "text/plain; charset=utf-8",
+        "xml" => "application/xml",
+        "svg" => "image/svg+xml",
+        "png" => "image/png",
+        "jpg" | "jpeg" => "image/jpeg",
+        "gif" => "image/gif",
+        "webp" => "image/webp",
+        "avif" => "image/avif",
+        "ico" => "image/x-icon",
+        "wasm" => "application/wasm",
+        "woff" => "font/woff",
+        "woff2" => "font/woff2",
+        "pdf" => "application/pdf",
+        _ => "application/octet-stream",
+    }
+}
@@ -0,0 +1,103 @@
+//! `X-Aegis-Explain: 1` and response timing: how a request was routed and
+//! where its time went
+
+use std::fmt::Write;
+use std::net::IpAddr;
+use std::time::{Duration, Instant};
+
+use crate::request::request::RawRequest;
+use crate::response::response::Response;
+use crate::server::config::ServerConfig;
+
+// the stages a request went through, recorded only when it asked for an
+// explanation or the server reports timings
+pub(crate) struct Explain {
+    started: Option<Instant>,
+    stages: Vec<(&'static str, Instant)>,
+    explained: bool,
+    timed: bool,
+    vhost: Option<String>,
+    route: Option<String>,
+}
+
+impl Explain {
+    // explanations are honored over loopback only, so remote clients can't
+    // map the routes
+    pub(crate) fn new(req: &RawRequest, res: &mut Response, config: &ServerConfig) -> Self {
+        let explained = config.explain_routes
+            && req.header("x-aegis-explain") == Some("1")
+            && req.remote_addr().map_or(false, |addr| loopback(addr.ip()));
+        let timed = config.response_timing;
+        if explained || timed {
+            res.track_serialization();
+        }
+        Explain {
+            started: (explained || timed).then(Instant::now),
+            stages: Vec::new(),
+            explained,
+            timed,
+            vhost: None,
+            route: None,
+        }
+    }
+
+    // the stage starting now; the last one entered is the one that answered
+    pub(crate) fn enter(&mut self, stage: &'static str) {
+        if self.started.is_some() {
+            self.stages.push((stage, Instant::now()));
+        }
+    }
+
+    pub(crate) fn matched(&mut self, vhost: Option<&str>, route: &str) {
+        if self.explained {
+            self.vhost = vhost.map(str::to_owned);
+            self.route = Some(route.to_owned());
+        }
+    }
+
+    // report in `Server-Timing`, which browser developer tools show per
+    // request, and `X-Aegis-Explain` or `X-Response-Time`
+    pub(crate) fn finish(self, res: &mut Response) {
+        let started = match self.started {
+            Some(started) => started,
+            None => return,
+        };
+        let end = Instant::now();
+        let mut timing = format!("Server-Timing: total;dur={:.3}", ms(end - started));
+        for (i, (stage, entered)) in self.stages.iter().enumerate() {
+            let until = self.stages.get(i + 1).map_or(end, |next| next.1);
+            write!(timing, ", {};dur={:.3}", stage, ms(until - *entered)).ok();
+        }
+        // part of the handler's time, when it used `Response::json`
+        if let Some(serialization) = res.serialization().filter(|d| !d.is_zero()) {
+            write!(timing, ", serialization;dur={:.3}", ms(serialization)).ok();
+        }
+        if self.explained {
+            let names: Vec<_> = self.stages.iter().map(|(stage, _)| *stage).collect();
+            res.header_owned(format!(
+                "X-Aegis-Explain: vhost={}; route={}; stages={}; answered-by={}",
+                self.vhost.as_deref().unwrap_or("default"),
+                self.route.as_deref().unwrap_or("none"),
+                names.join(","),
+                names.last().unwrap_or(&"none"),
+            ));
+        }
+        if self.timed {
+            res.header_owned(format!("X-Response-Time: {:.3}ms", ms(end - started)));
+        }
+        res.header_owned(timing);
+    }
+}
+
+fn ms(duration: Duration) -> f64 {
+    duration.as_secs_f64() * 1000.0
+}
+
+fn loopback(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => ip.is_loopback(),
+        IpAddr::V6(ip) => {
+            ip.is_loopback() || ip.to_ipv4_mapped().map_or(false, |v4| v4.is_loopback())
+        }
+    }
+}
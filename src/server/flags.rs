@@ -0,0 +1,57 @@
+use std::collections::BTreeMap;
+
+use crate::request::request::Request;
+
+/// Flag decisions for one request, made once by the server's
+/// `FlagProvider` so every handler sees the same answer.
+#[derive(Clone, Debug, Default)]
+pub struct FeatureFlags {
+    flags: BTreeMap<String, bool>,
+}
+
+impl FeatureFlags {
+    pub fn new() -> Self {
+        FeatureFlags::default()
+    }
+
+    pub fn set(&mut self, name: &str, enabled: bool) -> &mut Self {
+        self.flags.insert(name.to_owned(), enabled);
+        self
+    }
+
+    /// Whether `name` is on; flags the provider didn't decide are off.
+    pub fn is_enabled(&self, name: &str) -> bool {
+        self.flags.get(name).copied().unwrap_or(false)
+    }
+
+    pub fn iter(&self) -> impl Iterator<Item = (&str, bool)> {
+        self.flags.iter().map(|(name, on)| (name.as_str(), *on))
+    }
+}
+
+/// Decides the flags for each request, e.g. from a header, the tenant or
+/// a `rollout` percentage. Installed with `Server::feature_flags`.
+pub trait FlagProvider: Send + Sync {
+    fn evaluate(&self, req: &Request) -> FeatureFlags;
+}
+
+impl<F> FlagProvider for F
+where
+    F: Fn(&Request) -> FeatureFlags + Send + Sync,
+{
+    fn evaluate(&self, req: &Request) -> FeatureFlags {
+        self(req)
+    }
+}
+
+/// Puts `subject` (a user, tenant or client id) in the first `percent` of
+/// `flag`'s rollout or not; stable across requests and processes.
+pub fn rollout(flag: &str, subject: &str, percent: u8) -> bool {
+    // FNV-1a, fixed so buckets don't move between builds
+    let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+    for byte in flag.bytes().chain([0]).chain(subject.bytes()) {
+        hash ^= byte as u64;
+        hash = hash.wrapping_mul(0x0100_0000_01b3);
+    }
+    hash % 100 < percent as u64
+}
@@ -0,0 +1,117 @@
+use std::io::{self, Read};
+use std::sync::Arc;
+
+use juniper::http::graphiql::graphiql_source;
+use juniper::http::{GraphQLBatchRequest, GraphQLRequest};
+use juniper::{GraphQLType, InputValue, RootNode, ScalarValue};
+
+use crate::request::request::{percent_decode, Request};
+use crate::response::response::Response;
+use crate::server::server::Server;
+
+impl Server {
+    /// Serves `schema` at `path`: GET with `query`, `operationName` and
+    /// `variables` parameters, and POST with a JSON (optionally batched)
+    /// or `application/graphql` body. `context` builds the schema's
+    /// context for each request.
+    ///
+    /// Multipart file uploads aren't supported; juniper has no upload
+    /// scalar to hand them to.
+    pub fn graphql<Q, M, Sub, S, C, F>(
+        &mut self,
+        path: &str,
+        schema: Arc<RootNode<'static, Q, M, Sub, S>>,
+        context: F,
+    ) -> &mut Self
+    where
+        Q: GraphQLType<S, Context = C> + 'static,
+        M: GraphQLType<S, Context = C> + 'static,
+        Sub: GraphQLType<S, Context = C> + 'static,
+        S: ScalarValue + Send + Sync + 'static,
+        RootNode<'static, Q, M, Sub, S>: Send + Sync,
+        F: Fn(&Request) -> C + Send + Sync + 'static,
+    {
+        let context = Arc::new(context);
+        self.get(path, handler(schema.clone(), context.clone()));
+        self.post(path, handler(schema, context));
+        self
+    }
+
+    /// Serves the GraphiQL IDE at `path`, pointed at `endpoint`.
+    pub fn graphiql(&mut self, path: &str, endpoint: &str) -> &mut Self {
+        let html = graphiql_source(endpoint, None);
+        self.get(path, move |_req, res| {
+            res.header("Content-Type: text/html; charset=utf-8");
+            res.str(html.clone())
+        });
+        self
+    }
+}
+
+fn handler<Q, M, Sub, S, C, F>(
+    schema: Arc<RootNode<'static, Q, M, Sub, S>>,
+    context: Arc<F>,
+) -> impl Fn(Request, &mut Response) -> io::Result<()> + Send + Sync + 'static
+where
+    Q: GraphQLType<S, Context = C> + 'static,
+    M: GraphQLType<S, Context = C> + 'static,
+    Sub: GraphQLType<S, Context = C> + 'static,
+    S: ScalarValue + Send + Sync + 'static,
+    RootNode<'static, Q, M, Sub, S>: Send + Sync,
+    F: Fn(&Request) -> C + Send + Sync + 'static,
+{
+    move |req, res| {
+        let ctx = context(&req);
+        let batch = match parse::<S>(req)? {
+            Ok(batch) => batch,
+            Err(message) => {
+                res.status_code(400, "Bad Request");
+                return res.json(&serde_json::json!({ "errors": [{ "message": message }] }));
+            }
+        };
+        let response = batch.execute_sync(&schema, &ctx);
+        if !response.is_ok() {
+            res.status_code(400, "Bad Request");
+        }
+        res.json(&response)
+    }
+}
+
+// the GraphQL request(s) carried by `req`, or why it doesn't hold one
+fn parse<S: ScalarValue>(req: Request) -> io::Result<Result<GraphQLBatchRequest<S>, String>> {
+    if req.method() == "GET" {
+        let query = match req.url_parameter("query") {
+            Some(query) => percent_decode(query),
+            None => return Ok(Err("missing query parameter".to_owned())),
+        };
+        let operation = req.url_parameter("operationName").map(percent_decode);
+        let variables = match req.url_parameter("variables").map(percent_decode) {
+            Some(variables) => match serde_json::from_str::<InputValue<S>>(&variables) {
+                Ok(variables) => Some(variables),
+                Err(e) => return Ok(Err(format!("invalid variables: {}", e))),
+            },
+            None => None,
+        };
+        let request = GraphQLRequest::new(query, operation, variables);
+        return Ok(Ok(GraphQLBatchRequest::Single(request)));
+    }
+
+    let content_type = req
+        .header("content-type")
+        .unwrap_or("application/json")
+        .to_ascii_lowercase();
+    let mut body = Vec::new();
+    req.body().read_to_end(&mut body)?;
+    if content_type.starts_with("application/graphql") {
+        return Ok(match String::from_utf8(body) {
+            Ok(query) => Ok(GraphQLBatchRequest::Single(GraphQLRequest::new(
+                query, None, None,
+            ))),
+            Err(_) => Err("query is not UTF-8".to_owned()),
+        });
+    }
+    if content_type.starts_with("multipart/") {
+        return Ok(Err("file uploads are not supported".to_owned()));
+    }
+    Ok(serde_json::from_slice(&body).map_err(|e| format!("invalid request: {}", e)))
+}
@@ -0,0 +1,133 @@
+//! liveness and readiness probes for load balancers and orchestrators
+
+use std::io;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::Duration;
+
+use serde_json::{Map, Value};
+
+use crate::response::response::Response;
+use crate::server::server::Server;
+
+type Check = Arc<dyn Fn() -> Result<(), String> + Send + Sync>;
+
+/// `/healthz` and `/readyz`, mounted with `Server::health`.
+///
+/// Each answers 200 while all its checks pass and 503 otherwise, with a
+/// JSON body naming every check and its error. Liveness should only fail
+/// when restarting the process would help; readiness also covers what
+/// the server needs to serve, e.g. its database. Once
+/// `ServerHandle::shutdown` is called, readiness fails for the drain
+/// delay before connections are drained, so load balancers stop sending
+/// new requests first. Clones share that state.
+#[derive(Clone)]
+pub struct Health {
+    liveness: Vec<(String, Check)>,
+    readiness: Vec<(String, Check)>,
+    drain_delay: Duration,
+    shutting_down: Arc<AtomicBool>,
+}
+
+impl Default for Health {
+    fn default() -> Self {
+        Health::new()
+    }
+}
+
+impl Health {
+    /// Both probes pass until checks are added; the drain delay is five
+    /// seconds.
+    pub fn new() -> Self {
+        Health {
+            liveness: Vec::new(),
+            readiness: Vec::new(),
+            drain_delay: Duration::from_secs(5),
+            shutting_down: Arc::default(),
+        }
+    }
+
+    /// Runs `check` for `/healthz`, which fails with the error it returns.
+    pub fn liveness<F>(&mut self, name: &str, check: F) -> &mut Self
+    where
+        F: Fn() -> Result<(), String> + Send + Sync + 'static,
+    {
+        self.liveness.push((name.to_owned(), Arc::new(check)));
+        self
+    }
+
+    /// Runs `check`, e.g. a database ping, for `/readyz`.
+    pub fn readiness<F>(&mut self, name: &str, check: F) -> &mut Self
+    where
+        F: Fn() -> Result<(), String> + Send + Sync + 'static,
+    {
+        self.readiness.push((name.to_owned(), Arc::new(check)));
+        self
+    }
+
+    /// How long shutdown reports not ready before it stops accepting;
+    /// at least the load balancer's probe interval times its failure
+    /// threshold.
+    pub fn drain_delay(&mut self, delay: Duration) -> &mut Self {
+        self.drain_delay = delay;
+        self
+    }
+
+    pub fn is_ready(&self) -> bool {
+        !self.shutting_down.load(Ordering::Acquire)
+            && self.readiness.iter().all(|(_, check)| check().is_ok())
+    }
+
+    // fail readiness from now on, and give load balancers time to notice
+    pub(crate) fn shut_down(&self) {
+        self.shutting_down.store(true, Ordering::Release);
+        may::coroutine::sleep(self.drain_delay);
+    }
+
+    fn answer(&self, checks: &[(String, Check)], res: &mut Response) -> io::Result<()> {
+        let mut results = Map::new();
+        let mut healthy = true;
+        for (name, check) in checks {
+            let result = match check() {
+                Ok(()) => Value::from("ok"),
+                Err(e) => {
+                    healthy = false;
+                    Value::from(e)
+                }
+            };
+            results.insert(name.clone(), result);
+        }
+        if !healthy {
+            res.status_code(503, "Service Unavailable");
+        }
+        res.header("Cache-Control: no-store");
+        res.json(&serde_json::json!({
+            "status": if healthy { "ok" } else { "unavailable" },
+            "checks": results,
+        }))
+    }
+}
+
+impl Server {
+    /// Serves `health` at `/healthz` and `/readyz`, also in maintenance
+    /// mode, and has shutdown fail readiness before draining.
+    pub fn health(&mut self, health: &Health) -> &mut Self {
+        let live = health.clone();
+        self.get("/healthz", move |_req, res| {
+            live.answer(&live.liveness, res)
+        })
+        .maintenance_exempt();
+        let ready = health.clone();
+        self.get("/readyz", move |_req, res| {
+            if ready.shutting_down.load(Ordering::Acquire) {
+                res.status_code(503, "Service Unavailable");
+                res.header("Cache-Control: no-store");
+                return res.json(&serde_json::json!({ "status": "shutting_down" }));
+            }
+            ready.answer(&ready.readiness, res)
+        })
+        .maintenance_exempt();
+        self.config_mut().health = Some(health.clone());
+        self
+    }
+}
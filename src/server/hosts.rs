@@ -0,0 +1,117 @@
+//! the Host values a server answers to
+
+use std::io;
+use std::net::Ipv6Addr;
+
+use crate::errors::http_error::HttpError;
+use crate::request::request::RawRequest;
+use crate::response::respond::IntoResponse;
+use crate::response::response::Response;
+use crate::response::status::StatusCode;
+
+// lowercase names, `*.` wildcards, with a port or without
+#[derive(Clone, Debug, Default)]
+pub(crate) struct AllowedHosts {
+    hosts: Vec<(String, Option<u16>)>,
+}
+
+impl AllowedHosts {
+    pub(crate) fn add(&mut self, host: &str) -> io::Result<()> {
+        let (wildcard, rest) = match host.strip_prefix("*.") {
+            Some(rest) => (true, rest),
+            None => (false, host),
+        };
+        let (name, port) = parse(rest).ok_or_else(|| {
+            io::Error::new(
+                io::ErrorKind::InvalidInput,
+                format!("invalid host: {}", host),
+            )
+        })?;
+        let name = if wildcard {
+            format!("*.{}", name)
+        } else {
+            name
+        };
+        self.hosts.push((name, port));
+        Ok(())
+    }
+
+    // lets the request on, or answers 400 for a missing, repeated or
+    // malformed Host and 421 for one not allowed
+    pub(crate) fn check(&self, req: &mut RawRequest, res: &mut Response) -> io::Result<bool> {
+        let mut hosts = req
+            .headers()
+            .iter()
+            .filter(|header| header.name.eq_ignore_ascii_case("host"));
+        let error = match (hosts.next(), hosts.next()) {
+            (Some(host), None) => {
+                let host = std::str::from_utf8(host.value).ok().and_then(parse);
+                match host {
+                    Some((name, port)) if self.allows(&name, port) => return Ok(true),
+                    Some(_) => HttpError::Status(
+                        StatusCode::MISDIRECTED_REQUEST,
+                        "host not served here".to_owned(),
+                    ),
+                    None => HttpError::BadRequest("malformed Host header".to_owned()),
+                }
+            }
+            (None, _) => HttpError::BadRequest("Host header required".to_owned()),
+            (Some(_), Some(_)) => HttpError::BadRequest("more than one Host header".to_owned()),
+        };
+        req.reject_body();
+        error.into_response(res)?;
+        Ok(false)
+    }
+
+    fn allows(&self, name: &str, port: Option<u16>) -> bool {
+        self.hosts.iter().any(|(allowed, allowed_port)| {
+            let name_matches = match allowed.strip_prefix("*.") {
+                Some(domain) => name
+                    .strip_suffix(domain)
+                    .map_or(false, |sub| sub.len() > 1 && sub.ends_with('.')),
+                None => name == allowed,
+            };
+            name_matches && allowed_port.map_or(true, |allowed| port == Some(allowed))
+        })
+    }
+}
+
+// `name[:port]` or `[v6][:port]` into the lowercase name, without a
+// trailing dot, and the port
+fn parse(host: &str) -> Option<(String, Option<u16>)> {
+    let host = host.trim();
+    let (name, port) = match host.strip_prefix('[') {
+        Some(rest) => {
+            let (v6, port) = rest.split_once(']')?;
+            let port = match port {
+                "" => None,
+                port => Some(port.strip_prefix(':')?),
+            };
+            (format!("[{}]", v6.parse::<Ipv6Addr>().ok()?), port)
+        }
+        None => {
+            let (name, port) = match host.split_once(':') {
+                Some((name, port)) => (name, Some(port)),
+                None => (host, None),
+            };
+            let name = name.strip_suffix('.').unwrap_or(name);
+            let labels_valid = name.split('.').all(|label| {
+                !label.is_empty()
+                    && label.len() <= 63
+                    && label
+                        .bytes()
+                        .all(|b| b.is_ascii_alphanumeric() || b == b'-' || b == b'_')
+            });
+            if name.len() > 253 || !labels_valid {
+                return None;
+            }
+            (name.to_ascii_lowercase(), port)
+        }
+    };
+    let port = match port {
+        Some(port) if port.bytes().all(|b| b.is_ascii_digit()) => Some(port.parse().ok()?),
+        Some(_) => return None,
+        None => None,
+    };
+    Some((name, port))
+}
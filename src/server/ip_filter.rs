@@ -0,0 +1,92 @@
+//! allow and deny lists of client networks
+
+use std::io;
+use std::net::IpAddr;
+
+use crate::errors::http_error::HttpError;
+use crate::http::forwarded::IpNet;
+use crate::http::http_server::dropped;
+use crate::request::request::RawRequest;
+use crate::response::respond::IntoResponse;
+use crate::response::response::Response;
+
+/// Firewall-style filtering by client IP, installed with
+/// `Server::ip_filter` or per route with `RouteOptions::ip_filter`.
+///
+/// The client is the one `Request::client_ip` reports, so trusted proxies
+/// are looked through. Denied networks win; if any networks are allowed,
+/// the client must be in one of them. Refused requests get 403, or with
+/// `drop_connection` no answer at all.
+#[derive(Clone, Debug, Default)]
+pub struct IpFilter {
+    allow: Vec<IpNet>,
+    deny: Vec<IpNet>,
+    drop: bool,
+}
+
+impl IpFilter {
+    /// Lets every client through until networks are listed.
+    pub fn new() -> Self {
+        IpFilter::default()
+    }
+
+    /// Allows `net`, e.g. `10.0.0.0/8` or a single address; once any are
+    /// allowed, all others are refused.
+    pub fn allow(&mut self, net: &str) -> io::Result<&mut Self> {
+        self.allow.push(parse(net)?);
+        Ok(self)
+    }
+
+    /// Refuses `net`, even if it's also allowed.
+    pub fn deny(&mut self, net: &str) -> io::Result<&mut Self> {
+        self.deny.push(parse(net)?);
+        Ok(self)
+    }
+
+    /// Closes the connection on refused requests instead of answering 403,
+    /// which leaves abusive clients less to go on. Over HTTP/2 only the
+    /// stream is reset.
+    pub fn drop_connection(&mut self, drop: bool) -> &mut Self {
+        self.drop = drop;
+        self
+    }
+
+    pub fn permits(&self, ip: IpAddr) -> bool {
+        !self.deny.iter().any(|net| net.contains(ip))
+            && (self.allow.is_empty() || self.allow.iter().any(|net| net.contains(ip)))
+    }
+
+    // lets the request on, or refuses it and says so; a client whose
+    // address isn't known only passes when nothing has to be allowed
+    pub(crate) fn check(
+        &self,
+        ip: Option<IpAddr>,
+        req: &mut RawRequest,
+        res: &mut Response,
+    ) -> io::Result<bool> {
+        let permitted = match ip {
+            Some(ip) => self.permits(ip),
+            None => self.allow.is_empty(),
+        };
+        if permitted {
+            return Ok(true);
+        }
+        if self.drop {
+            // logged as what it would have been
+            res.status_code(403, "Forbidden");
+            return Err(dropped());
+        }
+        req.reject_body();
+        HttpError::Forbidden("client address not allowed".to_owned()).into_response(res)?;
+        Ok(false)
+    }
+}
+
+fn parse(net: &str) -> io::Result<IpNet> {
+    IpNet::parse(net).ok_or_else(|| {
+        io::Error::new(
+            io::ErrorKind::InvalidInput,
+            format!("invalid network: {}", net),
+        )
+    })
+}
@@ -0,0 +1,179 @@
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use serde_json::{Map, Value};
+
+use crate::request::request::Request;
+use crate::router::route_matcher::RouteOptions;
+use crate::server::server::Server;
+
+type Method = Arc<dyn Fn(Value, &Request) -> Result<Value, RpcError> + Send + Sync>;
+
+/// JSON-RPC 2.0 dispatcher, mounted at a route with `Server::json_rpc`.
+///
+/// Calls are routed by method name to the registered handlers, which get
+/// the `params` (null when absent) and the HTTP request. Batches and
+/// notifications are supported; a request made only of notifications is
+/// answered with 204.
+#[derive(Clone, Default)]
+pub struct JsonRpc {
+    methods: HashMap<String, Method>,
+}
+
+/// A JSON-RPC error object.
+#[derive(Clone, Debug)]
+pub struct RpcError {
+    pub code: i64,
+    pub message: String,
+    pub data: Option<Value>,
+}
+
+impl RpcError {
+    pub fn new(code: i64, message: &str) -> Self {
+        RpcError {
+            code,
+            message: message.to_owned(),
+            data: None,
+        }
+    }
+
+    pub fn parse_error() -> Self {
+        RpcError::new(-32700, "Parse error")
+    }
+
+    pub fn invalid_request() -> Self {
+        RpcError::new(-32600, "Invalid Request")
+    }
+
+    pub fn method_not_found() -> Self {
+        RpcError::new(-32601, "Method not found")
+    }
+
+    pub fn invalid_params() -> Self {
+        RpcError::new(-32602, "Invalid params")
+    }
+
+    pub fn internal_error() -> Self {
+        RpcError::new(-32603, "Internal error")
+    }
+
+    /// Attaches details for the client.
+    pub fn with_data(mut self, data: Value) -> Self {
+        self.data = Some(data);
+        self
+    }
+
+    fn to_value(&self) -> Value {
+        let mut error = serde_json::json!({
+            "code": self.code,
+            "message": self.message,
+        });
+        if let Some(data) = &self.data {
+            error["data"] = data.clone();
+        }
+        error
+    }
+}
+
+impl JsonRpc {
+    pub fn new() -> Self {
+        JsonRpc::default()
+    }
+
+    /// Handles calls to `name`; a `serde_json` error from reading the
+    /// params converts to Invalid params with `?`.
+    pub fn method<F>(&mut self, name: &str, f: F) -> &mut Self
+    where
+        F: Fn(Value, &Request) -> Result<Value, RpcError> + Send + Sync + 'static,
+    {
+        self.methods.insert(name.to_owned(), Arc::new(f));
+        self
+    }
+
+    /// The response document for `body`, `None` when nothing needs an
+    /// answer.
+    pub(crate) fn dispatch(&self, body: &[u8], req: &Request) -> Option<Value> {
+        let document = match serde_json::from_slice::<Value>(body) {
+            Ok(document) => document,
+            Err(_) => return Some(response(Value::Null, Err(RpcError::parse_error()))),
+        };
+        match document {
+            Value::Array(calls) if calls.is_empty() => {
+                Some(response(Value::Null, Err(RpcError::invalid_request())))
+            }
+            Value::Array(calls) => {
+                let responses: Vec<Value> = calls
+                    .into_iter()
+                    .filter_map(|call| self.call(call, req))
+                    .collect();
+                match responses.is_empty() {
+                    true => None,
+                    false => Some(Value::Array(responses)),
+                }
+            }
+            call => self.call(call, req),
+        }
+    }
+
+    fn call(&self, call: Value, req: &Request) -> Option<Value> {
+        let mut call = match call {
+            Value::Object(call) => call,
+            _ => return Some(response(Value::Null, Err(RpcError::invalid_request()))),
+        };
+        // without an id it's a notification, which gets no response
+        let id = call.remove("id");
+        let valid_id = matches!(
+            id,
+            None | Some(Value::Null | Value::String(_) | Value::Number(_))
+        );
+        if !valid_id || !well_formed(&call) {
+            return Some(response(Value::Null, Err(RpcError::invalid_request())));
+        }
+        let params = call.remove("params").unwrap_or(Value::Null);
+        let method = call.get("method").and_then(Value::as_str).unwrap_or("");
+        let result = match self.methods.get(method) {
+            Some(handler) => handler(params, req),
+            None => Err(RpcError::method_not_found()),
+        };
+        id.map(|id| response(id, result))
+    }
+}
+
+impl Server {
+    /// Serves `rpc` to POSTs at `path`.
+    pub fn json_rpc(&mut self, path: &str, rpc: &JsonRpc) -> &mut RouteOptions {
+        let rpc = rpc.clone();
+        self.post(path, move |mut req, res| {
+            let body = req.req.prefetch_body()?.to_vec();
+            match rpc.dispatch(&body, &req) {
+                Some(document) => res.json(&document),
+                None => {
+                    res.status_code(204, "No Content");
+                    Ok(())
+                }
+            }
+        })
+    }
+}
+
+impl From<serde_json::Error> for RpcError {
+    fn from(e: serde_json::Error) -> Self {
+        RpcError::invalid_params().with_data(e.to_string().into())
+    }
+}
+
+fn well_formed(call: &Map<String, Value>) -> bool {
+    call.get("jsonrpc").and_then(Value::as_str) == Some("2.0")
+        && call.get("method").map_or(false, Value::is_string)
+        && matches!(
+            call.get("params"),
+            None | Some(Value::Array(_) | Value::Object(_))
+        )
+}
+
+fn response(id: Value, result: Result<Value, RpcError>) -> Value {
+    match result {
+        Ok(result) => serde_json::json!({ "jsonrpc": "2.0", "result": result, "id": id }),
+        Err(error) => serde_json::json!({ "jsonrpc": "2.0", "error": error.to_value(), "id": id }),
+    }
+}
@@ -0,0 +1,309 @@
+//! JSON Web Token verification for bearer-authenticated routes
+
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use rsa::pkcs1::DecodeRsaPublicKey;
+use rsa::pkcs8::DecodePublicKey;
+use rsa::{Pkcs1v15Sign, RsaPublicKey};
+use serde::de::DeserializeOwned;
+use serde_json::Value;
+use sha2::{Digest, Sha256};
+
+use crate::errors::http_error::HttpError;
+use crate::request::request::Request;
+use crate::response::respond::IntoResponse;
+use crate::response::response::Response;
+use crate::server::auth::{bearer_challenge, is_b64token, BearerError};
+use crate::server::kv::unix_ms;
+use crate::server::secrets::{constant_time_eq, hmac_sha256, SecretsProvider};
+use crate::util::base64url;
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum Algorithm {
+    Hs256,
+    Rs256,
+}
+
+impl Algorithm {
+    fn name(self) -> &'static str {
+        match self {
+            Algorithm::Hs256 => "HS256",
+            Algorithm::Rs256 => "RS256",
+        }
+    }
+}
+
+/// Bearer JWT verification, installed with `RouteOptions::jwt`.
+///
+/// Tokens must be signed with the configured algorithm, HS256 or RS256,
+/// under the key the `SecretsProvider` holds, and carry an `exp` that
+/// hasn't passed; `nbf`, and `iss` and `aud` when they're required, are
+/// checked too. The key is fetched again for every request, so rotating
+/// the secret takes effect without a restart. A missing or invalid token
+/// gets 401 with a Bearer challenge, one without a required scope 403, and
+/// a malformed one 400; the challenge names the RFC 6750 error.
+/// The handler finds the verified payload with `req.extension::<Claims>()`.
+/// Clones share their key cache.
+#[derive(Clone)]
+pub struct Jwt {
+    algorithm: Algorithm,
+    secrets: Arc<dyn SecretsProvider>,
+    key: String,
+    realm: Option<String>,
+    issuer: Option<String>,
+    audience: Option<String>,
+    scopes: Vec<String>,
+    leeway: Duration,
+    rsa: Arc<Mutex<Option<ParsedKey>>>,
+}
+
+// the last RSA key parsed and the PEM it came from, so a rotated key is
+// parsed once rather than on every request
+type ParsedKey = (Vec<u8>, RsaPublicKey);
+
+/// The payload of a verified token.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Claims(Value);
+
+impl Claims {
+    pub fn get(&self, name: &str) -> Option<&Value> {
+        self.0.get(name)
+    }
+
+    /// The `sub` claim.
+    pub fn subject(&self) -> Option<&str> {
+        self.get("sub")?.as_str()
+    }
+
+    /// The granted scopes, from a space-separated `scope` claim or an `scp`
+    /// array.
+    pub fn scopes(&self) -> Vec<&str> {
+        if let Some(scope) = self.get("scope").and_then(Value::as_str) {
+            return scope.split_whitespace().collect();
+        }
+        self.get("scp")
+            .and_then(Value::as_array)
+            .map_or_else(Vec::new, |scp| {
+                scp.iter().filter_map(Value::as_str).collect()
+            })
+    }
+
+    /// The payload as `T`, e.g. a struct of the application's own claims.
+    pub fn deserialize<T: DeserializeOwned>(&self) -> io::Result<T> {
+        Ok(serde_json::from_value(self.0.clone())?)
+    }
+
+    pub fn as_value(&self) -> &Value {
+        &self.0
+    }
+}
+
+impl Jwt {
+    /// Verifies HMAC-SHA256 signatures with the shared secret `key` names.
+    pub fn hs256<S: SecretsProvider + 'static>(secrets: S, key: &str) -> Self {
+        Jwt::new(Algorithm::Hs256, Arc::new(secrets), key)
+    }
+
+    /// Verifies RSA PKCS#1 v1.5 SHA-256 signatures with the public key
+    /// `key` names, in SPKI or PKCS#1 PEM.
+    pub fn rs256<S: SecretsProvider + 'static>(secrets: S, key: &str) -> Self {
+        Jwt::new(Algorithm::Rs256, Arc::new(secrets), key)
+    }
+
+    fn new(algorithm: Algorithm, secrets: Arc<dyn SecretsProvider>, key: &str) -> Self {
+        Jwt {
+            algorithm,
+            secrets,
+            key: key.to_owned(),
+            realm: None,
+            issuer: None,
+            audience: None,
+            scopes: Vec::new(),
+            leeway: Duration::from_secs(60),
+            rsa: Arc::default(),
+        }
+    }
+
+    /// Names the protected area in challenges.
+    pub fn realm(&mut self, realm: &str) -> &mut Self {
+        self.realm = Some(realm.replace(['"', '\\'], ""));
+        self
+    }
+
+    /// Requires `iss` to be `issuer`.
+    pub fn issuer(&mut self, issuer: &str) -> &mut Self {
+        self.issuer = Some(issuer.to_owned());
+        self
+    }
+
+    /// Requires `aud` to be, or to list, `audience`.
+    pub fn audience(&mut self, audience: &str) -> &mut Self {
+        self.audience = Some(audience.to_owned());
+        self
+    }
+
+    /// Requires `scope` to have been granted; may be called more than once.
+    pub fn scope(&mut self, scope: &str) -> &mut Self {
+        self.scopes.push(scope.to_owned());
+        self
+    }
+
+    /// Clock skew tolerated on `exp` and `nbf`, a minute by default.
+    pub fn leeway(&mut self, leeway: Duration) -> &mut Self {
+        self.leeway = leeway;
+        self
+    }
+
+    // attaches the claims, or answers 401 or 403 and says so; failing to
+    // get the key fails the request
+    pub(crate) fn check(&self, req: &mut Request, res: &mut Response) -> io::Result<bool> {
+        let token = req
+            .authorization()
+            .filter(|auth| auth.scheme().eq_ignore_ascii_case("bearer"))
+            .map(|auth| auth.credentials().to_owned());
+        let refused = match token.as_deref() {
+            Some(token) if !is_b64token(token) => {
+                Some(BearerError::InvalidRequest("malformed token"))
+            }
+            Some(token) => match self.verify(token)? {
+                Ok(claims) => {
+                    req.insert_extension(claims);
+                    return Ok(true);
+                }
+                Err(refused) => Some(refused),
+            },
+            None => None,
+        };
+        req.req.reject_body();
+        let (error, scope) = match &refused {
+            None => (
+                HttpError::Unauthorized("authentication required".to_owned()),
+                None,
+            ),
+            Some(BearerError::InvalidRequest(reason)) => {
+                (HttpError::BadRequest((*reason).to_owned()), None)
+            }
+            Some(BearerError::InvalidToken(reason)) => {
+                (HttpError::Unauthorized((*reason).to_owned()), None)
+            }
+            Some(BearerError::InsufficientScope(reason)) => {
+                let scope = self.scopes.join(" ");
+                (HttpError::Forbidden((*reason).to_owned()), Some(scope))
+            }
+        };
+        let realm = self.realm.as_deref();
+        res.header_owned(bearer_challenge(realm, refused.as_ref(), scope.as_deref()));
+        error.into_response(res)?;
+        Ok(false)
+    }
+
+    fn verify(&self, token: &str) -> io::Result<Result<Claims, BearerError>> {
+        let mut parts = token.splitn(3, '.');
+        let (header, payload, signature) = match (parts.next(), parts.next(), parts.next()) {
+            (Some(header), Some(payload), Some(signature)) => (header, payload, signature),
+            _ => return Ok(Err(BearerError::InvalidToken("malformed token"))),
+        };
+        let decoded = (base64url(header), base64url(payload), base64url(signature));
+        let (header_json, payload_json, signature) = match decoded {
+            (Some(header), Some(payload), Some(signature)) => (header, payload, signature),
+            _ => return Ok(Err(BearerError::InvalidToken("malformed token"))),
+        };
+        // the algorithm is ours to pick, never the token's, so neither
+        // "none" nor an HS256 token signed with the RSA public key passes
+        let jose: Value = match serde_json::from_slice(&header_json) {
+            Ok(jose) => jose,
+            Err(_) => return Ok(Err(BearerError::InvalidToken("malformed token"))),
+        };
+        if jose.get("alg").and_then(Value::as_str) != Some(self.algorithm.name()) {
+            return Ok(Err(BearerError::InvalidToken("unexpected algorithm")));
+        }
+        let signed = &token[..header.len() + 1 + payload.len()];
+        if !self.signature_valid(signed.as_bytes(), &signature)? {
+            return Ok(Err(BearerError::InvalidToken("invalid signature")));
+        }
+        let claims: Value = match serde_json::from_slice(&payload_json) {
+            Ok(claims @ Value::Object(_)) => claims,
+            _ => return Ok(Err(BearerError::InvalidToken("malformed token"))),
+        };
+        Ok(self.validate(Claims(claims)))
+    }
+
+    fn signature_valid(&self, signed: &[u8], signature: &[u8]) -> io::Result<bool> {
+        let key = self.secrets.secret(&self.key)?;
+        match self.algorithm {
+            Algorithm::Hs256 => Ok(constant_time_eq(&hmac_sha256(&key, signed), signature)),
+            Algorithm::Rs256 => {
+                let key = self.rsa_key(key)?;
+                let digest = Sha256::digest(signed);
+                let scheme = Pkcs1v15Sign::new::<Sha256>();
+                Ok(key.verify(scheme, &digest, signature).is_ok())
+            }
+        }
+    }
+
+    fn rsa_key(&self, pem: Vec<u8>) -> io::Result<RsaPublicKey> {
+        let mut cached = self.rsa.lock().unwrap();
+        if let Some((source, key)) = &*cached {
+            if *source == pem {
+                return Ok(key.clone());
+            }
+        }
+        let invalid = || {
+            io::Error::new(
+                io::ErrorKind::InvalidData,
+                "jwt: key is not an RSA public key in PEM",
+            )
+        };
+        let text = std::str::from_utf8(&pem).map_err(|_| invalid())?;
+        let key = RsaPublicKey::from_public_key_pem(text)
+            .or_else(|_| RsaPublicKey::from_pkcs1_pem(text))
+            .map_err(|_| invalid())?;
+        *cached = Some((pem, key.clone()));
+        Ok(key)
+    }
+
+    fn validate(&self, claims: Claims) -> Result<Claims, BearerError> {
+        let now = unix_ms() / 1000;
+        let leeway = self.leeway.as_secs();
+        let time = |name| claims.get(name).and_then(Value::as_f64).map(|t| t as u64);
+        match time("exp") {
+            Some(exp) if exp.saturating_add(leeway) > now => {}
+            Some(_) => return Err(BearerError::InvalidToken("token expired")),
+            None => return Err(BearerError::InvalidToken("token has no expiry")),
+        }
+        if claims.get("nbf").is_some() {
+            match time("nbf") {
+                Some(nbf) if nbf <= now.saturating_add(leeway) => {}
+                _ => return Err(BearerError::InvalidToken("token not yet valid")),
+            }
+        }
+        if let Some(issuer) = &self.issuer {
+            if claims.get("iss").and_then(Value::as_str) != Some(issuer.as_str()) {
+                return Err(BearerError::InvalidToken("unexpected issuer"));
+            }
+        }
+        if let Some(audience) = &self.audience {
+            let listed = match claims.get("aud") {
+                Some(Value::String(aud)) => aud == audience,
+                Some(Value::Array(auds)) => auds
+                    .iter()
+                    .any(|aud| aud.as_str() == Some(audience.as_str())),
+                _ => false,
+            };
+            if !listed {
+                return Err(BearerError::InvalidToken("unexpected audience"));
+            }
+        }
+        let granted = claims.scopes();
+        if !self
+            .scopes
+            .iter()
+            .all(|scope| granted.contains(&scope.as_str()))
+        {
+            return Err(BearerError::InsufficientScope("insufficient scope"));
+        }
+        Ok(claims)
+    }
+}
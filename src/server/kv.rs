@@ -0,0 +1,334 @@
+//! one key-value interface for state that middleware keeps between requests
+
+use std::collections::HashMap;
+use std::fs::{self, File};
+use std::io::{self, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+use sha2::{Digest, Sha256};
+
+// past this many entries, expired ones are dropped on the next write
+const PRUNE_ABOVE: usize = 10_000;
+
+/// Where stateful middleware keeps what must outlive a request: rate
+/// limit buckets, sessions, idempotency keys, cached responses.
+///
+/// Implement it once to plug in a backend for all of them; `MemoryKv` and
+/// `FileKv` come with the crate, and `Redis` with the `redis` feature.
+/// Keys are opaque strings and values opaque bytes. Every operation on a
+/// single key must be atomic, `cas` and `incr` above all.
+pub trait KvStore: Send + Sync {
+    fn get(&self, key: &str) -> io::Result<Option<Vec<u8>>>;
+
+    /// Stores `value`, expiring after `ttl`, or never without one.
+    fn set(&self, key: &str, value: &[u8], ttl: Option<Duration>) -> io::Result<()>;
+
+    /// Removes `key`, saying whether it was there.
+    fn delete(&self, key: &str) -> io::Result<bool>;
+
+    /// How long `key` has left; `None` if it's missing or never expires.
+    fn ttl(&self, key: &str) -> io::Result<Option<Duration>>;
+
+    /// Adds `by` to the decimal integer at `key`, a missing key counting
+    /// as 0, and returns the sum. `ttl` applies only when this creates the
+    /// key, so a counter expires a fixed time after its first increment.
+    fn incr(&self, key: &str, by: i64, ttl: Option<Duration>) -> io::Result<i64>;
+
+    /// Sets `key` to `new` only if it currently holds `expected`, `None`
+    /// meaning absent, saying whether it did.
+    fn cas(
+        &self,
+        key: &str,
+        expected: Option<&[u8]>,
+        new: &[u8],
+        ttl: Option<Duration>,
+    ) -> io::Result<bool>;
+}
+
+impl<T: KvStore + ?Sized> KvStore for Arc<T> {
+    fn get(&self, key: &str) -> io::Result<Option<Vec<u8>>> {
+        (**self).get(key)
+    }
+
+    fn set(&self, key: &str, value: &[u8], ttl: Option<Duration>) -> io::Result<()> {
+        (**self).set(key, value, ttl)
+    }
+
+    fn delete(&self, key: &str) -> io::Result<bool> {
+        (**self).delete(key)
+    }
+
+    fn ttl(&self, key: &str) -> io::Result<Option<Duration>> {
+        (**self).ttl(key)
+    }
+
+    fn incr(&self, key: &str, by: i64, ttl: Option<Duration>) -> io::Result<i64> {
+        (**self).incr(key, by, ttl)
+    }
+
+    fn cas(
+        &self,
+        key: &str,
+        expected: Option<&[u8]>,
+        new: &[u8],
+        ttl: Option<Duration>,
+    ) -> io::Result<bool> {
+        (**self).cas(key, expected, new, ttl)
+    }
+}
+
+/// Entries in process memory, lost on restart and not shared between
+/// instances.
+#[derive(Default)]
+pub struct MemoryKv {
+    entries: Mutex<HashMap<String, MemoryEntry>>,
+}
+
+struct MemoryEntry {
+    value: Vec<u8>,
+    expires: Option<Instant>,
+}
+
+impl MemoryKv {
+    pub fn new() -> Self {
+        MemoryKv::default()
+    }
+
+    // the live entry at `key`, dropping it if it has expired
+    fn live<'e>(
+        entries: &'e mut HashMap<String, MemoryEntry>,
+        key: &str,
+        now: Instant,
+    ) -> Option<&'e mut MemoryEntry> {
+        let expired = entries
+            .get(key)
+            .map_or(false, |entry| entry.expires.map_or(false, |at| at <= now));
+        if expired {
+            entries.remove(key);
+        }
+        entries.get_mut(key)
+    }
+
+    fn insert(
+        entries: &mut HashMap<String, MemoryEntry>,
+        key: &str,
+        value: Vec<u8>,
+        ttl: Option<Duration>,
+        now: Instant,
+    ) {
+        if entries.len() > PRUNE_ABOVE {
+            entries.retain(|_, entry| entry.expires.map_or(true, |at| at > now));
+        }
+        let expires = ttl.map(|ttl| now + ttl);
+        entries.insert(key.to_owned(), MemoryEntry { value, expires });
+    }
+}
+
+impl KvStore for MemoryKv {
+    fn get(&self, key: &str) -> io::Result<Option<Vec<u8>>> {
+        let mut entries = self.entries.lock().unwrap();
+        let entry = MemoryKv::live(&mut entries, key, Instant::now());
+        Ok(entry.map(|entry| entry.value.clone()))
+    }
+
+    fn set(&self, key: &str, value: &[u8], ttl: Option<Duration>) -> io::Result<()> {
+        let mut entries = self.entries.lock().unwrap();
+        MemoryKv::insert(&mut entries, key, value.to_vec(), ttl, Instant::now());
+        Ok(())
+    }
+
+    fn delete(&self, key: &str) -> io::Result<bool> {
+        let mut entries = self.entries.lock().unwrap();
+        let live = MemoryKv::live(&mut entries, key, Instant::now()).is_some();
+        entries.remove(key);
+        Ok(live)
+    }
+
+    fn ttl(&self, key: &str) -> io::Result<Option<Duration>> {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        let entry = MemoryKv::live(&mut entries, key, now);
+        Ok(entry.and_then(|entry| entry.expires).map(|at| at - now))
+    }
+
+    fn incr(&self, key: &str, by: i64, ttl: Option<Duration>) -> io::Result<i64> {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        match MemoryKv::live(&mut entries, key, now) {
+            Some(entry) => {
+                let n = add(&entry.value, by)?;
+                entry.value = n.to_string().into_bytes();
+                Ok(n)
+            }
+            None => {
+                MemoryKv::insert(&mut entries, key, by.to_string().into_bytes(), ttl, now);
+                Ok(by)
+            }
+        }
+    }
+
+    fn cas(
+        &self,
+        key: &str,
+        expected: Option<&[u8]>,
+        new: &[u8],
+        ttl: Option<Duration>,
+    ) -> io::Result<bool> {
+        let now = Instant::now();
+        let mut entries = self.entries.lock().unwrap();
+        let current = MemoryKv::live(&mut entries, key, now).map(|entry| &entry.value[..]);
+        if current != expected {
+            return Ok(false);
+        }
+        MemoryKv::insert(&mut entries, key, new.to_vec(), ttl, now);
+        Ok(true)
+    }
+}
+
+/// Entries as files in a directory, one per key, surviving restarts.
+///
+/// File names are hashes of the keys. Writes go through a temporary file
+/// and a rename, so readers never see half an entry, but `incr` and `cas`
+/// are only atomic against other users of the same `FileKv`; processes
+/// sharing a directory should use a store with real locking. Expired
+/// entries are removed when next read.
+pub struct FileKv {
+    dir: PathBuf,
+    lock: Mutex<()>,
+}
+
+impl FileKv {
+    /// Keeps entries in `dir`, created on the first write.
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        FileKv {
+            dir: dir.into(),
+            lock: Mutex::new(()),
+        }
+    }
+
+    fn path(&self, key: &str) -> PathBuf {
+        let hash = Sha256::digest(key.as_bytes());
+        let name: String = hash.iter().map(|b| format!("{:02x}", b)).collect();
+        self.dir.join(name)
+    }
+
+    // the value and expiry, as milliseconds since the epoch, of a live
+    // entry; an entry is its expiry, 0 for none, as 8 big-endian bytes,
+    // then the value
+    fn read(&self, key: &str) -> io::Result<Option<(Vec<u8>, u64)>> {
+        let path = self.path(key);
+        let mut data = match fs::read(&path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e),
+        };
+        if data.len() < 8 {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidData,
+                format!("corrupt entry {}", path.display()),
+            ));
+        }
+        let mut expires = [0; 8];
+        expires.copy_from_slice(&data[..8]);
+        let expires = u64::from_be_bytes(expires);
+        if expires != 0 && expires <= unix_ms() {
+            remove(&path)?;
+            return Ok(None);
+        }
+        data.drain(..8);
+        Ok(Some((data, expires)))
+    }
+
+    fn write(&self, key: &str, value: &[u8], expires: u64) -> io::Result<()> {
+        fs::create_dir_all(&self.dir)?;
+        let path = self.path(key);
+        let tmp = path.with_extension(format!("{}.tmp", std::process::id()));
+        let mut file = File::create(&tmp)?;
+        file.write_all(&expires.to_be_bytes())?;
+        file.write_all(value)?;
+        file.sync_data()?;
+        fs::rename(&tmp, &path)
+    }
+}
+
+impl KvStore for FileKv {
+    fn get(&self, key: &str) -> io::Result<Option<Vec<u8>>> {
+        let _guard = self.lock.lock().unwrap();
+        Ok(self.read(key)?.map(|(value, _)| value))
+    }
+
+    fn set(&self, key: &str, value: &[u8], ttl: Option<Duration>) -> io::Result<()> {
+        let _guard = self.lock.lock().unwrap();
+        self.write(key, value, expiry(ttl))
+    }
+
+    fn delete(&self, key: &str) -> io::Result<bool> {
+        let _guard = self.lock.lock().unwrap();
+        let live = self.read(key)?.is_some();
+        remove(&self.path(key))?;
+        Ok(live)
+    }
+
+    fn ttl(&self, key: &str) -> io::Result<Option<Duration>> {
+        let _guard = self.lock.lock().unwrap();
+        Ok(match self.read(key)? {
+            Some((_, 0)) | None => None,
+            Some((_, expires)) => Some(Duration::from_millis(expires.saturating_sub(unix_ms()))),
+        })
+    }
+
+    fn incr(&self, key: &str, by: i64, ttl: Option<Duration>) -> io::Result<i64> {
+        let _guard = self.lock.lock().unwrap();
+        let (n, expires) = match self.read(key)? {
+            Some((value, expires)) => (add(&value, by)?, expires),
+            None => (by, expiry(ttl)),
+        };
+        self.write(key, n.to_string().as_bytes(), expires)?;
+        Ok(n)
+    }
+
+    fn cas(
+        &self,
+        key: &str,
+        expected: Option<&[u8]>,
+        new: &[u8],
+        ttl: Option<Duration>,
+    ) -> io::Result<bool> {
+        let _guard = self.lock.lock().unwrap();
+        let current = self.read(key)?;
+        if current.as_ref().map(|(value, _)| &value[..]) != expected {
+            return Ok(false);
+        }
+        self.write(key, new, expiry(ttl))?;
+        Ok(true)
+    }
+}
+
+// `by` added to a stored decimal integer
+fn add(value: &[u8], by: i64) -> io::Result<i64> {
+    std::str::from_utf8(value)
+        .ok()
+        .and_then(|s| s.parse::<i64>().ok())
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "value is not an integer"))?
+        .checked_add(by)
+        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "increment overflows"))
+}
+
+fn remove(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path) {
+        Err(e) if e.kind() != io::ErrorKind::NotFound => Err(e),
+        _ => Ok(()),
+    }
+}
+
+fn expiry(ttl: Option<Duration>) -> u64 {
+    ttl.map_or(0, |ttl| unix_ms() + (ttl.as_millis() as u64).max(1))
+}
+
+pub(crate) fn unix_ms() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_millis() as u64)
+}
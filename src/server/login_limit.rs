@@ -0,0 +1,135 @@
+use std::collections::HashMap;
+use std::hash::Hash;
+use std::net::IpAddr;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+// past this many tracked keys, quiet ones are dropped on the next failure
+const PRUNE_ABOVE: usize = 10_000;
+
+/// Failed login tracking with exponential backoff, per client IP and per
+/// account.
+///
+/// Attached to a route with `RouteOptions::login_limiter`, clients whose
+/// requests keep failing with 401 get 429 with Retry-After until their
+/// backoff runs out. Only the handler knows which account was tried, so
+/// it reports those through `account_failed`, `account_succeeded` and
+/// `account_blocked`. Clones share their state.
+#[derive(Clone)]
+pub struct LoginLimiter {
+    free_attempts: u32,
+    base_delay: Duration,
+    max_delay: Duration,
+    by_ip: Arc<Mutex<HashMap<IpAddr, Failures>>>,
+    by_account: Arc<Mutex<HashMap<String, Failures>>>,
+}
+
+impl Default for LoginLimiter {
+    fn default() -> Self {
+        LoginLimiter::new()
+    }
+}
+
+struct Failures {
+    count: u32,
+    last: Instant,
+}
+
+impl LoginLimiter {
+    /// Five free attempts, then waits doubling from one second up to a
+    /// fifteen minute lockout.
+    pub fn new() -> Self {
+        LoginLimiter {
+            free_attempts: 5,
+            base_delay: Duration::from_secs(1),
+            max_delay: Duration::from_secs(15 * 60),
+            by_ip: Arc::default(),
+            by_account: Arc::default(),
+        }
+    }
+
+    /// Failures allowed before any backoff applies.
+    pub fn free_attempts(&mut self, attempts: u32) -> &mut Self {
+        self.free_attempts = attempts;
+        self
+    }
+
+    /// Wait after the first failure past the free attempts; doubles with
+    /// every further one.
+    pub fn base_delay(&mut self, delay: Duration) -> &mut Self {
+        self.base_delay = delay;
+        self
+    }
+
+    /// Longest wait, i.e. the lockout; failures are forgotten once this
+    /// long has passed without one.
+    pub fn max_delay(&mut self, delay: Duration) -> &mut Self {
+        self.max_delay = delay;
+        self
+    }
+
+    /// How much longer `account` is locked out, if it is.
+    pub fn account_blocked(&self, account: &str) -> Option<Duration> {
+        self.blocked(&self.by_account, account)
+    }
+
+    pub fn account_failed(&self, account: &str) {
+        self.failed(&self.by_account, account.to_owned());
+    }
+
+    pub fn account_succeeded(&self, account: &str) {
+        self.by_account.lock().unwrap().remove(account);
+    }
+
+    pub(crate) fn ip_blocked(&self, ip: IpAddr) -> Option<Duration> {
+        self.blocked(&self.by_ip, &ip)
+    }
+
+    pub(crate) fn ip_failed(&self, ip: IpAddr) {
+        self.failed(&self.by_ip, ip);
+    }
+
+    pub(crate) fn ip_succeeded(&self, ip: IpAddr) {
+        self.by_ip.lock().unwrap().remove(&ip);
+    }
+
+    fn blocked<K, Q>(&self, map: &Mutex<HashMap<K, Failures>>, key: &Q) -> Option<Duration>
+    where
+        K: Eq + Hash + std::borrow::Borrow<Q>,
+        Q: Eq + Hash + ?Sized,
+    {
+        let map = map.lock().unwrap();
+        let failures = map.get(key)?;
+        let until = failures.last + self.delay(failures.count);
+        until.checked_duration_since(Instant::now())
+    }
+
+    fn failed<K: Eq + Hash>(&self, map: &Mutex<HashMap<K, Failures>>, key: K) {
+        let now = Instant::now();
+        let mut map = map.lock().unwrap();
+        if map.len() > PRUNE_ABOVE {
+            map.retain(|_, f| now.duration_since(f.last) < self.max_delay);
+        }
+        let failures = map.entry(key).or_insert(Failures {
+            count: 0,
+            last: now,
+        });
+        if now.duration_since(failures.last) >= self.max_delay {
+            failures.count = 0;
+        }
+        failures.count += 1;
+        failures.last = now;
+    }
+
+    fn delay(&self, count: u32) -> Duration {
+        match count.checked_sub(self.free_attempts.saturating_add(1)) {
+            None => Duration::ZERO,
+            Some(doublings) => {
+                let factor = 1u32.checked_shl(doublings).unwrap_or(u32::MAX);
+                self.base_delay
+                    .checked_mul(factor)
+                    .map_or(self.max_delay, |delay| delay.min(self.max_delay))
+            }
+        }
+    }
+}
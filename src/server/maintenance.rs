@@ -0,0 +1,155 @@
+use std::collections::HashMap;
+use std::path::PathBuf;
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, RwLock};
+use std::time::{Duration, Instant};
+
+use crate::request::request::RawRequest;
+use crate::response::response::Response;
+
+// how long a look at the flag file is trusted
+const FLAG_FILE_TTL: Duration = Duration::from_secs(1);
+
+/// Runtime maintenance switch, installed with `Server::maintenance`.
+///
+/// While on, routes not marked `RouteOptions::maintenance_exempt` get 503
+/// with Retry-After. It's on when enabled through any clone, e.g. from an
+/// exempt admin route, or while the flag file exists.
+///
+/// Single routes can be drained too, to take one feature down: new
+/// requests to a draining route get the same 503, exempt or not, while
+/// those already in it finish.
+#[derive(Clone)]
+pub struct Maintenance {
+    enabled: Arc<AtomicBool>,
+    flag_file: Option<PathBuf>,
+    // when the flag file was last looked at, and whether it was there
+    flag_seen: Arc<Mutex<Option<(Instant, bool)>>>,
+    retry_after: Duration,
+    // by route pattern, added as they're first requested or drained
+    routes: Arc<RwLock<HashMap<String, Arc<RouteState>>>>,
+}
+
+#[derive(Default)]
+struct RouteState {
+    draining: AtomicBool,
+    in_flight: AtomicUsize,
+}
+
+// a request being served by a route, counted until dropped
+pub(crate) struct RouteVisit(Arc<RouteState>);
+
+impl Drop for RouteVisit {
+    fn drop(&mut self) {
+        self.0.in_flight.fetch_sub(1, Ordering::SeqCst);
+    }
+}
+
+impl Maintenance {
+    pub fn new() -> Self {
+        Maintenance {
+            enabled: Arc::default(),
+            flag_file: None,
+            flag_seen: Arc::default(),
+            retry_after: Duration::from_secs(60),
+            routes: Arc::default(),
+        }
+    }
+
+    /// Also on while `path` exists; checked at most once a second.
+    pub fn flag_file<P: Into<PathBuf>>(&mut self, path: P) -> &mut Self {
+        self.flag_file = Some(path.into());
+        self
+    }
+
+    /// Retry-After sent with the 503s.
+    pub fn retry_after(&mut self, retry_after: Duration) -> &mut Self {
+        self.retry_after = retry_after;
+        self
+    }
+
+    pub fn enable(&self) {
+        self.enabled.store(true, Ordering::Relaxed);
+    }
+
+    pub fn disable(&self) {
+        self.enabled.store(false, Ordering::Relaxed);
+    }
+
+    pub fn is_enabled(&self) -> bool {
+        self.enabled.load(Ordering::Relaxed) || self.flagged()
+    }
+
+    /// Refuses new requests to the route registered as `route`, e.g.
+    /// `/reports/:id`, on every method and virtual host, until `resume`.
+    pub fn drain(&self, route: &str) {
+        self.route(route).draining.store(true, Ordering::SeqCst);
+    }
+
+    pub fn resume(&self, route: &str) {
+        self.route(route).draining.store(false, Ordering::SeqCst);
+    }
+
+    pub fn is_draining(&self, route: &str) -> bool {
+        let routes = self.routes.read().unwrap();
+        routes
+            .get(route)
+            .map_or(false, |state| state.draining.load(Ordering::SeqCst))
+    }
+
+    /// Requests `route` is serving, e.g. to wait for a drained route to
+    /// finish the ones it had.
+    pub fn in_flight(&self, route: &str) -> usize {
+        let routes = self.routes.read().unwrap();
+        routes
+            .get(route)
+            .map_or(0, |state| state.in_flight.load(Ordering::SeqCst))
+    }
+
+    // counts a request into `route`, or `None` while it's draining
+    pub(crate) fn enter(&self, route: &str) -> Option<RouteVisit> {
+        let state = self.route(route);
+        // counted before looking, so `in_flight` after `drain` includes
+        // every request that got past it
+        state.in_flight.fetch_add(1, Ordering::SeqCst);
+        let visit = RouteVisit(state);
+        (!visit.0.draining.load(Ordering::SeqCst)).then_some(visit)
+    }
+
+    // answers 503 with Retry-After
+    pub(crate) fn refuse(&self, req: &mut RawRequest, res: &mut Response) {
+        req.reject_body();
+        res.status_code(503, "Service Unavailable");
+        res.header_owned(format!("Retry-After: {}", self.retry_after.as_secs()));
+    }
+
+    fn route(&self, route: &str) -> Arc<RouteState> {
+        if let Some(state) = self.routes.read().unwrap().get(route) {
+            return state.clone();
+        }
+        let mut routes = self.routes.write().unwrap();
+        routes.entry(route.to_owned()).or_default().clone()
+    }
+
+    fn flagged(&self) -> bool {
+        let path = match &self.flag_file {
+            Some(path) => path,
+            None => return false,
+        };
+        let mut seen = self.flag_seen.lock().unwrap();
+        match *seen {
+            Some((at, present)) if at.elapsed() < FLAG_FILE_TTL => present,
+            _ => {
+                let present = path.exists();
+                *seen = Some((Instant::now(), present));
+                present
+            }
+        }
+    }
+}
+
+impl Default for Maintenance {
+    fn default() -> Self {
+        Maintenance::new()
+    }
+}
@@ -0,0 +1,260 @@
+use std::collections::BTreeMap;
+use std::fmt::Write as _;
+use std::io;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex, Weak};
+use std::time::Duration;
+
+use crate::http::shutdown::Lifecycle;
+use crate::request::request::Request;
+use crate::response::response::Response;
+use crate::server::access_log::AccessEntry;
+use crate::server::nonce::NonceRejection;
+
+/// Upper bounds, in seconds, of the request latency histogram buckets.
+pub const LATENCY_BUCKETS: [f64; 11] = [
+    0.005, 0.01, 0.025, 0.05, 0.1, 0.25, 0.5, 1.0, 2.5, 5.0, 10.0,
+];
+
+const STATUS_CLASSES: [&str; 5] = ["1xx", "2xx", "3xx", "4xx", "5xx"];
+const NONCE_REJECTIONS: [&str; 2] = ["replayed", "full"];
+
+/// Request and connection metrics, collected once installed with
+/// `Server::metrics`. Clones share their counters.
+///
+/// Read them with `snapshot`, or serve them in the Prometheus text format
+/// by routing a path to `endpoint`.
+#[derive(Clone, Default)]
+pub struct Metrics {
+    inner: Arc<Inner>,
+}
+
+#[derive(Default)]
+struct Inner {
+    requests: AtomicU64,
+    statuses: [AtomicU64; 5],
+    in_flight: AtomicUsize,
+    nonces_rejected: [AtomicU64; 2],
+    // keyed by method and route pattern, so ids in paths don't add series
+    routes: Mutex<BTreeMap<(String, String), Buckets>>,
+    // the servers started with these metrics, for the connection gauge
+    servers: Mutex<Vec<Weak<Lifecycle>>>,
+}
+
+#[derive(Default)]
+struct Buckets {
+    counts: [u64; LATENCY_BUCKETS.len()],
+    count: u64,
+    sum: f64,
+}
+
+/// Point-in-time copy of `Metrics`.
+#[derive(Clone, Debug)]
+pub struct MetricsSnapshot {
+    pub requests: u64,
+    /// Responses by status class, 1xx through 5xx.
+    pub statuses: [u64; 5],
+    pub in_flight: usize,
+    pub connections: usize,
+    /// Nonces refused as replayed, and because the tracker was full.
+    pub nonces_rejected: [u64; 2],
+    pub routes: Vec<RouteLatency>,
+}
+
+/// Latency of one routed method and pattern, e.g. `GET /users/:id`.
+#[derive(Clone, Debug)]
+pub struct RouteLatency {
+    pub method: String,
+    pub route: String,
+    /// Requests at or under each of `LATENCY_BUCKETS`, cumulative.
+    pub buckets: Vec<u64>,
+    pub count: u64,
+    pub sum: Duration,
+}
+
+impl Metrics {
+    pub fn new() -> Self {
+        Metrics::default()
+    }
+
+    pub fn snapshot(&self) -> MetricsSnapshot {
+        let inner = &*self.inner;
+        let routes = inner.routes.lock().unwrap();
+        MetricsSnapshot {
+            requests: inner.requests.load(Ordering::Relaxed),
+            statuses: [0, 1, 2, 3, 4].map(|i| inner.statuses[i].load(Ordering::Relaxed)),
+            in_flight: inner.in_flight.load(Ordering::Relaxed),
+            connections: self.connections(),
+            nonces_rejected: [0, 1].map(|i| inner.nonces_rejected[i].load(Ordering::Relaxed)),
+            routes: routes
+                .iter()
+                .map(|((method, route), buckets)| RouteLatency {
+                    method: method.clone(),
+                    route: route.clone(),
+                    buckets: buckets
+                        .counts
+                        .iter()
+                        .scan(0, |total, n| {
+                            *total += n;
+                            Some(*total)
+                        })
+                        .collect(),
+                    count: buckets.count,
+                    sum: Duration::from_secs_f64(buckets.sum),
+                })
+                .collect(),
+        }
+    }
+
+    /// The metrics in the Prometheus text exposition format.
+    pub fn render(&self) -> String {
+        let snapshot = self.snapshot();
+        let mut out = String::new();
+        let _ = writeln!(out, "# HELP aegis_requests_total Requests served.");
+        let _ = writeln!(out, "# TYPE aegis_requests_total counter");
+        let _ = writeln!(out, "aegis_requests_total {}", snapshot.requests);
+        let _ = writeln!(
+            out,
+            "# HELP aegis_responses_total Responses by status class."
+        );
+        let _ = writeln!(out, "# TYPE aegis_responses_total counter");
+        for (class, count) in STATUS_CLASSES.iter().zip(snapshot.statuses) {
+            let _ = writeln!(
+                out,
+                "aegis_responses_total{{class=\"{}\"}} {}",
+                class, count
+            );
+        }
+        let _ = writeln!(
+            out,
+            "# HELP aegis_requests_in_flight Requests being handled."
+        );
+        let _ = writeln!(out, "# TYPE aegis_requests_in_flight gauge");
+        let _ = writeln!(out, "aegis_requests_in_flight {}", snapshot.in_flight);
+        let _ = writeln!(out, "# HELP aegis_connections Connections open.");
+        let _ = writeln!(out, "# TYPE aegis_connections gauge");
+        let _ = writeln!(out, "aegis_connections {}", snapshot.connections);
+        let _ = writeln!(
+            out,
+            "# HELP aegis_nonces_rejected_total Requests refused by nonce checks."
+        );
+        let _ = writeln!(out, "# TYPE aegis_nonces_rejected_total counter");
+        for (reason, count) in NONCE_REJECTIONS.iter().zip(snapshot.nonces_rejected) {
+            let _ = writeln!(
+                out,
+                "aegis_nonces_rejected_total{{reason=\"{}\"}} {}",
+                reason, count
+            );
+        }
+        let _ = writeln!(
+            out,
+            "# HELP aegis_request_duration_seconds Latency of routed requests."
+        );
+        let _ = writeln!(out, "# TYPE aegis_request_duration_seconds histogram");
+        for route in &snapshot.routes {
+            let labels = format!(
+                "method=\"{}\",route=\"{}\"",
+                escape(&route.method),
+                escape(&route.route)
+            );
+            for (le, count) in LATENCY_BUCKETS.iter().zip(&route.buckets) {
+                let _ = writeln!(
+                    out,
+                    "aegis_request_duration_seconds_bucket{{{},le=\"{}\"}} {}",
+                    labels, le, count
+                );
+            }
+            let _ = writeln!(
+                out,
+                "aegis_request_duration_seconds_bucket{{{},le=\"+Inf\"}} {}",
+                labels, route.count
+            );
+            let _ = writeln!(
+                out,
+                "aegis_request_duration_seconds_sum{{{}}} {}",
+                labels,
+                route.sum.as_secs_f64()
+            );
+            let _ = writeln!(
+                out,
+                "aegis_request_duration_seconds_count{{{}}} {}",
+                labels, route.count
+            );
+        }
+        out
+    }
+
+    /// A handler serving `render`, e.g. `server.get("/metrics",
+    /// metrics.endpoint())`.
+    pub fn endpoint(
+        &self,
+    ) -> impl Fn(Request, &mut Response) -> io::Result<()> + Send + Sync + 'static {
+        let metrics = self.clone();
+        move |_req, res| {
+            res.header("Content-Type: text/plain; version=0.0.4");
+            res.str(metrics.render())
+        }
+    }
+
+    // counts the request as in flight until the guard drops
+    pub(crate) fn in_flight(&self) -> InFlight<'_> {
+        self.inner.in_flight.fetch_add(1, Ordering::Relaxed);
+        InFlight(self)
+    }
+
+    pub(crate) fn record(&self, entry: &AccessEntry) {
+        let inner = &*self.inner;
+        inner.requests.fetch_add(1, Ordering::Relaxed);
+        if let 100..=599 = entry.status {
+            inner.statuses[entry.status / 100 - 1].fetch_add(1, Ordering::Relaxed);
+        }
+        // unmatched paths would add a series per path probed
+        let route = match &entry.route {
+            Some(route) => route,
+            None => return,
+        };
+        let secs = entry.latency.as_secs_f64();
+        let mut routes = inner.routes.lock().unwrap();
+        let key = (entry.method.clone(), route.clone());
+        let buckets = routes.entry(key).or_default();
+        if let Some(i) = LATENCY_BUCKETS.iter().position(|&le| secs <= le) {
+            buckets.counts[i] += 1;
+        }
+        buckets.count += 1;
+        buckets.sum += secs;
+    }
+
+    pub(crate) fn nonce_rejected(&self, rejection: NonceRejection) {
+        self.inner.nonces_rejected[rejection as usize].fetch_add(1, Ordering::Relaxed);
+    }
+
+    pub(crate) fn track(&self, lifecycle: &Arc<Lifecycle>) {
+        let mut servers = self.inner.servers.lock().unwrap();
+        servers.retain(|server| server.strong_count() > 0);
+        servers.push(Arc::downgrade(lifecycle));
+    }
+
+    fn connections(&self) -> usize {
+        let servers = self.inner.servers.lock().unwrap();
+        servers
+            .iter()
+            .filter_map(Weak::upgrade)
+            .map(|lifecycle| lifecycle.open())
+            .sum()
+    }
+}
+
+pub(crate) struct InFlight<'a>(&'a Metrics);
+
+impl<'a> Drop for InFlight<'a> {
+    fn drop(&mut self) {
+        self.0.inner.in_flight.fetch_sub(1, Ordering::Relaxed);
+    }
+}
+
+fn escape(label: &str) -> String {
+    label
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
@@ -0,0 +1,125 @@
+//! one-time nonces against replayed requests
+
+use std::collections::{HashSet, VecDeque};
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::server::metrics::Metrics;
+
+/// Why a nonce was refused.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum NonceRejection {
+    /// Seen before within the TTL.
+    Replayed,
+    /// The tracker is full of live nonces, so it can't tell.
+    Full,
+}
+
+/// Remembers the nonces requests were sent with for a while, so the same
+/// request can't be sent twice. Installed with `RequestSigning::nonces`,
+/// or checked directly, e.g. by a handler verifying webhooks.
+///
+/// Nonces live for the TTL, ten minutes by default, which should cover
+/// the span a signed request is accepted in, twice the allowed skew. At
+/// most `capacity` are kept; once that many are live, new ones are
+/// refused rather than letting old ones be replayed. Clones share their
+/// nonces.
+#[derive(Clone)]
+pub struct NonceTracker {
+    ttl: Duration,
+    capacity: usize,
+    seen: Arc<Mutex<Seen>>,
+    rejected: Arc<[AtomicU64; 2]>,
+    metrics: Option<Metrics>,
+}
+
+// every nonce lives as long, so expiry follows insertion order
+#[derive(Default)]
+struct Seen {
+    order: VecDeque<(Instant, String)>,
+    keys: HashSet<String>,
+}
+
+impl Default for NonceTracker {
+    fn default() -> Self {
+        NonceTracker::new()
+    }
+}
+
+impl NonceTracker {
+    pub fn new() -> Self {
+        NonceTracker {
+            ttl: Duration::from_secs(10 * 60),
+            capacity: 100_000,
+            seen: Arc::default(),
+            rejected: Arc::default(),
+            metrics: None,
+        }
+    }
+
+    pub fn ttl(&mut self, ttl: Duration) -> &mut Self {
+        self.ttl = ttl;
+        self
+    }
+
+    /// How many live nonces are kept, 100 000 by default.
+    pub fn capacity(&mut self, capacity: usize) -> &mut Self {
+        self.capacity = capacity;
+        self
+    }
+
+    /// Counts rejections in `metrics` too, as
+    /// `aegis_nonces_rejected_total`.
+    pub fn metrics(&mut self, metrics: &Metrics) -> &mut Self {
+        self.metrics = Some(metrics.clone());
+        self
+    }
+
+    /// Records `nonce` as used by `client`, or says why it can't be.
+    /// Nonces are only compared within one client's.
+    pub fn check(&self, client: &str, nonce: &str) -> Result<(), NonceRejection> {
+        let rejection = match self.record(client, nonce) {
+            Ok(()) => return Ok(()),
+            Err(rejection) => rejection,
+        };
+        self.rejected[rejection as usize].fetch_add(1, Ordering::Relaxed);
+        if let Some(metrics) = &self.metrics {
+            metrics.nonce_rejected(rejection);
+        }
+        Err(rejection)
+    }
+
+    /// Nonces refused so far as replayed, and because the tracker was
+    /// full.
+    pub fn rejected(&self) -> (u64, u64) {
+        let [replayed, full] = &*self.rejected;
+        (
+            replayed.load(Ordering::Relaxed),
+            full.load(Ordering::Relaxed),
+        )
+    }
+
+    fn record(&self, client: &str, nonce: &str) -> Result<(), NonceRejection> {
+        let now = Instant::now();
+        // the client's length keeps `ab` + `c` apart from `a` + `bc`
+        let key = format!("{}:{}:{}", client.len(), client, nonce);
+        let mut seen = self.seen.lock().unwrap();
+        while let Some((expires, _)) = seen.order.front() {
+            if *expires > now {
+                break;
+            }
+            let (_, old) = seen.order.pop_front().unwrap();
+            seen.keys.remove(&old);
+        }
+        if seen.keys.contains(&key) {
+            return Err(NonceRejection::Replayed);
+        }
+        if seen.keys.len() >= self.capacity {
+            return Err(NonceRejection::Full);
+        }
+        seen.keys.insert(key.clone());
+        seen.order.push_back((now + self.ttl, key));
+        Ok(())
+    }
+}
@@ -0,0 +1,79 @@
+//! OpenAPI documents generated from the registered routes
+
+use serde_json::{json, Value};
+
+use crate::response::status::StatusCode;
+use crate::router::route_matcher::RouteMatcher;
+
+// the methods an OpenAPI path item has operations for
+const METHODS: [&str; 8] = [
+    "GET", "PUT", "POST", "DELETE", "OPTIONS", "HEAD", "PATCH", "TRACE",
+];
+
+// an OpenAPI 3.1 document of `routes`; routes for any method or with a
+// wildcard have no OpenAPI form and are left out
+pub(crate) fn document(title: &str, version: &str, routes: &RouteMatcher) -> Value {
+    let mut paths = json!({});
+    for (method, path, options) in routes.iter() {
+        if !METHODS.contains(&method) || path.split('/').any(|s| s == "*") {
+            continue;
+        }
+        let mut parameters = Vec::new();
+        let mut template = String::new();
+        for segment in path.split('/').filter(|s| !s.is_empty()) {
+            template.push('/');
+            match segment.strip_prefix(':') {
+                Some(name) => {
+                    template.push_str(&format!("{{{}}}", name));
+                    parameters.push(json!({
+                        "name": name,
+                        "in": "path",
+                        "required": true,
+                        "schema": {"type": "string"},
+                    }));
+                }
+                None => template.push_str(segment),
+            }
+        }
+        if template.is_empty() {
+            template.push('/');
+        }
+        let mut responses = json!({"default": {"description": "response"}});
+        for (status, schema) in &options.responses {
+            let reason = StatusCode::from_u16(*status).map_or("response", StatusCode::reason);
+            responses[status.to_string()] = json!({
+                "description": reason,
+                "content": {"application/json": {"schema": schema.as_value()}},
+            });
+        }
+        let mut operation = json!({ "responses": responses });
+        if let Some(summary) = &options.summary {
+            operation["summary"] = summary.as_str().into();
+        }
+        if let Some(description) = &options.description {
+            operation["description"] = description.as_str().into();
+        }
+        if !options.tags.is_empty() {
+            operation["tags"] = json!(options.tags);
+        }
+        if options.deprecated.is_some() {
+            operation["deprecated"] = true.into();
+        }
+        if !parameters.is_empty() {
+            operation["parameters"] = parameters.into();
+        }
+        if let Some(schema) = &options.json_schema {
+            operation["requestBody"] = json!({
+                "required": true,
+                "content": {"application/json": {"schema": schema.as_value()}},
+            });
+        }
+        // indexing creates the path item on its first operation
+        paths[template.as_str()][method.to_ascii_lowercase()] = operation;
+    }
+    json!({
+        "openapi": "3.1.0",
+        "info": {"title": title, "version": version},
+        "paths": paths,
+    })
+}
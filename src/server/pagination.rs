@@ -0,0 +1,172 @@
+use std::fmt::Write as _;
+
+use crate::request::request::{percent_decode, Request};
+use crate::response::response::Response;
+
+/// How list endpoints read `page`, `per_page` and `cursor` query
+/// parameters. Share one across routes so they page the same way.
+#[derive(Clone, Copy, Debug)]
+pub struct Pagination {
+    default_per_page: usize,
+    max_per_page: usize,
+}
+
+impl Default for Pagination {
+    fn default() -> Self {
+        Pagination::new()
+    }
+}
+
+impl Pagination {
+    /// 20 items a page, at most 100.
+    pub fn new() -> Self {
+        Pagination {
+            default_per_page: 20,
+            max_per_page: 100,
+        }
+    }
+
+    /// Page size when the request doesn't ask for one.
+    pub fn default_per_page(&mut self, per_page: usize) -> &mut Self {
+        self.default_per_page = per_page.max(1);
+        self
+    }
+
+    /// Largest `per_page` honoured; bigger requests are clamped to it.
+    pub fn max_per_page(&mut self, per_page: usize) -> &mut Self {
+        self.max_per_page = per_page.max(1);
+        self
+    }
+
+    /// The page `req` asks for; missing or malformed parameters fall back
+    /// to the first page at the default size.
+    pub fn page(&self, req: &Request) -> Page {
+        let number = |name| {
+            req.url_parameter(name)
+                .and_then(|n| n.parse::<usize>().ok())
+                .filter(|&n| n > 0)
+        };
+        let per_page = number("per_page")
+            .unwrap_or(self.default_per_page)
+            .min(self.max_per_page.max(self.default_per_page));
+        Page {
+            page: number("page").unwrap_or(1),
+            per_page,
+            cursor: req
+                .url_parameter("cursor")
+                .filter(|c| !c.is_empty())
+                .map(percent_decode),
+        }
+    }
+}
+
+/// One page of a list request, from `Pagination::page`.
+#[derive(Clone, Debug)]
+pub struct Page {
+    /// 1-based page number.
+    pub page: usize,
+    pub per_page: usize,
+    /// Opaque position for cursor pagination, decoded.
+    pub cursor: Option<String>,
+}
+
+impl Page {
+    /// Items to skip for offset pagination.
+    pub fn offset(&self) -> usize {
+        (self.page - 1).saturating_mul(self.per_page)
+    }
+
+    /// Adds `X-Total-Count` and `Link` headers with first, prev, next and
+    /// last for offset pagination over `total` items.
+    pub fn link_pages(&self, req: &Request, res: &mut Response, total: usize) {
+        let last = ((total + self.per_page - 1) / self.per_page).max(1);
+        let mut links = Vec::with_capacity(4);
+        links.push((1, "first"));
+        if self.page > 1 {
+            links.push(((self.page - 1).min(last), "prev"));
+        }
+        if self.page < last {
+            links.push((self.page + 1, "next"));
+        }
+        links.push((last, "last"));
+        let links: Vec<_> = links
+            .into_iter()
+            .map(|(page, rel)| {
+                let url = with_query(req.path(), &[("page", &page.to_string())]);
+                (url, rel)
+            })
+            .collect();
+        res.header_owned(format!("X-Total-Count: {}", total));
+        res.header_owned(link_header(&links));
+    }
+
+    /// Adds `Link` headers for cursor pagination; `next` and `prev` are
+    /// the cursors the store handed back, if there are such pages.
+    pub fn link_cursors(
+        &self,
+        req: &Request,
+        res: &mut Response,
+        next: Option<&str>,
+        prev: Option<&str>,
+    ) {
+        let links: Vec<_> = [(prev, "prev"), (next, "next")]
+            .into_iter()
+            .filter_map(|(cursor, rel)| {
+                let url = with_query(req.path(), &[("cursor", &encode(cursor?))]);
+                Some((url, rel))
+            })
+            .collect();
+        if !links.is_empty() {
+            res.header_owned(link_header(&links));
+        }
+    }
+}
+
+// RFC 8288: `<url>; rel="next", <url>; rel="last"`
+fn link_header(links: &[(String, &str)]) -> String {
+    let mut header = String::from("Link: ");
+    for (i, (url, rel)) in links.iter().enumerate() {
+        if i > 0 {
+            header.push_str(", ");
+        }
+        let _ = write!(header, "<{}>; rel=\"{}\"", url, rel);
+    }
+    header
+}
+
+// `url` with `params` set, keeping the other query parameters in order
+fn with_query(url: &str, params: &[(&str, &str)]) -> String {
+    let (path, query) = url.split_once('?').unwrap_or((url, ""));
+    let mut out = String::from(path);
+    let mut sep = '?';
+    let kept = query.split('&').filter(|pair| {
+        let name = pair.split('=').next().unwrap_or(pair);
+        !pair.is_empty() && !params.iter().any(|(param, _)| *param == name)
+    });
+    for pair in kept {
+        out.push(sep);
+        out.push_str(pair);
+        sep = '&';
+    }
+    for (name, value) in params {
+        let _ = write!(out, "{}{}={}", sep, name, value);
+        sep = '&';
+    }
+    out
+}
+
+// percent-encodes all but RFC 3986 unreserved characters
+fn encode(s: &str) -> String {
+    let mut out = String::with_capacity(s.len());
+    for byte in s.bytes() {
+        match byte {
+            b'A'..=b'Z' | b'a'..=b'z' | b'0'..=b'9' | b'-' | b'.' | b'_' | b'~' => {
+                out.push(byte as char)
+            }
+            _ => {
+                let _ = write!(out, "%{:02X}", byte);
+            }
+        }
+    }
+    out
+}
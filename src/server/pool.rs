@@ -0,0 +1,297 @@
+//! a pool of reusable resources, e.g. database connections, for handlers
+
+use std::fmt;
+use std::io;
+use std::ops::{Deref, DerefMut};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use may::sync::{Condvar, Mutex};
+
+const DEFAULT_CHECKOUT_TIMEOUT: Duration = Duration::from_secs(30);
+const DEFAULT_IDLE_TIMEOUT: Duration = Duration::from_secs(10 * 60);
+
+type CreateFn<T> = Arc<dyn Fn() -> io::Result<T> + Send + Sync>;
+type ValidateFn<T> = Arc<dyn Fn(&mut T) -> bool + Send + Sync>;
+
+struct PoolConfig<T> {
+    max_size: usize,
+    checkout_timeout: Duration,
+    idle_timeout: Duration,
+    max_lifetime: Option<Duration>,
+    create: CreateFn<T>,
+    validate: Option<ValidateFn<T>>,
+}
+
+// not derived, which would want `T: Clone`
+impl<T> Clone for PoolConfig<T> {
+    fn clone(&self) -> Self {
+        PoolConfig {
+            max_size: self.max_size,
+            checkout_timeout: self.checkout_timeout,
+            idle_timeout: self.idle_timeout,
+            max_lifetime: self.max_lifetime,
+            create: self.create.clone(),
+            validate: self.validate.clone(),
+        }
+    }
+}
+
+struct PoolState<T> {
+    idle: Vec<Idle<T>>,
+    // resources in existence, idle or checked out, and ones being created
+    size: usize,
+}
+
+struct Idle<T> {
+    resource: T,
+    created: Instant,
+    returned: Instant,
+}
+
+/// A bounded pool of resources such as database connections.
+///
+/// Waiting for a free resource parks the calling coroutine on may's
+/// `Mutex` and `Condvar` instead of blocking its scheduler thread. The
+/// resources themselves are only as coroutine-friendly as their driver:
+/// one that does blocking I/O still holds up a worker while it runs, so
+/// keep `max_size` near what the database can serve and the work brief.
+/// Clones share the same resources.
+pub struct Pool<T> {
+    config: Arc<PoolConfig<T>>,
+    shared: Arc<(Mutex<PoolState<T>>, Condvar)>,
+}
+
+impl<T> Clone for Pool<T> {
+    fn clone(&self) -> Self {
+        Pool {
+            config: self.config.clone(),
+            shared: self.shared.clone(),
+        }
+    }
+}
+
+impl<T: Send + 'static> Pool<T> {
+    /// At most `max_size` resources, each made by `create` when one is needed
+    /// and none is idle.
+    pub fn new<F>(max_size: usize, create: F) -> Self
+    where
+        F: Fn() -> io::Result<T> + Send + Sync + 'static,
+    {
+        Pool {
+            config: Arc::new(PoolConfig {
+                max_size: max_size.max(1),
+                checkout_timeout: DEFAULT_CHECKOUT_TIMEOUT,
+                idle_timeout: DEFAULT_IDLE_TIMEOUT,
+                max_lifetime: None,
+                create: Arc::new(create),
+                validate: None,
+            }),
+            shared: Arc::new((
+                Mutex::new(PoolState {
+                    idle: Vec::new(),
+                    size: 0,
+                }),
+                Condvar::new(),
+            )),
+        }
+    }
+
+    /// How long `get` waits for a resource to be free, 30 seconds by
+    /// default.
+    pub fn checkout_timeout(&mut self, timeout: Duration) -> &mut Self {
+        Arc::make_mut(&mut self.config).checkout_timeout = timeout;
+        self
+    }
+
+    /// How long a resource may sit unused before it's dropped instead of
+    /// handed out, 10 minutes by default.
+    pub fn idle_timeout(&mut self, timeout: Duration) -> &mut Self {
+        Arc::make_mut(&mut self.config).idle_timeout = timeout;
+        self
+    }
+
+    /// How long a resource is used at all before it's replaced, e.g. to
+    /// follow a database failover; unlimited by default.
+    pub fn max_lifetime(&mut self, lifetime: Duration) -> &mut Self {
+        Arc::make_mut(&mut self.config).max_lifetime = Some(lifetime);
+        self
+    }
+
+    /// Checks an idle resource before handing it out, e.g. with a
+    /// `SELECT 1`; ones it rejects are dropped and another is tried.
+    pub fn validate<F>(&mut self, f: F) -> &mut Self
+    where
+        F: Fn(&mut T) -> bool + Send + Sync + 'static,
+    {
+        Arc::make_mut(&mut self.config).validate = Some(Arc::new(f));
+        self
+    }
+
+    /// A resource, idle or newly created, waiting up to the checkout
+    /// timeout for one to be returned when the pool is full. It goes back
+    /// to the pool when dropped.
+    pub fn get(&self) -> io::Result<Pooled<T>> {
+        self.get_timeout(self.config.checkout_timeout)
+    }
+
+    /// `get` with its own timeout, e.g. what's left of a request's
+    /// deadline.
+    pub fn get_timeout(&self, timeout: Duration) -> io::Result<Pooled<T>> {
+        let deadline = Instant::now() + timeout;
+        let (lock, available) = &*self.shared;
+        let mut state = lock.lock().unwrap();
+        loop {
+            if let Some(idle) = state.idle.pop() {
+                drop(state);
+                if let Some(pooled) = self.revive(idle) {
+                    return Ok(pooled);
+                }
+                state = lock.lock().unwrap();
+                continue;
+            }
+            if state.size < self.config.max_size {
+                state.size += 1;
+                drop(state);
+                return match (self.config.create)() {
+                    Ok(resource) => Ok(self.wrap(resource, Instant::now())),
+                    Err(e) => {
+                        self.forget();
+                        Err(e)
+                    }
+                };
+            }
+            let now = Instant::now();
+            if now >= deadline {
+                return Err(io::Error::new(
+                    io::ErrorKind::TimedOut,
+                    "timed out waiting for a pooled resource",
+                ));
+            }
+            state = available.wait_timeout(state, deadline - now).unwrap().0;
+        }
+    }
+
+    /// Resources in existence, idle or checked out.
+    pub fn size(&self) -> usize {
+        self.shared.0.lock().unwrap().size
+    }
+
+    pub fn idle(&self) -> usize {
+        self.shared.0.lock().unwrap().idle.len()
+    }
+
+    // an idle resource that's still fit for use, else drops it
+    fn revive(&self, idle: Idle<T>) -> Option<Pooled<T>> {
+        let now = Instant::now();
+        let stale = now.duration_since(idle.returned) >= self.config.idle_timeout
+            || self.too_old(idle.created, now);
+        let mut resource = idle.resource;
+        let valid = !stale
+            && self
+                .config
+                .validate
+                .as_ref()
+                .map_or(true, |f| f(&mut resource));
+        if !valid {
+            drop(resource);
+            self.forget();
+            return None;
+        }
+        Some(self.wrap(resource, idle.created))
+    }
+
+    fn wrap(&self, resource: T, created: Instant) -> Pooled<T> {
+        Pooled {
+            resource: Some(resource),
+            created,
+            pool: self.clone(),
+        }
+    }
+
+    fn too_old(&self, created: Instant, now: Instant) -> bool {
+        self.config
+            .max_lifetime
+            .map_or(false, |lifetime| now.duration_since(created) >= lifetime)
+    }
+
+    // a resource is gone, making room for a new one
+    fn forget(&self) {
+        let (lock, available) = &*self.shared;
+        lock.lock().unwrap().size -= 1;
+        available.notify_one();
+    }
+
+    fn put_back(&self, resource: T, created: Instant) {
+        let now = Instant::now();
+        if self.too_old(created, now) {
+            drop(resource);
+            self.forget();
+            return;
+        }
+        let (lock, available) = &*self.shared;
+        lock.lock().unwrap().idle.push(Idle {
+            resource,
+            created,
+            returned: now,
+        });
+        available.notify_one();
+    }
+}
+
+impl<T> fmt::Debug for Pool<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let state = self.shared.0.lock().unwrap();
+        write!(
+            f,
+            "<Pool size={} idle={} max_size={}>",
+            state.size,
+            state.idle.len(),
+            self.config.max_size
+        )
+    }
+}
+
+/// A resource checked out of a `Pool`, returned to it when dropped.
+pub struct Pooled<T: Send + 'static> {
+    resource: Option<T>,
+    created: Instant,
+    pool: Pool<T>,
+}
+
+impl<T: Send + 'static> Pooled<T> {
+    /// Drops the resource instead of returning it, e.g. after its
+    /// connection broke mid-query.
+    pub fn discard(mut self) {
+        self.resource = None;
+        self.pool.forget();
+    }
+}
+
+impl<T: Send + 'static> Deref for Pooled<T> {
+    type Target = T;
+
+    fn deref(&self) -> &T {
+        self.resource.as_ref().unwrap()
+    }
+}
+
+impl<T: Send + 'static> DerefMut for Pooled<T> {
+    fn deref_mut(&mut self) -> &mut T {
+        self.resource.as_mut().unwrap()
+    }
+}
+
+impl<T: Send + 'static> Drop for Pooled<T> {
+    fn drop(&mut self) {
+        if let Some(resource) = self.resource.take() {
+            self.pool.put_back(resource, self.created);
+        }
+    }
+}
+
+impl<T: Send + fmt::Debug + 'static> fmt::Debug for Pooled<T> {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        fmt::Debug::fmt(&**self, f)
+    }
+}
@@ -0,0 +1,306 @@
+use std::collections::HashMap;
+use std::io;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+use crate::errors::http_error::HttpError;
+use crate::request::request::Request;
+use crate::response::respond::IntoResponse;
+use crate::response::response::Response;
+use crate::server::kv::{unix_ms, KvStore};
+
+// past this many buckets, refilled ones are dropped on the next request
+const PRUNE_ABOVE: usize = 10_000;
+// compare-and-swap rounds lost to other instances before giving up
+const CAS_ATTEMPTS: usize = 8;
+
+type KeyFn = Arc<dyn Fn(&Request) -> Option<String> + Send + Sync>;
+
+/// `burst` requests, refilled evenly over `per`.
+#[derive(Clone, Copy, Debug)]
+pub struct RateLimit {
+    pub burst: u32,
+    pub per: Duration,
+}
+
+/// What's left of a bucket once a token is taken.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub struct Quota {
+    pub remaining: u32,
+    /// How long until the bucket is full again.
+    pub reset: Duration,
+}
+
+impl Quota {
+    // what's reported when the store couldn't be asked and the request was
+    // let through
+    pub(crate) fn unknown(limit: RateLimit) -> Self {
+        Quota {
+            remaining: limit.burst,
+            reset: Duration::ZERO,
+        }
+    }
+
+    // `rate` in tokens per second
+    fn after(tokens: f64, burst: f64, rate: f64) -> Self {
+        Quota {
+            remaining: tokens.max(0.0).floor() as u32,
+            reset: Duration::from_secs_f64(((burst - tokens) / rate).max(0.0)),
+        }
+    }
+}
+
+/// Where bucket state lives. `MemoryStore` keeps it per process; a shared
+/// store, a `KvRateLimitStore` over a shared `KvStore` or `Redis`, can take
+/// its place to limit across instances.
+pub trait RateLimitStore: Send + Sync {
+    /// Takes a token from `key`'s bucket and says what's left, or says how
+    /// long until one is available.
+    fn acquire(&self, key: &str, limit: RateLimit) -> Result<Quota, Duration>;
+}
+
+/// Token buckets in process memory.
+#[derive(Default)]
+pub struct MemoryStore {
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated: Instant,
+}
+
+impl RateLimitStore for MemoryStore {
+    fn acquire(&self, key: &str, limit: RateLimit) -> Result<Quota, Duration> {
+        let now = Instant::now();
+        let burst = limit.burst.max(1) as f64;
+        let rate = burst / limit.per.as_secs_f64().max(f64::MIN_POSITIVE);
+        let mut buckets = self.buckets.lock().unwrap();
+        if buckets.len() > PRUNE_ABOVE {
+            buckets.retain(|_, b| now.duration_since(b.updated) < limit.per);
+        }
+        if !buckets.contains_key(key) {
+            let bucket = Bucket {
+                tokens: burst,
+                updated: now,
+            };
+            buckets.insert(key.to_owned(), bucket);
+        }
+        let bucket = buckets.get_mut(key).unwrap();
+        let elapsed = now.duration_since(bucket.updated).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(burst);
+        bucket.updated = now;
+        if bucket.tokens >= 1.0 {
+            bucket.tokens -= 1.0;
+            Ok(Quota::after(bucket.tokens, burst, rate))
+        } else {
+            Err(Duration::from_secs_f64((1.0 - bucket.tokens) / rate))
+        }
+    }
+}
+
+/// Token buckets in any `KvStore`, e.g. one shared by several instances.
+///
+/// Each bucket is updated with a compare-and-swap, retried when another
+/// request got there first; a store that fails or stays contended lets the
+/// request through rather than failing it. Buckets are timed by the
+/// instances' clocks, which should be kept in sync.
+pub struct KvRateLimitStore<S> {
+    store: S,
+}
+
+impl<S: KvStore> KvRateLimitStore<S> {
+    pub fn new(store: S) -> Self {
+        KvRateLimitStore { store }
+    }
+}
+
+impl<S: KvStore> RateLimitStore for KvRateLimitStore<S> {
+    fn acquire(&self, key: &str, limit: RateLimit) -> Result<Quota, Duration> {
+        let key = format!("ratelimit:{}", key);
+        let burst = limit.burst.max(1) as f64;
+        let rate = burst / (limit.per.as_millis() as f64).max(1.0);
+        for _ in 0..CAS_ATTEMPTS {
+            let now = unix_ms();
+            let current = match self.store.get(&key) {
+                Ok(current) => current,
+                Err(e) => {
+                    warn!("rate limit store unavailable: {}", e);
+                    return Ok(Quota::unknown(limit));
+                }
+            };
+            // stored as "<tokens> <updated, ms since the epoch>"
+            let (tokens, updated) = current
+                .as_deref()
+                .and_then(|state| std::str::from_utf8(state).ok())
+                .and_then(|state| state.split_once(' '))
+                .and_then(|(tokens, updated)| Some((tokens.parse().ok()?, updated.parse().ok()?)))
+                .unwrap_or((burst, now));
+            let elapsed = now.saturating_sub(updated) as f64;
+            let tokens = (tokens + elapsed * rate).min(burst);
+            if tokens < 1.0 {
+                return Err(Duration::from_millis(((1.0 - tokens) / rate).ceil() as u64));
+            }
+            let tokens = tokens - 1.0;
+            let state = format!("{} {}", tokens, now);
+            let ttl = Some(limit.per);
+            match self
+                .store
+                .cas(&key, current.as_deref(), state.as_bytes(), ttl)
+            {
+                Ok(true) => return Ok(Quota::after(tokens, burst, rate * 1000.0)),
+                Ok(false) => continue,
+                Err(e) => {
+                    warn!("rate limit store unavailable: {}", e);
+                    return Ok(Quota::unknown(limit));
+                }
+            }
+        }
+        Ok(Quota::unknown(limit))
+    }
+}
+
+/// Token bucket rate limiting, installed with `Server::rate_limit` or
+/// `RouteOptions::rate_limit`. Responses carry the draft standard
+/// RateLimit-Limit, RateLimit-Remaining and RateLimit-Reset headers;
+/// requests over the limit get a 429 problem document with Retry-After.
+/// Clones share their buckets.
+#[derive(Clone)]
+pub struct RateLimiter {
+    limit: RateLimit,
+    key: Option<KeyFn>,
+    store: Arc<dyn RateLimitStore>,
+}
+
+impl RateLimiter {
+    /// `burst` requests per client IP, refilled evenly over `per`, kept in
+    /// a `MemoryStore`.
+    pub fn new(burst: u32, per: Duration) -> Self {
+        RateLimiter {
+            limit: RateLimit { burst, per },
+            key: None,
+            store: Arc::new(MemoryStore::default()),
+        }
+    }
+
+    /// Buckets requests by `f` instead of client IP, e.g. an API key;
+    /// requests it returns `None` for aren't limited.
+    pub fn key_by<F>(&mut self, f: F) -> &mut Self
+    where
+        F: Fn(&Request) -> Option<String> + Send + Sync + 'static,
+    {
+        self.key = Some(Arc::new(f));
+        self
+    }
+
+    /// Keeps buckets in `store`; limiters sharing one should use keys
+    /// that don't collide.
+    pub fn store<S: RateLimitStore + 'static>(&mut self, store: S) -> &mut Self {
+        self.store = Arc::new(store);
+        self
+    }
+
+    // takes a token and reports the quota, or answers 429 and says so
+    pub(crate) fn check(&self, req: &mut Request, res: &mut Response) -> io::Result<bool> {
+        let key = match &self.key {
+            Some(key) => key(req),
+            None => req.client_ip().map(|ip| ip.to_string()),
+        };
+        let key = match key {
+            Some(key) => key,
+            None => return Ok(true),
+        };
+        let wait = match self.store.acquire(&key, self.limit) {
+            Ok(quota) => {
+                self.headers(res, quota);
+                return Ok(true);
+            }
+            Err(wait) => wait,
+        };
+        req.req.reject_body();
+        // an empty bucket resets, as far as the client cares, once there's
+        // a token again
+        let quota = Quota {
+            remaining: 0,
+            reset: wait,
+        };
+        self.headers(res, quota);
+        HttpError::RateLimited { retry_after: wait }.into_response(res)?;
+        Ok(false)
+    }
+
+    fn headers(&self, res: &mut Response, quota: Quota) {
+        // whole seconds, rounded up so the client doesn't come back early
+        let reset = quota.reset.as_secs() + u64::from(quota.reset.subsec_nanos() > 0);
+        res.header_owned(format!("RateLimit-Limit: {}", self.limit.burst));
+        res.header_owned(format!("RateLimit-Remaining: {}", quota.remaining));
+        res.header_owned(format!("RateLimit-Reset: {}", reset));
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use crate::server::server::Server;
+    use crate::test::TestClient;
+    use crate::testing::{race, THREADS};
+
+    // slow enough that no token comes back while a test runs
+    const DAY: Duration = Duration::from_secs(24 * 60 * 60);
+
+    #[test]
+    fn shared_bucket_grants_exactly_its_burst() {
+        let store = MemoryStore::default();
+        let limit = RateLimit {
+            burst: 50,
+            per: DAY,
+        };
+        let taken = race(|_| {
+            (0..25)
+                .filter_map(|_| store.acquire("client", limit).ok())
+                .collect::<Vec<_>>()
+        });
+        let mut remaining: Vec<u32> = taken.iter().flatten().map(|q| q.remaining).collect();
+        remaining.sort_unstable();
+        assert_eq!(remaining, (0..50).collect::<Vec<_>>());
+        assert!(store.acquire("client", limit).is_err());
+    }
+
+    #[test]
+    fn buckets_are_kept_per_key() {
+        let store = MemoryStore::default();
+        let limit = RateLimit {
+            burst: 10,
+            per: DAY,
+        };
+        let granted = race(|n| {
+            let key = format!("client-{}", n);
+            (0..20)
+                .filter(|_| store.acquire(&key, limit).is_ok())
+                .count()
+        });
+        assert_eq!(granted, vec![10; THREADS]);
+    }
+
+    #[test]
+    fn limiter_answers_429_past_the_burst() {
+        let mut server = Server::new();
+        server
+            .get("/", |_, res| res.send("ok"))
+            .rate_limit(&RateLimiter::new(5, DAY));
+        let statuses = race(|_| {
+            let client = TestClient::new(&server);
+            (0..3)
+                .map(|_| {
+                    let res = client.get("/").send();
+                    let retry_after = res.header("retry-after").is_some();
+                    (res.status(), retry_after)
+                })
+                .collect::<Vec<_>>()
+        });
+        let statuses: Vec<_> = statuses.into_iter().flatten().collect();
+        assert_eq!(statuses.iter().filter(|s| **s == (200, false)).count(), 5);
+        assert_eq!(statuses.iter().filter(|s| **s == (429, true)).count(), 19);
+    }
+}
@@ -0,0 +1,461 @@
+//! a small Redis client on may's sockets, for state shared across instances
+
+use std::io::{self, Write};
+use std::net::ToSocketAddrs;
+use std::sync::{Arc, Mutex};
+use std::time::Duration;
+
+use bytes::{Buf, BytesMut};
+use may::net::TcpStream;
+
+use crate::http::http_server::{read_into, reserve_buf};
+use crate::http::socket;
+use crate::server::kv::KvStore;
+use crate::server::rate_limit::{Quota, RateLimit, RateLimitStore};
+
+const DEFAULT_TIMEOUT: Duration = Duration::from_secs(5);
+const DEFAULT_MAX_IDLE: usize = 16;
+// longest reply we accept, a guard against a confused peer
+const MAX_REPLY: usize = 64 * 1024 * 1024;
+
+// token bucket kept in a hash, timed by the server's clock so instances
+// with skewed clocks agree; returns the milliseconds to wait, 0 if taken
+const ACQUIRE: &str = r#"
+local burst = tonumber(ARGV[1])
+local per = tonumber(ARGV[2])
+local t = redis.call('TIME')
+local now = tonumber(t[1]) * 1000 + math.floor(tonumber(t[2]) / 1000)
+local state = redis.call('HMGET', KEYS[1], 'tokens', 'updated')
+local tokens = tonumber(state[1]) or burst
+local updated = tonumber(state[2]) or now
+local rate = burst / per
+tokens = math.min(burst, tokens + math.max(0, now - updated) * rate)
+local wait = 0
+if tokens >= 1 then
+  tokens = tokens - 1
+else
+  wait = math.ceil((1 - tokens) / rate)
+end
+redis.call('HSET', KEYS[1], 'tokens', tostring(tokens), 'updated', now)
+redis.call('PEXPIRE', KEYS[1], per)
+return {wait, math.floor(tokens), math.ceil((burst - tokens) / rate)}
+"#;
+
+// INCRBY that sets the expiry of a key it creates
+const INCR: &str = r#"
+local created = redis.call('EXISTS', KEYS[1]) == 0
+local n = redis.call('INCRBY', KEYS[1], ARGV[1])
+if created and tonumber(ARGV[2]) > 0 then
+  redis.call('PEXPIRE', KEYS[1], ARGV[2])
+end
+return n
+"#;
+
+// SET if the value is ARGV[2], or the key is absent when ARGV[1] is '0'
+const CAS: &str = r#"
+local current = redis.call('GET', KEYS[1])
+if ARGV[1] == '1' then
+  if current ~= ARGV[2] then return 0 end
+elseif current then
+  return 0
+end
+if tonumber(ARGV[4]) > 0 then
+  redis.call('SET', KEYS[1], ARGV[3], 'PX', ARGV[4])
+else
+  redis.call('SET', KEYS[1], ARGV[3])
+end
+return 1
+"#;
+
+/// A reply from Redis.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum RedisValue {
+    Nil,
+    Status(String),
+    Integer(i64),
+    Bulk(Vec<u8>),
+    Array(Vec<RedisValue>),
+    /// An error inside an array; a top-level one is returned as `Err`.
+    Error(String),
+}
+
+#[derive(Clone)]
+struct RedisConfig {
+    addr: String,
+    password: Option<(Option<String>, String)>,
+    database: u32,
+    prefix: String,
+    timeout: Duration,
+    max_idle: usize,
+}
+
+/// Redis client, a `KvStore` and a `RateLimitStore` so state is shared
+/// across instances.
+///
+/// Waiting on a reply parks the calling coroutine. Clones share a pool of
+/// connections, each authenticated and switched to the configured
+/// database when opened. Every key is put under `prefix`, `aegis:` by
+/// default, so several applications can share a server. Plain TCP only,
+/// as the crate has no TLS.
+#[derive(Clone)]
+pub struct Redis {
+    config: Arc<RedisConfig>,
+    pool: Arc<Mutex<Vec<RedisConn>>>,
+}
+
+impl Redis {
+    /// A client for the server at `addr`, e.g. `127.0.0.1:6379`; nothing
+    /// is connected until the first command.
+    pub fn new(addr: &str) -> Self {
+        Redis {
+            config: Arc::new(RedisConfig {
+                addr: addr.to_owned(),
+                password: None,
+                database: 0,
+                prefix: "aegis:".to_owned(),
+                timeout: DEFAULT_TIMEOUT,
+                max_idle: DEFAULT_MAX_IDLE,
+            }),
+            pool: Arc::new(Mutex::new(Vec::new())),
+        }
+    }
+
+    /// Sends `AUTH password` on every new connection.
+    pub fn password(&mut self, password: &str) -> &mut Self {
+        Arc::make_mut(&mut self.config).password = Some((None, password.to_owned()));
+        self
+    }
+
+    /// Sends `AUTH username password`, for Redis 6 ACL users.
+    pub fn user(&mut self, username: &str, password: &str) -> &mut Self {
+        let credentials = (Some(username.to_owned()), password.to_owned());
+        Arc::make_mut(&mut self.config).password = Some(credentials);
+        self
+    }
+
+    /// The database to `SELECT`, 0 by default.
+    pub fn database(&mut self, database: u32) -> &mut Self {
+        Arc::make_mut(&mut self.config).database = database;
+        self
+    }
+
+    /// Put in front of every key, `aegis:` by default.
+    pub fn prefix(&mut self, prefix: &str) -> &mut Self {
+        Arc::make_mut(&mut self.config).prefix = prefix.to_owned();
+        self
+    }
+
+    /// How long connecting, or a single read or write, may take; 5 seconds
+    /// by default.
+    pub fn timeout(&mut self, timeout: Duration) -> &mut Self {
+        Arc::make_mut(&mut self.config).timeout = timeout;
+        self
+    }
+
+    /// How many idle connections are kept, 16 by default.
+    pub fn max_idle(&mut self, max_idle: usize) -> &mut Self {
+        Arc::make_mut(&mut self.config).max_idle = max_idle;
+        self
+    }
+
+    /// Runs a command as given, keys included verbatim without the prefix,
+    /// e.g. `redis.command(&[b"PING"])`. An error reply is an `Err`.
+    pub fn command(&self, args: &[&[u8]]) -> io::Result<RedisValue> {
+        let mut conn = match self.checkout() {
+            Some(conn) => conn,
+            None => self.connect()?,
+        };
+        let reply = conn.call(args)?;
+        self.checkin(conn);
+        reply
+    }
+
+    fn key(&self, key: &str) -> String {
+        format!("{}{}", self.config.prefix, key)
+    }
+
+    fn checkout(&self) -> Option<RedisConn> {
+        let mut pool = self.pool.lock().unwrap();
+        while let Some(conn) = pool.pop() {
+            if !socket::peer_gone(&conn.stream) {
+                return Some(conn);
+            }
+        }
+        None
+    }
+
+    fn checkin(&self, conn: RedisConn) {
+        // a connection with unread bytes is out of step with its replies
+        if !conn.buf.is_empty() {
+            return;
+        }
+        let mut pool = self.pool.lock().unwrap();
+        if pool.len() < self.config.max_idle {
+            pool.push(conn);
+        }
+    }
+
+    fn connect(&self) -> io::Result<RedisConn> {
+        let config = &self.config;
+        let mut last_error = None;
+        for addr in config.addr.to_socket_addrs()? {
+            match TcpStream::connect_timeout(&addr, config.timeout) {
+                Ok(stream) => {
+                    stream.set_nodelay(true)?;
+                    stream.set_read_timeout(Some(config.timeout))?;
+                    stream.set_write_timeout(Some(config.timeout))?;
+                    let mut conn = RedisConn {
+                        stream,
+                        buf: BytesMut::new(),
+                    };
+                    match &config.password {
+                        Some((Some(user), password)) => {
+                            conn.call(&[b"AUTH", user.as_bytes(), password.as_bytes()])??;
+                        }
+                        Some((None, password)) => {
+                            conn.call(&[b"AUTH", password.as_bytes()])??;
+                        }
+                        None => {}
+                    }
+                    if config.database != 0 {
+                        let database = config.database.to_string();
+                        conn.call(&[b"SELECT", database.as_bytes()])??;
+                    }
+                    return Ok(conn);
+                }
+                Err(e) => last_error = Some(e),
+            }
+        }
+        Err(last_error.unwrap_or_else(|| {
+            let msg = format!("{} resolved to no addresses", config.addr);
+            io::Error::new(io::ErrorKind::NotFound, msg)
+        }))
+    }
+}
+
+impl KvStore for Redis {
+    fn get(&self, key: &str) -> io::Result<Option<Vec<u8>>> {
+        match self.command(&[b"GET", self.key(key).as_bytes()])? {
+            RedisValue::Bulk(value) => Ok(Some(value)),
+            RedisValue::Nil => Ok(None),
+            other => Err(unexpected(&other)),
+        }
+    }
+
+    fn set(&self, key: &str, value: &[u8], ttl: Option<Duration>) -> io::Result<()> {
+        let key = self.key(key);
+        match ttl {
+            Some(ttl) => {
+                let ms = millis(Some(ttl));
+                self.command(&[b"SET", key.as_bytes(), value, b"PX", ms.as_bytes()])?;
+            }
+            None => {
+                self.command(&[b"SET", key.as_bytes(), value])?;
+            }
+        }
+        Ok(())
+    }
+
+    fn delete(&self, key: &str) -> io::Result<bool> {
+        match self.command(&[b"DEL", self.key(key).as_bytes()])? {
+            RedisValue::Integer(n) => Ok(n > 0),
+            other => Err(unexpected(&other)),
+        }
+    }
+
+    fn ttl(&self, key: &str) -> io::Result<Option<Duration>> {
+        // -2 for a missing key, -1 for one without an expiry
+        match self.command(&[b"PTTL", self.key(key).as_bytes()])? {
+            RedisValue::Integer(ms) if ms >= 0 => Ok(Some(Duration::from_millis(ms as u64))),
+            RedisValue::Integer(_) => Ok(None),
+            other => Err(unexpected(&other)),
+        }
+    }
+
+    fn incr(&self, key: &str, by: i64, ttl: Option<Duration>) -> io::Result<i64> {
+        let key = self.key(key);
+        let (by, ms) = (by.to_string(), millis(ttl));
+        let args: [&[u8]; 6] = [
+            b"EVAL",
+            INCR.as_bytes(),
+            b"1",
+            key.as_bytes(),
+            by.as_bytes(),
+            ms.as_bytes(),
+        ];
+        match self.command(&args)? {
+            RedisValue::Integer(n) => Ok(n),
+            other => Err(unexpected(&other)),
+        }
+    }
+
+    fn cas(
+        &self,
+        key: &str,
+        expected: Option<&[u8]>,
+        new: &[u8],
+        ttl: Option<Duration>,
+    ) -> io::Result<bool> {
+        let key = self.key(key);
+        let ms = millis(ttl);
+        let (present, expected): (&[u8], &[u8]) = match expected {
+            Some(expected) => (b"1", expected),
+            None => (b"0", b""),
+        };
+        let args: [&[u8]; 8] = [
+            b"EVAL",
+            CAS.as_bytes(),
+            b"1",
+            key.as_bytes(),
+            present,
+            expected,
+            new,
+            ms.as_bytes(),
+        ];
+        match self.command(&args)? {
+            RedisValue::Integer(n) => Ok(n == 1),
+            other => Err(unexpected(&other)),
+        }
+    }
+}
+
+impl RateLimitStore for Redis {
+    // an unreachable Redis lets requests through rather than failing them
+    fn acquire(&self, key: &str, limit: RateLimit) -> Result<Quota, Duration> {
+        let key = self.key(&format!("ratelimit:{}", key));
+        let burst = limit.burst.max(1).to_string();
+        let per = limit.per.as_millis().max(1).to_string();
+        let args: [&[u8]; 6] = [
+            b"EVAL",
+            ACQUIRE.as_bytes(),
+            b"1",
+            key.as_bytes(),
+            burst.as_bytes(),
+            per.as_bytes(),
+        ];
+        // the wait, the tokens left and the ms until the bucket is full
+        let reply = self.command(&args).and_then(|reply| match reply {
+            RedisValue::Array(reply) => Ok(reply),
+            other => Err(unexpected(&other)),
+        });
+        let reply = match reply {
+            Ok(reply) => reply,
+            Err(e) => {
+                warn!("rate limit store unavailable: {}", e);
+                return Ok(Quota::unknown(limit));
+            }
+        };
+        match reply.as_slice() {
+            [RedisValue::Integer(wait), ..] if *wait > 0 => {
+                Err(Duration::from_millis(*wait as u64))
+            }
+            [_, RedisValue::Integer(tokens), RedisValue::Integer(reset)] => Ok(Quota {
+                remaining: (*tokens).clamp(0, u32::MAX as i64) as u32,
+                reset: Duration::from_millis((*reset).max(0) as u64),
+            }),
+            _ => Ok(Quota::unknown(limit)),
+        }
+    }
+}
+
+struct RedisConn {
+    stream: TcpStream,
+    buf: BytesMut,
+}
+
+impl RedisConn {
+    // the outer error means the connection is unusable, the inner one is
+    // an error reply
+    fn call(&mut self, args: &[&[u8]]) -> io::Result<io::Result<RedisValue>> {
+        let mut out = Vec::with_capacity(16 + args.iter().map(|a| a.len() + 16).sum::<usize>());
+        write!(out, "*{}\r\n", args.len())?;
+        for arg in args {
+            write!(out, "${}\r\n", arg.len())?;
+            out.extend_from_slice(arg);
+            out.extend_from_slice(b"\r\n");
+        }
+        self.stream.write_all(&out)?;
+        loop {
+            if let Some((value, len)) = parse(&self.buf)? {
+                self.buf.advance(len);
+                return Ok(match value {
+                    RedisValue::Error(msg) => Err(io::Error::new(io::ErrorKind::Other, msg)),
+                    value => Ok(value),
+                });
+            }
+            if self.buf.len() > MAX_REPLY {
+                return Err(invalid("Redis reply too large"));
+            }
+            reserve_buf(&mut self.buf);
+            if read_into(&mut self.stream, &mut self.buf)? == 0 {
+                return Err(io::ErrorKind::UnexpectedEof.into());
+            }
+        }
+    }
+}
+
+// one RESP2 value from the front of `buf` and its length, `None` if it
+// isn't all there yet
+fn parse(buf: &[u8]) -> io::Result<Option<(RedisValue, usize)>> {
+    let end = match buf.windows(2).position(|w| w == b"\r\n") {
+        Some(0) => return Err(invalid("invalid Redis reply")),
+        Some(end) => end,
+        None => return Ok(None),
+    };
+    let line = std::str::from_utf8(&buf[1..end]).map_err(|_| invalid("invalid Redis reply"))?;
+    let number = || {
+        line.parse::<i64>()
+            .map_err(|_| invalid("invalid Redis reply"))
+    };
+    let value = match buf[0] {
+        b'+' => RedisValue::Status(line.to_owned()),
+        b'-' => RedisValue::Error(line.to_owned()),
+        b':' => RedisValue::Integer(number()?),
+        b'$' => {
+            let len = number()?;
+            if len < 0 {
+                return Ok(Some((RedisValue::Nil, end + 2)));
+            }
+            let start = end + 2;
+            let stop = start + len as usize;
+            if buf.len() < stop + 2 {
+                return Ok(None);
+            }
+            let value = RedisValue::Bulk(buf[start..stop].to_vec());
+            return Ok(Some((value, stop + 2)));
+        }
+        b'*' => {
+            let len = number()?;
+            if len < 0 {
+                return Ok(Some((RedisValue::Nil, end + 2)));
+            }
+            let mut pos = end + 2;
+            let mut items = Vec::with_capacity((len as usize).min(1024));
+            for _ in 0..len {
+                match parse(&buf[pos..])? {
+                    Some((item, n)) => {
+                        items.push(item);
+                        pos += n;
+                    }
+                    None => return Ok(None),
+                }
+            }
+            return Ok(Some((RedisValue::Array(items), pos)));
+        }
+        _ => return Err(invalid("invalid Redis reply")),
+    };
+    Ok(Some((value, end + 2)))
+}
+
+// a TTL in milliseconds for a command argument, 0 for none
+fn millis(ttl: Option<Duration>) -> String {
+    ttl.map_or(0, |ttl| ttl.as_millis().max(1)).to_string()
+}
+
+fn invalid(msg: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, msg)
+}
+
+fn unexpected(value: &RedisValue) -> io::Error {
+    invalid(&format!("unexpected Redis reply {:?}", value))
+}
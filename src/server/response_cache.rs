@@ -0,0 +1,530 @@
+//! an in-memory cache of handler responses
+
+use std::borrow::Cow;
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::io;
+use std::sync::Arc;
+use std::time::{Duration, Instant};
+
+use bytes::Bytes;
+use may::sync::{Condvar, Mutex};
+
+use crate::request::request::Request;
+use crate::response::response::Response;
+use crate::router::route_matcher::RouteHandler;
+use crate::server::versioned::matches;
+
+const DEFAULT_CAPACITY: usize = 1024;
+const DEFAULT_MAX_BODY: usize = 1024 * 1024;
+const DEFAULT_FILL_TIMEOUT: Duration = Duration::from_secs(5);
+const TRACKING_PARAMS: [&str; 8] = [
+    "utm_*", "fbclid", "gclid", "dclid", "msclkid", "mc_cid", "mc_eid", "_ga",
+];
+
+/// An in-memory cache of GET responses, installed per route with
+/// `RouteOptions::cache`, so an expensive handler runs once for many
+/// requests.
+///
+/// Responses are stored by target and by the request headers their
+/// `Vary` names. A handler opts a response in with `Cache-Control:
+/// max-age` or `s-maxage`, or `default_ttl` covers responses that say
+/// nothing; `no-store`, `no-cache`, `private`, `Set-Cookie` and `Vary: *`
+/// keep one out. Requests with Authorization or Cookie only store
+/// responses marked `public` or with `s-maxage`, and requests with
+/// `Cache-Control: no-cache` go to the handler and refresh the entry.
+/// Hits carry `Age`, and an If-None-Match naming their ETag gets 304.
+///
+/// Entries are keyed by path and query as sent unless `sort_query`,
+/// `ignore_param` or `key_by_host` say otherwise, so requests that only
+/// differ in those ways can share one.
+///
+/// While one request fills an entry, others for it wait, up to the fill
+/// timeout, instead of all running the handler. Once `capacity` targets
+/// are stored, the least recently used goes. Clones share the entries.
+#[derive(Clone)]
+pub struct ResponseCache {
+    capacity: usize,
+    max_body: usize,
+    default_ttl: Option<Duration>,
+    fill_timeout: Duration,
+    sort_query: bool,
+    // query parameters left out of keys, by name or by prefix with a `*`
+    ignored: Vec<String>,
+    by_host: bool,
+    shared: Arc<(Mutex<Entries>, Condvar)>,
+}
+
+#[derive(Default)]
+struct Entries {
+    targets: HashMap<String, Target>,
+    // last use of each target, oldest first
+    recency: BTreeMap<u64, String>,
+    clock: u64,
+    // targets a request is running the handler for
+    filling: HashSet<String>,
+}
+
+struct Target {
+    used: u64,
+    variants: Vec<Arc<Variant>>,
+}
+
+// one stored response, for requests whose headers match `vary`
+struct Variant {
+    vary: Vec<(String, Option<String>)>,
+    status: (usize, &'static str),
+    headers: Vec<Cow<'static, str>>,
+    body: Bytes,
+    etag: Option<String>,
+    stored: Instant,
+    ttl: Duration,
+}
+
+// a request running the handler for a target; the ones waiting on it
+// are woken when it's done
+struct Filling<'a> {
+    cache: &'a ResponseCache,
+    target: String,
+    owner: bool,
+}
+
+impl<'a> Drop for Filling<'a> {
+    fn drop(&mut self) {
+        if self.owner {
+            let (lock, filled) = &*self.cache.shared;
+            lock.lock().unwrap().filling.remove(&self.target);
+            filled.notify_all();
+        }
+    }
+}
+
+impl Default for ResponseCache {
+    fn default() -> Self {
+        ResponseCache::new()
+    }
+}
+
+impl ResponseCache {
+    pub fn new() -> Self {
+        ResponseCache {
+            capacity: DEFAULT_CAPACITY,
+            max_body: DEFAULT_MAX_BODY,
+            default_ttl: None,
+            fill_timeout: DEFAULT_FILL_TIMEOUT,
+            sort_query: false,
+            ignored: Vec::new(),
+            by_host: false,
+            shared: Arc::default(),
+        }
+    }
+
+    /// How many targets are stored, 1024 by default.
+    pub fn capacity(&mut self, capacity: usize) -> &mut Self {
+        self.capacity = capacity.max(1);
+        self
+    }
+
+    /// Largest body stored, 1 MiB by default; streamed and file bodies
+    /// never are.
+    pub fn max_body_size(&mut self, bytes: usize) -> &mut Self {
+        self.max_body = bytes;
+        self
+    }
+
+    /// How long responses without a max-age are fresh; by default they
+    /// aren't stored.
+    pub fn default_ttl(&mut self, ttl: Duration) -> &mut Self {
+        self.default_ttl = Some(ttl);
+        self
+    }
+
+    /// How long requests wait on another filling the same entry before
+    /// running the handler themselves, 5 seconds by default.
+    pub fn fill_timeout(&mut self, timeout: Duration) -> &mut Self {
+        self.fill_timeout = timeout;
+        self
+    }
+
+    /// Orders query parameters by name in keys, so `?a=1&b=2` and
+    /// `?b=2&a=1` share an entry; repeated ones keep their order.
+    pub fn sort_query(&mut self, sort: bool) -> &mut Self {
+        self.sort_query = sort;
+        self
+    }
+
+    /// Leaves a query parameter out of keys, e.g. `fbclid`; a trailing `*`
+    /// matches by prefix, e.g. `utm_*`. Handlers still see it.
+    pub fn ignore_param(&mut self, name: &str) -> &mut Self {
+        self.ignored.push(name.to_owned());
+        self
+    }
+
+    /// Leaves out the usual tracking parameters: `utm_*`, `fbclid`,
+    /// `gclid`, `dclid`, `msclkid`, `mc_cid`, `mc_eid` and `_ga`.
+    pub fn ignore_tracking_params(&mut self) -> &mut Self {
+        for name in TRACKING_PARAMS {
+            self.ignore_param(name);
+        }
+        self
+    }
+
+    /// Keys entries by the case-folded Host as well, for routes that
+    /// serve several hosts different content.
+    pub fn key_by_host(&mut self, enabled: bool) -> &mut Self {
+        self.by_host = enabled;
+        self
+    }
+
+    /// Drops what's stored for `target`, a path with its query, e.g.
+    /// after the resource changed; keyed by host, it goes for every host.
+    pub fn purge(&self, target: &str) {
+        let target = self.normalize(target);
+        let mut entries = self.shared.0.lock().unwrap();
+        let purged: Vec<String> = entries
+            .targets
+            .keys()
+            .filter(|key| key.find('/').map_or(false, |at| key[at..] == target))
+            .cloned()
+            .collect();
+        for key in purged {
+            if let Some(stored) = entries.targets.remove(&key) {
+                entries.recency.remove(&stored.used);
+            }
+        }
+    }
+
+    pub fn clear(&self) {
+        let mut entries = self.shared.0.lock().unwrap();
+        entries.targets.clear();
+        entries.recency.clear();
+    }
+
+    /// Targets stored.
+    pub fn len(&self) -> usize {
+        self.shared.0.lock().unwrap().targets.len()
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.len() == 0
+    }
+
+    // answers from the cache, or runs the handler and stores what it gives
+    pub(crate) fn serve(
+        &self,
+        req: Request,
+        res: &mut Response,
+        handler: &RouteHandler,
+    ) -> io::Result<()> {
+        if req.method() != "GET" {
+            return handler(req, res);
+        }
+        let target = self.key(&req);
+        let refresh = req
+            .header("cache-control")
+            .map_or(false, |cc| directive(cc, "no-cache").is_some())
+            || req
+                .header("pragma")
+                .map_or(false, |p| p.contains("no-cache"));
+        let _filling = match refresh {
+            true => None,
+            false => match self.lookup(&target, &req) {
+                Ok(variant) => return replay(&variant, &req, res),
+                Err(filling) => Some(filling),
+            },
+        };
+        let headers: Vec<(String, String)> = req
+            .headers()
+            .iter()
+            .map(|h| {
+                let value = String::from_utf8_lossy(h.value).trim().to_owned();
+                (h.name.to_ascii_lowercase(), value)
+            })
+            .collect();
+        let credentials = req.header("authorization").is_some() || req.header("cookie").is_some();
+        handler(req, res)?;
+        if let Some(variant) = self.capture(res, &headers, credentials) {
+            self.store(target, variant);
+        }
+        Ok(())
+    }
+
+    // the host, if keyed by it, then the target as normalized
+    fn key(&self, req: &Request) -> String {
+        let target = self.normalize(req.path());
+        match self.by_host {
+            true => {
+                let host = req.header("host").unwrap_or("").trim();
+                format!("{}{}", host.to_ascii_lowercase(), target)
+            }
+            false => target,
+        }
+    }
+
+    fn normalize(&self, target: &str) -> String {
+        let (path, query) = match target.split_once('?') {
+            Some(split) if self.sort_query || !self.ignored.is_empty() => split,
+            _ => return target.to_owned(),
+        };
+        let name = |param: &str| param.split('=').next().unwrap_or("").to_owned();
+        let mut params: Vec<&str> = query
+            .split('&')
+            .filter(|param| !param.is_empty() && !self.ignored(&name(param)))
+            .collect();
+        if self.sort_query {
+            params.sort_by_key(|param| name(param));
+        }
+        match params.is_empty() {
+            true => path.to_owned(),
+            false => format!("{}?{}", path, params.join("&")),
+        }
+    }
+
+    fn ignored(&self, name: &str) -> bool {
+        self.ignored
+            .iter()
+            .any(|ignored| match ignored.strip_suffix('*') {
+                Some(prefix) => name.starts_with(prefix),
+                None => name == ignored,
+            })
+    }
+
+    // a fresh response for the request, or the right to fill the entry
+    fn lookup(&self, target: &str, req: &Request) -> Result<Arc<Variant>, Filling> {
+        let deadline = Instant::now() + self.fill_timeout;
+        let (lock, filled) = &*self.shared;
+        let mut entries = lock.lock().unwrap();
+        let mut waited = false;
+        loop {
+            if let Some(variant) = entries.fresh(target, req) {
+                return Ok(variant);
+            }
+            let now = Instant::now();
+            if entries.filling.contains(target) && now < deadline {
+                waited = true;
+                entries = filled.wait_timeout(entries, deadline - now).unwrap().0;
+                continue;
+            }
+            // a fill that stored nothing for this request isn't waited on
+            // again; the handler runs alongside instead of one at a time
+            let owner = !waited && entries.filling.insert(target.to_owned());
+            return Err(Filling {
+                cache: self,
+                target: target.to_owned(),
+                owner,
+            });
+        }
+    }
+
+    // what of the response can be stored, if it may be
+    fn capture(
+        &self,
+        res: &mut Response,
+        headers: &[(String, String)],
+        credentials: bool,
+    ) -> Option<Variant> {
+        let status = res.status_message();
+        let cacheable_status = matches!(
+            status.0,
+            200 | 203 | 204 | 300 | 301 | 308 | 404 | 405 | 410 | 414 | 501
+        );
+        if !cacheable_status || res.header_value("set-cookie").is_some() {
+            return None;
+        }
+        let cache_control = res
+            .header_values("cache-control")
+            .collect::<Vec<_>>()
+            .join(",");
+        let has = |name| directive(&cache_control, name).is_some();
+        if has("no-store") || has("no-cache") || has("private") {
+            return None;
+        }
+        let seconds = |name| directive(&cache_control, name)?.parse::<u64>().ok();
+        let shared_age = seconds("s-maxage");
+        if credentials && !(has("public") || shared_age.is_some()) {
+            return None;
+        }
+        let ttl = match shared_age.or_else(|| seconds("max-age")) {
+            Some(secs) => Duration::from_secs(secs),
+            None => self.default_ttl?,
+        };
+        let mut vary = Vec::new();
+        for name in res.header_values("vary").flat_map(|v| v.split(',')) {
+            let name = name.trim().to_ascii_lowercase();
+            if name == "*" {
+                return None;
+            }
+            let value = headers
+                .iter()
+                .find(|(n, _)| *n == name)
+                .map(|(_, v)| v.clone());
+            vary.push((name, value));
+        }
+        if ttl.is_zero() {
+            return None;
+        }
+        let etag = res.header_value("etag").map(str::to_owned);
+        let headers = res.headers().to_vec();
+        let body = res.shared_body(self.max_body)?;
+        Some(Variant {
+            vary,
+            status,
+            headers,
+            body,
+            etag,
+            stored: Instant::now(),
+            ttl,
+        })
+    }
+
+    fn store(&self, target: String, variant: Variant) {
+        let mut entries = self.shared.0.lock().unwrap();
+        let used = entries.touch(&target);
+        let stored = entries.targets.entry(target).or_insert_with(|| Target {
+            used,
+            variants: Vec::new(),
+        });
+        stored.used = used;
+        stored
+            .variants
+            .retain(|v| v.vary != variant.vary && v.fresh());
+        stored.variants.push(Arc::new(variant));
+        while entries.targets.len() > self.capacity {
+            let oldest = match entries.recency.pop_first() {
+                Some((_, oldest)) => oldest,
+                None => break,
+            };
+            entries.targets.remove(&oldest);
+        }
+    }
+}
+
+impl Entries {
+    fn fresh(&mut self, target: &str, req: &Request) -> Option<Arc<Variant>> {
+        let stored = self.targets.get_mut(target)?;
+        stored.variants.retain(|v| v.fresh());
+        let variant = stored.variants.iter().find(|v| v.matches(req)).cloned()?;
+        self.touch(target);
+        Some(variant)
+    }
+
+    // marks `target` as just used and returns when that was
+    fn touch(&mut self, target: &str) -> u64 {
+        self.clock += 1;
+        let used = self.clock;
+        if let Some(stored) = self.targets.get_mut(target) {
+            self.recency.remove(&stored.used);
+            stored.used = used;
+        }
+        self.recency.insert(used, target.to_owned());
+        used
+    }
+}
+
+impl Variant {
+    fn fresh(&self) -> bool {
+        self.stored.elapsed() < self.ttl
+    }
+
+    fn matches(&self, req: &Request) -> bool {
+        self.vary
+            .iter()
+            .all(|(name, value)| req.header(name).map(str::trim) == value.as_deref())
+    }
+}
+
+fn replay(variant: &Variant, req: &Request, res: &mut Response) -> io::Result<()> {
+    for header in &variant.headers {
+        match header {
+            Cow::Borrowed(header) => res.header(header),
+            Cow::Owned(header) => res.header_owned(header.clone()),
+        };
+    }
+    res.header_owned(format!("Age: {}", variant.stored.elapsed().as_secs()));
+    let unchanged = match (&variant.etag, req.header("if-none-match")) {
+        (Some(etag), Some(tags)) => matches(tags, etag, false),
+        _ => false,
+    };
+    if unchanged {
+        res.status_code(304, "Not Modified");
+        return Ok(());
+    }
+    res.status_code(variant.status.0, variant.status.1);
+    res.body_segments(vec![variant.body.clone()]);
+    Ok(())
+}
+
+// the value of a Cache-Control directive, empty for one without
+fn directive<'a>(cache_control: &'a str, name: &str) -> Option<&'a str> {
+    cache_control.split(',').find_map(|d| {
+        let (key, value) = d.split_once('=').unwrap_or((d, ""));
+        key.trim()
+            .eq_ignore_ascii_case(name)
+            .then(|| value.trim().trim_matches('"'))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use std::sync::atomic::{AtomicUsize, Ordering};
+    use std::thread;
+
+    use crate::server::server::Server;
+    use crate::test::TestClient;
+    use crate::testing::{race, THREADS};
+
+    // a server whose `/n/:n` handler counts its runs, cached by `cache`
+    fn server(cache: &ResponseCache, runs: &Arc<AtomicUsize>) -> Server {
+        let mut server = Server::new();
+        let runs = runs.clone();
+        server
+            .get("/n/:n", move |req, res| {
+                runs.fetch_add(1, Ordering::SeqCst);
+                // long enough for the others to find the fill under way
+                thread::sleep(Duration::from_millis(20));
+                res.header("Cache-Control: max-age=60");
+                res.send(format!("n {}", req.parameter("n").unwrap_or("")))
+            })
+            .cache(cache);
+        server
+    }
+
+    // GETs `paths` from every thread at once, returning the bodies
+    fn get_all(server: &Server, paths: &[&str]) -> Vec<String> {
+        let bodies = race(|_| {
+            let client = TestClient::new(server);
+            let get = |path: &&str| {
+                let res = client.get(path).send();
+                assert_eq!(res.status(), 200);
+                res.text().to_owned()
+            };
+            paths.iter().map(get).collect::<Vec<_>>()
+        });
+        bodies.into_iter().flatten().collect()
+    }
+
+    #[test]
+    fn concurrent_misses_run_the_handler_once() {
+        let cache = ResponseCache::new();
+        let runs = Arc::new(AtomicUsize::new(0));
+        let bodies = get_all(&server(&cache, &runs), &["/n/1"]);
+        assert_eq!(bodies, vec!["n 1"; THREADS]);
+        assert_eq!(runs.load(Ordering::SeqCst), 1);
+        assert_eq!(cache.len(), 1);
+    }
+
+    #[test]
+    fn each_target_fills_once_and_capacity_holds() {
+        let mut cache = ResponseCache::new();
+        cache.capacity(4);
+        let runs = Arc::new(AtomicUsize::new(0));
+        let server = server(&cache, &runs);
+        let bodies = get_all(&server, &["/n/1", "/n/2", "/n/3"]);
+        assert_eq!(bodies.len(), 3 * THREADS);
+        assert!(bodies.chunks(3).all(|got| got == ["n 1", "n 2", "n 3"]));
+        assert_eq!(runs.load(Ordering::SeqCst), 3);
+        get_all(&server, &["/n/4", "/n/5", "/n/6"]);
+        assert_eq!(cache.len(), 4);
+    }
+}
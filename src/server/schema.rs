@@ -0,0 +1,320 @@
+use std::fs;
+use std::io;
+use std::path::Path;
+use std::sync::Arc;
+
+use serde_json::{Map, Value};
+
+// how many schemas deep validation goes before giving up, so a `$ref`
+// cycle can't recurse forever
+const MAX_DEPTH: usize = 64;
+
+/// A JSON Schema that request bodies are checked against, attached with
+/// `RouteOptions::json_schema`. Bodies that aren't JSON or don't match
+/// get 422 with the violations, as `ValidationErrors`, before the handler
+/// runs.
+///
+/// Covers the structural keywords: `type`, `enum`, `const`, `properties`,
+/// `required`, `additionalProperties`, `min`/`maxProperties`, `items`,
+/// `min`/`maxItems`, `uniqueItems`, `min`/`maxLength`, the numeric
+/// bounds, `multipleOf`, `allOf`, `anyOf`, `oneOf`, `not` and local
+/// `$ref`s. `pattern` and `format` are not checked.
+#[derive(Clone, Debug)]
+pub struct JsonSchema {
+    root: Arc<Value>,
+}
+
+/// One way a value breaks the schema.
+#[derive(Clone, Debug)]
+pub struct SchemaError {
+    /// JSON pointer to the offending value, `""` for the whole body.
+    pub path: String,
+    pub message: String,
+}
+
+impl JsonSchema {
+    pub fn new(schema: Value) -> Self {
+        JsonSchema {
+            root: Arc::new(schema),
+        }
+    }
+
+    /// Loads a schema file, e.g. at startup.
+    pub fn from_file<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        let schema = serde_json::from_slice(&fs::read(path)?)?;
+        Ok(JsonSchema::new(schema))
+    }
+
+    pub(crate) fn as_value(&self) -> &Value {
+        &self.root
+    }
+
+    pub fn validate(&self, value: &Value) -> Result<(), Vec<SchemaError>> {
+        let mut validator = Validator {
+            root: &self.root,
+            errors: Vec::new(),
+        };
+        validator.check(&self.root, value, "", 0);
+        match validator.errors.is_empty() {
+            true => Ok(()),
+            false => Err(validator.errors),
+        }
+    }
+}
+
+struct Validator<'s> {
+    root: &'s Value,
+    errors: Vec<SchemaError>,
+}
+
+impl<'s> Validator<'s> {
+    fn error(&mut self, path: &str, message: String) {
+        self.errors.push(SchemaError {
+            path: path.to_owned(),
+            message,
+        });
+    }
+
+    // whether `value` matches `schema`, without reporting why not
+    fn matches(&self, schema: &Value, value: &Value, path: &str, depth: usize) -> bool {
+        let mut inner = Validator {
+            root: self.root,
+            errors: Vec::new(),
+        };
+        inner.check(schema, value, path, depth);
+        inner.errors.is_empty()
+    }
+
+    fn check(&mut self, schema: &Value, value: &Value, path: &str, depth: usize) {
+        if depth > MAX_DEPTH {
+            return self.error(path, "schema nests too deeply".to_owned());
+        }
+        let schema = match schema {
+            Value::Bool(true) => return,
+            Value::Bool(false) => return self.error(path, "no value is allowed here".to_owned()),
+            Value::Object(schema) => schema,
+            _ => return,
+        };
+        let depth = depth + 1;
+
+        if let Some(reference) = schema.get("$ref").and_then(Value::as_str) {
+            match resolve(self.root, reference) {
+                Some(target) => self.check(target, value, path, depth),
+                None => self.error(path, format!("unresolvable $ref {}", reference)),
+            }
+        }
+        if let Some(expected) = schema.get("type") {
+            let allowed = match expected {
+                Value::String(name) => is_type(value, name),
+                Value::Array(names) => names
+                    .iter()
+                    .filter_map(Value::as_str)
+                    .any(|name| is_type(value, name)),
+                _ => true,
+            };
+            if !allowed {
+                self.error(
+                    path,
+                    format!("expected {}, got {}", expected, type_of(value)),
+                );
+            }
+        }
+        if let Some(options) = schema.get("enum").and_then(Value::as_array) {
+            if !options.contains(value) {
+                self.error(path, "not one of the allowed values".to_owned());
+            }
+        }
+        if let Some(constant) = schema.get("const") {
+            if constant != value {
+                self.error(path, format!("must be {}", constant));
+            }
+        }
+
+        match value {
+            Value::Object(object) => self.check_object(schema, object, path, depth),
+            Value::Array(items) => self.check_array(schema, items, path, depth),
+            Value::String(s) => self.check_string(schema, s, path),
+            Value::Number(_) => self.check_number(schema, value.as_f64().unwrap_or(0.0), path),
+            _ => {}
+        }
+
+        if let Some(all) = schema.get("allOf").and_then(Value::as_array) {
+            for sub in all {
+                self.check(sub, value, path, depth);
+            }
+        }
+        if let Some(any) = schema.get("anyOf").and_then(Value::as_array) {
+            if !any.iter().any(|sub| self.matches(sub, value, path, depth)) {
+                self.error(path, "matches none of anyOf".to_owned());
+            }
+        }
+        if let Some(one) = schema.get("oneOf").and_then(Value::as_array) {
+            let matched = one
+                .iter()
+                .filter(|sub| self.matches(sub, value, path, depth))
+                .count();
+            if matched != 1 {
+                self.error(
+                    path,
+                    format!("matches {} of oneOf, not exactly one", matched),
+                );
+            }
+        }
+        if let Some(not) = schema.get("not") {
+            if self.matches(not, value, path, depth) {
+                self.error(path, "matches a schema it must not".to_owned());
+            }
+        }
+    }
+
+    fn check_object(
+        &mut self,
+        schema: &Map<String, Value>,
+        object: &Map<String, Value>,
+        path: &str,
+        depth: usize,
+    ) {
+        if let Some(required) = schema.get("required").and_then(Value::as_array) {
+            for name in required.iter().filter_map(Value::as_str) {
+                if !object.contains_key(name) {
+                    self.error(path, format!("missing required property {}", name));
+                }
+            }
+        }
+        let properties = schema.get("properties").and_then(Value::as_object);
+        for (name, member) in object {
+            let member_path = format!("{}/{}", path, escape(name));
+            match properties.and_then(|properties| properties.get(name)) {
+                Some(sub) => self.check(sub, member, &member_path, depth),
+                None => match schema.get("additionalProperties") {
+                    Some(Value::Bool(false)) => {
+                        self.error(&member_path, "unexpected property".to_owned())
+                    }
+                    Some(sub) => self.check(sub, member, &member_path, depth),
+                    None => {}
+                },
+            }
+        }
+        if let Some(min) = schema.get("minProperties").and_then(Value::as_u64) {
+            if (object.len() as u64) < min {
+                self.error(path, format!("needs at least {} properties", min));
+            }
+        }
+        if let Some(max) = schema.get("maxProperties").and_then(Value::as_u64) {
+            if object.len() as u64 > max {
+                self.error(path, format!("allows at most {} properties", max));
+            }
+        }
+    }
+
+    fn check_array(
+        &mut self,
+        schema: &Map<String, Value>,
+        items: &[Value],
+        path: &str,
+        depth: usize,
+    ) {
+        if let Some(sub) = schema.get("items") {
+            for (i, item) in items.iter().enumerate() {
+                self.check(sub, item, &format!("{}/{}", path, i), depth);
+            }
+        }
+        if let Some(min) = schema.get("minItems").and_then(Value::as_u64) {
+            if (items.len() as u64) < min {
+                self.error(path, format!("needs at least {} items", min));
+            }
+        }
+        if let Some(max) = schema.get("maxItems").and_then(Value::as_u64) {
+            if items.len() as u64 > max {
+                self.error(path, format!("allows at most {} items", max));
+            }
+        }
+        if schema.get("uniqueItems").and_then(Value::as_bool) == Some(true) {
+            let repeated = items
+                .iter()
+                .enumerate()
+                .any(|(i, item)| items[..i].contains(item));
+            if repeated {
+                self.error(path, "items must be unique".to_owned());
+            }
+        }
+    }
+
+    fn check_string(&mut self, schema: &Map<String, Value>, s: &str, path: &str) {
+        let len = s.chars().count() as u64;
+        if let Some(min) = schema.get("minLength").and_then(Value::as_u64) {
+            if len < min {
+                self.error(path, format!("must be at least {} characters", min));
+            }
+        }
+        if let Some(max) = schema.get("maxLength").and_then(Value::as_u64) {
+            if len > max {
+                self.error(path, format!("must be at most {} characters", max));
+            }
+        }
+    }
+
+    fn check_number(&mut self, schema: &Map<String, Value>, n: f64, path: &str) {
+        let bound = |name| schema.get(name).and_then(Value::as_f64);
+        if let Some(min) = bound("minimum") {
+            if n < min {
+                self.error(path, format!("must be at least {}", min));
+            }
+        }
+        if let Some(max) = bound("maximum") {
+            if n > max {
+                self.error(path, format!("must be at most {}", max));
+            }
+        }
+        if let Some(min) = bound("exclusiveMinimum") {
+            if n <= min {
+                self.error(path, format!("must be more than {}", min));
+            }
+        }
+        if let Some(max) = bound("exclusiveMaximum") {
+            if n >= max {
+                self.error(path, format!("must be less than {}", max));
+            }
+        }
+        if let Some(step) = bound("multipleOf").filter(|&step| step > 0.0) {
+            let ratio = n / step;
+            if (ratio - ratio.round()).abs() > 1e-9 {
+                self.error(path, format!("must be a multiple of {}", step));
+            }
+        }
+    }
+}
+
+fn is_type(value: &Value, name: &str) -> bool {
+    match name {
+        "integer" => match value {
+            Value::Number(_) => value.as_f64().map_or(false, |n| n.fract() == 0.0),
+            _ => false,
+        },
+        "number" => value.is_number(),
+        _ => type_of(value) == name,
+    }
+}
+
+fn type_of(value: &Value) -> &'static str {
+    match value {
+        Value::Null => "null",
+        Value::Bool(_) => "boolean",
+        Value::Number(_) => "number",
+        Value::String(_) => "string",
+        Value::Array(_) => "array",
+        Value::Object(_) => "object",
+    }
+}
+
+// `#` or `#/json/pointer` within the same document
+fn resolve<'s>(root: &'s Value, reference: &str) -> Option<&'s Value> {
+    match reference.strip_prefix('#')? {
+        "" => Some(root),
+        pointer => root.pointer(pointer),
+    }
+}
+
+fn escape(name: &str) -> String {
+    name.replace('~', "~0").replace('/', "~1")
+}
@@ -0,0 +1,107 @@
+use std::env;
+use std::fs;
+use std::io;
+use std::path::PathBuf;
+
+use sha2::{Digest, Sha256};
+
+/// Source of named secrets such as signing keys or webhook tokens.
+///
+/// Providers are asked again on every use rather than once at startup, so
+/// a rotated secret takes effect without a restart.
+pub trait SecretsProvider: Send + Sync {
+    fn secret(&self, name: &str) -> io::Result<Vec<u8>>;
+}
+
+/// Any `Fn(&str) -> io::Result<Vec<u8>>`, e.g. a client for a vault.
+impl<F> SecretsProvider for F
+where
+    F: Fn(&str) -> io::Result<Vec<u8>> + Send + Sync,
+{
+    fn secret(&self, name: &str) -> io::Result<Vec<u8>> {
+        self(name)
+    }
+}
+
+/// Reads `<prefix><NAME>` from the environment, `name` upper-cased.
+pub struct EnvSecrets {
+    prefix: String,
+}
+
+impl EnvSecrets {
+    pub fn new(prefix: &str) -> Self {
+        EnvSecrets {
+            prefix: prefix.to_owned(),
+        }
+    }
+}
+
+impl SecretsProvider for EnvSecrets {
+    fn secret(&self, name: &str) -> io::Result<Vec<u8>> {
+        let key = format!("{}{}", self.prefix, name.to_ascii_uppercase());
+        env::var_os(&key)
+            .map(|value| value.to_string_lossy().into_owned().into_bytes())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::NotFound, format!("{} is not set", key)))
+    }
+}
+
+/// Reads the file `name` in a directory, as mounted secret volumes lay
+/// them out; one trailing newline is dropped.
+pub struct FileSecrets {
+    dir: PathBuf,
+}
+
+impl FileSecrets {
+    pub fn new<P: Into<PathBuf>>(dir: P) -> Self {
+        FileSecrets { dir: dir.into() }
+    }
+}
+
+impl SecretsProvider for FileSecrets {
+    fn secret(&self, name: &str) -> io::Result<Vec<u8>> {
+        // a name must not reach outside the directory
+        if name.is_empty() || name.contains(['/', '\\']) || name == "." || name == ".." {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "secret name must be a plain file name",
+            ));
+        }
+        let mut secret = fs::read(self.dir.join(name))?;
+        if secret.ends_with(b"\n") {
+            secret.pop();
+            if secret.ends_with(b"\r") {
+                secret.pop();
+            }
+        }
+        Ok(secret)
+    }
+}
+
+// RFC 2104 over SHA-256
+pub(crate) fn hmac_sha256(key: &[u8], message: &[u8]) -> Vec<u8> {
+    const BLOCK: usize = 64;
+    let mut block = [0u8; BLOCK];
+    if key.len() > BLOCK {
+        block[..32].copy_from_slice(&Sha256::digest(key));
+    } else {
+        block[..key.len()].copy_from_slice(key);
+    }
+    let mut inner = Sha256::new();
+    inner.update(block.map(|b| b ^ 0x36));
+    inner.update(message);
+    let inner = inner.finalize();
+    let mut outer = Sha256::new();
+    outer.update(block.map(|b| b ^ 0x5c));
+    outer.update(&inner[..]);
+    outer.finalize().to_vec()
+}
+
+// doesn't stop at the first difference, so timing says nothing about
+// how much of a forged signature was right
+pub(crate) fn constant_time_eq(a: &[u8], b: &[u8]) -> bool {
+    a.len() == b.len() && a.iter().zip(b).fold(0, |acc, (x, y)| acc | (x ^ y)) == 0
+}
+
+pub(crate) fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|b| format!("{:02x}", b)).collect()
+}
@@ -1,7 +1,48 @@
-
+use std::collections::hash_map::RandomState;
+use std::hash::{BuildHasher, Hasher};
 use std::io;
+use std::net::IpAddr;
+use std::sync::atomic::{AtomicU64, Ordering};
+use std::sync::{Arc, RwLock};
+use std::time::{Duration, Instant};
+
+use once_cell::sync::Lazy;
+use socket2::TcpKeepalive;
 
-use crate::{http::http_server::{HttpServer, HttpService }, request::request::{RawRequest,Request}, response::response::Response, router::route_matcher::RouteMatcher};
+use crate::errors::catalog::{Locale, MessageCatalog};
+use crate::errors::validation::ValidationErrors;
+use crate::http::forwarded::{self, IpNet};
+use crate::http::http_server::is_dropped;
+use crate::http::platform;
+use crate::http::shutdown::ServerHandle;
+use crate::request::logger::RequestLogger;
+use crate::server::access_log::{AccessEntry, AccessLog};
+use crate::server::affinity::{pin_workers, WorkerPinning};
+use crate::server::allocator::AllocatorStats;
+use crate::server::api_key::ApiKey;
+use crate::server::audit::AuditLog;
+use crate::server::config::{AtCapacity, MinWriteRate, ServerConfig, MIN_BUFFER_SIZE};
+use crate::server::connection_admin::ConnectionAdmin;
+use crate::server::cors::Cors;
+use crate::server::csrf::Csrf;
+use crate::server::deprecation::{self, DeprecationUsage};
+use crate::server::explain::Explain;
+use crate::server::flags::FlagProvider;
+use crate::server::ip_filter::IpFilter;
+use crate::server::maintenance::Maintenance;
+use crate::server::metrics::Metrics;
+use crate::server::openapi;
+use crate::server::rate_limit::RateLimiter;
+use crate::server::schema::JsonSchema;
+use crate::server::security_headers::SecurityHeaders;
+use crate::server::websocket::WebSocketOrigins;
+use crate::{
+    errors::http_error,
+    http::http_server::{HttpServer, HttpService},
+    request::request::{RawRequest, Request},
+    response::{respond::IntoResponse, response::Response, status::StatusCode},
+    router::route_matcher::{RouteInfo, RouteMatcher, RouteOptions, TrailingSlash},
+};
 
 pub type Middleware =
     Box<dyn Fn(&RawRequest, &mut Response) -> io::Result<()> + Send + Sync + 'static>;
@@ -12,109 +53,1189 @@ pub type RouteHandler =
 #[derive(Clone)]
 pub struct Server {
     route_handlers: RouteMatcher,
+    // per-hostname routers, tried before falling back to `route_handlers`
+    virtual_hosts: Vec<(String, RouteMatcher)>,
+    config: Arc<ServerConfig>,
+    // shared by the running server's clones, one per connection
+    live: Arc<Live>,
+    // the `live` generation this clone is
+    generation: u64,
+}
+
+// the server open connections switch to at their next request, once a
+// `ServerHandle` swaps one in
+#[derive(Default)]
+struct Live {
+    generation: AtomicU64,
+    current: RwLock<Option<Server>>,
 }
 
 impl Server {
     pub fn new() -> Self {
         Server {
             route_handlers: RouteMatcher::new(),
+            virtual_hosts: Vec::new(),
+            config: Arc::new(ServerConfig::default()),
+            live: Arc::default(),
+            generation: 0,
+        }
+    }
+
+    /// Largest request body accepted, in bytes; bigger requests get 413.
+    pub fn max_body_size(&mut self, limit: usize) -> &mut Self {
+        Arc::make_mut(&mut self.config).max_body_size = limit;
+        self
+    }
+
+    /// Disconnects clients that accept no response bytes for this long.
+    pub fn write_timeout(&mut self, timeout: Duration) -> &mut Self {
+        Arc::make_mut(&mut self.config).write_timeout = Some(timeout);
+        self
+    }
+
+    /// Disconnects clients that read responses slower than `bytes_per_sec`
+    /// once `grace` has elapsed.
+    pub fn min_write_rate(&mut self, bytes_per_sec: usize, grace: Duration) -> &mut Self {
+        Arc::make_mut(&mut self.config).min_write_rate = Some(MinWriteRate {
+            bytes_per_sec,
+            grace,
+        });
+        self
+    }
+
+    /// Has bulk transfers, i.e. files, streamed bodies and CONNECT tunnels,
+    /// yield the worker to its other coroutines after every `bytes`
+    /// written, so one large download can't hold up the requests sharing
+    /// its worker. Handlers looping over large data can do the same with
+    /// `RequestContext::yield_every`.
+    pub fn yield_every(&mut self, bytes: usize) -> &mut Self {
+        Arc::make_mut(&mut self.config).yield_every = Some(bytes);
+        self
+    }
+
+    /// Answers 408 and disconnects clients that take longer than this to
+    /// send a request head, counted from its first byte.
+    pub fn header_timeout(&mut self, timeout: Duration) -> &mut Self {
+        Arc::make_mut(&mut self.config).header_timeout = Some(timeout);
+        self
+    }
+
+    /// Answers 408 when a read of the request body waits longer than this.
+    pub fn body_read_timeout(&mut self, timeout: Duration) -> &mut Self {
+        Arc::make_mut(&mut self.config).body_read_timeout = Some(timeout);
+        self
+    }
+
+    /// Closes connections left idle between requests for this long.
+    pub fn keep_alive_timeout(&mut self, timeout: Duration) -> &mut Self {
+        Arc::make_mut(&mut self.config).keep_alive_timeout = Some(timeout);
+        self
+    }
+
+    /// Serves at most `limit` requests on one connection; the last gets
+    /// `Connection: close`.
+    pub fn max_requests_per_connection(&mut self, limit: usize) -> &mut Self {
+        Arc::make_mut(&mut self.config).max_requests = Some(limit);
+        self
+    }
+
+    /// Closes connections after the first response sent once they are
+    /// this old, so clients reconnect and get rebalanced.
+    pub fn max_connection_age(&mut self, age: Duration) -> &mut Self {
+        Arc::make_mut(&mut self.config).max_connection_age = Some(age);
+        self
+    }
+
+    /// Sets SO_LINGER on accepted connections.
+    pub fn linger(&mut self, linger: Duration) -> &mut Self {
+        Arc::make_mut(&mut self.config).linger = Some(linger);
+        self
+    }
+
+    /// Sets TCP_NODELAY on accepted connections.
+    pub fn nodelay(&mut self, enabled: bool) -> &mut Self {
+        Arc::make_mut(&mut self.config).nodelay = enabled;
+        self
+    }
+
+    /// Enables SO_KEEPALIVE on accepted connections, probing after `idle`
+    /// without traffic and every `interval` after that.
+    pub fn tcp_keepalive(&mut self, idle: Duration, interval: Duration) -> &mut Self {
+        Arc::make_mut(&mut self.config).tcp_keepalive =
+            Some(TcpKeepalive::new().with_time(idle).with_interval(interval));
+        self
+    }
+
+    /// Length of the listen queue for connections not yet accepted.
+    pub fn backlog(&mut self, len: u32) -> &mut Self {
+        Arc::make_mut(&mut self.config).backlog = Some(len.min(i32::MAX as u32) as i32);
+        self
+    }
+
+    /// Resets connections dropped for protocol errors or timeouts instead
+    /// of closing them gracefully.
+    pub fn abortive_close(&mut self, enabled: bool) -> &mut Self {
+        Arc::make_mut(&mut self.config).abortive_close = enabled;
+        self
+    }
+
+    /// Most header fields accepted per request; more get 431.
+    pub fn max_headers(&mut self, limit: usize) -> &mut Self {
+        Arc::make_mut(&mut self.config).max_headers = limit;
+        self
+    }
+
+    /// Largest request head accepted, in bytes; bigger heads get 431.
+    pub fn max_header_bytes(&mut self, limit: usize) -> &mut Self {
+        Arc::make_mut(&mut self.config).max_header_bytes = limit;
+        self
+    }
+
+    /// Longest request line accepted, in bytes; longer ones get 414.
+    pub fn max_request_line(&mut self, limit: usize) -> &mut Self {
+        Arc::make_mut(&mut self.config).max_request_line = limit;
+        self
+    }
+
+    /// The Server header sent on every response, `M` by default; `None`
+    /// leaves it out. A handler's own Server header wins.
+    pub fn server_header(&mut self, value: Option<&str>) -> &mut Self {
+        Arc::make_mut(&mut self.config).server_header = value.map(Arc::from);
+        self
+    }
+
+    /// Answers 400 to requests other servers might read differently:
+    /// folded header lines, control characters in header values, space
+    /// before a header's colon, methods that aren't tokens, and targets in
+    /// absolute form, which only proxies need. Off by default, which lets
+    /// absolute-form targets through and closes the connection on the
+    /// rest.
+    pub fn strict_requests(&mut self, enabled: bool) -> &mut Self {
+        Arc::make_mut(&mut self.config).strict_requests = enabled;
+        self
+    }
+
+    /// How deep JSON bodies may nest, 64 by default; deeper ones are
+    /// refused with 400 before they're parsed.
+    pub fn json_max_depth(&mut self, depth: usize) -> &mut Self {
+        Arc::make_mut(&mut self.config).json_limits.max_depth = depth;
+        self
+    }
+
+    /// How many keys and values a JSON body may hold, unlimited by
+    /// default; larger ones are refused with 400 before they're parsed.
+    pub fn json_max_tokens(&mut self, tokens: usize) -> &mut Self {
+        Arc::make_mut(&mut self.config).json_limits.max_tokens = Some(tokens);
+        self
+    }
+
+    /// Binds `acceptors` listeners to the address with SO_REUSEPORT, each
+    /// with its own accept loop, and lets the kernel spread connections;
+    /// other processes doing the same share the port too.
+    pub fn reuseport(&mut self, acceptors: usize) -> &mut Self {
+        Arc::make_mut(&mut self.config).reuseport = Some(acceptors);
+        self
+    }
+
+    /// Accepts on one SO_REUSEPORT listener per worker instead of a single
+    /// listener, which spreads accepting over the workers at the cost of
+    /// a socket each; `reuseport` sets the count itself.
+    pub fn accept_per_worker(&mut self, enabled: bool) -> &mut Self {
+        Arc::make_mut(&mut self.config).accept_per_worker = enabled;
+        self
+    }
+
+    /// In reuseport mode, hashes the client address so every connection
+    /// from one client reaches the same acceptor (Linux only).
+    pub fn reuseport_steering(&mut self, enabled: bool) -> &mut Self {
+        Arc::make_mut(&mut self.config).reuseport_steering = enabled;
+        self
+    }
+
+    /// Runs `workers` scheduler threads, 8 by default. may sets them up
+    /// once, so only the first server started in the process decides.
+    pub fn workers(&mut self, workers: usize) -> &mut Self {
+        Arc::make_mut(&mut self.config).workers = workers.max(1);
+        self
+    }
+
+    /// The stack size, in bytes, of the coroutines serving connections and
+    /// HTTP/2 streams; handlers run on it, so deep recursion or large
+    /// locals need more. It applies to every coroutine spawned in the
+    /// process after `start`, as may has one setting for all.
+    pub fn coroutine_stack_size(&mut self, bytes: usize) -> &mut Self {
+        Arc::make_mut(&mut self.config).stack_size = Some(bytes);
+        self
+    }
+
+    /// How many finished coroutines may keeps to reuse their stacks for
+    /// new ones, trading idle memory for cheaper connection setup.
+    pub fn coroutine_pool(&mut self, capacity: usize) -> &mut Self {
+        Arc::make_mut(&mut self.config).coroutine_pool = Some(capacity);
+        self
+    }
+
+    /// Pins worker `i`, and the acceptor it runs, to `cpus[i % cpus.len()]`
+    /// (Linux only).
+    pub fn pin_workers(&mut self, cpus: &[usize]) -> &mut Self {
+        Arc::make_mut(&mut self.config).pinning = Some(WorkerPinning::Cpus(cpus.to_vec()));
+        self
+    }
+
+    /// Keeps every worker on the CPUs of one NUMA node (Linux only).
+    pub fn pin_workers_to_node(&mut self, node: usize) -> &mut Self {
+        Arc::make_mut(&mut self.config).pinning = Some(WorkerPinning::NumaNode(node));
+        self
+    }
+
+    /// Refuses new connections with 503 while connection buffers across
+    /// the process hold at least `bytes`.
+    pub fn memory_budget(&mut self, bytes: usize) -> &mut Self {
+        Arc::make_mut(&mut self.config).memory_budget = Some(bytes);
+        self
+    }
+
+    /// Connection buffers start at `initial` bytes, at least 4 KiB, and
+    /// grow as requests need; an emptied one grown past `max` starts over.
+    /// 32 KiB and 1 MiB by default.
+    pub fn buffer_sizes(&mut self, initial: usize, max: usize) -> &mut Self {
+        let config = Arc::make_mut(&mut self.config);
+        config.buffer_size = initial.max(MIN_BUFFER_SIZE);
+        config.max_buffer_size = max.max(config.buffer_size);
+        self
+    }
+
+    /// Keeps up to `buffers` idle buffers of closed connections for new
+    /// ones rather than freeing them, which spares the allocator when
+    /// connections come and go quickly; none by default. The pool is
+    /// shared by the servers in the process.
+    pub fn buffer_pool(&mut self, buffers: usize) -> &mut Self {
+        Arc::make_mut(&mut self.config).buffer_pool = buffers;
+        self
+    }
+
+    /// Caps open connections, and with them connection coroutines;
+    /// `at_capacity` decides what happens to the ones past it.
+    pub fn max_connections(&mut self, limit: usize, at_capacity: AtCapacity) -> &mut Self {
+        Arc::make_mut(&mut self.config).max_connections = Some((limit, at_capacity));
+        self
+    }
+
+    /// Bytes currently held in connection buffers across the process.
+    pub fn buffered_bytes(&self) -> usize {
+        crate::http::memory::buffered()
+    }
+
+    /// Heap statistics from the global allocator; only the `jemalloc`
+    /// feature reports them.
+    pub fn allocator_stats(&self) -> Option<AllocatorStats> {
+        crate::server::allocator::stats()
+    }
+
+    /// How long `ServerHandle::shutdown` waits for open connections
+    /// before cancelling them.
+    pub fn shutdown_timeout(&mut self, timeout: Duration) -> &mut Self {
+        Arc::make_mut(&mut self.config).shutdown_timeout = timeout;
+        self
+    }
+
+    /// Also accepts on `addr`, with its own acceptors but the same routes,
+    /// state and settings as the address passed to `start`.
+    pub fn also_listen(&mut self, addr: &str) -> &mut Self {
+        Arc::make_mut(&mut self.config)
+            .extra_addrs
+            .push(addr.to_owned());
+        self
+    }
+
+    /// A server accepting on a listener bound elsewhere, e.g. by a parent
+    /// process on a privileged port; start it with `start_inherited`.
+    pub fn from_raw_listener<L: Into<std::net::TcpListener>>(listener: L) -> Self {
+        let mut server = Server::new();
+        server.inherit_listener(listener);
+        server
+    }
+
+    /// Also accepts on a listener bound elsewhere, as it is: its address,
+    /// backlog and socket options are left alone.
+    pub fn inherit_listener<L: Into<std::net::TcpListener>>(&mut self, listener: L) -> &mut Self {
+        Arc::make_mut(&mut self.config)
+            .inherited
+            .push(Arc::new(listener.into()));
+        self
+    }
+
+    /// Takes over the sockets systemd passes a socket activated service,
+    /// as named by `LISTEN_PID` and `LISTEN_FDS`, and clears those so child
+    /// processes don't take them too. A NotFound error when there are
+    /// none, so the server can bind its own instead. Unix only.
+    pub fn systemd_listeners(&mut self) -> io::Result<&mut Self> {
+        for listener in platform::systemd_listeners()? {
+            self.inherit_listener(listener);
+        }
+        Ok(self)
+    }
+
+    /// Expects every connection to open with a PROXY protocol (v1 or v2)
+    /// header and reports the client it names as the remote address.
+    /// Only for servers reachable solely through such a proxy.
+    pub fn proxy_protocol(&mut self, enabled: bool) -> &mut Self {
+        Arc::make_mut(&mut self.config).proxy_protocol = enabled;
+        self
+    }
+
+    /// Also speaks HTTP/2 over cleartext (h2c) to clients that open with
+    /// the HTTP/2 preface or ask for `Upgrade: h2c`, e.g. a TLS-terminating
+    /// proxy. Streamed responses are refused with HTTP_1_1_REQUIRED, which
+    /// tells the client to retry over HTTP/1.1. ALPN isn't implemented, so
+    /// TLS clients can't negotiate `h2` here; put a proxy that speaks it in
+    /// front.
+    pub fn http2(&mut self, enabled: bool) -> &mut Self {
+        Arc::make_mut(&mut self.config).http2 = enabled;
+        self
+    }
+
+    /// Answers every request with a redirect to the same host, path and
+    /// query over HTTPS on `port`, e.g. for a second server on port 80 next
+    /// to the TLS-terminating one. GET and HEAD get 301, other methods 308
+    /// so they aren't turned into GETs. Routes and security headers aren't
+    /// applied; HSTS in particular must not be sent over plain HTTP.
+    /// `allowed_hosts` still applies, so only known hosts are redirected.
+    pub fn redirect_to_https(&mut self, port: u16) -> &mut Self {
+        Arc::make_mut(&mut self.config).https_redirect = Some(port);
+        self
+    }
+
+    /// Relays connections whose TLS ClientHello names `server_name` to
+    /// `backend`, e.g. `10.0.0.5:443`, still encrypted, so one listener
+    /// fronts this server and TLS services behind it. A leading `*.`
+    /// matches any subdomain and `*` any name, or none. The server doesn't
+    /// terminate TLS, so TLS connections matching nothing are closed.
+    pub fn tls_passthrough(&mut self, server_name: &str, backend: &str) -> &mut Self {
+        let routes = &mut Arc::make_mut(&mut self.config).passthrough.by_name;
+        let server_name = server_name.to_ascii_lowercase();
+        match routes.iter_mut().find(|(name, _)| *name == server_name) {
+            Some(route) => route.1 = backend.to_owned(),
+            None => routes.push((server_name, backend.to_owned())),
+        }
+        self
+    }
+
+    /// Proxies at layer 4: no HTTP is served, and every connection is
+    /// relayed as it is to the backend of its `tls_passthrough` server
+    /// name, else of the first `tcp_passthrough` network holding the
+    /// client, else to the `tcp_fallback`, and closed when there's none.
+    /// Only the PROXY header and a TLS ClientHello are read first; with
+    /// `tls_passthrough` names, clients that don't speak first wait out the
+    /// header timeout before they're relayed.
+    pub fn tcp_proxy(&mut self, enabled: bool) -> &mut Self {
+        Arc::make_mut(&mut self.config).passthrough.tcp_proxy = enabled;
+        self
+    }
+
+    /// Relays `tcp_proxy` connections from `net`/`prefix_len` to `backend`.
+    pub fn tcp_passthrough(&mut self, net: IpAddr, prefix_len: u8, backend: &str) -> &mut Self {
+        Arc::make_mut(&mut self.config)
+            .passthrough
+            .by_source
+            .push((IpNet::new(net, prefix_len), backend.to_owned()));
+        self
+    }
+
+    /// Where `tcp_proxy` relays connections no other rule matches.
+    pub fn tcp_fallback(&mut self, backend: &str) -> &mut Self {
+        Arc::make_mut(&mut self.config).passthrough.fallback = Some(backend.to_owned());
+        self
+    }
+
+    /// Whether `/foo/` matches a route registered as `/foo`, and the
+    /// other way round; by default both forms match and are served as is.
+    pub fn trailing_slash(&mut self, policy: TrailingSlash) -> &mut Self {
+        Arc::make_mut(&mut self.config).routing.trailing_slash = policy;
+        self
+    }
+
+    /// Matches the static segments of route patterns regardless of ASCII
+    /// case, so `/Users/:id` serves `/users/1`. Parameter values keep the
+    /// case they were sent in. Off by default.
+    pub fn case_insensitive_routes(&mut self, enabled: bool) -> &mut Self {
+        Arc::make_mut(&mut self.config).routing.ignore_case = enabled;
+        self
+    }
+
+    /// Lets clients on loopback addresses send `X-Aegis-Explain: 1` to see
+    /// which virtual host and route served a request, which checks ran
+    /// before the handler and which of them answered, with the time spent
+    /// in each as `Server-Timing`. Off by default.
+    pub fn explain_routes(&mut self, enabled: bool) -> &mut Self {
+        Arc::make_mut(&mut self.config).explain_routes = enabled;
+        self
+    }
+
+    /// Adds `X-Response-Time` and a `Server-Timing` breakdown to every
+    /// response: the total, each check before the handler, the handler and
+    /// the part of it spent in `Response::json`.
+    pub fn response_timing(&mut self, enabled: bool) -> &mut Self {
+        Arc::make_mut(&mut self.config).response_timing = enabled;
+        self
+    }
+
+    /// The settings in effect, defaults filled in, e.g. to check which
+    /// limits apply. Installed components such as CORS or rate limiting
+    /// are only reported as present, never with what they hold.
+    pub fn effective_config(&self) -> serde_json::Value {
+        self.config.describe()
+    }
+
+    /// Logs the addresses served and `effective_config` at startup.
+    pub fn log_config(&mut self, enabled: bool) -> &mut Self {
+        Arc::make_mut(&mut self.config).log_config = enabled;
+        self
+    }
+
+    /// Trusts proxies in `net`/`prefix_len` to report the client in
+    /// Forwarded, X-Forwarded-For or X-Real-IP for `Request::client_ip`.
+    pub fn trust_proxy(&mut self, net: IpAddr, prefix_len: u8) -> &mut Self {
+        Arc::make_mut(&mut self.config)
+            .trusted_proxies
+            .push(IpNet::new(net, prefix_len));
+        self
+    }
+
+    /// Answers only requests whose Host is `host`, e.g. `api.example.com`,
+    /// `*.example.com` for its subdomains or `localhost:8080` for one
+    /// port; may be called more than once. Once any host is allowed,
+    /// requests without a single well-formed Host get 400 and those for
+    /// other hosts 421, which keeps DNS rebinding from reaching services
+    /// on internal networks.
+    pub fn allow_host(&mut self, host: &str) -> io::Result<&mut Self> {
+        Arc::make_mut(&mut self.config)
+            .allowed_hosts
+            .get_or_insert_with(Default::default)
+            .add(host)?;
+        Ok(self)
+    }
+
+    /// Refuses clients `filter` doesn't permit before any route is looked
+    /// up; clients are seen through trusted proxies.
+    pub fn ip_filter(&mut self, filter: &IpFilter) -> &mut Self {
+        Arc::make_mut(&mut self.config).ip_filter = Some(filter.clone());
+        self
+    }
+
+    /// Refuses WebSocket handshakes from origins `origins` doesn't allow,
+    /// with 403 before any route is looked up.
+    pub fn websocket_origins(&mut self, origins: &WebSocketOrigins) -> &mut Self {
+        Arc::make_mut(&mut self.config).websocket_origins = Some(origins.clone());
+        self
+    }
+
+    /// Applies a CORS policy to every route.
+    pub fn cors(&mut self, cors: &Cors) -> &mut Self {
+        Arc::make_mut(&mut self.config).cors = Some(cors.clone());
+        self
+    }
+
+    /// Verifies CSRF tokens on unsafe requests to every route, answering
+    /// 403 before the handler runs when they don't match.
+    pub fn csrf(&mut self, csrf: &Csrf) -> &mut Self {
+        Arc::make_mut(&mut self.config).csrf = Some(csrf.clone());
+        self
+    }
+
+    /// Sends `headers` on every response, except on routes with their own
+    /// `RouteOptions::security_headers`.
+    pub fn security_headers(&mut self, headers: &SecurityHeaders) -> &mut Self {
+        Arc::make_mut(&mut self.config).security_headers = Some(headers.clone());
+        self
+    }
+
+    /// Answers 503 on all but exempt routes while `maintenance` is on, and
+    /// on the routes it drains.
+    pub fn maintenance(&mut self, maintenance: &Maintenance) -> &mut Self {
+        Arc::make_mut(&mut self.config).maintenance = Some(maintenance.clone());
+        self
+    }
+
+    /// Evaluates feature flags once per routed request; handlers read them
+    /// from `Request::flags`.
+    pub fn feature_flags<P: FlagProvider + 'static>(&mut self, provider: P) -> &mut Self {
+        Arc::make_mut(&mut self.config).flags = Some(Arc::new(provider));
+        self
+    }
+
+    /// Limits every route that doesn't set its own limiter.
+    pub fn rate_limit(&mut self, limiter: &RateLimiter) -> &mut Self {
+        Arc::make_mut(&mut self.config).rate_limit = Some(limiter.clone());
+        self
+    }
+
+    /// Writes a line to `log` for every request served.
+    pub fn access_log(&mut self, log: &AccessLog) -> &mut Self {
+        Arc::make_mut(&mut self.config).access_log = Some(log.clone());
+        self
+    }
+
+    /// Collects request and connection metrics into `metrics`.
+    pub fn metrics(&mut self, metrics: &Metrics) -> &mut Self {
+        Arc::make_mut(&mut self.config).metrics = Some(metrics.clone());
+        self
+    }
+
+    /// Lets `admin` list and close this server's connections by tag.
+    pub fn connection_admin(&mut self, admin: &ConnectionAdmin) -> &mut Self {
+        Arc::make_mut(&mut self.config).connection_admin = Some(admin.clone());
+        self
+    }
+
+    /// Counts calls to routes marked `RouteOptions::deprecated` per caller
+    /// in `usage`.
+    pub fn deprecation_usage(&mut self, usage: &DeprecationUsage) -> &mut Self {
+        Arc::make_mut(&mut self.config).deprecation_usage = Some(usage.clone());
+        self
+    }
+
+    /// Records 403s, audited routes and reloads in `log`.
+    pub fn audit_log(&mut self, log: AuditLog) -> &mut Self {
+        Arc::make_mut(&mut self.config).audit = Some(log);
+        self
+    }
+
+    /// Localizes `HttpError` responses from `catalog` by Accept-Language.
+    pub fn error_catalog(&mut self, catalog: MessageCatalog) -> &mut Self {
+        Arc::make_mut(&mut self.config).error_catalog = Some(Arc::new(catalog));
+        self
+    }
+
+    // for modules that extend `Server` from their own files
+    pub(crate) fn config(&self) -> &ServerConfig {
+        &self.config
+    }
+
+    pub(crate) fn config_mut(&mut self) -> &mut ServerConfig {
+        Arc::make_mut(&mut self.config)
+    }
+
+    /// Starts serving in the background; the handle shuts the server down.
+    pub fn start(&mut self, addr: &str) -> io::Result<ServerHandle> {
+        self.launch(Some(addr))
+    }
+
+    /// Starts serving on the inherited listeners and `also_listen`
+    /// addresses only, binding nothing itself.
+    pub fn start_inherited(&mut self) -> io::Result<ServerHandle> {
+        if self.config.inherited.is_empty() && self.config.extra_addrs.is_empty() {
+            return Err(io::Error::new(
+                io::ErrorKind::InvalidInput,
+                "no inherited listeners to serve on",
+            ));
         }
+        self.launch(None)
+    }
+
+    pub fn listen_inherited(&mut self) -> io::Result<()> {
+        self.start_inherited()?.wait();
+        Ok(())
+    }
+
+    fn launch(&mut self, addr: Option<&str>) -> io::Result<ServerHandle> {
+        let workers = self.config.workers;
+        may::config().set_workers(workers);
+        if let Some(bytes) = self.config.stack_size {
+            // may counts in words
+            may::config().set_stack_size(bytes / std::mem::size_of::<usize>());
+        }
+        if let Some(capacity) = self.config.coroutine_pool {
+            may::config().set_pool_capacity(capacity);
+        }
+        if let Some(pinning) = &self.config.pinning {
+            pin_workers(workers, pinning)?;
+        }
+        // swaps reach this server's connections only, not those of other starts
+        let running = Server {
+            live: Arc::default(),
+            generation: 0,
+            ..self.clone()
+        };
+        let shared = Arc::new(RwLock::new(HttpServer(running, self.config.clone())));
+        let mut addrs: Vec<&str> = addr.into_iter().collect();
+        addrs.extend(self.config.extra_addrs.iter().map(String::as_str));
+        let mut handle = HttpServer::start(shared.clone(), &addrs)?;
+        if self.config.log_config {
+            let mut serving: Vec<String> = addrs.iter().map(|addr| addr.to_string()).collect();
+            for listener in &self.config.inherited {
+                match listener.local_addr() {
+                    Ok(addr) => serving.push(format!("{} (inherited)", addr)),
+                    Err(_) => serving.push("an inherited listener".to_owned()),
+                }
+            }
+            info!("serving {} on {} workers", serving.join(", "), workers);
+            info!("effective config: {}", self.config.describe());
+        }
+        if let Some(metrics) = &self.config.metrics {
+            metrics.track(handle.lifecycle());
+        }
+        if let Some(admin) = &self.config.connection_admin {
+            admin.track(handle.lifecycle());
+        }
+        if let Some(health) = &self.config.health {
+            handle.set_health(health.clone());
+        }
+        handle.set_reload(Box::new(move |update| {
+            // one swap at a time, each from the one before
+            let mut shared = shared.write().unwrap();
+            let server = shared.0.publish(update(&shared.0));
+            *shared = HttpServer(server.clone(), server.config.clone());
+            server.audit("config_reload", serde_json::json!({}));
+        }));
+        Ok(handle)
     }
 
     pub fn listen(&mut self, addr: &str) -> io::Result<()> {
-        may::config().set_workers(8);
-        let server = HttpServer(self.clone()).start(addr)?;
-        server.wait();
+        self.start(addr)?.wait();
         Ok(())
     }
 
-    pub fn add_route_handler<F>(&mut self, method: &str, path: &str, handler: F)
+    /// Serves requests for `host` from the routes registered on `routes` so
+    /// far; a leading `*.` matches any subdomain. Requests for other hosts
+    /// use this server's own routes.
+    pub fn virtual_host(&mut self, host: &str, routes: &Server) -> &mut Self {
+        let host = host.to_ascii_lowercase();
+        let routes = routes.route_handlers.clone();
+        match self.virtual_hosts.iter_mut().find(|(h, _)| *h == host) {
+            Some(entry) => entry.1 = routes,
+            None => self.virtual_hosts.push((host, routes)),
+        }
+        self
+    }
+
+    // `routes`' routes and virtual hosts with this server's settings
+    pub(crate) fn with_routes(&self, routes: &Server) -> Server {
+        let mut server = self.clone();
+        server.route_handlers = routes.route_handlers.clone();
+        server.virtual_hosts = routes.virtual_hosts.clone();
+        server
+    }
+
+    // make `next` the server every clone of this one, i.e. every open
+    // connection, switches to at its next request
+    fn publish(&self, mut next: Server) -> Server {
+        let mut current = self.live.current.write().unwrap();
+        next.live = self.live.clone();
+        next.generation = self.live.generation.load(Ordering::Relaxed) + 1;
+        *current = Some(next.clone());
+        self.live.generation.store(next.generation, Ordering::Release);
+        next
+    }
+
+    /// The routes registered so far and their documentation, in
+    /// registration order; virtual hosts' routes are not included.
+    pub fn routes(&self) -> Vec<RouteInfo> {
+        self.route_handlers.routes()
+    }
+
+    /// An OpenAPI 3.1 document of the routes registered so far, e.g. to
+    /// serve at `/openapi.json`. Paths are described with their path
+    /// parameters and `RouteOptions::json_schema` bodies; routes for any
+    /// method or with a wildcard are left out.
+    pub fn openapi(&self, title: &str, version: &str) -> serde_json::Value {
+        openapi::document(title, version, &self.route_handlers)
+    }
+
+    /// Answers GET `path`, e.g. `/openapi.json`, with `openapi` for the
+    /// routes of the virtual host asked for, generated on each request so
+    /// routes added later are in it.
+    pub fn serve_openapi(&mut self, path: &str, title: &str, version: &str) -> &mut Self {
+        let served = (path.to_owned(), title.to_owned(), version.to_owned());
+        Arc::make_mut(&mut self.config).openapi = Some(Arc::new(served));
+        self
+    }
+
+    // the virtual host serving `host`, if any, and its routes; exact names
+    // win over wildcards, and longer wildcards over shorter
+    fn routes_for(&self, host: Option<&str>) -> (Option<&str>, &RouteMatcher) {
+        let host = match host {
+            Some(host) if !self.virtual_hosts.is_empty() => host_name(host).to_ascii_lowercase(),
+            _ => return (None, &self.route_handlers),
+        };
+        let mut best: Option<&(String, RouteMatcher)> = None;
+        for entry in &self.virtual_hosts {
+            let name = entry.0.as_str();
+            let matches = match name.strip_prefix("*.") {
+                Some(domain) => host
+                    .strip_suffix(domain)
+                    .map_or(false, |sub| sub.len() > 1 && sub.ends_with('.')),
+                None => host == name,
+            };
+            if !matches {
+                continue;
+            }
+            if !name.starts_with("*.") {
+                return (Some(name), &entry.1);
+            }
+            if best.map_or(true, |b| b.0.len() < name.len()) {
+                best = Some(entry);
+            }
+        }
+        match best {
+            Some(entry) => (Some(&entry.0), &entry.1),
+            None => (None, &self.route_handlers),
+        }
+    }
+
+    // answer 503 if the server is in maintenance
+    fn unavailable(&self, req: &mut RawRequest, res: &mut Response) -> bool {
+        match &self.config.maintenance {
+            Some(maintenance) if maintenance.is_enabled() => {
+                maintenance.refuse(req, res);
+                true
+            }
+            _ => false,
+        }
+    }
+
+    // audit failures are logged; they shouldn't fail the request
+    fn audit(&self, event: &str, detail: serde_json::Value) {
+        if let Some(log) = &self.config.audit {
+            if let Err(e) = log.record(event, detail) {
+                error!("audit log write failed: {}", e);
+            }
+        }
+    }
+
+    pub fn add_route_handler<F>(
+        &mut self,
+        method: &str,
+        path: &str,
+        handler: F,
+    ) -> &mut RouteOptions
     where
         F: Fn(Request, &mut Response) -> io::Result<()> + Send + Sync + 'static,
     {
         self.route_handlers
-            .add_route(method, path, Box::new(handler));
+            .add_route(method, path, Box::new(handler))
     }
 
-    pub fn get<F>(&mut self, path: &str, handler: F)
+    pub fn get<F>(&mut self, path: &str, handler: F) -> &mut RouteOptions
     where
         F: Fn(Request, &mut Response) -> io::Result<()> + Send + Sync + 'static,
     {
-        self.add_route_handler("GET", path, handler);
+        self.add_route_handler("GET", path, handler)
     }
 
-    pub fn post<F>(&mut self, path: &str, handler: F)
+    pub fn post<F>(&mut self, path: &str, handler: F) -> &mut RouteOptions
     where
         F: Fn(Request, &mut Response) -> io::Result<()> + Send + Sync + 'static,
     {
-        self.add_route_handler("POST", path, handler);
+        self.add_route_handler("POST", path, handler)
     }
 
-    pub fn put<F>(&mut self, path: &str, handler: F)
+    pub fn put<F>(&mut self, path: &str, handler: F) -> &mut RouteOptions
     where
         F: Fn(Request, &mut Response) -> io::Result<()> + Send + Sync + 'static,
     {
-        self.add_route_handler("PUT", path, handler);
+        self.add_route_handler("PUT", path, handler)
     }
 
-    pub fn delete<F>(&mut self, path: &str, handler: F)
+    pub fn delete<F>(&mut self, path: &str, handler: F) -> &mut RouteOptions
     where
         F: Fn(Request, &mut Response) -> io::Result<()> + Send + Sync + 'static,
     {
-        self.add_route_handler("DELETE", path, handler);
+        self.add_route_handler("DELETE", path, handler)
     }
 
-    pub fn head<F>(&mut self, path: &str, handler: F)
+    pub fn head<F>(&mut self, path: &str, handler: F) -> &mut RouteOptions
     where
         F: Fn(Request, &mut Response) -> io::Result<()> + Send + Sync + 'static,
     {
-        self.add_route_handler("HEAD", path, handler);
+        self.add_route_handler("HEAD", path, handler)
     }
 
-    pub fn options<F>(&mut self, path: &str, handler: F)
+    pub fn options<F>(&mut self, path: &str, handler: F) -> &mut RouteOptions
     where
         F: Fn(Request, &mut Response) -> io::Result<()> + Send + Sync + 'static,
     {
-        self.add_route_handler("OPTIONS", path, handler);
+        self.add_route_handler("OPTIONS", path, handler)
     }
 
-    pub fn trace<F>(&mut self, path: &str, handler: F)
+    pub fn trace<F>(&mut self, path: &str, handler: F) -> &mut RouteOptions
     where
         F: Fn(Request, &mut Response) -> io::Result<()> + Send + Sync + 'static,
     {
-        self.add_route_handler("TRACE", path, handler);
+        self.add_route_handler("TRACE", path, handler)
     }
 
-    pub fn connect<F>(&mut self, path: &str, handler: F)
+    pub fn connect<F>(&mut self, path: &str, handler: F) -> &mut RouteOptions
     where
         F: Fn(Request, &mut Response) -> io::Result<()> + Send + Sync + 'static,
     {
-        self.add_route_handler("CONNECT", path, handler);
+        self.add_route_handler("CONNECT", path, handler)
     }
 
-    pub fn patch<F>(&mut self, path: &str, handler: F)
+    pub fn patch<F>(&mut self, path: &str, handler: F) -> &mut RouteOptions
     where
         F: Fn(Request, &mut Response) -> io::Result<()> + Send + Sync + 'static,
     {
-        self.add_route_handler("PATCH", path, handler);
+        self.add_route_handler("PATCH", path, handler)
     }
 }
 
 impl HttpService for Server {
     fn handler(&mut self, req: RawRequest, res: &mut Response) -> io::Result<()> {
+        if self.live.generation.load(Ordering::Acquire) != self.generation {
+            let current = self.live.current.read().unwrap().clone();
+            if let Some(server) = current {
+                *self = server;
+            }
+        }
+        let id = request_id(&req);
+        res.header_owned(format!("X-Request-Id: {}", id));
+        #[cfg(feature = "tracing")]
+        let span = info_span!(
+            "request",
+            method = req.method(),
+            path = req.path(),
+            request_id = %id,
+        );
+        #[cfg(feature = "tracing")]
+        let _entered = span.enter();
+        let mut explain = Explain::new(&req, res, &self.config);
+        let result = if self.config.access_log.is_none() && self.config.metrics.is_none() {
+            self.serve(req, res, None, &id, &mut explain)
+        } else {
+            self.observe(req, res, &id, &mut explain)
+        };
+        match res.security_headers.take() {
+            Some(headers) => headers.decorate(res),
+            None => {
+                let headers = self.config.security_headers.as_ref();
+                if let (Some(headers), None) = (headers, self.config.https_redirect) {
+                    headers.decorate(res);
+                }
+            }
+        }
+        explain.finish(res);
+        // the connection loop answers with a bare 500; let it name the request
+        result.map_err(|e| {
+            if is_dropped(&e) {
+                return e;
+            }
+            io::Error::new(e.kind(), format!("{} (request id {})", e, id))
+        })
+    }
+}
+
+impl Server {
+    // serve while feeding the access log and metrics
+    fn observe(
+        &mut self,
+        req: RawRequest,
+        res: &mut Response,
+        id: &str,
+        explain: &mut Explain,
+    ) -> io::Result<()> {
+        let config = self.config.clone();
+        let _in_flight = config.metrics.as_ref().map(Metrics::in_flight);
+        let started = Instant::now();
+        let mut entry = AccessEntry::new(&req, id);
+        entry.client_ip = forwarded::client_ip(
+            req.remote_addr(),
+            req.headers(),
+            &self.config.trusted_proxies,
+        );
+        let result = self.serve(req, res, Some(&mut entry), id, explain);
+        // a failed handler is answered with a 500 by the connection loop
+        entry.status = match &result {
+            Err(e) if !is_dropped(e) => 500,
+            _ => res.status(),
+        };
+        entry.bytes = res.body_size();
+        entry.latency = started.elapsed();
+        if let Some(log) = &config.access_log {
+            log.write(&entry);
+        }
+        if let Some(metrics) = &config.metrics {
+            metrics.record(&entry);
+        }
+        result
+    }
+
+    fn serve(
+        &mut self,
+        mut req: RawRequest,
+        res: &mut Response,
+        entry: Option<&mut AccessEntry>,
+        id: &str,
+        explain: &mut Explain,
+    ) -> io::Result<()> {
+        if let Some(hosts) = &self.config.allowed_hosts {
+            explain.enter("host");
+            if !hosts.check(&mut req, res)? {
+                return Ok(());
+            }
+        }
+        if let Some(port) = self.config.https_redirect {
+            explain.enter("https-redirect");
+            req.reject_body();
+            return https_redirect(&req, port, res);
+        }
+        if let Some(filter) = &self.config.ip_filter {
+            explain.enter("ip-filter");
+            let ip = forwarded::client_ip(
+                req.remote_addr(),
+                req.headers(),
+                &self.config.trusted_proxies,
+            );
+            if !filter.check(ip, &mut req, res)? {
+                return Ok(());
+            }
+        }
+        if let Some(origins) = &self.config.websocket_origins {
+            explain.enter("websocket-origin");
+            if !origins.check(&mut req, res)? {
+                return Ok(());
+            }
+        }
+        #[cfg(feature = "dev")]
+        if let Some(dev) = self.config.dev.clone() {
+            let result = self.serve_cors(req, res, entry, id, explain);
+            dev.decorate(res);
+            return result;
+        }
+        self.serve_cors(req, res, entry, id, explain)
+    }
+
+    fn serve_cors(
+        &mut self,
+        req: RawRequest,
+        res: &mut Response,
+        entry: Option<&mut AccessEntry>,
+        id: &str,
+        explain: &mut Explain,
+    ) -> io::Result<()> {
+        if self.config.cors.is_none() {
+            return self.route(req, res, entry, id, explain);
+        }
+        let config = self.config.clone();
+        let cors = config.cors.as_ref().unwrap();
+        let origin = req.header("origin").map(str::to_owned);
+        // preflights are answered here, whether or not OPTIONS is routed
+        if let (Some(origin), "OPTIONS") = (&origin, req.method()) {
+            if let Some(method) = req.header("access-control-request-method") {
+                explain.enter("cors-preflight");
+                let headers = req.header("access-control-request-headers");
+                let private_network = req
+                    .header("access-control-request-private-network")
+                    .map_or(false, |value| value.trim().eq_ignore_ascii_case("true"));
+                cors.preflight(origin, method, headers, private_network, res);
+                return Ok(());
+            }
+        }
+        let result = self.route(req, res, entry, id, explain);
+        cors.decorate(origin.as_deref(), res);
+        result
+    }
+
+    fn route(
+        &mut self,
+        mut req: RawRequest,
+        res: &mut Response,
+        mut entry: Option<&mut AccessEntry>,
+        id: &str,
+        explain: &mut Explain,
+    ) -> io::Result<()> {
         // Run route handler if exists
         let method = req.method();
         let url = req.path();
 
-        if let Some(matched_route) = self.route_handlers.match_route(method, url) {
-            let parameters = matched_route.parameters;
-            let url_parameters = matched_route.url_parameters;
-            let context_req = Request {
-                parameters,
-                url_parameters,
+        explain.enter("routing");
+        let (vhost, routes) = self.routes_for(req.header("host"));
+        if let Some(served) = &self.config.openapi {
+            let (path, title, version) = &**served;
+            if method == "GET" && url.split('?').next() == Some(path.as_str()) {
+                return res.json(&openapi::document(title, version, routes));
+            }
+        }
+        if let Some(matched_route) = routes.match_route(&req, self.config.routing) {
+            explain.matched(vhost, &matched_route.path);
+            if self.config.routing.trailing_slash == TrailingSlash::Redirect {
+                if let Some(location) = slash_like(url, &matched_route.path) {
+                    return res.permanent_redirect(&location);
+                }
+            }
+            res.security_headers = matched_route.options.security_headers.clone();
+            if let Some(entry) = entry.as_deref_mut() {
+                entry.route = Some(matched_route.path.clone());
+            }
+            // `req` is borrowed mutably and handed over below; copy what the
+            // audit needs first
+            let target = self
+                .config
+                .audit
+                .as_ref()
+                .map(|_| (method.to_owned(), url.to_owned()));
+
+            if !matched_route.options.maintenance_exempt {
+                explain.enter("maintenance");
+                if self.unavailable(&mut req, res) {
+                    return Ok(());
+                }
+            }
+            let _visit = match &self.config.maintenance {
+                Some(maintenance) => match maintenance.enter(&matched_route.path) {
+                    Some(visit) => Some(visit),
+                    None => {
+                        maintenance.refuse(&mut req, res);
+                        return Ok(());
+                    }
+                },
+                None => None,
+            };
+
+            let limit = matched_route
+                .options
+                .max_body_size
+                .unwrap_or(self.config.max_body_size);
+            explain.enter("body-limit");
+            if req.content_length().map_or(false, |len| len > limit) {
+                req.reject_body();
+                res.status_code(413, "Payload Too Large");
+                return Ok(());
+            }
+            req.set_max_body_size(limit);
+
+            let client_ip = forwarded::client_ip(
+                req.remote_addr(),
+                req.headers(),
+                &self.config.trusted_proxies,
+            );
+            if let Some(filter) = &matched_route.options.ip_filter {
+                explain.enter("ip-filter");
+                if !filter.check(client_ip, &mut req, res)? {
+                    return Ok(());
+                }
+            }
+            let limiter = matched_route.options.login_limiter.as_ref();
+            if let (Some(limiter), Some(ip)) = (limiter, client_ip) {
+                explain.enter("login-limit");
+                if let Some(wait) = limiter.ip_blocked(ip) {
+                    req.reject_body();
+                    res.status_code(429, "Too Many Requests");
+                    res.header_owned(format!("Retry-After: {}", retry_after(wait)));
+                    return Ok(());
+                }
+            }
+            let mut context_req = Request {
+                parameters: matched_route.parameters,
+                client_ip,
+                flags: Default::default(),
+                request_id: id.to_owned(),
+                extensions: Default::default(),
                 req,
             };
-            (matched_route.handler)(context_req, res)
+            let logger = RequestLogger::new(id, &matched_route.method, &matched_route.path);
+            context_req.insert_extension(logger);
+            if let Some(catalog) = &self.config.error_catalog {
+                res.locale = Some(Locale {
+                    catalog: catalog.clone(),
+                    accept_language: context_req.header("accept-language").map(str::to_owned),
+                });
+            }
+            #[cfg(feature = "templates")]
+            {
+                res.templates = self.config.templates.clone();
+            }
+            let rate_limit = matched_route.options.rate_limit.as_ref();
+            if let Some(limiter) = rate_limit.or(self.config.rate_limit.as_ref()) {
+                explain.enter("rate-limit");
+                if !limiter.check(&mut context_req, res)? {
+                    return Ok(());
+                }
+            }
+            if let Some(csrf) = &self.config.csrf {
+                explain.enter("csrf");
+                if !csrf.check(&mut context_req, res)? {
+                    return Ok(());
+                }
+            }
+            if let Some(auth) = &matched_route.options.auth {
+                explain.enter("auth");
+                if !auth.check(&mut context_req, res) {
+                    return Ok(());
+                }
+            }
+            #[cfg(feature = "jwt")]
+            if let Some(jwt) = &matched_route.options.jwt {
+                explain.enter("jwt");
+                if !jwt.check(&mut context_req, res)? {
+                    return Ok(());
+                }
+            }
+            if let Some(keys) = &matched_route.options.api_key {
+                explain.enter("api-key");
+                if !keys.check(&mut context_req, res)? {
+                    return Ok(());
+                }
+                if let (Some(entry), Some(key)) =
+                    (entry.as_deref_mut(), context_req.extension::<ApiKey>())
+                {
+                    entry.api_key = Some(key.prefix.clone());
+                }
+            }
+            if let Some(signing) = &matched_route.options.signing {
+                explain.enter("signature");
+                if !signing.check(&mut context_req, res)? {
+                    return Ok(());
+                }
+            }
+            if let Some(schema) = &matched_route.options.json_schema {
+                explain.enter("schema");
+                let body = context_req.req.prefetch_body()?;
+                if let Err(errors) = validate_body(schema, body) {
+                    return errors.into_response(res);
+                }
+            }
+            if let Some(provider) = &self.config.flags {
+                explain.enter("flags");
+                context_req.flags = provider.evaluate(&context_req);
+                if let Some(entry) = entry {
+                    entry.flags = context_req.flags.clone();
+                }
+            }
+            if let Some(usage) = &self.config.deprecation_usage {
+                if matched_route.options.deprecated.is_some() {
+                    usage.record(&matched_route.method, &matched_route.path, &context_req);
+                }
+            }
+            explain.enter("handler");
+            let result = match &matched_route.options.cache {
+                Some(cache) => cache.serve(context_req, res, &matched_route.handler),
+                None => (matched_route.handler)(context_req, res),
+            };
+            let result = http_error::answer(result, res, id);
+            deprecation::notices(&matched_route.options, res);
+            if let (Some(limiter), Some(ip)) = (limiter, client_ip) {
+                match res.status() {
+                    401 => limiter.ip_failed(ip),
+                    200..=299 => limiter.ip_succeeded(ip),
+                    _ => {}
+                }
+            }
+            if let Some((method, path)) = target {
+                let detail = serde_json::json!({
+                    "method": method,
+                    "path": path,
+                    "status": res.status(),
+                });
+                if res.status() == 403 {
+                    self.audit("forbidden", detail);
+                } else if matched_route.options.audited {
+                    self.audit("route_access", detail);
+                }
+            }
+            result
+        } else if self.unavailable(&mut req, res) {
+            Ok(())
         } else {
             // No route handler found, return 404
             res.status_code(404, "Not Found");
@@ -122,3 +1243,102 @@ impl HttpService for Server {
         }
     }
 }
+
+// `url` with its path ending in a slash if and only if `pattern`'s does,
+// when it doesn't already; wildcard routes take either
+fn slash_like(url: &str, pattern: &str) -> Option<String> {
+    let (path, query) = url.split_at(url.find('?').unwrap_or(url.len()));
+    let slash = pattern.ends_with('/');
+    if path.ends_with('/') == slash || pattern.ends_with('*') {
+        return None;
+    }
+    let trimmed = path.trim_end_matches('/');
+    // `//host` would send the client off to another site
+    if trimmed.is_empty() || trimmed.starts_with("//") {
+        return None;
+    }
+    let slash = if slash { "/" } else { "" };
+    Some(format!("{}{}{}", trimmed, slash, query))
+}
+
+// the Host header without its port or a trailing dot
+fn host_name(host: &str) -> &str {
+    let host = host.trim();
+    let host = match host.strip_prefix('[') {
+        // IPv6 literal
+        Some(rest) => return rest.split(']').next().unwrap_or(rest),
+        None => host.split(':').next().unwrap_or(host),
+    };
+    host.strip_suffix('.').unwrap_or(host)
+}
+
+// the same URL over HTTPS; a Host that isn't a plain name or address
+// can't be redirected
+fn https_redirect(req: &RawRequest, port: u16, res: &mut Response) -> io::Result<()> {
+    let host = req.header("host").map(host_name).filter(|host| {
+        !host.is_empty()
+            && host
+                .bytes()
+                .all(|b| b.is_ascii_alphanumeric() || b"-.:".contains(&b))
+    });
+    let host = match host {
+        Some(host) if host.contains(':') => format!("[{}]", host),
+        Some(host) => host.to_owned(),
+        None => {
+            res.status_code(400, "Bad Request");
+            return Ok(());
+        }
+    };
+    let port = match port {
+        443 => String::new(),
+        port => format!(":{}", port),
+    };
+    let path = match req.path() {
+        path if path.starts_with('/') => path,
+        _ => "/",
+    };
+    let status = match req.method() {
+        "GET" | "HEAD" => StatusCode::MOVED_PERMANENTLY,
+        _ => StatusCode::PERMANENT_REDIRECT,
+    };
+    res.redirect(&format!("https://{}{}{}", host, port, path), status)
+}
+
+// the body's schema violations, or why it isn't JSON at all
+fn validate_body(schema: &JsonSchema, body: &[u8]) -> Result<(), ValidationErrors> {
+    match serde_json::from_slice(body) {
+        Ok(value) => schema.validate(&value).map_err(ValidationErrors::from),
+        Err(e) => {
+            let mut errors = ValidationErrors::new();
+            errors.add("", &e.to_string());
+            Err(errors)
+        }
+    }
+}
+
+// the client's or proxy's X-Request-Id if it's sane, else a new one unique
+// across processes: a random per-process prefix and a sequence number
+fn request_id(req: &RawRequest) -> String {
+    static PREFIX: Lazy<u64> = Lazy::new(|| {
+        let mut hasher = RandomState::new().build_hasher();
+        hasher.write_u32(std::process::id());
+        hasher.finish()
+    });
+    static NEXT: AtomicU64 = AtomicU64::new(0);
+    let incoming = req
+        .header("x-request-id")
+        .filter(|id| (1..=128).contains(&id.len()) && id.bytes().all(|b| b.is_ascii_graphic()));
+    match incoming {
+        Some(id) => id.to_owned(),
+        None => format!(
+            "{:016x}-{:x}",
+            *PREFIX,
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ),
+    }
+}
+
+// whole seconds, rounded up so the client doesn't come back too early
+fn retry_after(wait: Duration) -> u64 {
+    wait.as_secs() + u64::from(wait.subsec_nanos() > 0)
+}
@@ -0,0 +1,123 @@
+use std::fmt::Write as _;
+use std::io;
+
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+use sha2::{Digest, Sha256};
+
+use crate::request::request::Request;
+use crate::response::response::Response;
+
+/// A JSON resource paired with an ETag hashed from its representation,
+/// for optimistic locking in CRUD handlers.
+///
+/// `respond` sends it with its ETag and answers a matching If-None-Match
+/// with 304. `update` takes a replacement from the request body only when
+/// If-Match names the current ETag, answering 428 or 412 otherwise, so
+/// concurrent writers can't overwrite each other's changes unseen.
+#[derive(Clone, Debug)]
+pub struct Versioned<T> {
+    value: T,
+    etag: String,
+}
+
+impl<T: Serialize> Versioned<T> {
+    pub fn new(value: T) -> io::Result<Self> {
+        let etag = strong_etag(&serde_json::to_vec(&value)?);
+        Ok(Versioned { value, etag })
+    }
+
+    /// The quoted strong ETag.
+    pub fn etag(&self) -> &str {
+        &self.etag
+    }
+
+    pub fn value(&self) -> &T {
+        &self.value
+    }
+
+    pub fn into_inner(self) -> T {
+        self.value
+    }
+
+    /// Writes the value as JSON with its ETag, or a bare 304 when the
+    /// client's If-None-Match already names it.
+    pub fn respond(&self, req: &Request, res: &mut Response) -> io::Result<()> {
+        res.header_owned(format!("ETag: {}", self.etag));
+        let cached = req
+            .header("if-none-match")
+            .map_or(false, |tags| matches(tags, &self.etag, false));
+        if cached && matches!(req.method(), "GET" | "HEAD") {
+            res.status_code(304, "Not Modified");
+            return Ok(());
+        }
+        res.json(&self.value)
+    }
+
+    /// The replacement in the request body, if If-Match names this
+    /// version. Otherwise answers 428 (no If-Match), 412 (stale) or 400
+    /// (bad JSON) and returns `None`.
+    pub fn update<U: DeserializeOwned>(
+        &self,
+        mut req: Request,
+        res: &mut Response,
+    ) -> io::Result<Option<U>> {
+        let current = req
+            .header("if-match")
+            .map(|tags| matches(tags, &self.etag, true));
+        match current {
+            Some(true) => {}
+            Some(false) => {
+                req.req.reject_body();
+                res.status_code(412, "Precondition Failed");
+                res.header_owned(format!("ETag: {}", self.etag));
+                return Ok(None);
+            }
+            None => {
+                req.req.reject_body();
+                res.status_code(428, "Precondition Required");
+                return Ok(None);
+            }
+        }
+        let value = match req.json_body() {
+            Ok(value) => value,
+            Err(_) => {
+                res.status_code(400, "Bad Request");
+                return Ok(None);
+            }
+        };
+        match serde_json::from_value(value) {
+            Ok(update) => Ok(Some(update)),
+            Err(_) => {
+                res.status_code(400, "Bad Request");
+                Ok(None)
+            }
+        }
+    }
+}
+
+// a quoted strong ETag from the first 128 bits of the content's SHA-256
+pub(crate) fn strong_etag(content: &[u8]) -> String {
+    let hash = Sha256::digest(content);
+    let mut etag = String::with_capacity(34);
+    etag.push('"');
+    for byte in &hash[..16] {
+        let _ = write!(etag, "{:02x}", byte);
+    }
+    etag.push('"');
+    etag
+}
+
+// whether an If-Match / If-None-Match list names `etag`; If-Match compares
+// strongly, so weak tags never match it
+pub(crate) fn matches(tags: &str, etag: &str, strong: bool) -> bool {
+    tags.split(',').map(str::trim).any(|tag| {
+        if tag == "*" {
+            return true;
+        }
+        match tag.strip_prefix("W/") {
+            Some(weak) => !strong && weak == etag,
+            None => tag == etag,
+        }
+    })
+}
@@ -0,0 +1,150 @@
+//! WebSocket handshakes: origin checks and subprotocol negotiation
+
+use std::io;
+use std::sync::Arc;
+
+use crate::errors::http_error::HttpError;
+use crate::request::request::{RawRequest, Request};
+use crate::response::respond::IntoResponse;
+use crate::response::response::Response;
+use crate::server::cors::origin_matches;
+
+type OriginFn = Arc<dyn Fn(&str) -> bool + Send + Sync>;
+type ChooseFn = Arc<dyn Fn(&[&str]) -> Option<String> + Send + Sync>;
+
+/// The origins WebSocket handshakes are accepted from, installed with
+/// `Server::websocket_origins`.
+///
+/// Browsers don't apply CORS to WebSockets, so any page can open one to
+/// this server with the user's cookies attached. Handshakes, requests
+/// with `Upgrade: websocket`, get 403 before routing unless their Origin
+/// is the server's own, i.e. names the Host they were sent to, or is
+/// allowed here. Handshakes without an Origin come from clients other
+/// than browsers and are refused unless `allow_missing_origin`.
+#[derive(Clone, Default)]
+pub struct WebSocketOrigins {
+    origins: Vec<String>,
+    origin_fn: Option<OriginFn>,
+    allow_missing: bool,
+}
+
+impl WebSocketOrigins {
+    /// Accepts same-origin handshakes only, until origins are allowed.
+    pub fn new() -> Self {
+        WebSocketOrigins::default()
+    }
+
+    /// Allows an origin: exact (`https://app.example.com`) or with one
+    /// `*` wildcard (`https://*.example.com`).
+    pub fn allow_origin(&mut self, origin: &str) -> &mut Self {
+        self.origins.push(origin.to_owned());
+        self
+    }
+
+    /// Allows origins `f` accepts, e.g. ones looked up per tenant.
+    pub fn allow_origin_fn<F>(&mut self, f: F) -> &mut Self
+    where
+        F: Fn(&str) -> bool + Send + Sync + 'static,
+    {
+        self.origin_fn = Some(Arc::new(f));
+        self
+    }
+
+    /// Accepts handshakes without an Origin, as native clients send them.
+    pub fn allow_missing_origin(&mut self, allow: bool) -> &mut Self {
+        self.allow_missing = allow;
+        self
+    }
+
+    // lets anything but a handshake from a refused origin on, and answers
+    // that with 403
+    pub(crate) fn check(&self, req: &mut RawRequest, res: &mut Response) -> io::Result<bool> {
+        let handshake = req.header("upgrade").map_or(false, |upgrade| {
+            upgrade
+                .split(',')
+                .any(|protocol| protocol.trim().eq_ignore_ascii_case("websocket"))
+        });
+        if !handshake {
+            return Ok(true);
+        }
+        let allowed = match req.header("origin") {
+            Some(origin) => self.allows(origin.trim(), req.header("host")),
+            None => self.allow_missing,
+        };
+        if allowed {
+            return Ok(true);
+        }
+        req.reject_body();
+        HttpError::Forbidden("WebSocket origin not allowed".to_owned()).into_response(res)?;
+        Ok(false)
+    }
+
+    fn allows(&self, origin: &str, host: Option<&str>) -> bool {
+        // `scheme://host[:port]`, the same authority as the Host header
+        let same_origin = match (origin.split_once("://"), host) {
+            (Some((_, authority)), Some(host)) => authority.eq_ignore_ascii_case(host.trim()),
+            _ => false,
+        };
+        same_origin
+            || self
+                .origins
+                .iter()
+                .any(|pattern| origin_matches(pattern, origin))
+            || self.origin_fn.as_ref().map_or(false, |f| f(origin))
+    }
+}
+
+/// The subprotocols a WebSocket endpoint speaks, e.g. `graphql-ws` or
+/// `mqtt`, for negotiating `Sec-WebSocket-Protocol` in its handshake.
+#[derive(Clone)]
+pub struct Subprotocols {
+    choose: ChooseFn,
+}
+
+impl Subprotocols {
+    /// Picks the first of the client's offers, in its order of
+    /// preference, that is in `supported`.
+    pub fn new(supported: &[&str]) -> Self {
+        let supported: Vec<String> = supported.iter().map(|p| p.to_string()).collect();
+        Subprotocols::from_fn(move |offered| {
+            offered
+                .iter()
+                .find(|offer| supported.iter().any(|p| p == *offer))
+                .map(|offer| offer.to_string())
+        })
+    }
+
+    /// Lets `f` pick among the client's offers, e.g. by tenant; it should
+    /// return one of them or `None`.
+    pub fn from_fn<F>(f: F) -> Self
+    where
+        F: Fn(&[&str]) -> Option<String> + Send + Sync + 'static,
+    {
+        Subprotocols {
+            choose: Arc::new(f),
+        }
+    }
+
+    /// The subprotocol to answer `req`'s handshake with, echoed in `res`'s
+    /// `Sec-WebSocket-Protocol`. `None` when the client offered none or
+    /// none is spoken here; the handshake then goes on without one and
+    /// the client decides whether to proceed.
+    pub fn negotiate(&self, req: &Request, res: &mut Response) -> Option<String> {
+        let offered: Vec<&str> = req
+            .headers()
+            .iter()
+            .filter(|header| header.name.eq_ignore_ascii_case("sec-websocket-protocol"))
+            .filter_map(|header| std::str::from_utf8(header.value).ok())
+            .flat_map(|value| value.split(','))
+            .map(str::trim)
+            .filter(|offer| !offer.is_empty())
+            .collect();
+        if offered.is_empty() {
+            return None;
+        }
+        // answering with one the client didn't offer fails the handshake
+        let chosen = (self.choose)(&offered).filter(|chosen| offered.contains(&chosen.as_str()))?;
+        res.header_owned(format!("Sec-WebSocket-Protocol: {}", chosen));
+        Some(chosen)
+    }
+}
@@ -0,0 +1,144 @@
+use std::collections::HashMap;
+use std::sync::{Arc, RwLock};
+
+use serde_json::Value;
+
+use crate::request::request::percent_decode;
+use crate::server::server::Server;
+
+type Webfinger = Arc<dyn Fn(&str) -> Option<Value> + Send + Sync>;
+
+/// Documents served under `/.well-known/` (RFC 8615), mounted with
+/// `Server::well_known`. The routes stay up in maintenance mode, so
+/// certificate renewals don't stall behind it.
+#[derive(Clone, Default)]
+pub struct WellKnown {
+    documents: Vec<(String, String, Arc<[u8]>)>,
+    acme: Option<AcmeChallenges>,
+    webfinger: Option<Webfinger>,
+}
+
+/// Pending ACME http-01 challenges, answered at
+/// `/.well-known/acme-challenge/<token>`. Clones share their tokens, so
+/// the ACME client can add and remove them while the server runs.
+#[derive(Clone, Default)]
+pub struct AcmeChallenges {
+    tokens: Arc<RwLock<HashMap<String, String>>>,
+}
+
+impl AcmeChallenges {
+    pub fn new() -> Self {
+        AcmeChallenges::default()
+    }
+
+    /// Answers `token` with its key authorization.
+    pub fn set(&self, token: &str, key_authorization: &str) {
+        let mut tokens = self.tokens.write().unwrap();
+        tokens.insert(token.to_owned(), key_authorization.to_owned());
+    }
+
+    pub fn remove(&self, token: &str) {
+        self.tokens.write().unwrap().remove(token);
+    }
+
+    fn get(&self, token: &str) -> Option<String> {
+        self.tokens.read().unwrap().get(token).cloned()
+    }
+}
+
+impl WellKnown {
+    pub fn new() -> Self {
+        WellKnown::default()
+    }
+
+    /// Serves `body` at `/.well-known/<name>`.
+    pub fn document<B: Into<Vec<u8>>>(
+        &mut self,
+        name: &str,
+        content_type: &str,
+        body: B,
+    ) -> &mut Self {
+        let name = name.trim_matches('/').to_owned();
+        let body: Vec<u8> = body.into();
+        self.documents
+            .push((name, content_type.to_owned(), body.into()));
+        self
+    }
+
+    /// Serves an RFC 9116 `security.txt`.
+    pub fn security_txt(&mut self, contents: &str) -> &mut Self {
+        self.document("security.txt", "text/plain; charset=utf-8", contents)
+    }
+
+    pub fn acme_challenges(&mut self, challenges: &AcmeChallenges) -> &mut Self {
+        self.acme = Some(challenges.clone());
+        self
+    }
+
+    /// Answers RFC 7033 WebFinger queries with the JRD `f` returns for the
+    /// `resource` parameter, or 404 when it returns `None`.
+    pub fn webfinger<F>(&mut self, f: F) -> &mut Self
+    where
+        F: Fn(&str) -> Option<Value> + Send + Sync + 'static,
+    {
+        self.webfinger = Some(Arc::new(f));
+        self
+    }
+}
+
+impl Server {
+    /// Mounts the documents in `registry`.
+    pub fn well_known(&mut self, registry: &WellKnown) -> &mut Self {
+        for (name, content_type, body) in &registry.documents {
+            let header = format!("Content-Type: {}", content_type);
+            let body = body.clone();
+            self.get(&format!("/.well-known/{}", name), move |_req, res| {
+                res.header_owned(header.clone());
+                res.body_bytes(&body);
+                Ok(())
+            })
+            .maintenance_exempt();
+        }
+        if let Some(acme) = registry.acme.clone() {
+            let path = "/.well-known/acme-challenge/:token";
+            self.get(path, move |req, res| {
+                match req.parameter("token").and_then(|token| acme.get(token)) {
+                    Some(key_authorization) => {
+                        res.header("Content-Type: application/octet-stream");
+                        res.body_vec(key_authorization.into_bytes());
+                    }
+                    None => {
+                        res.status_code(404, "Not Found");
+                    }
+                }
+                Ok(())
+            })
+            .maintenance_exempt();
+        }
+        if let Some(webfinger) = registry.webfinger.clone() {
+            self.get("/.well-known/webfinger", move |req, res| {
+                // RFC 7033 4.5: queries are meant to work from any origin
+                res.header("Access-Control-Allow-Origin: *");
+                let resource = match req.url_parameter("resource") {
+                    Some(resource) => percent_decode(resource),
+                    None => {
+                        res.status_code(400, "Bad Request");
+                        return Ok(());
+                    }
+                };
+                match webfinger(&resource) {
+                    Some(jrd) => {
+                        res.header("Content-Type: application/jrd+json");
+                        res.body_vec(serde_json::to_vec(&jrd)?);
+                    }
+                    None => {
+                        res.status_code(404, "Not Found");
+                    }
+                }
+                Ok(())
+            })
+            .maintenance_exempt();
+        }
+        self
+    }
+}
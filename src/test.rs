@@ -0,0 +1,403 @@
+//! in-process requests against a `Server`, for testing handlers without
+//! binding a port
+
+use std::mem::MaybeUninit;
+use std::net::SocketAddr;
+
+use bytes::{Bytes, BytesMut};
+use serde::de::DeserializeOwned;
+use serde::Serialize;
+
+use crate::http::h2::Detached;
+use crate::http::http_server::dispatch;
+use crate::request::request::{decode, BodyState, DecodeError, Endpoints};
+use crate::response::response::{self, Response};
+use crate::server::server::Server;
+
+const DEFAULT_REMOTE: ([u8; 4], u16) = ([127, 0, 0, 1], 40000);
+const DEFAULT_LOCAL: ([u8; 4], u16) = ([127, 0, 0, 1], 80);
+
+/// Runs requests through a server's decoding, routing and middleware in
+/// memory, returning what the client would have received.
+///
+/// Requests come from 127.0.0.1 unless `TestRequest::remote_addr` says
+/// otherwise. Streamed bodies, from `Response::stream` or `Response::sse`,
+/// need a socket to be written to, so only their head comes back.
+pub struct TestClient {
+    server: Server,
+}
+
+impl TestClient {
+    pub fn new(server: &Server) -> Self {
+        TestClient {
+            server: server.clone(),
+        }
+    }
+
+    pub fn request(&self, method: &str, path: &str) -> TestRequest {
+        TestRequest {
+            client: self,
+            method: method.to_owned(),
+            path: path.to_owned(),
+            headers: Vec::new(),
+            body: Vec::new(),
+            remote: SocketAddr::from(DEFAULT_REMOTE),
+        }
+    }
+
+    pub fn get(&self, path: &str) -> TestRequest {
+        self.request("GET", path)
+    }
+
+    pub fn post(&self, path: &str) -> TestRequest {
+        self.request("POST", path)
+    }
+
+    pub fn put(&self, path: &str) -> TestRequest {
+        self.request("PUT", path)
+    }
+
+    pub fn patch(&self, path: &str) -> TestRequest {
+        self.request("PATCH", path)
+    }
+
+    pub fn delete(&self, path: &str) -> TestRequest {
+        self.request("DELETE", path)
+    }
+}
+
+/// A request being built by `TestClient`.
+pub struct TestRequest<'c> {
+    client: &'c TestClient,
+    method: String,
+    path: String,
+    headers: Vec<(String, String)>,
+    body: Vec<u8>,
+    remote: SocketAddr,
+}
+
+impl<'c> TestRequest<'c> {
+    /// Adds a header, sent exactly as given. A Content-Length matching the
+    /// body is added unless one, or Transfer-Encoding, is set here.
+    pub fn header(mut self, name: &str, value: &str) -> Self {
+        self.headers.push((name.to_owned(), value.to_owned()));
+        self
+    }
+
+    pub fn body<B: Into<Vec<u8>>>(mut self, body: B) -> Self {
+        self.body = body.into();
+        self
+    }
+
+    /// Sends `value` as a JSON body.
+    pub fn json<T: Serialize + ?Sized>(self, value: &T) -> Self {
+        let body = serde_json::to_vec(value).expect("test body serializes to JSON");
+        self.header("Content-Type", "application/json").body(body)
+    }
+
+    /// The address the request appears to come from.
+    pub fn remote_addr(mut self, addr: SocketAddr) -> Self {
+        self.remote = addr;
+        self
+    }
+
+    pub fn send(self) -> TestResponse {
+        let mut server = self.client.server.clone();
+        let config = server.config().clone();
+        let mut req_buf = BytesMut::from(&self.encode()[..]);
+        let mut body_buf = BytesMut::new();
+        let mut headers = vec![MaybeUninit::uninit(); config.max_headers];
+        let mut state = BodyState::default();
+        let mut detached = Detached;
+        let endpoints = Endpoints {
+            remote: Some(self.remote),
+            local: Some(SocketAddr::from(DEFAULT_LOCAL)),
+            ..Endpoints::default()
+        };
+        let mut rsp = Response::new(&mut body_buf);
+        rsp.server = config.server_header.clone();
+        let req = decode(
+            &mut headers,
+            &mut req_buf,
+            &mut detached,
+            &mut state,
+            &config,
+            endpoints,
+        );
+        let mut result = match req {
+            Ok(Some(req)) => dispatch(&mut server, req, &mut rsp),
+            Err(DecodeError::Reject(code, msg)) => {
+                rsp.status_code(code, msg);
+                Ok(())
+            }
+            _ => {
+                rsp.status_code(400, "Bad Request");
+                Ok(())
+            }
+        };
+        if let Some((code, msg)) = state.error.take() {
+            rsp.clear();
+            rsp.status_code(code, msg);
+            result = Ok(());
+        }
+
+        // the bytes a connection would send from the file
+        if result.is_ok() {
+            result = rsp.load_file();
+        }
+        let mut wire = BytesMut::new();
+        let mut streamed = false;
+        match result {
+            Ok(()) => match rsp.take_stream() {
+                Some(_) => {
+                    response::encode_stream_head(rsp, true, &mut wire);
+                    streamed = true;
+                }
+                None => match rsp.take_segments() {
+                    Some(segments) => {
+                        response::encode_segments_head(rsp, &segments, &mut wire);
+                        segments.iter().for_each(|s| wire.extend_from_slice(s));
+                    }
+                    None => response::encode(rsp, &mut wire),
+                },
+            },
+            Err(e) => response::encode_error(e, config.server_header.as_deref(), &mut wire),
+        }
+        TestResponse::parse(&wire, streamed)
+    }
+
+    fn encode(&self) -> Vec<u8> {
+        let mut head = format!("{} {} HTTP/1.1\r\n", self.method, self.path);
+        let has = |name: &str| {
+            self.headers
+                .iter()
+                .any(|(key, _)| key.eq_ignore_ascii_case(name))
+        };
+        if !has("host") {
+            head.push_str("Host: localhost\r\n");
+        }
+        for (name, value) in &self.headers {
+            head.push_str(&format!("{}: {}\r\n", name, value));
+        }
+        if !has("content-length") && !has("transfer-encoding") {
+            head.push_str(&format!("Content-Length: {}\r\n", self.body.len()));
+        }
+        head.push_str("\r\n");
+        let mut request = head.into_bytes();
+        request.extend_from_slice(&self.body);
+        request
+    }
+}
+
+/// A response as the client would have received it.
+#[derive(Debug)]
+pub struct TestResponse {
+    status: u16,
+    reason: String,
+    headers: Vec<(String, String)>,
+    body: Bytes,
+    streamed: bool,
+}
+
+impl TestResponse {
+    fn parse(wire: &[u8], streamed: bool) -> Self {
+        // a slot per line of the head, however many headers it holds
+        let end = wire.windows(4).position(|w| w == b"\r\n\r\n");
+        let lines = wire[..end.unwrap_or(wire.len())]
+            .split(|&b| b == b'\n')
+            .count();
+        let mut headers = vec![httparse::EMPTY_HEADER; lines];
+        let mut res = httparse::Response::new(&mut headers);
+        let len = match res.parse(wire) {
+            Ok(httparse::Status::Complete(len)) => len,
+            _ => panic!("server wrote an unparsable response head"),
+        };
+        TestResponse {
+            status: res.code.unwrap_or(0),
+            reason: res.reason.unwrap_or("").to_owned(),
+            headers: res
+                .headers
+                .iter()
+                .map(|header| {
+                    let value = String::from_utf8_lossy(header.value);
+                    (header.name.to_owned(), value.into_owned())
+                })
+                .collect(),
+            body: Bytes::copy_from_slice(&wire[len..]),
+            streamed,
+        }
+    }
+
+    pub fn status(&self) -> u16 {
+        self.status
+    }
+
+    pub fn reason(&self) -> &str {
+        &self.reason
+    }
+
+    /// The first `name` header, compared case-insensitively.
+    pub fn header(&self, name: &str) -> Option<&str> {
+        self.headers
+            .iter()
+            .find(|(key, _)| key.eq_ignore_ascii_case(name))
+            .map(|(_, value)| value.as_str())
+    }
+
+    pub fn headers(&self) -> &[(String, String)] {
+        &self.headers
+    }
+
+    pub fn body(&self) -> &[u8] {
+        &self.body
+    }
+
+    /// The body as text; panics if it isn't UTF-8.
+    pub fn text(&self) -> &str {
+        std::str::from_utf8(&self.body).expect("response body is UTF-8")
+    }
+
+    /// The body parsed as JSON; panics if it isn't a `T`.
+    pub fn json<T: DeserializeOwned>(&self) -> T {
+        serde_json::from_slice(&self.body).expect("response body is JSON")
+    }
+
+    /// Whether the handler streamed the body, which isn't captured.
+    pub fn is_streamed(&self) -> bool {
+        self.streamed
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::{self, Write};
+    use std::time::{Duration, SystemTime, UNIX_EPOCH};
+
+    use serde_json::{json, Value};
+
+    use super::TestClient;
+    use crate::server::cors::Cors;
+    use crate::server::rate_limit::RateLimiter;
+    use crate::server::security_headers::SecurityHeaders;
+    use crate::server::server::Server;
+
+    fn server() -> Server {
+        let mut server = Server::new();
+        server.get("/users/:id", |req, res| {
+            res.header("X-Handler: users");
+            res.send(format!("user {}", req.parameter("id").unwrap_or("")))
+        });
+        server.post("/echo", |req, res| {
+            let body = req.json_body().map_err(io::Error::from)?;
+            res.json(&body)
+        });
+        server.post("/text", |req, res| {
+            let body = req.text().map_err(io::Error::from)?;
+            res.send(body)
+        });
+        server.get("/stream", |_, res| res.stream(|w| w.write_all(b"streamed")));
+        server
+    }
+
+    #[test]
+    fn get_with_path_parameter() {
+        let client = TestClient::new(&server());
+        let res = client.get("/users/42").send();
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.text(), "user 42");
+        assert_eq!(res.header("x-handler"), Some("users"));
+        assert_eq!(res.header("Content-Length"), Some("7"));
+        assert!(!res.is_streamed());
+    }
+
+    #[test]
+    fn post_json_round_trips() {
+        let client = TestClient::new(&server());
+        let sent = json!({ "name": "ada", "tags": [1, 2] });
+        let res = client.post("/echo").json(&sent).send();
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.header("content-type"), Some("application/json"));
+        assert_eq!(res.json::<Value>(), sent);
+    }
+
+    #[test]
+    fn chunked_request_body_is_decoded() {
+        let client = TestClient::new(&server());
+        let res = client
+            .post("/text")
+            .header("Transfer-Encoding", "chunked")
+            .body("4\r\nWiki\r\n5\r\npedia\r\n0\r\n\r\n")
+            .send();
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.text(), "Wikipedia");
+    }
+
+    #[test]
+    fn unrouted_path_is_404() {
+        let client = TestClient::new(&server());
+        let res = client.get("/nowhere").send();
+        assert_eq!(res.status(), 404);
+        assert!(res.body().is_empty());
+    }
+
+    #[test]
+    fn streamed_response_returns_its_head_only() {
+        let client = TestClient::new(&server());
+        let res = client.get("/stream").send();
+        assert_eq!(res.status(), 200);
+        assert!(res.is_streamed());
+        assert_eq!(res.header("transfer-encoding"), Some("chunked"));
+        assert!(res.body().is_empty());
+    }
+
+    #[test]
+    fn stacked_middleware_headers_all_reach_the_client() {
+        let mut server = Server::new();
+        let mut cors = Cors::new();
+        cors.allow_origin("https://app.example")
+            .allow_credentials(true);
+        let day = Duration::from_secs(24 * 60 * 60);
+        server
+            .security_headers(&SecurityHeaders::strict())
+            .cors(&cors)
+            .response_timing(true);
+        server
+            .get("/stacked", |_, res| {
+                res.header("X-Handler: stacked")
+                    .header("Cache-Control: no-store");
+                res.send("ok")
+            })
+            .rate_limit(&RateLimiter::new(100, day))
+            .deprecated(UNIX_EPOCH)
+            .sunset(SystemTime::now() + day)
+            .deprecation_link("https://docs.example/migrate");
+        let res = TestClient::new(&server)
+            .get("/stacked")
+            .header("Origin", "https://app.example")
+            .send();
+        assert_eq!(res.status(), 200);
+        assert_eq!(res.text(), "ok");
+        for name in [
+            "x-request-id",
+            "strict-transport-security",
+            "x-content-type-options",
+            "x-frame-options",
+            "referrer-policy",
+            "content-security-policy",
+            "access-control-allow-origin",
+            "access-control-allow-credentials",
+            "ratelimit-limit",
+            "ratelimit-remaining",
+            "ratelimit-reset",
+            "server-timing",
+            "x-response-time",
+            "deprecation",
+            "sunset",
+            "link",
+            "x-handler",
+            "cache-control",
+        ] {
+            assert!(res.header(name).is_some(), "{} missing", name);
+        }
+    }
+}
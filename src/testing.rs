@@ -0,0 +1,85 @@
+//! helpers shared by the crate's unit tests
+
+use std::sync::Barrier;
+use std::thread;
+
+#[cfg(not(any(feature = "jemalloc", feature = "mimalloc")))]
+pub(crate) use counting::allocations;
+
+/// How many threads `race` runs at once.
+pub(crate) const THREADS: usize = 8;
+
+// runs `f` on `THREADS` threads released together, giving each its number,
+// and returns what they returned in that order
+pub(crate) fn race<T: Send>(f: impl Fn(usize) -> T + Sync) -> Vec<T> {
+    let start = Barrier::new(THREADS);
+    thread::scope(|scope| {
+        let threads: Vec<_> = (0..THREADS)
+            .map(|n| {
+                let (f, start) = (&f, &start);
+                scope.spawn(move || {
+                    start.wait();
+                    f(n)
+                })
+            })
+            .collect();
+        threads.into_iter().map(|t| t.join().unwrap()).collect()
+    })
+}
+
+// another global allocator leaves nothing to count with
+#[cfg(not(any(feature = "jemalloc", feature = "mimalloc")))]
+mod counting {
+    use std::alloc::{GlobalAlloc, Layout, System};
+    use std::cell::Cell;
+
+    /// What a closure allocated on the current thread.
+    #[derive(Clone, Copy, Debug, PartialEq, Eq)]
+    pub(crate) struct Allocations {
+        pub(crate) count: usize,
+        pub(crate) bytes: usize,
+    }
+
+    // counts the current thread's allocations, so tests running alongside
+    // don't show up in them
+    struct Counting;
+
+    thread_local! {
+        static COUNT: Cell<usize> = const { Cell::new(0) };
+        static BYTES: Cell<usize> = const { Cell::new(0) };
+    }
+
+    unsafe impl GlobalAlloc for Counting {
+        unsafe fn alloc(&self, layout: Layout) -> *mut u8 {
+            COUNT.try_with(|n| n.set(n.get() + 1)).ok();
+            BYTES.try_with(|n| n.set(n.get() + layout.size())).ok();
+            System.alloc(layout)
+        }
+
+        unsafe fn dealloc(&self, ptr: *mut u8, layout: Layout) {
+            System.dealloc(ptr, layout)
+        }
+    }
+
+    #[global_allocator]
+    static COUNTING: Counting = Counting;
+
+    fn now() -> Allocations {
+        Allocations {
+            count: COUNT.with(Cell::get),
+            bytes: BYTES.with(Cell::get),
+        }
+    }
+
+    // what `f` allocates, not counting the drop of what it returns
+    pub(crate) fn allocations<T>(f: impl FnOnce() -> T) -> Allocations {
+        let before = now();
+        let result = f();
+        let after = now();
+        drop(result);
+        Allocations {
+            count: after.count - before.count,
+            bytes: after.bytes - before.bytes,
+        }
+    }
+}
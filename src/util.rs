@@ -0,0 +1,69 @@
+//! encodings shared by the protocol and authentication code
+
+// standard base64, padding optional, as in Basic credentials
+pub(crate) fn base64(s: &str) -> Option<Vec<u8>> {
+    decode(s, b'+', b'/')
+}
+
+// base64url, padding optional: HTTP2-Settings goes without, as do JWTs
+pub(crate) fn base64url(s: &str) -> Option<Vec<u8>> {
+    decode(s, b'-', b'_')
+}
+
+// the alphabets differ only in the last two characters
+fn decode(s: &str, c62: u8, c63: u8) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(s.len() * 3 / 4);
+    let mut acc = 0u32;
+    let mut bits = 0;
+    for c in s.trim().trim_end_matches('=').bytes() {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            _ if c == c62 => 62,
+            _ if c == c63 => 63,
+            _ => return None,
+        };
+        acc = acc << 6 | u32::from(value);
+        bits += 6;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((acc >> bits) as u8);
+            acc &= (1 << bits) - 1;
+        }
+    }
+    Some(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{base64, base64url};
+
+    #[test]
+    fn decodes_rfc_4648_vectors() {
+        let vectors = [
+            ("", ""),
+            ("Zg==", "f"),
+            ("Zm8=", "fo"),
+            ("Zm9v", "foo"),
+            ("Zm9vYg==", "foob"),
+            ("Zm9vYmE=", "fooba"),
+            ("Zm9vYmFy", "foobar"),
+        ];
+        for (encoded, decoded) in vectors {
+            assert_eq!(base64(encoded).as_deref(), Some(decoded.as_bytes()));
+            assert_eq!(base64url(encoded).as_deref(), Some(decoded.as_bytes()));
+        }
+        // padding is optional
+        assert_eq!(base64("Zm8").as_deref(), Some(&b"fo"[..]));
+    }
+
+    #[test]
+    fn alphabets_keep_to_their_own_characters() {
+        assert_eq!(base64("+/8=").as_deref(), Some(&[0xfb, 0xff][..]));
+        assert_eq!(base64url("-_8").as_deref(), Some(&[0xfb, 0xff][..]));
+        assert_eq!(base64("-_8="), None);
+        assert_eq!(base64url("+/8"), None);
+        assert_eq!(base64("Zm9v!"), None);
+    }
+}